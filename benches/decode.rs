@@ -0,0 +1,76 @@
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use mp4::{AudioObjectType, ChannelConfig, Mp4Sample, SampleFreqIndex};
+use redlux::{adts, Decoder, DecoderBuilder};
+use std::io::Cursor;
+
+const AAC_SAMPLES: [(&str, &str); 4] = [
+  ("aac_lc", "tests/samples/Simbai & Elke Bay - Energy.aac"),
+  ("he_aac_v1", "tests/samples/sine-he-aac-v1.aac"),
+  ("he_aac_v2", "tests/samples/sine-he-aac-v2.aac"),
+  ("aac_lc_5.1", "tests/samples/sine-5.1.aac"),
+];
+const M4A_SAMPLE: &str = "tests/samples/Simbai & Elke Bay - Energy.m4a";
+
+fn decode_aac(c: &mut Criterion) {
+  let mut group = c.benchmark_group("decode_aac");
+  for (name, path) in AAC_SAMPLES.iter() {
+    let bytes = std::fs::read(path).expect("Error reading sample");
+    let sample_count = Decoder::new_aac(Cursor::new(&bytes)).count();
+    group.throughput(Throughput::Elements(sample_count as u64));
+    group.bench_function(*name, |b| {
+      b.iter(|| Decoder::new_aac(Cursor::new(&bytes)).count())
+    });
+    group.bench_function(format!("{}_batch", name), |b| {
+      b.iter(|| {
+        let builder = DecoderBuilder::new().batch_mode();
+        let mut decoder = builder.open_aac(Cursor::new(&bytes)).unwrap();
+        let mut pcm = Vec::with_capacity(sample_count);
+        while decoder.next_chunk(&mut pcm).unwrap() > 0 {}
+        pcm
+      })
+    });
+  }
+  group.finish();
+}
+
+fn decode_m4a(c: &mut Criterion) {
+  let bytes = std::fs::read(M4A_SAMPLE).expect("Error reading sample");
+  let size = bytes.len() as u64;
+  let sample_count = Decoder::new_mpeg4(Cursor::new(&bytes), size)
+    .unwrap()
+    .count();
+  let mut group = c.benchmark_group("decode_m4a");
+  group.throughput(Throughput::Elements(sample_count as u64));
+  group.bench_function("aac_lc", |b| {
+    b.iter(|| {
+      Decoder::new_mpeg4(Cursor::new(&bytes), size)
+        .unwrap()
+        .count()
+    })
+  });
+  group.finish();
+}
+
+fn adts_header(c: &mut Criterion) {
+  let sample = Mp4Sample {
+    start_time: 0,
+    duration: 1024,
+    rendering_offset: 0,
+    is_sync: true,
+    bytes: mp4::Bytes::from(vec![0; 371]),
+  };
+  c.bench_function("construct_adts_header", |b| {
+    b.iter(|| {
+      adts::construct_adts_header(
+        AudioObjectType::AacLowComplexity,
+        SampleFreqIndex::Freq44100,
+        ChannelConfig::Stereo,
+        &sample,
+      )
+      .unwrap()
+    })
+  });
+}
+
+criterion_group!(benches, decode_aac, decode_m4a, adts_header);
+criterion_main!(benches);
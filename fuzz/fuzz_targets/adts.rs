@@ -0,0 +1,9 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use redlux::Decoder;
+use std::io::Cursor;
+
+fuzz_target!(|data: &[u8]| {
+  let decoder = Decoder::new_aac(Cursor::new(data));
+  for _ in decoder {}
+});
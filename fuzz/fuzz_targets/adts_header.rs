@@ -0,0 +1,32 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use mp4::{AudioObjectType, ChannelConfig, Mp4Sample, SampleFreqIndex};
+use redlux::adts;
+use std::convert::TryFrom;
+
+fuzz_target!(|data: &[u8]| {
+  let _ = adts::parse_header(data);
+  if data.len() < 3 {
+    return;
+  }
+  let object_type = match AudioObjectType::try_from(data[0]) {
+    Ok(object_type) => object_type,
+    Err(_) => return,
+  };
+  let sample_freq_index = match SampleFreqIndex::try_from(data[1]) {
+    Ok(sample_freq_index) => sample_freq_index,
+    Err(_) => return,
+  };
+  let channel_config = match ChannelConfig::try_from(data[2]) {
+    Ok(channel_config) => channel_config,
+    Err(_) => return,
+  };
+  let sample = Mp4Sample {
+    start_time: 0,
+    duration: 1024,
+    rendering_offset: 0,
+    is_sync: true,
+    bytes: mp4::Bytes::copy_from_slice(&data[3..]),
+  };
+  let _ = adts::construct_adts_header(object_type, sample_freq_index, channel_config, &sample);
+});
@@ -0,0 +1,11 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use redlux::Decoder;
+use std::io::Cursor;
+
+fuzz_target!(|data: &[u8]| {
+  let size = data.len() as u64;
+  if let Ok(decoder) = Decoder::new_mpeg4(Cursor::new(data), size) {
+    for _ in decoder {}
+  }
+});
@@ -1,54 +1,122 @@
-use mp4::{AudioObjectType, ChannelConfig, Mp4Sample, SampleFreqIndex};
-use std::ops::Range;
+//! ADTS headers, for remuxing MP4 samples or other raw AAC frames to AAC
+//! files. Everything but `raw` needs the `std` feature.
+pub mod raw;
+
+#[cfg(feature = "std")]
 use crate::Error;
+#[cfg(feature = "std")]
+use mp4::{AudioObjectType, ChannelConfig, Mp4Sample, SampleFreqIndex};
+#[cfg(feature = "std")]
+pub(crate) use raw::ADTS_HEADER_LENGTH;
+pub use raw::MAX_FRAME_LENGTH;
+#[cfg(feature = "std")]
+use std::convert::TryFrom;
+
+/// AAC profile to signal in an ADTS header
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Profile {
+  /// AAC-LC
+  Lc,
+  /// HE-AAC, which is AAC-LC with SBR
+  HeAac,
+  /// HE-AAC v2, which is HE-AAC with PS
+  HeAacV2,
+}
 
-fn get_bits(byte: u16, range: Range<u16>) -> u16 {
-  let shaved_left = byte << range.start - 1;
-  let moved_back = shaved_left >> range.start - 1;
-  let shave_right = moved_back >> 16 - range.end;
-  return shave_right;
+#[cfg(feature = "std")]
+impl Profile {
+  /// Audio object type, like 2 for AAC-LC
+  pub fn object_type(self) -> u8 {
+    match self {
+      Profile::Lc => 2,
+      Profile::HeAac => 5,
+      Profile::HeAacV2 => 29,
+    }
+  }
 }
 
-fn get_bits_u8(byte: u8, range: Range<u8>) -> u8 {
-  let shaved_left = byte << range.start - 1;
-  let moved_back = shaved_left >> range.start - 1;
-  let shave_right = moved_back >> 8 - range.end;
-  return shave_right;
+/// Construct a 7 byte ADTS header for a raw data block of `payload_length`
+/// bytes, without any mp4 crate types. `sample_rate` is the output sample
+/// rate in Hz, and `channels` has to be 1-6 or 8. HE-AAC profiles are
+/// signaled as AAC-LC at the core sample rate, which is half the output
+/// sample rate, and the decoder detects SBR. The core sample rate has to be
+/// one of the 13 standard rates.
+#[cfg(feature = "std")]
+pub fn construct_header(
+  profile: Profile,
+  sample_rate: u32,
+  channels: u16,
+  payload_length: usize,
+) -> Result<Vec<u8>, Error> {
+  let core_sample_rate = match profile {
+    Profile::Lc => sample_rate,
+    Profile::HeAac | Profile::HeAacV2 => sample_rate / 2,
+  };
+  let result = raw::sample_freq_index(core_sample_rate)
+    .and_then(|freq_index| Ok((freq_index, raw::channel_config(channels)?)))
+    .and_then(|(freq_index, channel_config)| {
+      raw::write_header_for_length(
+        profile.object_type(),
+        freq_index,
+        channel_config,
+        payload_length,
+      )
+    });
+  result.map_err(|err| Error::from_adts(err, AudioObjectType::AacLowComplexity))
 }
 
+#[cfg(feature = "std")]
 pub fn construct_adts_header(
   object_type: AudioObjectType,
   sample_freq_index: SampleFreqIndex,
   channel_config: ChannelConfig,
   sample: &Mp4Sample,
 ) -> Result<Vec<u8>, Error> {
-  // ADTS header wiki reference: https://wiki.multimedia.cx/index.php/ADTS#:~:text=Audio%20Data%20Transport%20Stream%20(ADTS,to%20stream%20audio%2C%20usually%20AAC.
-
-  // byte7 and byte9 not included without CRC
-  let adts_header_length = 7;
-
-  // AAAA_AAAA
-  let byte0 = 0b1111_1111;
-
-  // AAAA_BCCD
-  // D: Only support 1 (without CRC)
-  let byte1 = 0b1111_0001;
-
-  // EEFF_FFGH
-  let mut byte2 = 0b0000_0000;
-  let object_type = match object_type {
-    AudioObjectType::AacLowComplexity => 2,
-    // Audio object types 5 (SBR) and 29 (PS) are coerced to type 2 (AAC-LC).
-    // The decoder will have to detect SBR/PS. This is called "Implicit
-    // Signaling" and it's the only option for ADTS.
-    AudioObjectType::SpectralBandReplication => 2, // SBR, needed to support HE-AAC v1
-    AudioObjectType::ParametricStereo => 2, // PS, needed to support HE-AAC v2
-    aot => return Err(Error::UnsupportedObjectType(aot)),
-  };
-  let adts_object_type = object_type - 1;
-  byte2 = (byte2 << 2) | adts_object_type; // EE
+  write_header(
+    object_type,
+    sample_freq_index,
+    channel_config,
+    sample,
+    false,
+  )
+}
 
-  let sample_freq_index = match sample_freq_index {
+/// Construct a 9 byte ADTS header with a CRC of the sample. See
+/// `raw::write_header` for what the CRC covers. Samples using tools beyond
+/// AAC-LC, such as coupling channels, return `Error::CrcPayloadError`.
+#[cfg(feature = "std")]
+pub fn construct_adts_header_with_crc(
+  object_type: AudioObjectType,
+  sample_freq_index: SampleFreqIndex,
+  channel_config: ChannelConfig,
+  sample: &Mp4Sample,
+) -> Result<Vec<u8>, Error> {
+  write_header(object_type, sample_freq_index, channel_config, sample, true)
+}
+
+#[cfg(feature = "std")]
+fn write_header(
+  object_type: AudioObjectType,
+  sample_freq_index: SampleFreqIndex,
+  channel_config: ChannelConfig,
+  sample: &Mp4Sample,
+  crc: bool,
+) -> Result<Vec<u8>, Error> {
+  let header = raw::write_header(
+    object_type as u8,
+    freq_index(sample_freq_index) as u8,
+    channel_config as u8,
+    &sample.bytes,
+    crc,
+  );
+  header.map_err(|err| Error::from_adts(err, object_type))
+}
+
+/// Sampling frequency index used in ADTS headers and AudioSpecificConfig
+#[cfg(feature = "std")]
+pub(crate) fn freq_index(sample_freq_index: SampleFreqIndex) -> u32 {
+  match sample_freq_index {
     SampleFreqIndex::Freq96000 => 0,
     SampleFreqIndex::Freq88200 => 1,
     SampleFreqIndex::Freq64000 => 2,
@@ -64,43 +132,223 @@ pub fn construct_adts_header(
     SampleFreqIndex::Freq7350 => 12,
     // 13-14 = reserved
     // 15 = explicit frequency (forbidden in adts)
-  };
-  byte2 = (byte2 << 4) | sample_freq_index; // FFFF
-  byte2 = (byte2 << 1) | 0b1; // G
-
-  let channel_config = match channel_config {
-    // 0 = for when channel config is sent via an inband PCE
-    ChannelConfig::Mono => 1,
-    ChannelConfig::Stereo => 2,
-    ChannelConfig::Three => 3,
-    ChannelConfig::Four => 4,
-    ChannelConfig::Five => 5,
-    ChannelConfig::FiveOne => 6,
-    ChannelConfig::SevenOne => 7,
-    // 8-15 = reserved
-  };
-  byte2 = (byte2 << 1) | get_bits_u8(channel_config, 6..6); // H
+  }
+}
+
+/// Fields of an ADTS header
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AdtsHeader {
+  /// Audio object type, from the profile field
+  pub object_type: AudioObjectType,
+  pub sample_freq_index: SampleFreqIndex,
+  /// Channel config, where 0 means it's defined by a program config element
+  pub channel_config: u8,
+  /// Length of the frame, including the header
+  pub frame_length: usize,
+  /// Whether the header has a CRC
+  pub crc: bool,
+  /// Number of AAC frames in the ADTS frame
+  pub raw_data_blocks: u8,
+}
+
+#[cfg(feature = "std")]
+impl AdtsHeader {
+  /// Length of the header, including the CRC if there is one
+  pub fn header_length(&self) -> usize {
+    match self.crc {
+      true => raw::ADTS_CRC_HEADER_LENGTH,
+      false => ADTS_HEADER_LENGTH,
+    }
+  }
+}
+
+/// Parse the ADTS header at the start of `bytes`. Returns
+/// `Error::AdtsHeaderError` if there's no valid header, or if `bytes` is
+/// shorter than 7 bytes.
+#[cfg(feature = "std")]
+pub fn parse_header(bytes: &[u8]) -> Result<AdtsHeader, Error> {
+  let header = raw::parse_header(bytes).or(Err(Error::AdtsHeaderError))?;
+  let object_type = AudioObjectType::try_from(header.object_type);
+  let sample_freq_index = SampleFreqIndex::try_from(header.sample_freq_index);
+  Ok(AdtsHeader {
+    object_type: object_type.or(Err(Error::AdtsHeaderError))?,
+    sample_freq_index: sample_freq_index.or(Err(Error::AdtsHeaderError))?,
+    channel_config: header.channel_config,
+    frame_length: header.frame_length,
+    crc: header.crc,
+    raw_data_blocks: header.raw_data_blocks,
+  })
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+  use super::raw::ADTS_CRC_HEADER_LENGTH;
+  use super::*;
+
+  fn sample(len: usize) -> Mp4Sample {
+    Mp4Sample {
+      start_time: 0,
+      duration: 1024,
+      rendering_offset: 0,
+      is_sync: true,
+      bytes: mp4::Bytes::from(vec![0; len]),
+    }
+  }
+
+  #[test]
+  fn stereo_header() {
+    let header = construct_adts_header(
+      AudioObjectType::AacLowComplexity,
+      SampleFreqIndex::Freq44100,
+      ChannelConfig::Stereo,
+      &sample(281),
+    )
+    .unwrap();
+    // frame length 288
+    assert_eq!(header, vec![0xFF, 0xF1, 0x52, 0xBC, 0x24, 0x1F, 0xFC]);
+  }
+
+  #[test]
+  fn seven_one_header() {
+    let header = construct_adts_header(
+      AudioObjectType::SpectralBandReplication,
+      SampleFreqIndex::Freq96000,
+      ChannelConfig::SevenOne,
+      &sample(8184),
+    )
+    .unwrap();
+    // channel config 7 spans bytes 2 and 3, frame length 8191 spans bytes 3-5
+    assert_eq!(header, vec![0xFF, 0xF1, 0x43, 0xFF, 0xFF, 0xFF, 0xFC]);
+  }
+
+  #[test]
+  fn max_frame_length() {
+    for len in [0, 1, 8184] {
+      let header = construct_adts_header(
+        AudioObjectType::AacLowComplexity,
+        SampleFreqIndex::Freq7350,
+        ChannelConfig::Mono,
+        &sample(len),
+      )
+      .unwrap();
+      assert_eq!(header.len(), ADTS_HEADER_LENGTH);
+    }
+    for len in [8185, 65535, 70000] {
+      let result = construct_adts_header(
+        AudioObjectType::AacLowComplexity,
+        SampleFreqIndex::Freq7350,
+        ChannelConfig::Mono,
+        &sample(len),
+      );
+      assert!(matches!(result, Err(Error::FrameTooLarge(l)) if l == len));
+    }
+    let result = construct_adts_header_with_crc(
+      AudioObjectType::AacLowComplexity,
+      SampleFreqIndex::Freq7350,
+      ChannelConfig::Mono,
+      &sample(8183),
+    );
+    assert!(matches!(result, Err(Error::FrameTooLarge(8183))));
+  }
 
-  // HHIJ_KLMM
-  let mut byte3 = 0b0000_0000;
-  byte3 = (byte3 << 2) | get_bits_u8(channel_config, 7..8); // HH
-  byte3 = (byte3 << 4) | 0b1111; // IJKL
+  #[test]
+  fn unsupported_object_type() {
+    let result = construct_adts_header(
+      AudioObjectType::AacMain,
+      SampleFreqIndex::Freq44100,
+      ChannelConfig::Stereo,
+      &sample(100),
+    );
+    assert!(matches!(result, Err(Error::UnsupportedObjectType(_))));
+  }
 
-  let frame_length = adts_header_length + sample.bytes.len() as u16;
-  byte3 = (byte3 << 2) | get_bits(frame_length, 3..5) as u8; // MM
+  #[test]
+  fn crc_header() {
+    // silent single channel element followed by an end element
+    let mut silent = sample(0);
+    silent.bytes = mp4::Bytes::from_static(&[0, 0, 0, 0b111]);
+    let header = construct_adts_header_with_crc(
+      AudioObjectType::AacLowComplexity,
+      SampleFreqIndex::Freq44100,
+      ChannelConfig::Mono,
+      &silent,
+    )
+    .unwrap();
+    assert_eq!(header.len(), ADTS_CRC_HEADER_LENGTH);
+    // protection absent
+    assert_eq!(header[1] & 1, 0);
+    // frame length 13
+    assert_eq!(&header[3..6], &[0x7C, 0x01, 0xBF]);
 
-  // MMMM_MMMM
-  let byte4 = get_bits(frame_length, 6..13) as u8;
+    // zeros parse as an endless run of elements
+    let result = construct_adts_header_with_crc(
+      AudioObjectType::AacLowComplexity,
+      SampleFreqIndex::Freq44100,
+      ChannelConfig::Mono,
+      &sample(100),
+    );
+    assert!(matches!(result, Err(Error::CrcPayloadError)));
+  }
 
-  // MMMO_OOOO
-  let mut byte5 = 0b0000_0000;
-  byte5 = (byte5 << 3) | get_bits(frame_length, 14..16) as u8;
-  byte5 = (byte5 << 5) | 0b11111; // OOOOO
+  #[test]
+  fn header_without_mp4_types() {
+    let header = construct_header(Profile::Lc, 44100, 2, 281).unwrap();
+    assert_eq!(header, vec![0xFF, 0xF1, 0x52, 0xBC, 0x24, 0x1F, 0xFC]);
+    let he_aac = construct_header(Profile::HeAac, 88200, 2, 281).unwrap();
+    assert_eq!(he_aac, header);
+    let mp4_header = construct_adts_header(
+      AudioObjectType::SpectralBandReplication,
+      SampleFreqIndex::Freq48000,
+      ChannelConfig::SevenOne,
+      &sample(1000),
+    );
+    let header = construct_header(Profile::HeAacV2, 96000, 8, 1000);
+    assert_eq!(header.unwrap(), mp4_header.unwrap());
+    let result = construct_header(Profile::Lc, 44000, 2, 100);
+    assert!(matches!(result, Err(Error::InvalidConfig(_))));
+    let result = construct_header(Profile::Lc, 44100, 7, 100);
+    assert!(matches!(result, Err(Error::InvalidConfig(_))));
+    let result = construct_header(Profile::Lc, 44100, 2, 8185);
+    assert!(matches!(result, Err(Error::FrameTooLarge(8185))));
+  }
 
-  // OOOO_OOPP
-  let mut byte6 = 0b0000_0000;
-  byte6 = (byte6 << 6) | 0b111111; // OOOOOO
-  byte6 = (byte6 << 2) | 0b00; // PP
+  #[test]
+  fn parse_constructed_header() {
+    let header = construct_adts_header(
+      AudioObjectType::SpectralBandReplication,
+      SampleFreqIndex::Freq48000,
+      ChannelConfig::FiveOne,
+      &sample(1000),
+    )
+    .unwrap();
+    let parsed = parse_header(&header).unwrap();
+    assert_eq!(
+      parsed,
+      AdtsHeader {
+        object_type: AudioObjectType::AacLowComplexity,
+        sample_freq_index: SampleFreqIndex::Freq48000,
+        channel_config: 6,
+        frame_length: 1007,
+        crc: false,
+        raw_data_blocks: 1,
+      }
+    );
+    assert_eq!(parsed.header_length(), 7);
+  }
 
-  return Ok(vec![byte0, byte1, byte2, byte3, byte4, byte5, byte6])
+  #[test]
+  fn parse_invalid_header() {
+    let header = [0xFF, 0xF1, 0x50, 0x80, 0x02, 0x1F, 0xFC];
+    assert!(parse_header(&header).is_ok());
+    assert!(parse_header(&header[..6]).is_err());
+    // sync word
+    assert!(parse_header(&[0xFF, 0xE1, 0x50, 0x80, 0x02, 0x1F, 0xFC]).is_err());
+    // layer
+    assert!(parse_header(&[0xFF, 0xF3, 0x50, 0x80, 0x02, 0x1F, 0xFC]).is_err());
+    // sampling frequency index 13
+    assert!(parse_header(&[0xFF, 0xF1, 0x74, 0x80, 0x02, 0x1F, 0xFC]).is_err());
+    // frame length 7
+    assert!(parse_header(&[0xFF, 0xF1, 0x50, 0x80, 0x00, 0xFF, 0xFC]).is_err());
+  }
 }
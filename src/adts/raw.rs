@@ -0,0 +1,405 @@
+//! ADTS headers and AudioSpecificConfigs from plain integers. Unlike the rest
+//! of the crate, this only needs `alloc`, so it's available without the `std`
+//! feature, like for embedded firmware.
+use crate::syntax::{self, BitReader, ID_CPE, ID_DSE, ID_LFE, ID_PCE, ID_SCE};
+use alloc::vec::Vec;
+use core::fmt;
+
+/// Header length without CRC
+pub const ADTS_HEADER_LENGTH: usize = 7;
+/// Header length with CRC
+pub const ADTS_CRC_HEADER_LENGTH: usize = 9;
+/// Largest frame length the 13 bit frame length field can hold, including the
+/// header
+pub const MAX_FRAME_LENGTH: usize = 8191;
+/// Number of bits of a channel element protected by the CRC
+const ELEMENT_CRC_BITS: usize = 192;
+/// Number of bits of the second channel of a channel pair element protected
+/// by the CRC
+const SECOND_CHANNEL_CRC_BITS: usize = 128;
+
+/// ADTS error
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AdtsError {
+  /// No valid ADTS header
+  InvalidHeader,
+  /// Payload too large to fit in an ADTS frame, with its length
+  FrameTooLarge(usize),
+  /// Audio object type that ADTS can't signal
+  UnsupportedObjectType(u8),
+  /// The payload couldn't be parsed to compute the CRC
+  CrcPayload,
+  /// Sample rate in Hz that doesn't have a sampling frequency index
+  UnsupportedSampleRate(u32),
+  /// Channel count that doesn't have a channel config
+  UnsupportedChannels(u16),
+}
+
+impl fmt::Display for AdtsError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      AdtsError::InvalidHeader => write!(f, "Invalid ADTS header"),
+      AdtsError::FrameTooLarge(len) => write!(f, "Payload of {} bytes is too large", len),
+      AdtsError::UnsupportedObjectType(aot) => write!(f, "Unsupported object type {}", aot),
+      AdtsError::CrcPayload => write!(f, "Unable to parse payload for CRC"),
+      AdtsError::UnsupportedSampleRate(rate) => write!(f, "Unsupported sample rate {} Hz", rate),
+      AdtsError::UnsupportedChannels(channels) => {
+        write!(f, "Unsupported channel count {}", channels)
+      }
+    }
+  }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for AdtsError {}
+
+/// Writes values bit by bit, most significant bit first
+struct BitWriter {
+  bytes: Vec<u8>,
+  bit_len: usize,
+}
+
+impl BitWriter {
+  fn with_capacity(bytes: usize) -> Self {
+    BitWriter {
+      bytes: Vec::with_capacity(bytes),
+      bit_len: 0,
+    }
+  }
+  /// Write the lowest `bits` bits of `value`. Higher bits are ignored.
+  fn write(&mut self, value: u32, bits: u32) {
+    for i in (0..bits.min(32)).rev() {
+      let bit_offset = self.bit_len % 8;
+      if bit_offset == 0 {
+        self.bytes.push(0);
+      }
+      let bit = ((value >> i) & 1) as u8;
+      if let Some(byte) = self.bytes.last_mut() {
+        *byte |= bit << (7 - bit_offset);
+      }
+      self.bit_len += 1;
+    }
+  }
+  fn into_bytes(self) -> Vec<u8> {
+    self.bytes
+  }
+}
+
+/// CRC-16 with the polynomial 0x8005, as used by ADTS
+struct Crc16(u16);
+
+impl Crc16 {
+  fn new() -> Self {
+    Crc16(0xFFFF)
+  }
+  fn update_bit(&mut self, bit: bool) {
+    let flag = (self.0 & 0x8000 != 0) != bit;
+    self.0 <<= 1;
+    if flag {
+      self.0 ^= 0x8005;
+    }
+  }
+  /// Update with `bits` bits of `bytes`, starting at bit `start`
+  fn update(&mut self, bytes: &[u8], start: usize, bits: usize) {
+    for i in start..start + bits {
+      self.update_bit(bytes[i / 8] & (0x80 >> (i % 8)) != 0);
+    }
+  }
+  /// Update with a region of at most `max_bits` bits. Regions shorter than
+  /// that are padded with zeros.
+  fn update_region(&mut self, bytes: &[u8], start: usize, end: usize, max_bits: usize) {
+    let bits = (end - start).min(max_bits);
+    self.update(bytes, start, bits);
+    for _ in bits..max_bits {
+      self.update_bit(false);
+    }
+  }
+}
+
+/// Object type to signal for an audio object type. SBR (5) and PS (29) are
+/// coerced to AAC-LC (2), so the decoder has to detect them. This is called
+/// "implicit signaling", and it's the only option for ADTS.
+fn core_object_type(object_type: u8) -> Result<u8, AdtsError> {
+  match object_type {
+    2 | 5 | 29 => Ok(2),
+    aot => Err(AdtsError::UnsupportedObjectType(aot)),
+  }
+}
+
+/// Construct an ADTS header for a raw data block. `sample_freq_index` and
+/// `channel_config` are the 4 and 3 bit fields, and `object_type` is the
+/// audio object type, like 2 for AAC-LC. With `crc`, the header is 9 bytes and
+/// has a CRC of the payload, as specified in ISO/IEC 13818-7. The CRC covers
+/// the header, the first 192 bits of each channel element, the first 128 bits
+/// of the second channel of channel pair elements, and all data stream and
+/// program config elements. Finding those requires parsing the payload, so
+/// payloads using tools beyond AAC-LC, such as coupling channels, return
+/// `AdtsError::CrcPayload`.
+pub fn write_header(
+  object_type: u8,
+  sample_freq_index: u8,
+  channel_config: u8,
+  payload: &[u8],
+  crc: bool,
+) -> Result<Vec<u8>, AdtsError> {
+  // ADTS header wiki reference: https://wiki.multimedia.cx/index.php/ADTS#:~:text=Audio%20Data%20Transport%20Stream%20(ADTS,to%20stream%20audio%2C%20usually%20AAC.
+  // Letters refer to the fields on that page. byte7 and byte8 (Q) are only
+  // included with CRC.
+  let header_length = match crc {
+    true => ADTS_CRC_HEADER_LENGTH,
+    false => ADTS_HEADER_LENGTH,
+  };
+  let mut header = write_fields(
+    object_type,
+    sample_freq_index,
+    channel_config,
+    header_length,
+    payload.len(),
+  )?;
+  if crc {
+    let crc = payload_crc(&header, payload, sample_freq_index)?;
+    header.extend_from_slice(&crc.to_be_bytes());
+  }
+  Ok(header)
+}
+
+/// Construct a 7 byte ADTS header without CRC for a raw data block of
+/// `payload_length` bytes, like `write_header` but without needing the
+/// payload
+pub fn write_header_for_length(
+  object_type: u8,
+  sample_freq_index: u8,
+  channel_config: u8,
+  payload_length: usize,
+) -> Result<Vec<u8>, AdtsError> {
+  write_fields(
+    object_type,
+    sample_freq_index,
+    channel_config,
+    ADTS_HEADER_LENGTH,
+    payload_length,
+  )
+}
+
+/// Write the header fields before the CRC
+fn write_fields(
+  object_type: u8,
+  sample_freq_index: u8,
+  channel_config: u8,
+  header_length: usize,
+  payload_length: usize,
+) -> Result<Vec<u8>, AdtsError> {
+  let crc = header_length == ADTS_CRC_HEADER_LENGTH;
+  let mut writer = BitWriter::with_capacity(header_length);
+
+  writer.write(0xFFF, 12); // A: syncword
+  writer.write(0, 1); // B: MPEG-4
+  writer.write(0, 2); // C: layer
+  writer.write(!crc as u32, 1); // D: protection absent
+  writer.write(core_object_type(object_type)? as u32 - 1, 2); // E
+                                                              // 13-14 = reserved, 15 = explicit frequency (forbidden in adts)
+  writer.write(sample_freq_index as u32, 4); // F
+  writer.write(1, 1); // G
+                      // 0 = for when channel config is sent via an inband PCE, 8-15 = reserved
+  writer.write(channel_config as u32, 3); // H
+  writer.write(0b1111, 4); // IJKL
+
+  // computed as usize so large payloads can't overflow
+  let frame_length = header_length.saturating_add(payload_length);
+  if frame_length > MAX_FRAME_LENGTH {
+    // a raw data block can't be split across ADTS frames
+    return Err(AdtsError::FrameTooLarge(payload_length));
+  }
+  writer.write(frame_length as u32, 13); // M
+  writer.write(0x7FF, 11); // O: buffer fullness, 0x7FF for VBR
+  writer.write(0, 2); // P: one AAC frame per ADTS frame
+  Ok(writer.into_bytes())
+}
+
+/// CRC of a header without its CRC field and the protected parts of the
+/// payload
+fn payload_crc(header: &[u8], payload: &[u8], sample_freq_index: u8) -> Result<u16, AdtsError> {
+  let mut reader = BitReader::new(payload);
+  let elements = syntax::parse_raw_data_block(&mut reader, sample_freq_index as usize)
+    .ok_or(AdtsError::CrcPayload)?;
+
+  let mut crc = Crc16::new();
+  crc.update(header, 0, ADTS_HEADER_LENGTH * 8);
+  // element IDs aren't protected
+  for element in elements {
+    match (element.id, element.second_channel) {
+      (ID_SCE | ID_LFE, _) => {
+        crc.update_region(payload, element.start, element.end, ELEMENT_CRC_BITS);
+      }
+      (ID_CPE, Some(second_channel)) => {
+        crc.update_region(payload, element.start, element.end, ELEMENT_CRC_BITS);
+        crc.update_region(
+          payload,
+          second_channel,
+          element.end,
+          SECOND_CHANNEL_CRC_BITS,
+        );
+      }
+      (ID_DSE | ID_PCE, _) => crc.update(payload, element.start, element.end - element.start),
+      _ => {}
+    }
+  }
+  Ok(crc.0)
+}
+
+/// Fields of an ADTS header as plain integers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RawHeader {
+  /// Audio object type, from the profile field
+  pub object_type: u8,
+  /// Sampling frequency index from 0 to 12
+  pub sample_freq_index: u8,
+  /// Channel config, where 0 means it's defined by a program config element
+  pub channel_config: u8,
+  /// Length of the frame, including the header
+  pub frame_length: usize,
+  /// Whether the header has a CRC
+  pub crc: bool,
+  /// Number of AAC frames in the ADTS frame
+  pub raw_data_blocks: u8,
+}
+
+impl RawHeader {
+  /// Length of the header, including the CRC if there is one
+  pub fn header_length(&self) -> usize {
+    match self.crc {
+      true => ADTS_CRC_HEADER_LENGTH,
+      false => ADTS_HEADER_LENGTH,
+    }
+  }
+}
+
+/// Parse the ADTS header at the start of `bytes`. Returns
+/// `AdtsError::InvalidHeader` if there's no valid header, or if `bytes` is
+/// shorter than 7 bytes.
+pub fn parse_header(bytes: &[u8]) -> Result<RawHeader, AdtsError> {
+  let bytes = bytes
+    .get(..ADTS_HEADER_LENGTH)
+    .ok_or(AdtsError::InvalidHeader)?;
+  let mut reader = BitReader::new(bytes);
+  let mut read = |bits| reader.read(bits).ok_or(AdtsError::InvalidHeader);
+  let syncword = read(12)?;
+  read(1)?; // B: MPEG version
+  let layer = read(2)?;
+  if syncword != 0xFFF || layer != 0 {
+    return Err(AdtsError::InvalidHeader);
+  }
+  let protection_absent = read(1)?;
+  let object_type = read(2)? as u8 + 1;
+  let sample_freq_index = read(4)? as u8;
+  if sample_freq_index > 12 {
+    return Err(AdtsError::InvalidHeader);
+  }
+  read(1)?; // G: private bit
+  let channel_config = read(3)? as u8;
+  read(4)?; // IJKL
+  let frame_length = read(13)? as usize;
+  read(11)?; // O: buffer fullness
+  let raw_data_blocks = read(2)? as u8 + 1;
+
+  let header = RawHeader {
+    object_type,
+    sample_freq_index,
+    channel_config,
+    frame_length,
+    crc: protection_absent == 0,
+    raw_data_blocks,
+  };
+  if header.frame_length <= header.header_length() {
+    return Err(AdtsError::InvalidHeader);
+  }
+  Ok(header)
+}
+
+/// Sampling frequency index of a sample rate in Hz, used in ADTS headers and
+/// AudioSpecificConfigs. Only the 13 standard rates have one.
+pub fn sample_freq_index(sample_rate: u32) -> Result<u8, AdtsError> {
+  const RATES: [u32; 13] = [
+    96000, 88200, 64000, 48000, 44100, 32000, 24000, 22050, 16000, 12000, 11025, 8000, 7350,
+  ];
+  let index = RATES.iter().position(|&rate| rate == sample_rate);
+  index
+    .map(|index| index as u8)
+    .ok_or(AdtsError::UnsupportedSampleRate(sample_rate))
+}
+
+/// Channel config of a channel count, in the default MPEG-4 channel order.
+/// 7.1 is config 7, and other counts above 6 need a program config element.
+pub fn channel_config(channels: u16) -> Result<u8, AdtsError> {
+  match channels {
+    1..=6 => Ok(channels as u8),
+    8 => Ok(7),
+    _ => Err(AdtsError::UnsupportedChannels(channels)),
+  }
+}
+
+/// Construct a 2 byte AudioSpecificConfig, like for an MP4 track or for
+/// configuring a decoder with raw access units. SBR and PS are left to be
+/// detected by the decoder.
+pub fn audio_specific_config(
+  object_type: u8,
+  sample_freq_index: u8,
+  channel_config: u8,
+) -> Result<[u8; 2], AdtsError> {
+  let object_type = core_object_type(object_type)? as u16;
+  let config = (object_type << 11)
+    | ((sample_freq_index as u16 & 0xF) << 7)
+    | ((channel_config as u16 & 0xF) << 3);
+  Ok(config.to_be_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use alloc::vec;
+
+  #[test]
+  fn bit_writer() {
+    let mut writer = BitWriter::with_capacity(2);
+    writer.write(0b101, 3);
+    writer.write(0b1_1110_0001, 9);
+    writer.write(u32::MAX, 0);
+    writer.write(0b11, 1);
+    assert_eq!(writer.into_bytes(), vec![0b1011_1110, 0b0001_1000]);
+  }
+
+  #[test]
+  fn raw_header() {
+    let header = write_header(5, 4, 2, &[0; 281], false).unwrap();
+    assert_eq!(header, vec![0xFF, 0xF1, 0x52, 0xBC, 0x24, 0x1F, 0xFC]);
+    let parsed = parse_header(&header).unwrap();
+    assert_eq!(parsed.object_type, 2);
+    assert_eq!(parsed.sample_freq_index, 4);
+    assert_eq!(parsed.channel_config, 2);
+    assert_eq!(parsed.frame_length, 288);
+    assert_eq!(
+      write_header(1, 4, 2, &[], false),
+      Err(AdtsError::UnsupportedObjectType(1))
+    );
+    assert_eq!(audio_specific_config(29, 4, 2), Ok([0x12, 0x10]));
+  }
+
+  #[test]
+  fn header_for_length() {
+    let header = write_header_for_length(2, 4, 2, 281).unwrap();
+    assert_eq!(header, write_header(2, 4, 2, &[0; 281], false).unwrap());
+    assert_eq!(
+      write_header_for_length(2, 4, 2, usize::MAX),
+      Err(AdtsError::FrameTooLarge(usize::MAX))
+    );
+    assert_eq!(sample_freq_index(44100), Ok(4));
+    assert_eq!(sample_freq_index(7350), Ok(12));
+    assert_eq!(
+      sample_freq_index(44000),
+      Err(AdtsError::UnsupportedSampleRate(44000))
+    );
+    assert_eq!(channel_config(2), Ok(2));
+    assert_eq!(channel_config(8), Ok(7));
+    assert_eq!(channel_config(7), Err(AdtsError::UnsupportedChannels(7)));
+  }
+}
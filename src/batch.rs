@@ -0,0 +1,141 @@
+//! Decode or scan many files concurrently
+use crate::probe::{self, AudioInfo};
+use crate::{boxes, export, Decoder, Error, Format, Metadata};
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+/// Decoded PCM of a whole file
+pub struct DecodedFile {
+  pub channels: u16,
+  pub sample_rate: u32,
+  /// Interleaved samples
+  pub samples: Vec<i16>,
+}
+
+/// Info about a file for a music library, from `scan`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ScannedFile {
+  /// Audio tracks, like from `probe::probe`
+  pub tracks: Vec<AudioInfo>,
+  /// None for AAC input and MP4 files without tags
+  pub metadata: Option<Metadata>,
+  /// Duration of the track that plays by default
+  pub duration: Option<Duration>,
+}
+
+fn open_file(path: &Path) -> Result<Decoder<File>, Error> {
+  let builder = crate::DecoderBuilder::new().batch_mode().fixed_channels();
+  builder.open_path(path)
+}
+
+/// Run `job` for every path, spread over `threads` threads. Results are in
+/// the same order as `paths`.
+fn run<P, T, F>(paths: &[P], threads: usize, job: F) -> Vec<T>
+where
+  P: AsRef<Path> + Sync,
+  T: Send,
+  F: Fn(&Path) -> T + Sync,
+{
+  let next_index = AtomicUsize::new(0);
+  let threads = threads.clamp(1, paths.len().max(1));
+  let mut results: Vec<(usize, T)> = thread::scope(|scope| {
+    let handles: Vec<_> = (0..threads)
+      .map(|_| {
+        scope.spawn(|| {
+          let mut results = Vec::new();
+          loop {
+            let index = next_index.fetch_add(1, Ordering::Relaxed);
+            match paths.get(index) {
+              Some(path) => results.push((index, job(path.as_ref()))),
+              None => return results,
+            }
+          }
+        })
+      })
+      .collect();
+    handles
+      .into_iter()
+      .flat_map(|handle| handle.join().expect("Batch thread panicked"))
+      .collect()
+  });
+  results.sort_by_key(|(index, _)| *index);
+  results.into_iter().map(|(_, result)| result).collect()
+}
+
+/// Decode a file to PCM
+pub fn decode_file(path: &Path) -> Result<DecodedFile, Error> {
+  let mut decoder = open_file(path)?;
+  let (channels, sample_rate) = (decoder.channels(), decoder.sample_rate());
+  let mut samples = Vec::new();
+  while decoder.next_chunk(&mut samples)? > 0 {}
+  Ok(DecodedFile {
+    channels,
+    sample_rate,
+    samples,
+  })
+}
+
+/// Decode files to PCM using `threads` threads, with one decoder per thread.
+/// Results are in the same order as `paths`.
+pub fn decode_files<P>(paths: &[P], threads: usize) -> Vec<Result<DecodedFile, Error>>
+where
+  P: AsRef<Path> + Sync,
+{
+  run(paths, threads, decode_file)
+}
+
+/// Decode files to WAV files in `out_dir` using `threads` threads. Each WAV
+/// file is named after the input file. Returns the paths of the WAV files, in
+/// the same order as `paths`.
+pub fn decode_files_to_wav<P, Q>(
+  paths: &[P],
+  out_dir: Q,
+  threads: usize,
+) -> Vec<Result<PathBuf, Error>>
+where
+  P: AsRef<Path> + Sync,
+  Q: AsRef<Path>,
+{
+  let out_dir = out_dir.as_ref();
+  run(paths, threads, |path| {
+    let mut decoder = open_file(path)?;
+    let mut file_name = path.file_stem().unwrap_or(path.as_os_str()).to_os_string();
+    file_name.push(".wav");
+    let out_path = out_dir.join(file_name);
+    let out_file = File::create(&out_path).map_err(Error::WriterError)?;
+    export::to_wav(&mut decoder, BufWriter::new(out_file))?;
+    Ok(out_path)
+  })
+}
+
+/// Get the audio tracks, tags and duration of a file
+pub fn scan_file(path: &Path) -> Result<ScannedFile, Error> {
+  let mut file = File::open(path).map_err(Error::ReaderError)?;
+  let size = file.metadata().map_err(Error::ReaderError)?.len();
+  let tracks = probe::probe(&mut file, size)?;
+  file.seek(SeekFrom::Start(0)).map_err(Error::ReaderError)?;
+  let moov = match Format::detect(&mut file).map_err(Error::ReaderError)? {
+    Format::Mp4 => boxes::read_moov(&mut file, size).map_err(Error::ReaderError)?,
+    Format::Aac => None,
+  };
+  let default_track = probe::default_track(&tracks).or(tracks.first());
+  Ok(ScannedFile {
+    duration: default_track.and_then(|track| track.duration),
+    metadata: moov.as_deref().and_then(Metadata::from_moov),
+    tracks,
+  })
+}
+
+/// Scan files using `threads` threads, like for importing a music library.
+/// Only the first frame of each track is decoded, and AAC files are scanned
+/// for their duration. Results are in the same order as `paths`.
+pub fn scan<P>(paths: &[P], threads: usize) -> Vec<Result<ScannedFile, Error>>
+where
+  P: AsRef<Path> + Sync,
+{
+  run(paths, threads, scan_file)
+}
@@ -0,0 +1,378 @@
+//! Minimal MP4 box reader for data the mp4 crate doesn't keep, such as the
+//! raw AudioSpecificConfig of a track.
+use crate::syntax::BitReader;
+use crate::{adts, Error};
+use mp4::{AudioObjectType, ChannelConfig, SampleFreqIndex};
+use std::convert::{TryFrom, TryInto};
+use std::io::{self, Read, Seek, SeekFrom};
+
+/// Boxes that only contain other boxes
+const CONTAINERS: [&[u8; 4]; 6] = [b"moov", b"trak", b"mdia", b"minf", b"stbl", b"wave"];
+
+/// Boxes that MP4 files start with
+pub(crate) const TOP_LEVEL: [&[u8; 4]; 7] = [
+  b"ftyp", b"moov", b"mdat", b"free", b"skip", b"wide", b"pnot",
+];
+
+/// Iterator over the boxes in a slice, yielding each box type and content
+pub(crate) struct Boxes<'a> {
+  data: &'a [u8],
+}
+
+impl<'a> Iterator for Boxes<'a> {
+  type Item = ([u8; 4], &'a [u8]);
+  fn next(&mut self) -> Option<Self::Item> {
+    let size = u32::from_be_bytes(self.data.get(0..4)?.try_into().ok()?) as u64;
+    let box_type = self.data.get(4..8)?.try_into().ok()?;
+    let (header_len, size) = match size {
+      0 => (8, self.data.len() as u64),
+      1 => (
+        16,
+        u64::from_be_bytes(self.data.get(8..16)?.try_into().ok()?),
+      ),
+      size => (8, size),
+    };
+    if size < header_len || size > self.data.len() as u64 {
+      self.data = &[];
+      return None;
+    }
+    let content = &self.data[header_len as usize..size as usize];
+    self.data = &self.data[size as usize..];
+    Some((box_type, content))
+  }
+}
+
+pub(crate) fn boxes(data: &[u8]) -> Boxes<'_> {
+  Boxes { data }
+}
+
+/// Find the first box of the given type
+pub(crate) fn find<'a>(data: &'a [u8], box_type: &[u8; 4]) -> Option<&'a [u8]> {
+  boxes(data)
+    .find(|(t, _)| t == box_type)
+    .map(|(_, content)| content)
+}
+
+/// Walk the top-level boxes, calling `visit` with the reader at the start of
+/// each box's content, the box type, its offset and its content length until
+/// it returns false. Then seek back to where the reader started.
+fn walk_top_level<R, F>(reader: &mut R, size: u64, mut visit: F) -> io::Result<()>
+where
+  R: Read + Seek,
+  F: FnMut(&mut R, [u8; 4], u64, u64) -> io::Result<bool>,
+{
+  let start = reader.stream_position()?;
+  let mut position = 0;
+  while position + 8 <= size {
+    let mut header = [0; 16];
+    reader.read_exact(&mut header[..8])?;
+    let mut box_size = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as u64;
+    let mut header_len = 8;
+    if box_size == 1 {
+      reader.read_exact(&mut header[8..])?;
+      box_size = u64::from_be_bytes(header[8..].try_into().unwrap_or_default());
+      header_len = 16;
+    } else if box_size == 0 {
+      box_size = size - position;
+    }
+    if box_size < header_len || box_size > size - position {
+      break;
+    }
+    let box_type = [header[4], header[5], header[6], header[7]];
+    if !visit(reader, box_type, start + position, box_size - header_len)? {
+      break;
+    }
+    position += box_size;
+    reader.seek(SeekFrom::Start(start + position))?;
+  }
+  reader.seek(SeekFrom::Start(start))?;
+  Ok(())
+}
+
+/// Read the contents of the moov box, then seek back to where the reader
+/// started. Returns None if there's no moov box.
+pub(crate) fn read_moov<R: Read + Seek>(reader: &mut R, size: u64) -> io::Result<Option<Vec<u8>>> {
+  let mut moov = None;
+  walk_top_level(reader, size, |reader, box_type, _, len| {
+    if &box_type != b"moov" {
+      return Ok(true);
+    }
+    let mut content = Vec::new();
+    reader.take(len).read_to_end(&mut content)?;
+    moov = Some(content);
+    Ok(false)
+  })?;
+  Ok(moov)
+}
+
+/// Segment index of a fragmented file, from its sidx box
+pub(crate) struct SegmentIndex {
+  /// Track the index is for
+  pub reference_id: u32,
+  pub timescale: u32,
+  /// Start time and offset in the file of each subsegment
+  pub subsegments: Vec<(u64, u64)>,
+  /// Offset in the file of each moof box
+  pub moofs: Vec<u64>,
+}
+
+/// Read the first sidx box and the offsets of the moof boxes, then seek back
+/// to where the reader started. Returns None if there's no sidx box, or if
+/// it's malformed or refers to other sidx boxes.
+pub(crate) fn read_segment_index<R: Read + Seek>(
+  reader: &mut R,
+  size: u64,
+) -> io::Result<Option<SegmentIndex>> {
+  let mut sidx = None;
+  let mut moofs = Vec::new();
+  walk_top_level(reader, size, |reader, box_type, offset, len| {
+    match &box_type {
+      b"moof" => moofs.push(offset),
+      b"sidx" if sidx.is_none() => {
+        let end = reader.stream_position()? + len;
+        let mut content = Vec::new();
+        reader.take(len).read_to_end(&mut content)?;
+        sidx = Some((content, end));
+      }
+      _ => {}
+    }
+    Ok(true)
+  })?;
+  let (sidx, end) = match sidx {
+    Some(sidx) => sidx,
+    None => return Ok(None),
+  };
+  Ok(segment_index(&sidx, end, moofs))
+}
+
+/// Parse the contents of a sidx box that ends at `end` in the file
+fn segment_index(sidx: &[u8], end: u64, moofs: Vec<u64>) -> Option<SegmentIndex> {
+  let u32_at = |i: usize| Some(u32::from_be_bytes(sidx.get(i..i + 4)?.try_into().ok()?));
+  let u64_at = |i: usize| Some(u64::from_be_bytes(sidx.get(i..i + 8)?.try_into().ok()?));
+  let (mut time, first_offset, references) = match sidx.first()? {
+    0 => (u32_at(12)? as u64, u32_at(16)? as u64, 20),
+    _ => (u64_at(12)?, u64_at(20)?, 28),
+  };
+  let count = u16::from_be_bytes(sidx.get(references + 2..references + 4)?.try_into().ok()?);
+  let references = sidx.get(references + 4..)?.chunks_exact(12);
+  let mut offset = end.checked_add(first_offset)?;
+  let mut subsegments = Vec::with_capacity(count as usize);
+  for reference in references.take(count as usize) {
+    let referenced_size = u32::from_be_bytes(reference[0..4].try_into().ok()?);
+    if referenced_size >> 31 == 1 {
+      return None; // refers to another sidx box
+    }
+    let duration = u32::from_be_bytes(reference[4..8].try_into().ok()?);
+    subsegments.push((time, offset));
+    time = time.checked_add(duration as u64)?;
+    offset = offset.checked_add(referenced_size as u64)?;
+  }
+  if subsegments.len() != count as usize {
+    return None;
+  }
+  Some(SegmentIndex {
+    reference_id: u32_at(4)?,
+    timescale: u32_at(8)?,
+    subsegments,
+    moofs,
+  })
+}
+
+/// Get the contents of the ilst box, which has the iTunes tags, from the
+/// contents of the moov box
+pub(crate) fn ilst(moov: &[u8]) -> Option<&[u8]> {
+  let meta = find(find(moov, b"udta")?, b"meta")?;
+  // meta is a full box in MP4, but not in QuickTime
+  find(meta.get(4..)?, b"ilst").or_else(|| find(meta, b"ilst"))
+}
+
+/// Get the value and type indicator of the data box of an ilst item
+pub(crate) fn item_data(item: &[u8]) -> Option<(u32, &[u8])> {
+  let data = find(item, b"data")?;
+  let type_indicator = u32::from_be_bytes(data.get(0..4)?.try_into().ok()?);
+  Some((type_indicator & 0xff_ffff, data.get(8..)?))
+}
+
+/// Get the value of a freeform iTunes tag from the contents of the moov box.
+/// Tag names are compared case-insensitively.
+pub(crate) fn freeform_tag(moov: &[u8], name: &str) -> Option<String> {
+  let value = boxes(ilst(moov)?).find_map(|(box_type, item)| {
+    if &box_type != b"----" {
+      return None;
+    }
+    let item_name = find(item, b"name")?.get(4..)?;
+    match item_name.eq_ignore_ascii_case(name.as_bytes()) {
+      true => item_data(item).map(|(_, value)| value),
+      false => None,
+    }
+  })?;
+  String::from_utf8(value.to_vec()).ok()
+}
+
+/// Read an MPEG-4 descriptor, returning its tag, content and the rest of the
+/// data
+fn descriptor(data: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+  let (&tag, mut data) = data.split_first()?;
+  let mut len = 0;
+  for _ in 0..4 {
+    let (&byte, rest) = data.split_first()?;
+    data = rest;
+    len = (len << 7) | (byte & 0x7F) as usize;
+    if byte & 0x80 == 0 {
+      break;
+    }
+  }
+  let content = data.get(..len)?;
+  Some((tag, content, &data[len..]))
+}
+
+/// Get the AudioSpecificConfig from the contents of an esds box
+fn esds_audio_specific_config(esds: &[u8]) -> Option<&[u8]> {
+  // ES_Descriptor
+  let (tag, es, _) = descriptor(esds.get(4..)?)?;
+  if tag != 0x03 {
+    return None;
+  }
+  let flags = *es.get(2)?;
+  let mut offset = 3;
+  if flags & 0x80 != 0 {
+    offset += 2; // dependsOn_ES_ID
+  }
+  if flags & 0x40 != 0 {
+    offset += 1 + *es.get(offset)? as usize; // URL
+  }
+  if flags & 0x20 != 0 {
+    offset += 2; // OCR_ES_Id
+  }
+  // DecoderConfigDescriptor
+  let (tag, config, _) = descriptor(es.get(offset..)?)?;
+  if tag != 0x04 {
+    return None;
+  }
+  // DecoderSpecificInfo
+  let (tag, specific_info, _) = descriptor(config.get(13..)?)?;
+  if tag != 0x05 || specific_info.is_empty() {
+    return None;
+  }
+  Some(specific_info)
+}
+
+/// Find the esds box in a box tree, such as a sample entry's child boxes
+fn find_esds(data: &[u8]) -> Option<&[u8]> {
+  boxes(data).find_map(|(box_type, content)| match &box_type {
+    b"esds" => Some(content),
+    t if CONTAINERS.contains(&t) => find_esds(content),
+    _ => None,
+  })
+}
+
+/// Get the AudioSpecificConfig from an mp4a sample entry
+pub(crate) fn sample_entry_audio_specific_config(entry: &[u8]) -> Option<Vec<u8>> {
+  // skip the AudioSampleEntry fields, which are longer in QuickTime sound
+  // description versions 1 and 2
+  let version = u16::from_be_bytes(entry.get(8..10)?.try_into().ok()?);
+  let fields_len = match version {
+    1 => 28 + 16,
+    2 => 28 + 36,
+    _ => 28,
+  };
+  let esds = find_esds(entry.get(fields_len..)?)?;
+  esds_audio_specific_config(esds).map(|config| config.to_vec())
+}
+
+/// Get the track ID from the contents of a trak box
+pub(crate) fn track_id(trak: &[u8]) -> Option<u32> {
+  let tkhd = find(trak, b"tkhd")?;
+  let id_offset = if *tkhd.first()? == 1 { 20 } else { 12 };
+  Some(u32::from_be_bytes(
+    tkhd.get(id_offset..id_offset + 4)?.try_into().ok()?,
+  ))
+}
+
+/// Get the contents of the trak box of a track from the contents of the moov
+/// box
+pub(crate) fn trak(moov: &[u8], track_id: u32) -> Option<&[u8]> {
+  boxes(moov).find_map(|(box_type, trak)| match &box_type {
+    b"trak" if self::track_id(trak) == Some(track_id) => Some(trak),
+    _ => None,
+  })
+}
+
+/// Get the contents of the stsd box of a trak box, after its header
+pub(crate) fn stsd(trak: &[u8]) -> Option<&[u8]> {
+  let stbl = find(find(find(trak, b"mdia")?, b"minf")?, b"stbl")?;
+  find(stbl, b"stsd")?.get(8..)
+}
+
+/// Get the type of the first sample description entry of a trak box, like
+/// "mp4a" or "ec-3". None if it's not an audio track.
+pub(crate) fn audio_sample_entry_type(trak: &[u8]) -> Option<[u8; 4]> {
+  let mdia = find(trak, b"mdia")?;
+  if find(mdia, b"hdlr")?.get(8..12)? != b"soun" {
+    return None;
+  }
+  boxes(stsd(trak)?).next().map(|(box_type, _)| box_type)
+}
+
+/// Get the raw AudioSpecificConfig of each sample description entry of a
+/// track from the contents of the moov box. Entries without one are None.
+pub(crate) fn audio_specific_configs(moov: &[u8], track_id: u32) -> Vec<Option<Vec<u8>>> {
+  let entries = boxes(trak(moov, track_id).and_then(stsd).unwrap_or_default());
+  entries
+    .map(|(_, entry)| sample_entry_audio_specific_config(entry))
+    .collect()
+}
+
+/// Get the sampling frequency index of an AudioSpecificConfig. For SBR and PS
+/// this is the core AAC sample rate.
+pub(crate) fn config_sample_freq_index(audio_specific_config: &[u8]) -> Option<SampleFreqIndex> {
+  let mut reader = BitReader::new(audio_specific_config);
+  if reader.read(5)? == 31 {
+    reader.read(6)?; // audioObjectTypeExt
+  }
+  SampleFreqIndex::try_from(reader.read(4)? as u8).ok()
+}
+
+/// Construct a 2 byte AudioSpecificConfig from track info, for when a track
+/// has no esds box. SBR and PS are left to be detected by the decoder.
+pub(crate) fn construct_audio_specific_config(
+  object_type: AudioObjectType,
+  sample_freq_index: SampleFreqIndex,
+  channel_config: ChannelConfig,
+) -> Result<Vec<u8>, Error> {
+  let config = adts::raw::audio_specific_config(
+    object_type as u8,
+    adts::freq_index(sample_freq_index) as u8,
+    channel_config as u8,
+  );
+  let config = config.map_err(|err| Error::from_adts(err, object_type))?;
+  Ok(config.to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn esds_config() {
+    let esds = [
+      0, 0, 0, 0, // version and flags
+      0x03, 0x19, 0x00, 0x01, 0x00, // ES_Descriptor
+      0x04, 0x11, 0x40, 0x15, 0, 0, 0, 0, 0x01, 0xF4, 0, 0, 0x01, 0xF4, 0, // DecoderConfig
+      0x05, 0x02, 0x12, 0x10, // DecoderSpecificInfo
+      0x06, 0x01, 0x02, // SLConfigDescriptor
+    ];
+    assert_eq!(esds_audio_specific_config(&esds), Some(&[0x12, 0x10][..]));
+    assert_eq!(esds_audio_specific_config(&esds[..20]), None);
+
+    let config = construct_audio_specific_config(
+      AudioObjectType::SpectralBandReplication,
+      SampleFreqIndex::Freq44100,
+      ChannelConfig::Stereo,
+    );
+    let config = config.unwrap();
+    assert_eq!(config, [0x12, 0x10]);
+    let sample_freq_index = config_sample_freq_index(&config);
+    assert_eq!(sample_freq_index, Some(SampleFreqIndex::Freq44100));
+  }
+}
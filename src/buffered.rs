@@ -0,0 +1,81 @@
+//! Read buffering for the decoder's reader
+use std::convert::TryFrom;
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom};
+
+/// Buffered reader that seeks within its buffer when it can. `BufReader`
+/// discards the buffer on every seek, but MP4 samples are read by seeking to
+/// each one, and they're usually next to each other.
+pub struct BufferedReader<R> {
+  inner: BufReader<R>,
+  /// Position of the next byte to read
+  pub(crate) position: u64,
+}
+
+impl<R: Read + Seek> BufferedReader<R> {
+  /// Wrap `inner`. A reader that can't report its position is assumed to be
+  /// at the start.
+  pub fn with_capacity(capacity: usize, mut inner: R) -> Self {
+    let position = inner.stream_position().unwrap_or(0);
+    BufferedReader {
+      inner: BufReader::with_capacity(capacity, inner),
+      position,
+    }
+  }
+  pub fn get_ref(&self) -> &R {
+    self.inner.get_ref()
+  }
+  pub fn into_inner(self) -> R {
+    self.inner.into_inner()
+  }
+}
+
+impl<R: Read> Read for BufferedReader<R> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    let bytes_read = self.inner.read(buf)?;
+    self.position += bytes_read as u64;
+    Ok(bytes_read)
+  }
+}
+
+impl<R: Read> BufRead for BufferedReader<R> {
+  fn fill_buf(&mut self) -> io::Result<&[u8]> {
+    self.inner.fill_buf()
+  }
+  fn consume(&mut self, amt: usize) {
+    self.inner.consume(amt);
+    self.position += amt as u64;
+  }
+}
+
+impl<R: Read + Seek> Seek for BufferedReader<R> {
+  fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+    let offset = match pos {
+      SeekFrom::Start(position) => i64::try_from(position as i128 - self.position as i128).ok(),
+      SeekFrom::Current(offset) => Some(offset),
+      SeekFrom::End(_) => {
+        self.position = self.inner.seek(pos)?;
+        return Ok(self.position);
+      }
+    };
+    let seek = offset.and_then(|offset| {
+      let position = u64::try_from(self.position as i128 + offset as i128).ok()?;
+      Some((offset, position))
+    });
+    let (offset, position) = match seek {
+      Some(seek) => seek,
+      None => {
+        return Err(io::Error::new(
+          io::ErrorKind::InvalidInput,
+          "invalid seek to a negative or overflowing position",
+        ))
+      }
+    };
+    // stays within the buffer when it can
+    self.inner.seek_relative(offset)?;
+    self.position = position;
+    Ok(self.position)
+  }
+  fn stream_position(&mut self) -> io::Result<u64> {
+    Ok(self.position)
+  }
+}
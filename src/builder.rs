@@ -0,0 +1,436 @@
+use crate::live::Live;
+use crate::{ChannelOrder, Decoder, DownmixMatrix, Drc, Error, Format, GainMode};
+use std::fs::File;
+use std::io::{Cursor, Read, Seek};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Default number of bytes read from the reader at a time for AAC input
+pub const DEFAULT_READ_CHUNK_SIZE: usize = 8192;
+/// Default size of the buffer the reader is wrapped in
+pub const DEFAULT_READ_BUFFER_SIZE: usize = 64 * 1024;
+/// Default capacity of the PCM buffer, in samples
+pub const DEFAULT_PCM_CAPACITY: usize = 8192;
+/// Most channels the output can have, which is 7.1
+pub const MAX_CHANNELS: u16 = 8;
+/// Largest frame fdk-aac can output, in samples. That's 2048 samples per
+/// channel (HE-AAC) for up to `MAX_CHANNELS` channels.
+pub const MAX_FRAME_SIZE: usize = 2048 * MAX_CHANNELS as usize;
+/// Default size of the largest compressed MP4 sample that's read
+pub const DEFAULT_MAX_SAMPLE_SIZE: usize = 1024 * 1024;
+/// Read chunk size used by `batch_mode`
+const BATCH_READ_CHUNK_SIZE: usize = 256 * 1024;
+/// Frames decoded per `next_chunk` call in `batch_mode`
+const BATCH_FRAMES_PER_CHUNK: usize = 64;
+/// Smallest allowed PCM capacity, which fits one mono AAC-LC frame
+const MIN_PCM_CAPACITY: usize = 1024;
+
+/// Decoder for shared data in memory, from `open_shared`
+type SharedDecoder = Decoder<Cursor<Arc<[u8]>>>;
+
+/// Builder for configuring a Decoder
+#[derive(Clone, Debug)]
+pub struct DecoderBuilder {
+  pub(crate) read_chunk_size: usize,
+  pub(crate) read_buffer_size: usize,
+  pub(crate) pcm_capacity: usize,
+  pub(crate) frames_per_chunk: usize,
+  pub(crate) fixed_channels: bool,
+  pub(crate) replay_gain: Option<GainMode>,
+  pub(crate) trim_silence: Option<f32>,
+  pub(crate) fade_in: Option<Duration>,
+  pub(crate) fade_out: Option<Duration>,
+  pub(crate) soft_limiter: bool,
+  pub(crate) dither: bool,
+  pub(crate) track: Option<u32>,
+  pub(crate) downmix_stereo: bool,
+  pub(crate) channel_order: ChannelOrder,
+  pub(crate) downmix_matrix: Option<DownmixMatrix>,
+  pub(crate) drc: Option<Drc>,
+  pub(crate) lenient: bool,
+  pub(crate) live: Option<Live>,
+  pub(crate) start_position: Option<Duration>,
+  pub(crate) trim_start: Option<Duration>,
+  pub(crate) trim_end: Option<Duration>,
+  pub(crate) preview: Option<Duration>,
+  pub(crate) frame_time_budget: Option<Duration>,
+  pub(crate) file_time_budget: Option<Duration>,
+  pub(crate) max_sample_size: usize,
+  pub(crate) realtime: bool,
+}
+
+impl Default for DecoderBuilder {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl DecoderBuilder {
+  pub fn new() -> Self {
+    DecoderBuilder {
+      read_chunk_size: DEFAULT_READ_CHUNK_SIZE,
+      read_buffer_size: DEFAULT_READ_BUFFER_SIZE,
+      pcm_capacity: DEFAULT_PCM_CAPACITY,
+      frames_per_chunk: 1,
+      fixed_channels: false,
+      replay_gain: None,
+      trim_silence: None,
+      fade_in: None,
+      fade_out: None,
+      soft_limiter: false,
+      dither: false,
+      track: None,
+      downmix_stereo: false,
+      channel_order: ChannelOrder::Wav,
+      downmix_matrix: None,
+      drc: None,
+      lenient: false,
+      live: None,
+      start_position: None,
+      trim_start: None,
+      trim_end: None,
+      preview: None,
+      frame_time_budget: None,
+      file_time_budget: None,
+      max_sample_size: DEFAULT_MAX_SAMPLE_SIZE,
+      realtime: false,
+    }
+  }
+  /// Number of bytes to read from the reader at a time. Only used for AAC
+  /// input, since MP4 input is read one sample at a time.
+  pub fn read_chunk_size(mut self, bytes: usize) -> Self {
+    self.read_chunk_size = bytes;
+    self
+  }
+  /// Size of the buffer the reader is wrapped in, so small reads like MP4
+  /// box headers don't each need a syscall. Readers that are already
+  /// buffered or in memory can use 0 to skip it.
+  pub fn read_buffer_size(mut self, bytes: usize) -> Self {
+    self.read_buffer_size = bytes;
+    self
+  }
+  /// Capacity of the buffer frames are decoded into, in samples. It needs to
+  /// fit a whole frame for all channels, so streams with frames larger than
+  /// this will fail to decode. At most `MAX_FRAME_SIZE`.
+  pub fn pcm_capacity(mut self, samples: usize) -> Self {
+    self.pcm_capacity = samples;
+    self
+  }
+  /// Number of frames decoded per `Decoder::next_chunk` call
+  pub fn frames_per_chunk(mut self, frames: usize) -> Self {
+    self.frames_per_chunk = frames;
+    self
+  }
+  /// Mix every frame to the channel count of the first frame, instead of
+  /// letting it change mid-stream. Useful when the output can't change format,
+  /// like a WAV file.
+  pub fn fixed_channels(mut self) -> Self {
+    self.fixed_channels = true;
+    self
+  }
+  /// Apply the ReplayGain from the file's tags to the output. Only MP4 input
+  /// has tags, and files without them are left as is.
+  pub fn replay_gain(mut self, mode: GainMode) -> Self {
+    self.replay_gain = Some(mode);
+    self
+  }
+  /// Skip leading and trailing silence at or below `threshold_db` dBFS. See
+  /// `Decoder::trim_silence`.
+  pub fn trim_silence(mut self, threshold_db: f32) -> Self {
+    self.trim_silence = Some(threshold_db);
+    self
+  }
+  /// Fade in linearly over `duration` at the start of the stream
+  pub fn fade_in(mut self, duration: Duration) -> Self {
+    self.fade_in = Some(duration);
+    self
+  }
+  /// Fade out linearly over `duration` before the end of the stream. The end
+  /// is the end of the range from `Decoder::decode_range` if there is one,
+  /// and AAC input is scanned for frame headers to find its duration.
+  pub fn fade_out(mut self, duration: Duration) -> Self {
+    self.fade_out = Some(duration);
+    self
+  }
+  /// Compress samples that the ReplayGain or output gain would push past
+  /// full scale, instead of clipping them. Downmixing in fdk-aac has its own
+  /// limiter.
+  pub fn soft_limiter(mut self) -> Self {
+    self.soft_limiter = true;
+    self
+  }
+  /// Add TPDF dither when gain or fades are applied, so quiet passages don't
+  /// get quantization distortion when the result is rounded to 16 bits
+  pub fn dither(mut self) -> Self {
+    self.dither = true;
+    self
+  }
+  /// Decode the MP4 track with this ID instead of the default one, which is
+  /// the enabled AAC track with the lowest ID. See `probe::default_track`.
+  pub fn track(mut self, track_id: u32) -> Self {
+    self.track = Some(track_id);
+    self
+  }
+  /// Mix the output to stereo, downmixing surround and duplicating mono
+  pub fn downmix_stereo(mut self, downmix: bool) -> Self {
+    self.downmix_stereo = downmix;
+    self
+  }
+  /// Mix frames that have the matrix's input channel count with custom
+  /// coefficients, instead of fdk-aac's ITU downmix. Frames with other
+  /// channel counts are left as is. Applies to fdk-aac's output, so it
+  /// isn't combined with `downmix_stereo`.
+  pub fn downmix_matrix(mut self, matrix: DownmixMatrix) -> Self {
+    self.downmix_matrix = Some(matrix);
+    self
+  }
+  /// Apply the dynamic range control in the stream's metadata, and choose
+  /// how its downmix metadata is interpreted, so dialog stays audible when
+  /// movie audio is downmixed
+  pub fn drc(mut self, drc: Drc) -> Self {
+    self.drc = Some(drc);
+    self
+  }
+  /// Order of the channels in the output. The default is WAV order, which
+  /// plays correctly through rodio and most other sinks.
+  pub fn channel_order(mut self, order: ChannelOrder) -> Self {
+    self.channel_order = order;
+    self
+  }
+  /// Keep going when a frame fails to decode, using the frame fdk-aac
+  /// conceals it with, and skip MP4 samples that can't be read
+  pub fn lenient(mut self, lenient: bool) -> Self {
+    self.lenient = lenient;
+    self
+  }
+  /// Treat AAC input as an endless live stream, like internet radio. When a
+  /// read returns no data or fails with a transient error, such as a timeout
+  /// or reset connection, it's retried every `DEFAULT_LIVE_POLL_INTERVAL` until
+  /// `DEFAULT_LIVE_TIMEOUT` has passed without input.
+  pub fn live(self) -> Self {
+    self.live_retry(
+      crate::DEFAULT_LIVE_POLL_INTERVAL,
+      crate::DEFAULT_LIVE_TIMEOUT,
+    )
+  }
+  /// Like `live`, with the time between retries and the time without input
+  /// before the stream ends
+  pub fn live_retry(mut self, poll_interval: Duration, timeout: Duration) -> Self {
+    self.live = Some(Live {
+      poll_interval,
+      timeout,
+    });
+    self
+  }
+  /// Start decoding at `position`, by seeking before the decoder is returned.
+  /// See `Decoder::open_at`.
+  pub fn start_at(mut self, position: Duration) -> Self {
+    self.start_position = Some(position);
+    self
+  }
+  /// Start the output at `position`, with sample accuracy, like for a track
+  /// in a cue sheet. Fades and `Decoder::remaining_samples` apply to the
+  /// trimmed range. See `Decoder::decode_range`.
+  pub fn trim_start(mut self, position: Duration) -> Self {
+    self.trim_start = Some(position);
+    self
+  }
+  /// End the output at `position`, with sample accuracy. Like `trim_start`,
+  /// it's a position in the stream, so a track in a cue sheet ends where the
+  /// next one starts.
+  pub fn trim_end(mut self, position: Duration) -> Self {
+    self.trim_end = Some(position);
+    self
+  }
+  /// Decode only the first `duration`, as cheaply as possible, like for a
+  /// preview on hover. Tags aren't read and the length of MP4 tracks isn't
+  /// computed, so there's no metadata, gapless info or ReplayGain.
+  pub fn preview(mut self, duration: Duration) -> Self {
+    self.preview = Some(duration);
+    self
+  }
+  /// Fail with `Error::TimeBudgetExceeded` when decoding a single frame takes
+  /// longer than `budget`, like for untrusted input on a server. The time is
+  /// checked between reads and fdk-aac calls, so a frame can go over the
+  /// budget by however long one of those takes.
+  pub fn frame_time_budget(mut self, budget: Duration) -> Self {
+    self.frame_time_budget = Some(budget);
+    self
+  }
+  /// Fail with `Error::TimeBudgetExceeded` when the total time spent decoding
+  /// frames goes over `budget`. Time spent outside the decoder, like in the
+  /// audio output, doesn't count.
+  pub fn file_time_budget(mut self, budget: Duration) -> Self {
+    self.file_time_budget = Some(budget);
+    self
+  }
+  /// Decode no faster than realtime, sleeping between frames, like for
+  /// simulating a live source or feeding a system that expects realtime
+  /// input. Frames are paced from when the decoder is created or seeked, so
+  /// after a consumer falls behind, frames are returned without sleeping until
+  /// it catches up. The sleeps don't count towards time budgets.
+  pub fn realtime(mut self) -> Self {
+    self.realtime = true;
+    self
+  }
+  /// Largest compressed MP4 sample to read, in bytes. Larger samples fail
+  /// with `Error::SampleTooLarge`, or are skipped when `lenient`, so files that
+  /// declare huge samples don't cause huge allocations. ADTS frames are
+  /// limited to 8191 bytes, so the input buffer for AAC input doesn't grow
+  /// past `read_chunk_size` plus one frame.
+  pub fn max_sample_size(mut self, bytes: usize) -> Self {
+    self.max_sample_size = bytes;
+    self
+  }
+  /// Configure for offline decoding, where throughput matters more than
+  /// latency. Reads large chunks of input and decodes many frames per
+  /// `Decoder::next_chunk` call.
+  pub fn batch_mode(self) -> Self {
+    self
+      .read_chunk_size(BATCH_READ_CHUNK_SIZE)
+      .frames_per_chunk(BATCH_FRAMES_PER_CHUNK)
+  }
+  fn validate(&self) -> Result<(), Error> {
+    if self.read_chunk_size == 0 {
+      return Err(Error::InvalidConfig(
+        "read_chunk_size must be greater than 0",
+      ));
+    }
+    if self.frames_per_chunk == 0 {
+      return Err(Error::InvalidConfig(
+        "frames_per_chunk must be greater than 0",
+      ));
+    }
+    if self.max_sample_size == 0 {
+      return Err(Error::InvalidConfig(
+        "max_sample_size must be greater than 0",
+      ));
+    }
+    if self.pcm_capacity < MIN_PCM_CAPACITY {
+      return Err(Error::InvalidConfig(
+        "pcm_capacity is smaller than one frame",
+      ));
+    }
+    if let Some(drc) = &self.drc {
+      drc.validate()?;
+    }
+    if let (Some(start), Some(end)) = (self.trim_start, self.trim_end) {
+      if end < start {
+        return Err(Error::InvalidConfig("trim_end is before trim_start"));
+      }
+    }
+    if self.trim_silence.is_some() && (self.trim_start.is_some() || self.trim_end.is_some()) {
+      return Err(Error::InvalidConfig(
+        "trim_silence can't be combined with trim_start or trim_end",
+      ));
+    }
+    if self.pcm_capacity > MAX_FRAME_SIZE {
+      return Err(Error::InvalidConfig(
+        "pcm_capacity is larger than fdk's maximum frame size",
+      ));
+    }
+    Ok(())
+  }
+  /// Create a decoder from an aac buffer
+  pub fn open_aac<R: Read + Seek>(&self, reader: R) -> Result<Decoder<R>, Error> {
+    self.validate()?;
+    self.finish(Decoder::from_aac(reader, self))
+  }
+  /// Create a decoder from an mpeg buffer
+  pub fn open_mpeg4<R: Read + Seek>(&self, reader: R, size: u64) -> Result<Decoder<R>, Error> {
+    self.validate()?;
+    self.finish(Decoder::from_mpeg4(reader, size, self)?)
+  }
+  /// Create a decoder, detecting whether the input is MPEG-4 or AAC from its
+  /// contents. `size` is only used for MPEG-4.
+  pub fn open<R: Read + Seek>(&self, mut reader: R, size: u64) -> Result<Decoder<R>, Error> {
+    match Format::detect(&mut reader).map_err(Error::ReaderError)? {
+      Format::Mp4 => self.open_mpeg4(reader, size),
+      Format::Aac => self.open_aac(reader),
+    }
+  }
+  /// Create a decoder using a MIME type or file extension hint. See
+  /// `Decoder::new_with_mime`.
+  pub fn open_with_mime<R: Read + Seek>(
+    &self,
+    reader: R,
+    size: u64,
+    mime: &str,
+  ) -> Result<Decoder<R>, Error> {
+    let hint = Format::from_mime(mime).or_else(|| Format::from_extension(mime));
+    match hint {
+      Some(Format::Mp4) => self.open_mpeg4(reader, size),
+      Some(Format::Aac) => self.open_aac(reader),
+      None => self.open(reader, size),
+    }
+  }
+  /// Open a file, detecting whether it's MPEG-4 or AAC from its contents
+  pub fn open_path<P: AsRef<Path>>(&self, path: P) -> Result<Decoder<File>, Error> {
+    let file = File::open(path).map_err(Error::ReaderError)?;
+    let size = file.metadata().map_err(Error::ReaderError)?.len();
+    self.open(file, size)
+  }
+  /// Memory-map a file and decode from the mapping, detecting whether it's
+  /// MPEG-4 or AAC. See `Decoder::from_mmap`.
+  #[cfg(feature = "mmap")]
+  pub fn open_mmap<P: AsRef<Path>>(
+    &self,
+    path: P,
+  ) -> Result<Decoder<Cursor<memmap2::Mmap>>, Error> {
+    let file = File::open(path).map_err(Error::ReaderError)?;
+    // safety: see Decoder::from_mmap
+    let mmap = unsafe { memmap2::Mmap::map(&file) }.map_err(Error::ReaderError)?;
+    let size = mmap.len() as u64;
+    self.open(Cursor::new(mmap), size)
+  }
+  /// Create a decoder from data in memory, detecting whether it's MPEG-4 or
+  /// AAC
+  pub fn open_bytes<B: Into<Vec<u8>>>(&self, bytes: B) -> Result<Decoder<Cursor<Vec<u8>>>, Error> {
+    let bytes = bytes.into();
+    let size = bytes.len() as u64;
+    self.open(Cursor::new(bytes), size)
+  }
+  /// Create a decoder from shared data in memory, detecting whether it's
+  /// MPEG-4 or AAC. See `Decoder::reopen`.
+  pub fn open_shared<B: Into<Arc<[u8]>>>(
+    &self,
+    bytes: B,
+  ) -> Result<Decoder<Cursor<Arc<[u8]>>>, Error> {
+    let bytes = bytes.into();
+    let size = bytes.len() as u64;
+    let mut decoder = self.open(Cursor::new(bytes.clone()), size)?;
+    decoder.shared = Some(bytes);
+    Ok(decoder)
+  }
+  /// Open a decoder for each of the MP4 tracks with `track_ids`, like
+  /// narration and music stems, from shared data in memory. The data isn't
+  /// copied, and the decoders stay in sync as long as they're consumed and
+  /// seeked together, like with rodio's `Source::mix`.
+  pub fn open_tracks<B: Into<Arc<[u8]>>>(
+    &self,
+    bytes: B,
+    track_ids: &[u32],
+  ) -> Result<Vec<SharedDecoder>, Error> {
+    let bytes = bytes.into();
+    let open = |&track_id| self.clone().track(track_id).open_shared(bytes.clone());
+    track_ids.iter().map(open).collect()
+  }
+  /// Apply the options that need a whole decoder
+  fn finish<R: Read + Seek>(&self, mut decoder: Decoder<R>) -> Result<Decoder<R>, Error> {
+    if let Some(threshold_db) = self.trim_silence {
+      decoder.trim_silence(threshold_db)?;
+    }
+    if self.trim_start.is_some() || self.trim_end.is_some() {
+      let start = self.trim_start.unwrap_or_default();
+      decoder.range_start = start;
+      // keeps the end from `preview` without `trim_end`
+      decoder.end = self.trim_end.or(decoder.end);
+      decoder.seek(start)?;
+    }
+    if let Some(position) = self.start_position {
+      decoder.seek(position)?;
+    }
+    Ok(decoder)
+  }
+}
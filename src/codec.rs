@@ -0,0 +1,121 @@
+//! Decoder for the codec of the stream, which is AAC unless it's an MP4
+//! track with MP3 samples
+use crate::fdk::AacDecoder;
+#[cfg(feature = "mp3")]
+use crate::mp3::Mp3Decoder;
+use fdk_aac::dec::{DecoderError, StreamInfo};
+use std::mem;
+
+/// objectTypeIndication of MPEG-1 audio, like 44.1 kHz MP3
+const MPEG1_AUDIO: u8 = 0x6B;
+/// objectTypeIndication of MPEG-2 audio, like 22.05 kHz MP3
+const MPEG2_AUDIO: u8 = 0x69;
+
+/// objectTypeIndication of an MP4 track with MP3 samples. None for other
+/// tracks.
+pub(crate) fn mp3_object_type(track: &mp4::Mp4Track) -> Option<u8> {
+  let esds = track
+    .trak
+    .mdia
+    .minf
+    .stbl
+    .stsd
+    .mp4a
+    .as_ref()?
+    .esds
+    .as_ref()?;
+  let object_type = esds.es_desc.dec_config.object_type_indication;
+  matches!(object_type, MPEG1_AUDIO | MPEG2_AUDIO).then_some(object_type)
+}
+
+pub(crate) struct UnavailableDecoder {
+  error: DecoderError,
+  stream_info: StreamInfo,
+}
+
+// The stream info's channel pointers are always null
+unsafe impl Send for UnavailableDecoder {}
+unsafe impl Sync for UnavailableDecoder {}
+
+pub(crate) enum FrameDecoder {
+  Aac(AacDecoder),
+  /// fdk-aac couldn't open a decoder, so every call returns the error
+  Unavailable(Box<UnavailableDecoder>),
+  #[cfg(feature = "mp3")]
+  Mp3(Box<Mp3Decoder>),
+}
+
+impl FrameDecoder {
+  pub fn unavailable(error: DecoderError) -> Self {
+    FrameDecoder::Unavailable(Box::new(UnavailableDecoder {
+      error,
+      // zeroed like the stream info before the first frame is decoded
+      stream_info: unsafe { mem::zeroed() },
+    }))
+  }
+  /// objectTypeIndication of the MP3 track being decoded. None for AAC.
+  pub fn mp3_object_type(&self) -> Option<u8> {
+    match self {
+      FrameDecoder::Aac(_) | FrameDecoder::Unavailable(..) => None,
+      #[cfg(feature = "mp3")]
+      FrameDecoder::Mp3(decoder) => Some(decoder.object_type()),
+    }
+  }
+  /// Configure the AAC decoder's raw transport. MP3 has no configuration.
+  pub fn config_raw(&mut self, audio_specific_config: &[u8]) -> Result<(), DecoderError> {
+    match self {
+      FrameDecoder::Aac(decoder) => decoder.config_raw(audio_specific_config),
+      FrameDecoder::Unavailable(decoder) => Err(decoder.error),
+      #[cfg(feature = "mp3")]
+      FrameDecoder::Mp3(_) => Ok(()),
+    }
+  }
+  pub fn set_output_channels(&mut self, channels: u16) -> Result<(), DecoderError> {
+    match self {
+      FrameDecoder::Aac(decoder) => decoder.set_output_channels(channels),
+      FrameDecoder::Unavailable(decoder) => Err(decoder.error),
+      #[cfg(feature = "mp3")]
+      FrameDecoder::Mp3(decoder) => decoder.set_output_channels(channels),
+    }
+  }
+  pub fn clear_buffer(&mut self) -> Result<(), DecoderError> {
+    match self {
+      FrameDecoder::Aac(decoder) => decoder.clear_buffer(),
+      FrameDecoder::Unavailable(decoder) => Err(decoder.error),
+      #[cfg(feature = "mp3")]
+      FrameDecoder::Mp3(decoder) => decoder.clear_buffer(),
+    }
+  }
+  pub fn fill(&mut self, data: &[u8]) -> Result<usize, DecoderError> {
+    match self {
+      FrameDecoder::Aac(decoder) => decoder.fill(data),
+      FrameDecoder::Unavailable(decoder) => Err(decoder.error),
+      #[cfg(feature = "mp3")]
+      FrameDecoder::Mp3(decoder) => decoder.fill(data),
+    }
+  }
+  pub fn decode_frame(&mut self, pcm: &mut [i16]) -> Result<(), DecoderError> {
+    match self {
+      FrameDecoder::Aac(decoder) => decoder.decode_frame(pcm),
+      FrameDecoder::Unavailable(decoder) => Err(decoder.error),
+      #[cfg(feature = "mp3")]
+      FrameDecoder::Mp3(decoder) => decoder.decode_frame(pcm),
+    }
+  }
+  pub fn decoded_frame_size(&self) -> usize {
+    match self {
+      FrameDecoder::Aac(decoder) => decoder.decoded_frame_size(),
+      FrameDecoder::Unavailable(..) => 0,
+      #[cfg(feature = "mp3")]
+      FrameDecoder::Mp3(decoder) => decoder.decoded_frame_size(),
+    }
+  }
+  pub fn stream_info(&self) -> &StreamInfo {
+    match self {
+      FrameDecoder::Aac(decoder) => decoder.stream_info(),
+      FrameDecoder::Unavailable(decoder) => &decoder.stream_info,
+      #[cfg(feature = "mp3")]
+      FrameDecoder::Mp3(decoder) => decoder.stream_info(),
+    }
+  }
+}
@@ -0,0 +1,81 @@
+//! Finding out how a decoder finished, such as once a rodio `Sink` owns it
+use crate::Decoder;
+use std::io::{Read, Seek};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::Duration;
+
+/// How a decoder finished
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Completed {
+  /// The end of the stream was reached
+  EndOfStream,
+  /// Decoding failed with the error's message. The error itself is added to
+  /// the decoder's `iter_error`.
+  Error(String),
+  /// The decoder was stopped by its `Controller`, or dropped before it
+  /// finished, like when a rodio `Sink` is stopped or cleared
+  Stopped,
+}
+
+#[derive(Default)]
+struct Shared {
+  completed: Mutex<Option<Completed>>,
+  changed: Condvar,
+}
+
+/// Handle for learning when a decoder's iterator finished, and whether it
+/// was the end of the stream or an error. Get one with
+/// `Decoder::completion`. It can be cloned and used from other threads.
+#[derive(Clone, Default)]
+pub struct Completion {
+  shared: Arc<Shared>,
+}
+
+impl Completion {
+  fn lock(&self) -> MutexGuard<'_, Option<Completed>> {
+    let completed = self.shared.completed.lock();
+    completed.unwrap_or_else(|err| err.into_inner())
+  }
+  /// How the decoder finished, or None if it hasn't yet
+  pub fn get(&self) -> Option<Completed> {
+    self.lock().clone()
+  }
+  pub fn is_finished(&self) -> bool {
+    self.lock().is_some()
+  }
+  /// Block until the decoder finishes
+  pub fn wait(&self) -> Completed {
+    let completed = self.shared.changed.wait_while(self.lock(), |c| c.is_none());
+    let completed = completed.unwrap_or_else(|err| err.into_inner());
+    completed.clone().unwrap_or(Completed::EndOfStream)
+  }
+  /// Block until the decoder finishes or `timeout` passes. None if it timed
+  /// out.
+  pub fn wait_timeout(&self, timeout: Duration) -> Option<Completed> {
+    let result = self
+      .shared
+      .changed
+      .wait_timeout_while(self.lock(), timeout, |c| c.is_none());
+    let (completed, _) = result.unwrap_or_else(|err| err.into_inner());
+    completed.clone()
+  }
+  /// Record how the decoder finished. Only the first call counts.
+  pub(crate) fn complete(&self, completed: Completed) {
+    self.lock().get_or_insert(completed);
+    self.shared.changed.notify_all();
+  }
+}
+
+impl<R> Drop for Decoder<R>
+where
+  R: Read + Seek,
+{
+  fn drop(&mut self) {
+    if let Some(completion) = &self.completion {
+      match self.ended {
+        true => completion.complete(Completed::EndOfStream),
+        false => completion.complete(Completed::Stopped),
+      }
+    }
+  }
+}
@@ -0,0 +1,144 @@
+//! Controlling a decoder after giving it away, such as to a rodio `Sink`
+use crate::{Completed, Decoder, GainHandle};
+use std::io::{Read, Seek};
+use std::sync::mpsc;
+use std::time::Duration;
+
+enum Command {
+  Seek(Duration),
+  Stop,
+}
+
+/// Handle for controlling a `ControlledDecoder` from another thread. rodio
+/// takes sources by value, so this is how to reach a decoder once it's
+/// appended to a `Sink`. Seeking and stopping take effect at the end of the
+/// current frame, so the frame length reported to rodio stays correct and
+/// channels don't get swapped.
+#[derive(Clone)]
+pub struct Controller {
+  commands: mpsc::Sender<Command>,
+  gain: GainHandle,
+}
+
+impl Controller {
+  /// Seek to `position`. If seeking fails, the decoder ends and the error is
+  /// in its `iter_error`.
+  pub fn seek(&self, position: Duration) {
+    // the decoder is gone once it's finished, so there's nothing to seek
+    let _ = self.commands.send(Command::Seek(position));
+  }
+  /// Set the linear gain, where 1.0 leaves the output unchanged
+  pub fn set_gain(&self, gain: f32) {
+    self.gain.set_gain(gain);
+  }
+  /// Set the gain in dB
+  pub fn set_gain_db(&self, db: f32) {
+    self.gain.set_gain_db(db);
+  }
+  /// End the decoder, so rodio removes it from the sink. Its `Completion`
+  /// finishes with `Completed::Stopped`.
+  pub fn stop(&self) {
+    let _ = self.commands.send(Command::Stop);
+  }
+}
+
+/// Decoder that can be seeked, have its gain changed and be stopped through a
+/// `Controller` while something else owns it
+pub struct ControlledDecoder<R>
+where
+  R: Read + Seek,
+{
+  decoder: Decoder<R>,
+  commands: mpsc::Receiver<Command>,
+  stopped: bool,
+  /// Samples left before the end of the current frame, where commands are
+  /// run
+  frame_left: usize,
+}
+
+impl<R> ControlledDecoder<R>
+where
+  R: Read + Seek,
+{
+  pub fn new(decoder: Decoder<R>) -> (Self, Controller) {
+    let (sender, receiver) = mpsc::channel();
+    let controller = Controller {
+      commands: sender,
+      gain: decoder.gain_handle(),
+    };
+    let controlled = ControlledDecoder {
+      decoder,
+      commands: receiver,
+      stopped: false,
+      frame_left: 0,
+    };
+    (controlled, controller)
+  }
+  pub fn decoder(&self) -> &Decoder<R> {
+    &self.decoder
+  }
+  pub fn into_decoder(self) -> Decoder<R> {
+    self.decoder
+  }
+  /// Whether the decoder was stopped by its controller or by a failed seek
+  pub fn is_stopped(&self) -> bool {
+    self.stopped
+  }
+  /// Number of samples left in the current frame, which is 0 once stopped
+  pub fn current_frame_len(&self) -> Option<usize> {
+    match self.stopped {
+      true => Some(0),
+      false => self.decoder.current_frame_len(),
+    }
+  }
+  fn handle_commands(&mut self) {
+    while let Ok(command) = self.commands.try_recv() {
+      match command {
+        Command::Seek(position) => {
+          if let Err(err) = self.decoder.seek(position) {
+            self.decoder.fail(err);
+            self.stopped = true;
+          }
+        }
+        Command::Stop => {
+          if let Some(completion) = &self.decoder.completion {
+            completion.complete(Completed::Stopped);
+          }
+          self.stopped = true;
+        }
+      }
+    }
+  }
+}
+
+impl<R> Iterator for ControlledDecoder<R>
+where
+  R: Read + Seek,
+{
+  type Item = i16;
+  /// Returns the next sample, running the controller's commands first at the
+  /// start of a frame. Errors are added to the decoder's `iter_error`.
+  fn next(&mut self) -> Option<i16> {
+    if self.frame_left == 0 {
+      self.handle_commands();
+      if self.stopped {
+        return None;
+      }
+      self.frame_left = self.decoder.current_frame_len().unwrap_or(0);
+    }
+    let sample = self.decoder.next()?;
+    self.frame_left = self.frame_left.saturating_sub(1);
+    Some(sample)
+  }
+}
+
+impl<R> Decoder<R>
+where
+  R: Read + Seek,
+{
+  /// Wrap the decoder so it can be controlled after being given away, like
+  /// to a rodio `Sink`. See `ControlledDecoder`.
+  pub fn into_controlled(self) -> (ControlledDecoder<R>, Controller) {
+    ControlledDecoder::new(self)
+  }
+}
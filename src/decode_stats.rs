@@ -0,0 +1,42 @@
+//! Decoding speed, for benchmarking and capacity planning
+use crate::Decoder;
+use std::io::{Read, Seek};
+use std::time::Duration;
+
+/// How fast a decoder has decoded, from `Decoder::decode_stats`
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct DecodeStats {
+  /// Number of decoded frames
+  pub frames: u64,
+  /// Wall-clock time spent decoding frames, including reading the input
+  pub decode_time: Duration,
+  /// Duration of the decoded audio
+  pub audio_time: Duration,
+}
+
+impl DecodeStats {
+  /// Seconds of audio decoded per second, like 85.0 for 85x realtime. None
+  /// before anything has been decoded.
+  pub fn realtime_factor(&self) -> Option<f64> {
+    match self.decode_time.is_zero() {
+      true => None,
+      false => Some(self.audio_time.as_secs_f64() / self.decode_time.as_secs_f64()),
+    }
+  }
+}
+
+impl<R> Decoder<R>
+where
+  R: Read + Seek,
+{
+  /// Decoding speed since the decoder was created. Seeking doesn't reset it,
+  /// and time spent returning samples, like applying the gain, isn't
+  /// included.
+  pub fn decode_stats(&self) -> DecodeStats {
+    DecodeStats {
+      frames: self.decoded_frames,
+      decode_time: self.decode_time,
+      audio_time: self.decoded_audio,
+    }
+  }
+}
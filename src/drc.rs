@@ -0,0 +1,83 @@
+//! Dynamic range control and downmix metadata, applied by fdk-aac
+use crate::Error;
+
+/// Effect requested from MPEG-D DRC (USAC), which picks a DRC set from the
+/// stream's metadata
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DrcEffect {
+  /// Disable MPEG-D DRC completely
+  Off,
+  /// No effect, but DRC is still used to prevent clipping
+  #[default]
+  None,
+  LateNight,
+  NoisyEnvironment,
+  LimitedPlaybackRange,
+  LowPlaybackLevel,
+  DialogEnhancement,
+  /// General compression
+  General,
+}
+
+/// Which standard to interpret downmix and DRC metadata by
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MetadataProfile {
+  /// MPEG standard
+  #[default]
+  Mpeg,
+  /// ARIB STD-B32, used for Japanese broadcasts
+  Arib,
+  /// ETSI TS 101 154, used for DVB broadcasts
+  Dvb,
+}
+
+/// How to apply the dynamic range control and downmix metadata in the
+/// stream, like from MPEG-4 DRC, DVB heavy compression or MPEG-D DRC. Set it
+/// with `DecoderBuilder::drc`. Streams without metadata are left as is.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Drc {
+  /// Loudness to normalize to, in LKFS from -31.75 to 0, using the program
+  /// reference level in the stream. None disables loudness normalization.
+  pub target_level: Option<f32>,
+  /// How much of the DRC gain reductions to apply, from 0.0 to 1.0
+  pub cut: f32,
+  /// How much of the DRC gain boosts to apply, from 0.0 to 1.0
+  pub boost: f32,
+  /// Use DVB heavy compression instead of light compression when the stream
+  /// has both
+  pub heavy_compression: bool,
+  pub effect: DrcEffect,
+  pub metadata_profile: MetadataProfile,
+}
+
+impl Default for Drc {
+  fn default() -> Self {
+    Drc {
+      target_level: None,
+      cut: 1.0,
+      boost: 1.0,
+      heavy_compression: false,
+      effect: DrcEffect::None,
+      metadata_profile: MetadataProfile::Mpeg,
+    }
+  }
+}
+
+impl Drc {
+  pub(crate) fn validate(&self) -> Result<(), Error> {
+    if self
+      .target_level
+      .is_some_and(|level| !(-31.75..=0.0).contains(&level))
+    {
+      return Err(Error::InvalidConfig(
+        "DRC target level must be from -31.75 to 0 LKFS",
+      ));
+    }
+    if !(0.0..=1.0).contains(&self.cut) || !(0.0..=1.0).contains(&self.boost) {
+      return Err(Error::InvalidConfig(
+        "DRC cut and boost must be from 0.0 to 1.0",
+      ));
+    }
+    Ok(())
+  }
+}
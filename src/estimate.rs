@@ -0,0 +1,144 @@
+//! Estimating the duration of AAC input without scanning all of it
+use crate::{scan_adts_frames, Decoder, Error, Reader};
+use std::io::{Read, Seek, SeekFrom};
+use std::time::Duration;
+
+/// Number of frames sampled at the start and end of the stream
+const ESTIMATE_FRAMES: usize = 64;
+
+/// Duration from `Decoder::estimate_duration`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DurationEstimate {
+  pub duration: Duration,
+  /// Whether the duration is exact, because the input is MP4, the stream has
+  /// been scanned, or it's no longer than the sampled frames
+  pub exact: bool,
+  /// Duration if every frame had the bitrate of the sampled frame with the
+  /// highest bitrate
+  pub min: Duration,
+  /// Duration if every frame had the bitrate of the sampled frame with the
+  /// lowest bitrate
+  pub max: Duration,
+}
+
+impl DurationEstimate {
+  fn exact(duration: Duration) -> Self {
+    DurationEstimate {
+      duration,
+      exact: true,
+      min: duration,
+      max: duration,
+    }
+  }
+}
+
+/// Frames sampled from part of the stream
+#[derive(Default)]
+struct Sample {
+  bytes: u64,
+  duration: Duration,
+  /// Lowest and highest bytes per second of a frame
+  min_rate: f64,
+  max_rate: f64,
+}
+
+impl Sample {
+  /// Sample up to `ESTIMATE_FRAMES` frames from `offset`. Returns the sample,
+  /// and whether the stream ended first.
+  fn read<R: Read + Seek>(reader: &mut R, offset: u64) -> Result<(Self, bool), Error> {
+    let mut frames = Vec::with_capacity(ESTIMATE_FRAMES + 1);
+    let end = scan_adts_frames(reader, offset, |offset, time| {
+      frames.push((offset, time));
+      frames.len() <= ESTIMATE_FRAMES
+    })?;
+    let ended = frames.len() <= ESTIMATE_FRAMES;
+    if ended {
+      // the stream ended at the end of the last frame
+      let stream_end = reader.stream_position().map_err(Error::ReaderError)?;
+      frames.push((stream_end, end));
+    }
+    let mut sample = Sample {
+      min_rate: f64::INFINITY,
+      ..Default::default()
+    };
+    for pair in frames.windows(2) {
+      let (bytes, duration) = (pair[1].0 - pair[0].0, pair[1].1 - pair[0].1);
+      sample.bytes += bytes;
+      sample.duration += duration;
+      if duration > Duration::ZERO {
+        let rate = bytes as f64 / duration.as_secs_f64();
+        sample.min_rate = sample.min_rate.min(rate);
+        sample.max_rate = sample.max_rate.max(rate);
+      }
+    }
+    Ok((sample, ended))
+  }
+}
+
+impl<R> Decoder<R>
+where
+  R: Read + Seek,
+{
+  /// Estimate the duration from the average bitrate of some frames at the
+  /// start and end of the stream, which is much faster than scanning all of
+  /// AAC input, like over a network. The reader is left where it was, and
+  /// the known duration is returned for MP4 input or AAC input that's already
+  /// been scanned.
+  ///
+  /// For constant bitrate streams, the estimate is within a frame of the
+  /// actual duration, plus however much metadata or garbage there is between
+  /// frames. For variable bitrate streams, it's off by how much the sampled
+  /// frames' average bitrate differs from the whole stream's, which is
+  /// usually a few percent. It can't be off by more than `min` and `max`,
+  /// unless frames outside of the sampled ones have more extreme bitrates.
+  pub fn estimate_duration(&mut self) -> Result<DurationEstimate, Error> {
+    if self.is_live() {
+      return Err(Error::InvalidConfig("live streams have no duration"));
+    }
+    if let Some(duration) = self.duration {
+      return Ok(DurationEstimate::exact(duration));
+    }
+    let aac_reader = match &mut self.reader {
+      Reader::AacReader(aac_reader) => aac_reader,
+      // MP4 input has its duration in the sample table
+      Reader::Mp4Reader(_) => return Ok(DurationEstimate::exact(self.stream_duration()?)),
+    };
+    let position = aac_reader.stream_position().map_err(Error::ReaderError)?;
+    let result = estimate(aac_reader, self.stream_start);
+    aac_reader
+      .seek(SeekFrom::Start(position))
+      .map_err(Error::ReaderError)?;
+    let estimate = result?;
+    if estimate.exact {
+      self.duration = Some(estimate.duration);
+    }
+    Ok(estimate)
+  }
+}
+
+fn estimate<R: Read + Seek>(reader: &mut R, stream_start: u64) -> Result<DurationEstimate, Error> {
+  let (start, ended) = Sample::read(reader, stream_start)?;
+  if ended {
+    return Ok(DurationEstimate::exact(start.duration));
+  }
+  let len = reader.seek(SeekFrom::End(0)).map_err(Error::ReaderError)?;
+  // the frames at the end are found by syncing to the next frame header
+  let sampled_len = start.bytes.saturating_mul(2);
+  let tail_offset = len
+    .saturating_sub(sampled_len)
+    .max(stream_start + start.bytes);
+  let (end, _) = Sample::read(reader, tail_offset)?;
+  let bytes = (start.bytes + end.bytes) as f64;
+  let seconds = (start.duration + end.duration).as_secs_f64();
+  let stream_len = len.saturating_sub(stream_start) as f64;
+  let duration = |rate: f64| match rate.is_finite() && rate > 0.0 {
+    true => Duration::from_secs_f64(stream_len / rate),
+    false => Duration::ZERO,
+  };
+  Ok(DurationEstimate {
+    duration: duration(bytes / seconds),
+    exact: false,
+    min: duration(start.max_rate.max(end.max_rate)),
+    max: duration(start.min_rate.min(end.min_rate)),
+  })
+}
@@ -0,0 +1,22 @@
+/// Stream event, received from `Decoder::subscribe`. Events are sent when a
+/// frame is decoded, which happens one frame ahead of the samples being
+/// returned. The first frame is decoded when the decoder is created, before
+/// there can be any subscribers.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Event {
+  /// The sample rate changed to the given rate, starting with the next frame
+  SampleRateChanged(u32),
+  /// The channel count changed to the given count, starting with the next
+  /// frame
+  ChannelsChanged(u16),
+  /// Invalid AAC input was skipped to find the next ADTS frame
+  Resync {
+    /// Number of bytes skipped
+    skipped_bytes: usize,
+  },
+  /// The decoder concealed errors in the input, so the next frame may
+  /// contain interpolated audio
+  Concealment,
+  /// The end of the stream was reached
+  EndOfStream,
+}
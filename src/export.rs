@@ -0,0 +1,190 @@
+//! Export decoded audio to other formats
+use crate::{Decoder, Error};
+use std::convert::TryFrom;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+#[cfg(feature = "flac")]
+mod flac;
+#[cfg(feature = "flac")]
+pub use flac::{to_flac, write_flac};
+
+const WAV_HEADER_SIZE: u32 = 44;
+
+fn too_large() -> io::Error {
+  io::Error::new(
+    io::ErrorKind::InvalidInput,
+    "decoded audio is too large for a WAV file",
+  )
+}
+
+fn write_wav_header<W: Write>(
+  writer: &mut W,
+  channels: u16,
+  sample_rate: u32,
+  data_size: u64,
+) -> io::Result<()> {
+  let riff_size = u32::try_from(WAV_HEADER_SIZE as u64 - 8 + data_size).or(Err(too_large()))?;
+  let block_align = channels.checked_mul(2).ok_or_else(too_large)?;
+  let byte_rate = sample_rate.checked_mul(block_align as u32);
+  let byte_rate = byte_rate.ok_or_else(too_large)?;
+  writer.write_all(b"RIFF")?;
+  writer.write_all(&riff_size.to_le_bytes())?;
+  writer.write_all(b"WAVE")?;
+  writer.write_all(b"fmt ")?;
+  writer.write_all(&16u32.to_le_bytes())?; // fmt chunk size
+  writer.write_all(&1u16.to_le_bytes())?; // PCM
+  writer.write_all(&channels.to_le_bytes())?;
+  writer.write_all(&sample_rate.to_le_bytes())?;
+  writer.write_all(&byte_rate.to_le_bytes())?;
+  writer.write_all(&block_align.to_le_bytes())?;
+  writer.write_all(&16u16.to_le_bytes())?; // bits per sample
+  writer.write_all(b"data")?;
+  writer.write_all(&(data_size as u32).to_le_bytes())?;
+  Ok(())
+}
+
+fn write_samples<W: Write>(writer: &mut W, samples: &[i16]) -> io::Result<()> {
+  let mut bytes = Vec::with_capacity(samples.len() * 2);
+  for sample in samples {
+    bytes.extend_from_slice(&sample.to_le_bytes());
+  }
+  writer.write_all(&bytes)
+}
+
+/// Write 16-bit PCM samples as a WAV file
+pub fn write_wav<W: Write>(
+  mut writer: W,
+  channels: u16,
+  sample_rate: u32,
+  samples: &[i16],
+) -> Result<(), Error> {
+  let data_size = samples.len() as u64 * 2;
+  write_wav_header(&mut writer, channels, sample_rate, data_size).map_err(Error::WriterError)?;
+  write_samples(&mut writer, samples).map_err(Error::WriterError)?;
+  Ok(())
+}
+
+/// Decode the rest of the decoder's output into a 16-bit WAV file. The header
+/// is written last, which is why the writer needs to be seekable. Use
+/// `DecoderBuilder::fixed_channels` for streams that change channel count.
+pub fn to_wav<R, W>(decoder: &mut Decoder<R>, mut writer: W) -> Result<(), Error>
+where
+  R: Read + Seek,
+  W: Write + Seek,
+{
+  let (channels, sample_rate) = (decoder.channels(), decoder.sample_rate());
+  let mut pcm = Vec::new();
+  decoder.next_chunk(&mut pcm)?;
+
+  write_wav_header(&mut writer, channels, sample_rate, 0).map_err(Error::WriterError)?;
+  let mut data_size: u64 = 0;
+  while !pcm.is_empty() {
+    write_samples(&mut writer, &pcm).map_err(Error::WriterError)?;
+    data_size += pcm.len() as u64 * 2;
+    pcm.clear();
+    decoder.next_chunk(&mut pcm)?;
+  }
+  writer
+    .seek(SeekFrom::Start(0))
+    .map_err(Error::WriterError)?;
+  write_wav_header(&mut writer, channels, sample_rate, data_size).map_err(Error::WriterError)?;
+  writer.flush().map_err(Error::WriterError)?;
+  Ok(())
+}
+
+/// Sample format of headerless PCM from `to_raw_pcm`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PcmFormat {
+  /// 16-bit signed little-endian integers, like `ffmpeg -f s16le`
+  #[default]
+  S16Le,
+  /// 32-bit little-endian floats from -1.0 to 1.0, like `ffmpeg -f f32le`
+  F32Le,
+}
+
+impl PcmFormat {
+  fn write_sample(self, bytes: &mut Vec<u8>, sample: i16) {
+    match self {
+      PcmFormat::S16Le => bytes.extend_from_slice(&sample.to_le_bytes()),
+      PcmFormat::F32Le => bytes.extend_from_slice(&(sample as f32 / 32768.0).to_le_bytes()),
+    }
+  }
+}
+
+/// Decode the rest of the decoder's output into headerless interleaved PCM,
+/// like for tools that read raw samples from stdin.
+///
+/// `channel_map` has the decoder channel for each output channel, so
+/// `[1, 0]` swaps left and right and `[0]` only writes the first channel.
+/// Output channels mapped to a channel the decoder doesn't have are silent.
+/// Without a channel map, the channels are written as they're decoded, in
+/// the order from `DecoderBuilder::channel_order`.
+pub fn to_raw_pcm<R, W>(
+  decoder: &mut Decoder<R>,
+  mut writer: W,
+  format: PcmFormat,
+  channel_map: Option<&[usize]>,
+) -> Result<(), Error>
+where
+  R: Read + Seek,
+  W: Write,
+{
+  let mut pcm = Vec::new();
+  let mut bytes = Vec::new();
+  loop {
+    let channels = decoder.channels().max(1) as usize;
+    pcm.clear();
+    if decoder.next_chunk(&mut pcm)? == 0 {
+      break;
+    }
+    bytes.clear();
+    for samples in pcm.chunks_exact(channels) {
+      match channel_map {
+        Some(map) => {
+          for &channel in map {
+            format.write_sample(&mut bytes, samples.get(channel).copied().unwrap_or(0));
+          }
+        }
+        None => samples
+          .iter()
+          .for_each(|&sample| format.write_sample(&mut bytes, sample)),
+      }
+    }
+    writer.write_all(&bytes).map_err(Error::WriterError)?;
+  }
+  writer.flush().map_err(Error::WriterError)
+}
+
+/// Like `to_raw_pcm`, with each channel written to a separate writer, like
+/// for DSP tools that process one channel per file. Writer `i` gets decoder
+/// channel `i`, and is silent if the decoder doesn't have that channel.
+pub fn to_raw_pcm_channels<R, W>(
+  decoder: &mut Decoder<R>,
+  writers: &mut [W],
+  format: PcmFormat,
+) -> Result<(), Error>
+where
+  R: Read + Seek,
+  W: Write,
+{
+  let mut pcm = Vec::new();
+  let mut bytes = vec![Vec::new(); writers.len()];
+  loop {
+    let channels = decoder.channels().max(1) as usize;
+    pcm.clear();
+    if decoder.next_chunk(&mut pcm)? == 0 {
+      break;
+    }
+    for (channel, (writer, bytes)) in writers.iter_mut().zip(&mut bytes).enumerate() {
+      bytes.clear();
+      for samples in pcm.chunks_exact(channels) {
+        format.write_sample(bytes, samples.get(channel).copied().unwrap_or(0));
+      }
+      writer.write_all(bytes).map_err(Error::WriterError)?;
+    }
+  }
+  for writer in writers {
+    writer.flush().map_err(Error::WriterError)?;
+  }
+  Ok(())
+}
@@ -0,0 +1,726 @@
+//! FLAC encoding, with fixed predictors and Rice coded residuals like the
+//! `flac` tool's fastest presets, which don't use LPC
+use crate::{Decoder, Error};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+/// Samples per channel in a frame
+const BLOCK_SIZE: usize = 4096;
+const BITS_PER_SAMPLE: u32 = 16;
+const MAX_PARTITION_ORDER: u32 = 8;
+/// Largest Rice parameter with 4-bit parameters
+const MAX_RICE_PARAMETER: u32 = 14;
+/// Size of the "fLaC" marker and the STREAMINFO block
+const HEADER_SIZE: usize = 4 + 4 + 34;
+
+fn invalid(message: &'static str) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+/// Encoder for a FLAC stream, that keeps what the STREAMINFO block needs
+struct FlacEncoder {
+  channels: u16,
+  sample_rate: u32,
+  /// Interleaved samples of a block that isn't full yet
+  pending: Vec<i16>,
+  frame_number: u64,
+  samples: u64,
+  min_frame_size: u32,
+  max_frame_size: u32,
+  md5: Md5,
+}
+
+impl FlacEncoder {
+  fn new(channels: u16, sample_rate: u32) -> io::Result<Self> {
+    if !(1..=8).contains(&channels) {
+      return Err(invalid("FLAC supports 1 to 8 channels"));
+    }
+    if sample_rate == 0 || sample_rate >= 1 << 20 {
+      return Err(invalid("sample rate not supported by FLAC"));
+    }
+    Ok(FlacEncoder {
+      channels,
+      sample_rate,
+      pending: Vec::with_capacity(BLOCK_SIZE * channels as usize),
+      frame_number: 0,
+      samples: 0,
+      min_frame_size: u32::MAX,
+      max_frame_size: 0,
+      md5: Md5::new(),
+    })
+  }
+  /// The "fLaC" marker and STREAMINFO block
+  fn header(&self) -> [u8; HEADER_SIZE] {
+    let mut writer = BitWriter::default();
+    writer.bytes.extend_from_slice(b"fLaC");
+    writer.write(1, 1); // last metadata block
+    writer.write(0, 7); // STREAMINFO
+    writer.write(34, 24);
+    writer.write(BLOCK_SIZE as u64, 16);
+    writer.write(BLOCK_SIZE as u64, 16);
+    // 0 means unknown, for when nothing has been encoded
+    let min_frame_size = self.min_frame_size.min(self.max_frame_size);
+    writer.write(min_frame_size as u64, 24);
+    writer.write(self.max_frame_size as u64, 24);
+    writer.write(self.sample_rate as u64, 20);
+    writer.write(self.channels as u64 - 1, 3);
+    writer.write(BITS_PER_SAMPLE as u64 - 1, 5);
+    writer.write(self.samples.min((1 << 36) - 1), 36);
+    let md5 = self.md5.clone().finish();
+    writer.bytes.extend_from_slice(&md5);
+    let mut header = [0; HEADER_SIZE];
+    header.copy_from_slice(&writer.bytes);
+    header
+  }
+  fn push<W: Write>(&mut self, mut samples: &[i16], writer: &mut W) -> io::Result<()> {
+    let block_len = BLOCK_SIZE * self.channels as usize;
+    while !samples.is_empty() {
+      let len = (block_len - self.pending.len()).min(samples.len());
+      self.pending.extend_from_slice(&samples[..len]);
+      samples = &samples[len..];
+      if self.pending.len() == block_len {
+        self.write_frame(writer)?;
+      }
+    }
+    Ok(())
+  }
+  /// Encode the last block, which can be shorter
+  fn finish<W: Write>(&mut self, writer: &mut W) -> io::Result<()> {
+    let channels = self.channels as usize;
+    self
+      .pending
+      .truncate(self.pending.len() / channels * channels);
+    if !self.pending.is_empty() {
+      self.write_frame(writer)?;
+    }
+    Ok(())
+  }
+  fn write_frame<W: Write>(&mut self, writer: &mut W) -> io::Result<()> {
+    let channels = self.channels as usize;
+    let block_size = self.pending.len() / channels;
+    for sample in &self.pending {
+      self.md5.update(&sample.to_le_bytes());
+    }
+    let mut planes = vec![Vec::with_capacity(block_size); channels];
+    for samples in self.pending.chunks_exact(channels) {
+      for (plane, &sample) in planes.iter_mut().zip(samples) {
+        plane.push(sample as i32);
+      }
+    }
+    self.pending.clear();
+
+    let mut frame = BitWriter::default();
+    frame.write(0b1111_1111_1111_1000, 16); // sync code and fixed block size
+    let (block_size_code, block_size_bits) = match block_size {
+      BLOCK_SIZE => (0b1100, 0),
+      1..=256 => (0b0110, 8),
+      _ => (0b0111, 16),
+    };
+    frame.write(block_size_code, 4);
+    let sample_rate_code = match self.sample_rate {
+      88200 => 0b0001,
+      176400 => 0b0010,
+      192000 => 0b0011,
+      8000 => 0b0100,
+      16000 => 0b0101,
+      22050 => 0b0110,
+      24000 => 0b0111,
+      32000 => 0b1000,
+      44100 => 0b1001,
+      48000 => 0b1010,
+      96000 => 0b1011,
+      // from STREAMINFO
+      _ => 0b0000,
+    };
+    frame.write(sample_rate_code, 4);
+    let subframes = match planes.as_slice() {
+      [left, right] => stereo_subframes(left, right),
+      _ => {
+        let subframes = planes.iter().map(|plane| best_subframe(plane, 16));
+        (channels as u64 - 1, subframes.collect())
+      }
+    };
+    frame.write(subframes.0, 4);
+    frame.write(0b100, 3); // 16 bits per sample
+    frame.write(0, 1);
+    write_utf8(&mut frame, self.frame_number);
+    frame.write(block_size as u64 - 1, block_size_bits);
+    let crc = crc8(&frame.bytes);
+    frame.write(crc as u64, 8);
+    for subframe in &subframes.1 {
+      subframe.write(&mut frame);
+    }
+    frame.align();
+    let crc = crc16(&frame.bytes);
+    frame.write(crc as u64, 16);
+
+    let frame_size = frame.bytes.len() as u32;
+    self.min_frame_size = self.min_frame_size.min(frame_size);
+    self.max_frame_size = self.max_frame_size.max(frame_size);
+    self.frame_number += 1;
+    self.samples += block_size as u64;
+    writer.write_all(&frame.bytes)
+  }
+}
+
+/// Channel assignment and subframes of a stereo frame, with whichever of
+/// left, right, side and mid channels takes the fewest bits
+fn stereo_subframes(left: &[i32], right: &[i32]) -> (u64, Vec<Subframe>) {
+  let side: Vec<i32> = left.iter().zip(right).map(|(l, r)| l - r).collect();
+  let mid: Vec<i32> = left.iter().zip(right).map(|(l, r)| (l + r) >> 1).collect();
+  let left = best_subframe(left, 16);
+  let right = best_subframe(right, 16);
+  let side = best_subframe(&side, 17);
+  let mid = best_subframe(&mid, 16);
+  let options = [
+    (left.bits + right.bits, 0b0001),
+    (left.bits + side.bits, 0b1000),
+    (side.bits + right.bits, 0b1001),
+    (mid.bits + side.bits, 0b1010),
+  ];
+  let assignment = options.iter().min_by_key(|(bits, _)| *bits).unwrap().1;
+  let subframes = match assignment {
+    0b0001 => vec![left, right],
+    0b1000 => vec![left, side],
+    0b1001 => vec![side, right],
+    _ => vec![mid, side],
+  };
+  (assignment, subframes)
+}
+
+enum SubframeType {
+  Constant,
+  Verbatim,
+  /// Fixed predictor order, Rice partition order and parameters
+  Fixed(usize, u32, Vec<u32>),
+}
+
+struct Subframe {
+  kind: SubframeType,
+  bits_per_sample: u32,
+  samples: Vec<i32>,
+  residuals: Vec<i32>,
+  /// Estimated size in bits
+  bits: u64,
+}
+
+impl Subframe {
+  fn write(&self, writer: &mut BitWriter) {
+    let sample_bits = self.bits_per_sample;
+    let write_sample = |writer: &mut BitWriter, sample: i32| {
+      writer.write(sample as u64 & ((1 << sample_bits) - 1), sample_bits);
+    };
+    match &self.kind {
+      SubframeType::Constant => {
+        writer.write(0, 8);
+        write_sample(writer, self.samples[0]);
+      }
+      SubframeType::Verbatim => {
+        writer.write(0b0000_0010, 8);
+        for &sample in &self.samples {
+          write_sample(writer, sample);
+        }
+      }
+      SubframeType::Fixed(order, partition_order, parameters) => {
+        writer.write(0b0001_0000 | (*order as u64) << 1, 8);
+        for &sample in &self.samples[..*order] {
+          write_sample(writer, sample);
+        }
+        writer.write(0, 2); // Rice coding with 4-bit parameters
+        writer.write(*partition_order as u64, 4);
+        let partition_len = self.samples.len() >> partition_order;
+        let mut residuals = self.residuals.iter();
+        for (i, &parameter) in parameters.iter().enumerate() {
+          writer.write(parameter as u64, 4);
+          let len = if i == 0 {
+            partition_len - order
+          } else {
+            partition_len
+          };
+          for &residual in residuals.by_ref().take(len) {
+            let value = zigzag(residual);
+            writer.write_unary(value >> parameter);
+            writer.write(value as u64 & ((1 << parameter) - 1), parameter);
+          }
+        }
+      }
+    }
+  }
+}
+
+fn zigzag(residual: i32) -> u32 {
+  ((residual << 1) ^ (residual >> 31)) as u32
+}
+
+/// Residuals of a fixed predictor
+fn fixed_residuals(samples: &[i32], order: usize) -> Vec<i32> {
+  let s = |i: usize| samples[i] as i64;
+  (order..samples.len())
+    .map(|i| {
+      let residual = match order {
+        0 => s(i),
+        1 => s(i) - s(i - 1),
+        2 => s(i) - 2 * s(i - 1) + s(i - 2),
+        3 => s(i) - 3 * s(i - 1) + 3 * s(i - 2) - s(i - 3),
+        _ => s(i) - 4 * s(i - 1) + 6 * s(i - 2) - 4 * s(i - 3) + s(i - 4),
+      };
+      residual as i32
+    })
+    .collect()
+}
+
+/// Estimated bits and parameter of a Rice partition with `len` residuals
+/// whose zigzag values add up to `sum`
+fn rice_parameter(sum: u64, len: usize) -> (u64, u32) {
+  (0..=MAX_RICE_PARAMETER)
+    .map(|parameter| {
+      let bits = 4 + len as u64 * (parameter as u64 + 1) + (sum >> parameter);
+      (bits, parameter)
+    })
+    .min()
+    .unwrap()
+}
+
+/// Partition order, parameters and estimated bits of the residuals, with the
+/// partition order that takes the fewest bits
+fn rice_partitions(residuals: &[i32], block_size: usize, order: usize) -> (u32, Vec<u32>, u64) {
+  let mut best = (0, Vec::new(), u64::MAX);
+  // partitions all have the same length
+  let max_partition_order = MAX_PARTITION_ORDER.min(block_size.trailing_zeros());
+  for partition_order in 0..=max_partition_order {
+    let partitions = 1 << partition_order;
+    let partition_len = block_size >> partition_order;
+    if partition_len <= order {
+      break;
+    }
+    let mut bits = 0;
+    let mut parameters = Vec::with_capacity(partitions);
+    let mut start = 0;
+    for i in 0..partitions {
+      let len = if i == 0 {
+        partition_len - order
+      } else {
+        partition_len
+      };
+      let partition = &residuals[start..start + len];
+      let sum = partition.iter().map(|&r| zigzag(r) as u64).sum();
+      let (partition_bits, parameter) = rice_parameter(sum, len);
+      bits += partition_bits;
+      parameters.push(parameter);
+      start += len;
+    }
+    if bits < best.2 {
+      best = (partition_order, parameters, bits);
+    }
+  }
+  best
+}
+
+fn best_subframe(samples: &[i32], bits_per_sample: u32) -> Subframe {
+  let subframe = |kind, residuals, bits| Subframe {
+    kind,
+    bits_per_sample,
+    samples: samples.to_vec(),
+    residuals,
+    bits: 8 + bits,
+  };
+  if samples.iter().all(|&sample| sample == samples[0]) {
+    return subframe(SubframeType::Constant, Vec::new(), bits_per_sample as u64);
+  }
+  let verbatim_bits = samples.len() as u64 * bits_per_sample as u64;
+  let mut best = subframe(SubframeType::Verbatim, Vec::new(), verbatim_bits);
+  for order in 0..=4.min(samples.len() - 1) {
+    let residuals = fixed_residuals(samples, order);
+    let (partition_order, parameters, rice_bits) =
+      rice_partitions(&residuals, samples.len(), order);
+    let bits = order as u64 * bits_per_sample as u64 + 6 + rice_bits;
+    if 8 + bits < best.bits {
+      let kind = SubframeType::Fixed(order, partition_order, parameters);
+      best = subframe(kind, residuals, bits);
+    }
+  }
+  best
+}
+
+#[derive(Default)]
+struct BitWriter {
+  bytes: Vec<u8>,
+  /// Bits that don't fill a byte yet, in the low bits
+  buffer: u64,
+  buffered: u32,
+}
+
+impl BitWriter {
+  /// Write the low `bits` bits of `value`, for up to 56 bits
+  fn write(&mut self, value: u64, bits: u32) {
+    if bits > 32 {
+      self.write(value >> 32, bits - 32);
+      self.write(value & 0xFFFF_FFFF, 32);
+      return;
+    }
+    self.buffer = self.buffer << bits | value;
+    self.buffered += bits;
+    while self.buffered >= 8 {
+      self.buffered -= 8;
+      self.bytes.push((self.buffer >> self.buffered) as u8);
+    }
+    self.buffer &= (1 << self.buffered) - 1;
+  }
+  /// Write `value` zeros followed by a one
+  fn write_unary(&mut self, mut value: u32) {
+    while value >= 32 {
+      self.write(0, 32);
+      value -= 32;
+    }
+    self.write(1, value + 1);
+  }
+  /// Pad with zeros to a whole byte
+  fn align(&mut self) {
+    if self.buffered > 0 {
+      self.write(0, 8 - self.buffered);
+    }
+  }
+}
+
+/// Write a frame number with FLAC's extension of UTF-8
+fn write_utf8(writer: &mut BitWriter, value: u64) {
+  if value < 0x80 {
+    writer.write(value, 8);
+    return;
+  }
+  // continuation bytes each have 6 bits, and the first byte has the rest
+  let mut continuation = 1;
+  while value >= 1 << (5 * continuation + 6) && continuation < 6 {
+    continuation += 1;
+  }
+  let prefix = (0xFF00 >> (continuation + 1)) as u64 & 0xFF;
+  writer.write(prefix | value >> (6 * continuation), 8);
+  for i in (0..continuation).rev() {
+    writer.write(0x80 | (value >> (6 * i)) & 0x3F, 8);
+  }
+}
+
+fn crc8(data: &[u8]) -> u8 {
+  let mut crc = 0u8;
+  for &byte in data {
+    crc ^= byte;
+    for _ in 0..8 {
+      crc = if crc & 0x80 != 0 {
+        crc << 1 ^ 0x07
+      } else {
+        crc << 1
+      };
+    }
+  }
+  crc
+}
+
+fn crc16(data: &[u8]) -> u16 {
+  let mut crc = 0u16;
+  for &byte in data {
+    crc ^= (byte as u16) << 8;
+    for _ in 0..8 {
+      crc = if crc & 0x8000 != 0 {
+        crc << 1 ^ 0x8005
+      } else {
+        crc << 1
+      };
+    }
+  }
+  crc
+}
+
+/// MD5 of the samples, for the STREAMINFO block
+#[derive(Clone)]
+struct Md5 {
+  state: [u32; 4],
+  /// Bytes of the block that isn't full yet
+  buffer: Vec<u8>,
+  len: u64,
+}
+
+impl Md5 {
+  fn new() -> Self {
+    Md5 {
+      state: [0x6745_2301, 0xEFCD_AB89, 0x98BA_DCFE, 0x1032_5476],
+      buffer: Vec::with_capacity(64),
+      len: 0,
+    }
+  }
+  fn update(&mut self, mut data: &[u8]) {
+    self.len += data.len() as u64;
+    while !data.is_empty() {
+      let len = (64 - self.buffer.len()).min(data.len());
+      self.buffer.extend_from_slice(&data[..len]);
+      data = &data[len..];
+      if self.buffer.len() == 64 {
+        let mut block = [0; 64];
+        block.copy_from_slice(&self.buffer);
+        self.buffer.clear();
+        self.process(&block);
+      }
+    }
+  }
+  fn process(&mut self, block: &[u8; 64]) {
+    const SHIFTS: [u32; 16] = [7, 12, 17, 22, 5, 9, 14, 20, 4, 11, 16, 23, 6, 10, 15, 21];
+    let mut words = [0u32; 16];
+    for (word, bytes) in words.iter_mut().zip(block.chunks_exact(4)) {
+      *word = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    }
+    let [mut a, mut b, mut c, mut d] = self.state;
+    for i in 0..64 {
+      let (f, g) = match i / 16 {
+        0 => ((b & c) | (!b & d), i),
+        1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+        2 => (b ^ c ^ d, (3 * i + 5) % 16),
+        _ => (c ^ (b | !d), (7 * i) % 16),
+      };
+      let k = ((i as f64 + 1.0).sin().abs() * 4_294_967_296.0) as u32;
+      let rotated = a
+        .wrapping_add(f)
+        .wrapping_add(k)
+        .wrapping_add(words[g])
+        .rotate_left(SHIFTS[i / 16 * 4 + i % 4]);
+      a = d;
+      d = c;
+      c = b;
+      b = b.wrapping_add(rotated);
+    }
+    for (state, value) in self.state.iter_mut().zip([a, b, c, d]) {
+      *state = state.wrapping_add(value);
+    }
+  }
+  fn finish(mut self) -> [u8; 16] {
+    let bits = self.len.wrapping_mul(8);
+    self.update(&[0x80]);
+    while self.buffer.len() != 56 {
+      self.update(&[0]);
+    }
+    self.update(&bits.to_le_bytes());
+    let mut digest = [0; 16];
+    for (bytes, state) in digest.chunks_exact_mut(4).zip(self.state) {
+      bytes.copy_from_slice(&state.to_le_bytes());
+    }
+    digest
+  }
+}
+
+/// Write 16-bit PCM samples as a FLAC file
+pub fn write_flac<W: Write>(
+  mut writer: W,
+  channels: u16,
+  sample_rate: u32,
+  samples: &[i16],
+) -> Result<(), Error> {
+  let mut encoder = FlacEncoder::new(channels, sample_rate).map_err(Error::WriterError)?;
+  let mut frames = Vec::new();
+  encoder
+    .push(samples, &mut frames)
+    .and_then(|_| encoder.finish(&mut frames))
+    .map_err(Error::WriterError)?;
+  writer
+    .write_all(&encoder.header())
+    .and_then(|_| writer.write_all(&frames))
+    .map_err(Error::WriterError)
+}
+
+/// Decode the rest of the decoder's output into a 16-bit FLAC file, which is
+/// lossless like WAV but usually around half the size. The STREAMINFO block
+/// is written last, which is why the writer needs to be seekable. Use
+/// `DecoderBuilder::fixed_channels` for streams that change channel count.
+pub fn to_flac<R, W>(decoder: &mut Decoder<R>, mut writer: W) -> Result<(), Error>
+where
+  R: Read + Seek,
+  W: Write + Seek,
+{
+  let (channels, sample_rate) = (decoder.channels(), decoder.sample_rate());
+  let mut pcm = Vec::new();
+  decoder.next_chunk(&mut pcm)?;
+
+  let mut encoder = FlacEncoder::new(channels, sample_rate).map_err(Error::WriterError)?;
+  writer
+    .write_all(&encoder.header())
+    .map_err(Error::WriterError)?;
+  while !pcm.is_empty() {
+    encoder
+      .push(&pcm, &mut writer)
+      .map_err(Error::WriterError)?;
+    pcm.clear();
+    decoder.next_chunk(&mut pcm)?;
+  }
+  encoder.finish(&mut writer).map_err(Error::WriterError)?;
+  writer
+    .seek(SeekFrom::Start(0))
+    .and_then(|_| writer.write_all(&encoder.header()))
+    .and_then(|_| writer.flush())
+    .map_err(Error::WriterError)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+  }
+
+  impl<'a> BitReader<'a> {
+    fn read(&mut self, bits: u32) -> u64 {
+      let mut value = 0;
+      for _ in 0..bits {
+        let bit = self.data[self.position / 8] >> (7 - self.position % 8) & 1;
+        value = value << 1 | bit as u64;
+        self.position += 1;
+      }
+      value
+    }
+    fn read_signed(&mut self, bits: u32) -> i32 {
+      let value = self.read(bits) as i64;
+      (value << (64 - bits) >> (64 - bits)) as i32
+    }
+  }
+
+  /// Decode the subframe types the encoder writes
+  fn decode_subframe(reader: &mut BitReader, block_size: usize, bits: u32) -> Vec<i32> {
+    let kind = reader.read(8) >> 1;
+    match kind {
+      0 => vec![reader.read_signed(bits); block_size],
+      1 => (0..block_size).map(|_| reader.read_signed(bits)).collect(),
+      _ => {
+        let order = (kind & 0b111) as usize;
+        let mut samples: Vec<i32> = (0..order).map(|_| reader.read_signed(bits)).collect();
+        assert_eq!(reader.read(2), 0);
+        let partition_order = reader.read(4);
+        let partition_len = block_size >> partition_order;
+        let mut residuals = Vec::new();
+        for i in 0..1 << partition_order {
+          let parameter = reader.read(4) as u32;
+          let len = if i == 0 {
+            partition_len - order
+          } else {
+            partition_len
+          };
+          for _ in 0..len {
+            let mut quotient = 0;
+            while reader.read(1) == 0 {
+              quotient += 1;
+            }
+            let value = (quotient << parameter | reader.read(parameter)) as u32;
+            residuals.push((value >> 1) as i32 ^ -((value & 1) as i32));
+          }
+        }
+        let coefficients: &[i64] = match order {
+          0 => &[],
+          1 => &[1],
+          2 => &[2, -1],
+          3 => &[3, -3, 1],
+          _ => &[4, -6, 4, -1],
+        };
+        for residual in residuals {
+          let i = samples.len();
+          let prediction: i64 = (coefficients.iter().enumerate())
+            .map(|(j, c)| c * samples[i - j - 1] as i64)
+            .sum();
+          samples.push((prediction + residual as i64) as i32);
+        }
+        samples
+      }
+    }
+  }
+
+  fn decode(data: &[u8]) -> (u16, u64, Vec<i16>) {
+    assert_eq!(&data[..4], b"fLaC");
+    let mut reader = BitReader {
+      data,
+      position: 8 * 8,
+    };
+    reader.read(16 + 16 + 24 + 24 + 20);
+    let channels = reader.read(3) as u16 + 1;
+    reader.read(5);
+    let total = reader.read(36);
+    let md5 = &data[26..42];
+    reader.position = HEADER_SIZE * 8;
+    let mut samples = Vec::new();
+    while reader.position / 8 < data.len() {
+      let frame_start = reader.position / 8;
+      assert_eq!(reader.read(16), 0xFFF8);
+      let block_size_code = reader.read(4);
+      reader.read(4);
+      let assignment = reader.read(4);
+      reader.read(4);
+      let first = reader.read(8);
+      for _ in 0..(first as u8).leading_ones().saturating_sub(1) {
+        reader.read(8);
+      }
+      let block_size = match block_size_code {
+        0b1100 => BLOCK_SIZE,
+        0b0110 => reader.read(8) as usize + 1,
+        _ => reader.read(16) as usize + 1,
+      };
+      let crc = crc8(&data[frame_start..reader.position / 8]);
+      assert_eq!(reader.read(8), crc as u64);
+      let bits = |i: u64| match (assignment, i) {
+        (8, 1) | (9, 0) | (10, 1) => 17,
+        _ => 16,
+      };
+      let planes: Vec<Vec<i32>> = (0..channels as u64)
+        .map(|i| decode_subframe(&mut reader, block_size, bits(i)))
+        .collect();
+      reader.position = reader.position.div_ceil(8) * 8;
+      let crc = crc16(&data[frame_start..reader.position / 8]);
+      assert_eq!(reader.read(16), crc as u64);
+      for i in 0..block_size {
+        let channel_samples: Vec<i32> = match assignment {
+          8 => vec![planes[0][i], planes[0][i] - planes[1][i]],
+          9 => vec![planes[0][i] + planes[1][i], planes[1][i]],
+          10 => {
+            let (mid, side) = (planes[0][i], planes[1][i]);
+            let sum = mid * 2 + (side & 1);
+            vec![(sum + side) >> 1, (sum - side) >> 1]
+          }
+          _ => planes.iter().map(|plane| plane[i]).collect(),
+        };
+        samples.extend(channel_samples.iter().map(|&sample| sample as i16));
+      }
+    }
+    let mut expected_md5 = Md5::new();
+    for sample in &samples {
+      expected_md5.update(&sample.to_le_bytes());
+    }
+    assert_eq!(md5, expected_md5.finish());
+    (channels, total, samples)
+  }
+
+  #[test]
+  fn md5() {
+    let mut md5 = Md5::new();
+    md5.update(b"The quick brown fox jumps over the lazy dog");
+    let hex: String = md5.finish().iter().map(|b| format!("{:02x}", b)).collect();
+    assert_eq!(hex, "9e107d9d372bb6826bd81d3542a419d6");
+  }
+
+  #[test]
+  fn round_trip() {
+    // more than 128 frames, so frame numbers take 2 bytes
+    let len = BLOCK_SIZE * 130 + 1000;
+    let stereo: Vec<i16> = (0..len)
+      .flat_map(|i| {
+        let left = ((i as f64 * 0.05).sin() * 20000.0) as i16;
+        let noise = (i * 7919 % 601) as i16 - 300;
+        [left, left / 2 + noise]
+      })
+      .collect();
+    let mut flac = Vec::new();
+    write_flac(&mut flac, 2, 44100, &stereo).unwrap();
+    assert!(flac.len() < stereo.len() * 2);
+    assert_eq!(decode(&flac), (2, len as u64, stereo));
+
+    // silence, extremes and a final block too short for some predictors
+    let mut mono = vec![0; BLOCK_SIZE];
+    mono.extend((0..BLOCK_SIZE).map(|i| if i % 2 == 0 { i16::MAX } else { i16::MIN }));
+    mono.extend([5, -3]);
+    let mut flac = Vec::new();
+    write_flac(&mut flac, 1, 12345, &mono).unwrap();
+    assert_eq!(decode(&flac), (1, mono.len() as u64, mono));
+  }
+}
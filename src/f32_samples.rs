@@ -0,0 +1,158 @@
+//! f32 output, for rodio 0.21 and other sinks that take float samples
+use crate::Decoder;
+#[cfg(feature = "rodio-0_21")]
+use crate::{playlist::Playlist, ChannelSource, ControlledDecoder, LoopingDecoder};
+use std::io::{Read, Seek};
+#[cfg(feature = "rodio-0_21")]
+use std::time::Duration;
+
+/// Iterator that converts the i16 samples of a decoder, `LoopingDecoder`,
+/// `ControlledDecoder`, `ChannelSource` or `Playlist` to f32 samples from
+/// -1.0 to 1.0. It implements rodio 0.21's `Source` trait with the
+/// `rodio-0_21` feature.
+pub struct F32Samples<S> {
+  inner: S,
+}
+
+impl<S> F32Samples<S>
+where
+  S: Iterator<Item = i16>,
+{
+  pub fn new(inner: S) -> Self {
+    F32Samples { inner }
+  }
+  pub fn inner(&self) -> &S {
+    &self.inner
+  }
+  /// Mutable access to the inner iterator, like for seeking a decoder
+  pub fn inner_mut(&mut self) -> &mut S {
+    &mut self.inner
+  }
+  pub fn into_inner(self) -> S {
+    self.inner
+  }
+}
+
+impl<S> Iterator for F32Samples<S>
+where
+  S: Iterator<Item = i16>,
+{
+  type Item = f32;
+  fn next(&mut self) -> Option<f32> {
+    self.inner.next().map(|sample| sample as f32 / 32768.0)
+  }
+  fn size_hint(&self) -> (usize, Option<usize>) {
+    self.inner.size_hint()
+  }
+}
+
+impl<R> Decoder<R>
+where
+  R: Read + Seek,
+{
+  /// Output f32 samples instead of i16, like for rodio 0.21
+  pub fn into_f32(self) -> F32Samples<Self> {
+    F32Samples::new(self)
+  }
+}
+
+#[cfg(feature = "rodio-0_21")]
+impl<R> rodio_0_21::Source for F32Samples<Decoder<R>>
+where
+  R: Read + Seek,
+{
+  fn current_span_len(&self) -> Option<usize> {
+    self.inner.current_frame_len()
+  }
+  fn channels(&self) -> u16 {
+    self.inner.channels()
+  }
+  fn sample_rate(&self) -> u32 {
+    self.inner.sample_rate()
+  }
+  fn total_duration(&self) -> Option<Duration> {
+    self.inner.total_duration()
+  }
+}
+
+#[cfg(feature = "rodio-0_21")]
+impl<R> rodio_0_21::Source for F32Samples<LoopingDecoder<R>>
+where
+  R: Read + Seek,
+{
+  fn current_span_len(&self) -> Option<usize> {
+    self.inner.decoder().current_frame_len()
+  }
+  fn channels(&self) -> u16 {
+    self.inner.decoder().channels()
+  }
+  fn sample_rate(&self) -> u32 {
+    self.inner.decoder().sample_rate()
+  }
+  fn total_duration(&self) -> Option<Duration> {
+    None
+  }
+}
+
+#[cfg(feature = "rodio-0_21")]
+impl<R> rodio_0_21::Source for F32Samples<ControlledDecoder<R>>
+where
+  R: Read + Seek,
+{
+  fn current_span_len(&self) -> Option<usize> {
+    self.inner.current_frame_len()
+  }
+  fn channels(&self) -> u16 {
+    self.inner.decoder().channels()
+  }
+  fn sample_rate(&self) -> u32 {
+    self.inner.decoder().sample_rate()
+  }
+  fn total_duration(&self) -> Option<Duration> {
+    None
+  }
+}
+
+#[cfg(feature = "rodio-0_21")]
+impl<R> rodio_0_21::Source for F32Samples<ChannelSource<R>>
+where
+  R: Read + Seek,
+{
+  fn current_span_len(&self) -> Option<usize> {
+    self.inner.current_frame_len()
+  }
+  fn channels(&self) -> u16 {
+    1
+  }
+  fn sample_rate(&self) -> u32 {
+    self.inner.sample_rate()
+  }
+  fn total_duration(&self) -> Option<Duration> {
+    None
+  }
+}
+
+#[cfg(feature = "rodio-0_21")]
+impl<R> rodio_0_21::Source for F32Samples<Playlist<R>>
+where
+  R: Read + Seek,
+{
+  fn current_span_len(&self) -> Option<usize> {
+    match self.inner.current() {
+      Some(decoder) => decoder.current_frame_len(),
+      None => Some(0),
+    }
+  }
+  fn channels(&self) -> u16 {
+    self.inner.current().map_or(2, |decoder| decoder.channels())
+  }
+  fn sample_rate(&self) -> u32 {
+    self
+      .inner
+      .current()
+      .map_or(44100, |decoder| decoder.sample_rate())
+  }
+  fn total_duration(&self) -> Option<Duration> {
+    None
+  }
+}
@@ -0,0 +1,295 @@
+//! Wrapper around the fdk-aac decoder. `fdk_aac::dec::Decoder` only supports
+//! ADTS input, so this adds the raw transport used for MPEG-4 samples.
+use crate::{ChannelOrder, Drc, DrcEffect, MetadataProfile};
+use fdk_aac::dec::{DecoderError, StreamInfo};
+use fdk_aac_sys as sys;
+use std::os::raw::{c_int, c_uint};
+
+/// Decoder errors by their fdk-aac error code
+const ERRORS: &[(sys::AAC_DECODER_ERROR, DecoderError)] = &[
+  (
+    sys::AAC_DECODER_ERROR_AAC_DEC_OUT_OF_MEMORY,
+    DecoderError::OUT_OF_MEMORY,
+  ),
+  (
+    sys::AAC_DECODER_ERROR_AAC_DEC_UNKNOWN,
+    DecoderError::UNKNOWN,
+  ),
+  (
+    sys::AAC_DECODER_ERROR_AAC_DEC_TRANSPORT_SYNC_ERROR,
+    DecoderError::TRANSPORT_SYNC_ERROR,
+  ),
+  (
+    sys::AAC_DECODER_ERROR_AAC_DEC_NOT_ENOUGH_BITS,
+    DecoderError::NOT_ENOUGH_BITS,
+  ),
+  (
+    sys::AAC_DECODER_ERROR_AAC_DEC_INVALID_HANDLE,
+    DecoderError::INVALID_HANDLE,
+  ),
+  (
+    sys::AAC_DECODER_ERROR_AAC_DEC_UNSUPPORTED_AOT,
+    DecoderError::UNSUPPORTED_AOT,
+  ),
+  (
+    sys::AAC_DECODER_ERROR_AAC_DEC_UNSUPPORTED_FORMAT,
+    DecoderError::UNSUPPORTED_FORMAT,
+  ),
+  (
+    sys::AAC_DECODER_ERROR_AAC_DEC_UNSUPPORTED_ER_FORMAT,
+    DecoderError::UNSUPPORTED_ER_FORMAT,
+  ),
+  (
+    sys::AAC_DECODER_ERROR_AAC_DEC_UNSUPPORTED_EPCONFIG,
+    DecoderError::UNSUPPORTED_EPCONFIG,
+  ),
+  (
+    sys::AAC_DECODER_ERROR_AAC_DEC_UNSUPPORTED_MULTILAYER,
+    DecoderError::UNSUPPORTED_MULTILAYER,
+  ),
+  (
+    sys::AAC_DECODER_ERROR_AAC_DEC_UNSUPPORTED_CHANNELCONFIG,
+    DecoderError::UNSUPPORTED_CHANNELCONFIG,
+  ),
+  (
+    sys::AAC_DECODER_ERROR_AAC_DEC_UNSUPPORTED_SAMPLINGRATE,
+    DecoderError::UNSUPPORTED_SAMPLINGRATE,
+  ),
+  (
+    sys::AAC_DECODER_ERROR_AAC_DEC_INVALID_SBR_CONFIG,
+    DecoderError::INVALID_SBR_CONFIG,
+  ),
+  (
+    sys::AAC_DECODER_ERROR_AAC_DEC_SET_PARAM_FAIL,
+    DecoderError::SET_PARAM_FAIL,
+  ),
+  (
+    sys::AAC_DECODER_ERROR_AAC_DEC_NEED_TO_RESTART,
+    DecoderError::NEED_TO_RESTART,
+  ),
+  (
+    sys::AAC_DECODER_ERROR_AAC_DEC_OUTPUT_BUFFER_TOO_SMALL,
+    DecoderError::OUTPUT_BUFFER_TOO_SMALL,
+  ),
+  (
+    sys::AAC_DECODER_ERROR_AAC_DEC_TRANSPORT_ERROR,
+    DecoderError::TRANSPORT_ERROR,
+  ),
+  (
+    sys::AAC_DECODER_ERROR_AAC_DEC_PARSE_ERROR,
+    DecoderError::PARSE_ERROR,
+  ),
+  (
+    sys::AAC_DECODER_ERROR_AAC_DEC_UNSUPPORTED_EXTENSION_PAYLOAD,
+    DecoderError::UNSUPPORTED_EXTENSION_PAYLOAD,
+  ),
+  (
+    sys::AAC_DECODER_ERROR_AAC_DEC_DECODE_FRAME_ERROR,
+    DecoderError::DECODE_FRAME_ERROR,
+  ),
+  (
+    sys::AAC_DECODER_ERROR_AAC_DEC_CRC_ERROR,
+    DecoderError::CRC_ERROR,
+  ),
+  (
+    sys::AAC_DECODER_ERROR_AAC_DEC_INVALID_CODE_BOOK,
+    DecoderError::INVALID_CODE_BOOK,
+  ),
+  (
+    sys::AAC_DECODER_ERROR_AAC_DEC_UNSUPPORTED_PREDICTION,
+    DecoderError::UNSUPPORTED_PREDICTION,
+  ),
+  (
+    sys::AAC_DECODER_ERROR_AAC_DEC_UNSUPPORTED_CCE,
+    DecoderError::UNSUPPORTED_CCE,
+  ),
+  (
+    sys::AAC_DECODER_ERROR_AAC_DEC_UNSUPPORTED_LFE,
+    DecoderError::UNSUPPORTED_LFE,
+  ),
+  (
+    sys::AAC_DECODER_ERROR_AAC_DEC_UNSUPPORTED_GAIN_CONTROL_DATA,
+    DecoderError::UNSUPPORTED_GAIN_CONTROL_DATA,
+  ),
+  (
+    sys::AAC_DECODER_ERROR_AAC_DEC_UNSUPPORTED_SBA,
+    DecoderError::UNSUPPORTED_SBA,
+  ),
+  (
+    sys::AAC_DECODER_ERROR_AAC_DEC_TNS_READ_ERROR,
+    DecoderError::TNS_READ_ERROR,
+  ),
+  (
+    sys::AAC_DECODER_ERROR_AAC_DEC_RVLC_ERROR,
+    DecoderError::RVLC_ERROR,
+  ),
+  (
+    sys::AAC_DECODER_ERROR_AAC_DEC_ANC_DATA_ERROR,
+    DecoderError::ANC_DATA_ERROR,
+  ),
+  (
+    sys::AAC_DECODER_ERROR_AAC_DEC_TOO_SMALL_ANC_BUFFER,
+    DecoderError::TOO_SMALL_ANC_BUFFER,
+  ),
+  (
+    sys::AAC_DECODER_ERROR_AAC_DEC_TOO_MANY_ANC_ELEMENTS,
+    DecoderError::TOO_MANY_ANC_ELEMENTS,
+  ),
+];
+
+fn check(code: sys::AAC_DECODER_ERROR) -> Result<(), DecoderError> {
+  if code == sys::AAC_DECODER_ERROR_AAC_DEC_OK {
+    return Ok(());
+  }
+  let error = ERRORS.iter().find(|(error_code, _)| *error_code == code);
+  Err(error.map_or(DecoderError::UNKNOWN, |(_, error)| *error))
+}
+
+/// Whether an error happened while decoding a frame, in which case fdk-aac
+/// still outputs a concealed frame
+pub(crate) fn is_decode_error(error: &DecoderError) -> bool {
+  let code = ERRORS.iter().find(|(_, e)| e == error);
+  code.is_some_and(|(code, _)| (0x4000..=0x7fff).contains(code))
+}
+
+/// Transport format of the decoder input
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Transport {
+  /// ADTS frames
+  Adts,
+  /// Raw access units, configured with an AudioSpecificConfig
+  Raw,
+}
+
+#[derive(Debug)]
+pub(crate) struct AacDecoder {
+  handle: sys::HANDLE_AACDECODER,
+}
+
+// An fdk-aac decoder instance has no thread-local or global state, so it can
+// be used from any thread. Everything that changes it takes &mut self, and
+// the only &self call reads the stream info.
+unsafe impl Send for AacDecoder {}
+unsafe impl Sync for AacDecoder {}
+
+impl AacDecoder {
+  /// Open a decoder. fdk-aac returns a null handle when it can't allocate
+  /// one, which is an `OUT_OF_MEMORY` error.
+  pub fn new(transport: Transport) -> Result<Self, DecoderError> {
+    let transport_type = match transport {
+      Transport::Adts => sys::TRANSPORT_TYPE_TT_MP4_ADTS,
+      Transport::Raw => sys::TRANSPORT_TYPE_TT_MP4_RAW,
+    };
+    let handle = unsafe { sys::aacDecoder_Open(transport_type, 1) };
+    if handle.is_null() {
+      return Err(DecoderError::OUT_OF_MEMORY);
+    }
+    Ok(AacDecoder { handle })
+  }
+  /// Configure the raw transport with an AudioSpecificConfig
+  pub fn config_raw(&mut self, audio_specific_config: &[u8]) -> Result<(), DecoderError> {
+    let mut config_ptr = audio_specific_config.as_ptr() as *mut u8;
+    let config_len = audio_specific_config.len() as c_uint;
+    check(unsafe { sys::aacDecoder_ConfigRaw(self.handle, &mut config_ptr, &config_len) })
+  }
+  /// Mix the output to a number of channels, duplicating mono to stereo and
+  /// downmixing streams with more channels
+  pub fn set_output_channels(&mut self, channels: u16) -> Result<(), DecoderError> {
+    let channels = channels as c_int;
+    let param = sys::AACDEC_PARAM_AAC_PCM_MIN_OUTPUT_CHANNELS;
+    check(unsafe { sys::aacDecoder_SetParam(self.handle, param, channels) })?;
+    let param = sys::AACDEC_PARAM_AAC_PCM_MAX_OUTPUT_CHANNELS;
+    check(unsafe { sys::aacDecoder_SetParam(self.handle, param, channels) })
+  }
+  /// Set the most channels to output, downmixing streams with more
+  pub fn set_max_output_channels(&mut self, channels: u16) -> Result<(), DecoderError> {
+    let param = sys::AACDEC_PARAM_AAC_PCM_MAX_OUTPUT_CHANNELS;
+    check(unsafe { sys::aacDecoder_SetParam(self.handle, param, channels as c_int) })
+  }
+  /// Set the order of interleaved channels in the output
+  pub fn set_channel_order(&mut self, order: ChannelOrder) -> Result<(), DecoderError> {
+    let mapping = match order {
+      ChannelOrder::Mpeg => 0,
+      ChannelOrder::Wav => 1,
+    };
+    let param = sys::AACDEC_PARAM_AAC_PCM_OUTPUT_CHANNEL_MAPPING;
+    check(unsafe { sys::aacDecoder_SetParam(self.handle, param, mapping) })
+  }
+  /// Configure dynamic range control and how metadata is interpreted
+  pub fn set_drc(&mut self, drc: &Drc) -> Result<(), DecoderError> {
+    // levels are in steps of -0.25 dB, where -1 disables normalization
+    let target_level = drc
+      .target_level
+      .map_or(-1, |level| (-level * 4.0).round() as c_int);
+    let effect = match drc.effect {
+      DrcEffect::Off => -1,
+      DrcEffect::None => 0,
+      DrcEffect::LateNight => 1,
+      DrcEffect::NoisyEnvironment => 2,
+      DrcEffect::LimitedPlaybackRange => 3,
+      DrcEffect::LowPlaybackLevel => 4,
+      DrcEffect::DialogEnhancement => 5,
+      DrcEffect::General => 6,
+    };
+    let metadata_profile = match drc.metadata_profile {
+      MetadataProfile::Mpeg => 0,
+      MetadataProfile::Arib => 2,
+      MetadataProfile::Dvb => 3,
+    };
+    let params = [
+      (sys::AACDEC_PARAM_AAC_DRC_REFERENCE_LEVEL, target_level),
+      (
+        sys::AACDEC_PARAM_AAC_DRC_ATTENUATION_FACTOR,
+        (drc.cut * 127.0).round() as c_int,
+      ),
+      (
+        sys::AACDEC_PARAM_AAC_DRC_BOOST_FACTOR,
+        (drc.boost * 127.0).round() as c_int,
+      ),
+      (
+        sys::AACDEC_PARAM_AAC_DRC_HEAVY_COMPRESSION,
+        drc.heavy_compression as c_int,
+      ),
+      (sys::AACDEC_PARAM_AAC_UNIDRC_SET_EFFECT, effect),
+      (sys::AACDEC_PARAM_AAC_METADATA_PROFILE, metadata_profile),
+    ];
+    for (param, value) in params {
+      check(unsafe { sys::aacDecoder_SetParam(self.handle, param, value) })?;
+    }
+    Ok(())
+  }
+  /// Discard buffered input, such as before seeking
+  pub fn clear_buffer(&mut self) -> Result<(), DecoderError> {
+    let param = sys::AACDEC_PARAM_AAC_TPDEC_CLEAR_BUFFER;
+    check(unsafe { sys::aacDecoder_SetParam(self.handle, param, 1) })
+  }
+  /// Copy input into the decoder's internal buffer. Returns the number of
+  /// bytes copied.
+  pub fn fill(&mut self, data: &[u8]) -> Result<usize, DecoderError> {
+    let mut data_ptr = data.as_ptr() as *mut u8;
+    let data_len = data.len() as c_uint;
+    let mut bytes_valid = data_len;
+    check(unsafe {
+      sys::aacDecoder_Fill(self.handle, &mut data_ptr, &data_len, &mut bytes_valid)
+    })?;
+    Ok(data.len() - bytes_valid as usize)
+  }
+  pub fn decode_frame(&mut self, pcm: &mut [i16]) -> Result<(), DecoderError> {
+    let pcm_len = pcm.len() as c_int;
+    check(unsafe { sys::aacDecoder_DecodeFrame(self.handle, pcm.as_mut_ptr(), pcm_len, 0) })
+  }
+  /// Number of samples in the last decoded frame, across all channels
+  pub fn decoded_frame_size(&self) -> usize {
+    let stream_info = self.stream_info();
+    stream_info.numChannels as usize * stream_info.frameSize as usize
+  }
+  pub fn stream_info(&self) -> &StreamInfo {
+    unsafe { &*sys::aacDecoder_GetStreamInfo(self.handle) }
+  }
+}
+
+impl Drop for AacDecoder {
+  fn drop(&mut self) {
+    unsafe { sys::aacDecoder_Close(self.handle) }
+  }
+}
@@ -0,0 +1,124 @@
+//! Output gain that can be changed while playing
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+
+/// Handle for changing the gain of a decoder from another thread, such as
+/// while rodio is playing it. Get one with `Decoder::gain_handle`.
+#[derive(Clone, Debug)]
+pub struct GainHandle {
+  /// Linear gain as f32 bits
+  gain: Arc<AtomicU32>,
+}
+
+impl Default for GainHandle {
+  fn default() -> Self {
+    GainHandle {
+      gain: Arc::new(AtomicU32::new(1f32.to_bits())),
+    }
+  }
+}
+
+impl GainHandle {
+  /// Linear gain, where 1.0 leaves the output unchanged
+  pub fn gain(&self) -> f32 {
+    f32::from_bits(self.gain.load(Ordering::Relaxed))
+  }
+  /// Set the linear gain, where 1.0 leaves the output unchanged
+  pub fn set_gain(&self, gain: f32) {
+    self.gain.store(gain.max(0.0).to_bits(), Ordering::Relaxed);
+  }
+  /// Set the gain in dB
+  pub fn set_gain_db(&self, db: f32) {
+    self.set_gain(10f32.powf(db / 20.0));
+  }
+}
+
+/// Level where the soft limiter starts compressing, as a fraction of full
+/// scale
+const LIMITER_THRESHOLD: f32 = 0.9;
+
+/// Compress a sample above the limiter threshold so it approaches full scale
+/// without reaching it. The curve is smooth at the threshold.
+fn soft_limit(sample: f32) -> f32 {
+  let level = sample.abs() / 32768.0;
+  if level <= LIMITER_THRESHOLD {
+    return sample;
+  }
+  let headroom = 1.0 - LIMITER_THRESHOLD;
+  let over = (level - LIMITER_THRESHOLD) / headroom;
+  let limited = LIMITER_THRESHOLD + headroom * over.tanh();
+  (limited * 32768.0).copysign(sample)
+}
+
+/// Converts processed samples back to i16
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Quantizer {
+  pub soft_limit: bool,
+  /// State of the random number generator for dithering, if enabled
+  pub dither: Option<u32>,
+}
+
+impl Quantizer {
+  pub fn new(soft_limit: bool, dither: bool) -> Self {
+    Quantizer {
+      soft_limit,
+      dither: dither.then_some(0x9E37_79B9),
+    }
+  }
+  /// Random number from -0.5 to 0.5, using xorshift
+  fn random(state: &mut u32) -> f32 {
+    *state ^= *state << 13;
+    *state ^= *state >> 17;
+    *state ^= *state << 5;
+    *state as f32 / u32::MAX as f32 - 0.5
+  }
+  /// Round a sample to i16, with soft limiting and TPDF dither if enabled.
+  /// Values outside the i16 range are clamped.
+  pub fn quantize(&mut self, mut sample: f32) -> i16 {
+    if self.soft_limit {
+      sample = soft_limit(sample);
+    }
+    if let Some(state) = &mut self.dither {
+      // triangular noise from -1 to 1 LSB
+      sample += Self::random(state) + Self::random(state);
+    }
+    sample.round().clamp(i16::MIN as f32, i16::MAX as f32) as i16
+  }
+}
+
+/// Scale samples by a factor
+pub(crate) fn apply_gain(samples: &mut [i16], factor: f32, quantizer: &mut Quantizer) {
+  for sample in samples {
+    *sample = quantizer.quantize(*sample as f32 * factor);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn soft_limiter() {
+    let mut samples = [1000, 20000, -20000, 32767, -32768];
+    apply_gain(&mut samples, 1.0, &mut Quantizer::new(true, false));
+    assert_eq!(samples[..3], [1000, 20000, -20000]);
+    assert!(samples[3] < 32767 && samples[3] > 29491);
+    assert!(samples[4] > -32768 && samples[4] < -29491);
+
+    // louder input stays louder
+    let levels = [29000.0, 30000.0, 35000.0, 40000.0];
+    let limited: Vec<f32> = levels.iter().map(|&level| soft_limit(level)).collect();
+    assert!(limited.windows(2).all(|pair| pair[0] < pair[1]));
+    assert!(limited[3] < 32768.0);
+  }
+
+  #[test]
+  fn dither() {
+    let mut quantizer = Quantizer::new(false, true);
+    let samples: Vec<i16> = (0..10000).map(|_| quantizer.quantize(0.25)).collect();
+    assert!(samples.iter().all(|&sample| (-1..=1).contains(&sample)));
+    // the noise averages out to the input
+    let mean = samples.iter().map(|&sample| sample as f32).sum::<f32>() / 10000.0;
+    assert!((mean - 0.25).abs() < 0.05);
+  }
+}
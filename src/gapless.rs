@@ -0,0 +1,34 @@
+//! Gapless playback info, read from the iTunSMPB tag that iTunes and most
+//! AAC encoders write
+use crate::boxes;
+use std::convert::TryInto;
+
+/// Encoder delay and padding of a track, in samples per channel at the output
+/// sample rate
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GaplessInfo {
+  /// Priming samples the encoder added at the start
+  pub encoder_delay: u32,
+  /// Samples the encoder added at the end to fill the last frame
+  pub padding: u32,
+  /// Number of samples of actual audio, between the delay and padding
+  pub samples: u64,
+}
+
+impl GaplessInfo {
+  /// Parse the text of an iTunSMPB tag, which is a list of hex numbers where
+  /// the 2nd, 3rd and 4th are the delay, padding and sample count
+  pub(crate) fn from_itunes_smpb(text: &str) -> Option<Self> {
+    let mut fields = text.split_whitespace().skip(1);
+    let mut next = || u64::from_str_radix(fields.next()?, 16).ok();
+    Some(GaplessInfo {
+      encoder_delay: next()?.try_into().ok()?,
+      padding: next()?.try_into().ok()?,
+      samples: next()?,
+    })
+  }
+  /// Find the iTunSMPB tag in the contents of the moov box
+  pub(crate) fn from_moov(moov: &[u8]) -> Option<Self> {
+    Self::from_itunes_smpb(&boxes::freeform_tag(moov, "iTunSMPB")?)
+  }
+}
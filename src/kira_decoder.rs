@@ -0,0 +1,99 @@
+//! kira streaming sound support
+use crate::time::{duration_to_ticks, ticks_to_duration};
+use crate::{Decoder, Error};
+use kira::dsp::Frame;
+use kira::sound::streaming::{self, StreamingSoundData, StreamingSoundSettings};
+use std::io::{Read, Seek};
+
+/// Decoder adapter that implements kira's streaming `Decoder` trait, so it
+/// can be played with `StreamingSoundData`. kira plays stereo, so mono is
+/// played on both sides and only the first two channels of multichannel
+/// audio are used. Use `DecoderBuilder::downmix_stereo` to mix them instead.
+pub struct KiraDecoder<R>
+where
+  R: Read + Seek,
+{
+  decoder: Decoder<R>,
+  sample_rate: u32,
+  num_frames: usize,
+  pcm: Vec<i16>,
+}
+
+impl<R> KiraDecoder<R>
+where
+  R: Read + Seek,
+{
+  /// Wrap `decoder`. AAC input is scanned for its duration, since kira needs
+  /// the length of the sound. Live streams aren't supported.
+  pub fn new(mut decoder: Decoder<R>) -> Result<Self, Error> {
+    let sample_rate = decoder.sample_rate();
+    let duration = decoder.stream_duration()?;
+    Ok(KiraDecoder {
+      num_frames: duration_to_ticks(duration, sample_rate) as usize,
+      decoder,
+      sample_rate,
+      pcm: Vec::new(),
+    })
+  }
+  pub fn decoder(&self) -> &Decoder<R> {
+    &self.decoder
+  }
+  pub fn into_inner(self) -> Decoder<R> {
+    self.decoder
+  }
+}
+
+impl<R> KiraDecoder<R>
+where
+  R: Read + Seek + Send + 'static,
+{
+  /// Create kira sound data that streams from the decoder
+  pub fn into_sound_data(self, settings: StreamingSoundSettings) -> StreamingSoundData<Error> {
+    StreamingSoundData::from_decoder(self, settings)
+  }
+}
+
+impl<R> streaming::Decoder for KiraDecoder<R>
+where
+  R: Read + Seek + Send,
+{
+  type Error = Error;
+  fn sample_rate(&self) -> u32 {
+    self.sample_rate
+  }
+  fn num_frames(&self) -> usize {
+    self.num_frames
+  }
+  fn decode(&mut self) -> Result<Vec<Frame>, Error> {
+    let channels = self.decoder.channels().max(1) as usize;
+    self.pcm.clear();
+    if self.decoder.next_chunk(&mut self.pcm)? == 0 {
+      // kira decodes until it reaches num_frames, which can be past the
+      // last sample if the duration was rounded up
+      return Ok(vec![Frame::ZERO]);
+    }
+    let frames = self.pcm.chunks_exact(channels).map(|samples| {
+      let left = samples[0] as f32 / 32768.0;
+      match samples.get(1) {
+        Some(&right) => Frame::new(left, right as f32 / 32768.0),
+        None => Frame::from_mono(left),
+      }
+    });
+    Ok(frames.collect())
+  }
+  fn seek(&mut self, index: usize) -> Result<usize, Error> {
+    let position = ticks_to_duration(index as u64, self.sample_rate);
+    self.decoder.seek(position)?;
+    Ok(index)
+  }
+}
+
+impl<R> Decoder<R>
+where
+  R: Read + Seek,
+{
+  /// Play with kira's streaming sounds. See `KiraDecoder`.
+  pub fn into_kira(self) -> Result<KiraDecoder<R>, Error> {
+    KiraDecoder::new(self)
+  }
+}
@@ -0,0 +1,112 @@
+//! Peak and RMS metering, such as for normalizing waveforms or finding
+//! clipping
+use crate::time::duration_to_ticks;
+use crate::{Decoder, Error};
+use std::io::{Read, Seek};
+use std::time::Duration;
+
+/// Levels of one channel. Peak and RMS are linear, where 1.0 is full scale.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ChannelLevels {
+  pub peak: f64,
+  pub rms: f64,
+  /// Number of samples at the largest or smallest possible value, which are
+  /// likely clipped
+  pub clipped_samples: u64,
+}
+
+impl ChannelLevels {
+  /// Peak in dBFS
+  pub fn peak_db(&self) -> f64 {
+    20.0 * self.peak.log10()
+  }
+  /// RMS in dBFS
+  pub fn rms_db(&self) -> f64 {
+    20.0 * self.rms.log10()
+  }
+}
+
+/// Levels of a stream
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Levels {
+  /// Levels of the whole stream, per channel
+  pub channels: Vec<ChannelLevels>,
+  /// Levels of each window, per channel. Empty if no window was given.
+  pub windows: Vec<Vec<ChannelLevels>>,
+}
+
+#[derive(Clone, Copy, Default)]
+struct Accumulator {
+  peak: u16,
+  sum_squares: f64,
+  samples: u64,
+  clipped_samples: u64,
+}
+
+impl Accumulator {
+  fn push(&mut self, sample: i16) {
+    self.peak = self.peak.max(sample.unsigned_abs());
+    self.sum_squares += (sample as f64) * (sample as f64);
+    self.samples += 1;
+    if sample == i16::MIN || sample == i16::MAX {
+      self.clipped_samples += 1;
+    }
+  }
+  fn levels(&self) -> ChannelLevels {
+    let mean_square = match self.samples {
+      0 => 0.0,
+      samples => self.sum_squares / samples as f64,
+    };
+    ChannelLevels {
+      peak: self.peak as f64 / 32768.0,
+      rms: mean_square.sqrt() / 32768.0,
+      clipped_samples: self.clipped_samples,
+    }
+  }
+}
+
+/// Decode the rest of a stream and measure the peak and RMS of each channel.
+/// With a `window`, the levels of each window of that length are measured
+/// too. If the channel count changes, channels that are missing from part of
+/// the stream are measured over the rest.
+pub fn scan<R: Read + Seek>(
+  decoder: &mut Decoder<R>,
+  window: Option<Duration>,
+) -> Result<Levels, Error> {
+  let mut total: Vec<Accumulator> = Vec::new();
+  let mut current_window: Vec<Accumulator> = Vec::new();
+  let mut window_samples = 0;
+  let mut levels = Levels::default();
+  let finish_window = |window: &mut Vec<Accumulator>, levels: &mut Levels| {
+    levels
+      .windows
+      .push(window.iter().map(Accumulator::levels).collect());
+    window.clear();
+  };
+  while let Some(frame) = decoder.next_frame()? {
+    let channels = frame.channels.max(1) as usize;
+    if total.len() < channels {
+      total.resize(channels, Accumulator::default());
+    }
+    let window_len = window.map(|window| duration_to_ticks(window, frame.sample_rate).max(1));
+    for samples in frame.samples.chunks_exact(channels) {
+      if current_window.len() < channels {
+        current_window.resize(channels, Accumulator::default());
+      }
+      for (channel, &sample) in samples.iter().enumerate() {
+        total[channel].push(sample);
+        current_window[channel].push(sample);
+      }
+      window_samples += 1;
+      if window_len.is_some_and(|len| window_samples >= len) {
+        finish_window(&mut current_window, &mut levels);
+        window_samples = 0;
+      }
+    }
+  }
+  if window.is_some() && window_samples > 0 {
+    finish_window(&mut current_window, &mut levels);
+  }
+  levels.channels = total.iter().map(Accumulator::levels).collect();
+  Ok(levels)
+}
@@ -1,14 +1,196 @@
 //! AAC decoder for MPEG-4 (MP4, M4A etc) and AAC files. Supports rodio.
-use fdk_aac::dec::{Decoder as AacDecoder, DecoderError, Transport};
+#![cfg_attr(not(feature = "std"), no_std)]
+extern crate alloc;
+
+#[cfg(feature = "std")]
+use adts::raw::AdtsError;
+#[cfg(feature = "std")]
+use codec::FrameDecoder;
+#[cfg(feature = "std")]
+use fdk::{AacDecoder, Transport};
+#[cfg(feature = "std")]
+use fdk_aac::dec::DecoderError;
+#[cfg(feature = "std")]
 use mp4::AudioObjectType;
-use std::io::{Read, Seek};
-use std::time::Duration;
+#[cfg(feature = "std")]
+use std::collections::{HashMap, VecDeque};
+#[cfg(feature = "std")]
+use std::convert::TryFrom;
+#[cfg(feature = "std")]
+use std::fs::File;
+#[cfg(feature = "std")]
+use std::io::{Cursor, Read, Seek, SeekFrom};
+#[cfg(feature = "std")]
+use std::ops::Range;
+#[cfg(feature = "std")]
+use std::path::Path;
+#[cfg(feature = "std")]
+use std::sync::{mpsc, Arc};
+#[cfg(feature = "std")]
+use std::thread;
+#[cfg(feature = "std")]
+use std::time::{Duration, Instant};
+#[cfg(feature = "std")]
 use std::{error, fmt, io};
+#[cfg(feature = "std")]
+use time::{duration_to_ticks, ticks_to_duration, Clock};
 
 pub mod adts;
+#[cfg(feature = "std")]
+pub mod batch;
+#[cfg(feature = "std")]
+mod boxes;
+#[cfg(feature = "std")]
+mod buffered;
+#[cfg(feature = "std")]
+mod builder;
+#[cfg(feature = "std")]
+mod codec;
+#[cfg(feature = "std")]
+mod completion;
+#[cfg(feature = "std")]
+mod controlled;
+#[cfg(feature = "std")]
+mod decode_stats;
+#[cfg(feature = "std")]
+mod drc;
+#[cfg(feature = "std")]
+mod estimate;
+#[cfg(feature = "std")]
+mod event;
+#[cfg(feature = "std")]
+pub mod export;
+#[cfg(feature = "std")]
+mod f32_samples;
+#[cfg(feature = "std")]
+mod fdk;
+#[cfg(feature = "std")]
+mod gain;
+#[cfg(feature = "std")]
+mod gapless;
+#[cfg(feature = "kira")]
+mod kira_decoder;
+#[cfg(feature = "std")]
+pub mod levels;
+#[cfg(feature = "std")]
+mod live;
+#[cfg(feature = "std")]
+mod looping;
+#[cfg(feature = "loudness")]
+pub mod loudness;
+#[cfg(feature = "std")]
+mod ludt;
+#[cfg(feature = "std")]
+mod metadata;
+#[cfg(feature = "std")]
+mod mix;
+#[cfg(feature = "mp3")]
+mod mp3;
+#[cfg(feature = "loudness")]
+pub mod normalize;
+#[cfg(feature = "std")]
+pub mod peaks;
+#[cfg(feature = "std")]
+mod pipe;
+#[cfg(feature = "std")]
+mod planar;
+#[cfg(feature = "play")]
+pub mod play;
+#[cfg(feature = "std")]
+pub mod playlist;
+#[cfg(feature = "std")]
+pub mod probe;
+#[cfg(feature = "std")]
+mod replaygain;
+#[cfg(feature = "std")]
+mod retry;
+#[cfg(feature = "std")]
+mod sample_table;
+#[cfg(feature = "std")]
+pub mod silence;
+#[cfg(any(feature = "rodio", feature = "rodio-0_19"))]
+mod sources;
+#[cfg(feature = "std")]
+mod split;
+#[cfg(feature = "std")]
+mod stream_info;
+mod syntax;
+#[cfg(feature = "tags")]
+pub mod tags;
+#[cfg(feature = "std")]
+mod time;
+#[cfg(feature = "std")]
+mod validate;
+
+#[cfg(feature = "std")]
+pub use batch::scan;
+#[cfg(feature = "std")]
+pub use buffered::BufferedReader;
+#[cfg(feature = "std")]
+pub use builder::{
+  DecoderBuilder, DEFAULT_MAX_SAMPLE_SIZE, DEFAULT_PCM_CAPACITY, DEFAULT_READ_BUFFER_SIZE,
+  DEFAULT_READ_CHUNK_SIZE, MAX_CHANNELS, MAX_FRAME_SIZE,
+};
+#[cfg(feature = "std")]
+pub use completion::{Completed, Completion};
+#[cfg(feature = "std")]
+pub use controlled::{ControlledDecoder, Controller};
+#[cfg(feature = "std")]
+pub use decode_stats::DecodeStats;
+#[cfg(feature = "std")]
+pub use drc::{Drc, DrcEffect, MetadataProfile};
+#[cfg(feature = "std")]
+pub use estimate::DurationEstimate;
+#[cfg(feature = "std")]
+pub use event::Event;
+#[cfg(feature = "std")]
+pub use f32_samples::F32Samples;
+#[cfg(feature = "std")]
+pub use gain::GainHandle;
+#[cfg(feature = "std")]
+pub use gapless::GaplessInfo;
+#[cfg(feature = "kira")]
+pub use kira_decoder::KiraDecoder;
+#[cfg(feature = "std")]
+pub use live::{DEFAULT_LIVE_POLL_INTERVAL, DEFAULT_LIVE_TIMEOUT};
+#[cfg(feature = "std")]
+pub use looping::LoopingDecoder;
+#[cfg(feature = "std")]
+pub use ludt::{LoudnessInfo, StoredLoudness};
+#[cfg(feature = "std")]
+pub use metadata::{MediaKind, Metadata, Rating};
+#[cfg(feature = "std")]
+pub use mix::DownmixMatrix;
+#[cfg(feature = "std")]
+pub use mp4;
+#[cfg(feature = "std")]
+pub use pipe::{pipe, PipeReader, PipeWriter};
+#[cfg(feature = "std")]
+pub use planar::PlanarFrame;
+#[cfg(feature = "play")]
+pub use play::play_file;
+#[cfg(feature = "std")]
+pub use probe::is_drm_protected;
+#[cfg(feature = "std")]
+pub use replaygain::{GainMode, ReplayGain};
+#[cfg(feature = "std")]
+pub use retry::{RetryReader, DEFAULT_BACKOFF, DEFAULT_MAX_BACKOFF, DEFAULT_MAX_RETRIES};
+#[cfg(feature = "std")]
+pub use sample_table::SampleTableStats;
+#[cfg(feature = "std")]
+pub use split::ChannelSource;
+#[cfg(feature = "std")]
+pub use stream_info::{ChannelOrder, ChannelType, StreamInfo};
+
+/// Number of frames decoded and discarded before the seek position. AAC
+/// frames overlap, and the decoder fades in the first frame after a seek.
+#[cfg(feature = "std")]
+const PREROLL_FRAMES: usize = 3;
 
 /// Redlux error
 #[derive(Debug)]
+#[non_exhaustive]
+#[cfg(feature = "std")]
 pub enum Error {
   /// Error reading header of file
   FileHeaderError,
@@ -17,6 +199,10 @@ pub enum Error {
   TrackReadingError,
   /// Unsupported audio object type
   UnsupportedObjectType(AudioObjectType),
+  /// The audio track uses a codec that can't be decoded. It has the sample
+  /// entry type, like "ec-3" or "Opus", or a codec string like "mp4a.6B" for
+  /// MP3 tracks when the `mp3` feature isn't enabled.
+  UnsupportedCodec(String),
   // Unable to find track in file
   TrackNotFound,
   /// Error decoding track
@@ -25,204 +211,1878 @@ pub enum Error {
   SamplesError,
   /// Error from the underlying reader R
   ReaderError(io::Error),
+  /// Error from the underlying reader R partway through the stream. `offset`
+  /// is where the input that hasn't been decoded starts in the reader, and
+  /// `position` is the time of the next sample, so a stream can be resumed
+  /// with `Decoder::open_at` after reconnecting.
+  StreamReaderError {
+    error: io::Error,
+    offset: u64,
+    position: Duration,
+  },
+  /// Error writing output
+  WriterError(io::Error),
+  /// The sample couldn't be parsed to compute an ADTS CRC
+  CrcPayloadError,
+  /// Invalid or missing ADTS header
+  AdtsHeaderError,
+  /// A sample of the given size doesn't fit in an ADTS frame, which is limited
+  /// to 8191 bytes including the header
+  FrameTooLarge(usize),
+  /// Invalid decoder configuration, such as a buffer size that's out of range
+  InvalidConfig(&'static str),
+  /// Error from the audio output, like when there's no output device
+  PlaybackError(String),
+  /// Decoding went over the `DecoderBuilder::frame_time_budget` or
+  /// `DecoderBuilder::file_time_budget`. Has the position of the frame.
+  TimeBudgetExceeded(Duration),
+  /// An MP4 sample of the given size is larger than
+  /// `DecoderBuilder::max_sample_size`
+  SampleTooLarge(u32),
+  /// The tags couldn't be written, like when the samples of a fragmented file
+  /// would have to move
+  TagWriteError(&'static str),
 }
 
+#[cfg(feature = "std")]
 impl error::Error for Error {}
 
+#[cfg(feature = "std")]
 impl Error {
   pub fn message(&self) -> &'static str {
     match &self {
       Error::FileHeaderError => "Error reading file header",
       Error::TrackReadingError => "Error reading file track info",
       Error::UnsupportedObjectType(_) => "Unsupported audio object type",
+      Error::UnsupportedCodec(_) => "Unsupported codec",
       Error::TrackNotFound => "Unable to find track in file",
       Error::TrackDecodingError(_) => "Error decoding track",
       Error::SamplesError => "Error reading samples",
       Error::ReaderError(_) => "Error reading file",
+      Error::StreamReaderError { .. } => "Error reading file",
+      Error::WriterError(_) => "Error writing output",
+      Error::CrcPayloadError => "Error parsing sample for ADTS CRC",
+      Error::AdtsHeaderError => "Invalid ADTS header",
+      Error::FrameTooLarge(_) => "Sample too large for an ADTS frame",
+      Error::InvalidConfig(_) => "Invalid decoder configuration",
+      Error::PlaybackError(_) => "Error playing audio",
+      Error::TimeBudgetExceeded(_) => "Decoding exceeded its time budget",
+      Error::SampleTooLarge(_) => "Sample larger than the size limit",
+      Error::TagWriteError(_) => "Error writing tags",
+    }
+  }
+  /// Convert an error from `adts::raw` for an object type
+  pub(crate) fn from_adts(err: AdtsError, object_type: AudioObjectType) -> Self {
+    match err {
+      AdtsError::InvalidHeader => Error::AdtsHeaderError,
+      AdtsError::FrameTooLarge(len) => Error::FrameTooLarge(len),
+      AdtsError::UnsupportedObjectType(_) => Error::UnsupportedObjectType(object_type),
+      AdtsError::CrcPayload => Error::CrcPayloadError,
+      AdtsError::UnsupportedSampleRate(_) => {
+        Error::InvalidConfig("sample rate not supported by ADTS")
+      }
+      AdtsError::UnsupportedChannels(_) => {
+        Error::InvalidConfig("channel count not supported by ADTS")
+      }
     }
   }
 }
 
+#[cfg(feature = "std")]
 impl fmt::Display for Error {
   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-    write!(f, "{}", self.message())
+    let message = self.message();
+    match self {
+      Error::UnsupportedObjectType(object_type) => write!(f, "{}: {}", message, object_type),
+      Error::TrackDecodingError(err) => write!(f, "{}: {}", message, err),
+      Error::ReaderError(err) | Error::WriterError(err) => write!(f, "{}: {}", message, err),
+      Error::StreamReaderError {
+        error,
+        offset,
+        position,
+      } => write!(
+        f,
+        "{} at byte {} ({:?}): {}",
+        message, offset, position, error
+      ),
+      Error::FrameTooLarge(len) => write!(f, "{}: {} bytes", message, len),
+      Error::InvalidConfig(reason) | Error::TagWriteError(reason) => {
+        write!(f, "{}: {}", message, reason)
+      }
+      Error::UnsupportedCodec(codec) => write!(f, "{}: {}", message, codec),
+      Error::PlaybackError(reason) => write!(f, "{}: {}", message, reason),
+      Error::SampleTooLarge(size) => write!(f, "{}: {} bytes", message, size),
+      Error::TimeBudgetExceeded(position) => write!(f, "{} at {:?}", message, position),
+      _ => write!(f, "{}", message),
+    }
   }
 }
 
 /// File container format
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg(feature = "std")]
 pub enum Format {
   Mp4,
   Aac,
 }
 
-/// Underlying reader
+#[cfg(feature = "std")]
+impl Format {
+  /// Detect the format from the start of the input, leaving the reader where
+  /// it was. Input that doesn't start with a known MP4 box is treated as AAC.
+  pub fn detect<R: Read + Seek>(reader: &mut R) -> io::Result<Self> {
+    let start = reader.stream_position()?;
+    let mut header = Vec::with_capacity(8);
+    reader.take(8).read_to_end(&mut header)?;
+    reader.seek(SeekFrom::Start(start))?;
+    match header.get(4..8) {
+      Some(box_type) if boxes::TOP_LEVEL.iter().any(|t| &t[..] == box_type) => Ok(Format::Mp4),
+      _ => Ok(Format::Aac),
+    }
+  }
+  /// Format for a MIME type like "audio/mp4" or "audio/aac". Parameters and
+  /// case are ignored. None for other types.
+  pub fn from_mime(mime: &str) -> Option<Self> {
+    let essence = mime.split(';').next().unwrap_or("").trim();
+    match essence.to_ascii_lowercase().as_str() {
+      "audio/mp4" | "audio/m4a" | "audio/x-m4a" | "video/mp4" | "application/mp4" => {
+        Some(Format::Mp4)
+      }
+      "audio/aac" | "audio/aacp" | "audio/x-aac" | "audio/x-hx-aac-adts" => Some(Format::Aac),
+      _ => None,
+    }
+  }
+  /// Format for a file extension like "m4a" or "aac", with or without the
+  /// leading dot. None for other extensions.
+  pub fn from_extension(extension: &str) -> Option<Self> {
+    let extension = extension.trim_start_matches('.');
+    match extension.to_ascii_lowercase().as_str() {
+      "mp4" | "m4a" | "m4b" | "m4p" | "m4r" | "m4v" => Some(Format::Mp4),
+      "aac" | "adts" => Some(Format::Aac),
+      _ => None,
+    }
+  }
+}
+
+/// Underlying reader, with buffering from `DecoderBuilder::read_buffer_size`
+#[cfg(feature = "std")]
 pub enum Reader<R> {
-  Mp4Reader(mp4::Mp4Reader<R>),
-  AacReader(R),
+  Mp4Reader(Box<mp4::Mp4Reader<BufferedReader<R>>>),
+  AacReader(BufferedReader<R>),
 }
 
+/// Decoded frame, from `Decoder::next_frame`
+#[cfg(feature = "std")]
+pub struct Frame<'a> {
+  /// Presentation timestamp of the first sample
+  pub pts: Duration,
+  pub channels: u16,
+  pub sample_rate: u32,
+  /// Interleaved samples
+  pub samples: &'a [i16],
+  pub flags: FrameFlags,
+}
+
+/// What fdk-aac did to decode a frame
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg(feature = "std")]
+pub struct FrameFlags {
+  /// Errors in the input were concealed, so the frame may contain
+  /// interpolated audio
+  pub concealed: bool,
+  /// Spectral band replication (HE-AAC) was applied
+  pub sbr: bool,
+  /// Parametric stereo (HE-AAC v2) was applied
+  pub ps: bool,
+}
+
+/// AAC decoder. It's `Send` when the reader is, so it can be created on one
+/// thread and played on another, like rodio's audio thread. To change the
+/// gain while it's playing, use `gain_handle`. Events are received with
+/// `subscribe`, which also works across threads.
+#[cfg(feature = "std")]
 pub struct Decoder<R>
 where
   R: Read + Seek,
 {
   pub format: Format,
   reader: Reader<R>,
-  aac_decoder: AacDecoder,
+  frame_decoder: FrameDecoder,
   bytes: Vec<u8>,
+  read_chunk_size: usize,
   current_pcm_index: usize,
   current_pcm: Vec<i16>,
+  pcm_capacity: usize,
+  frames_per_chunk: usize,
+  /// Whether to mix frames to the channel count of the first frame, which is
+  /// set once the first frame is decoded
+  fixed_channels: bool,
+  output_channels: Option<u16>,
+  /// Channel count of the current frame after `DecoderBuilder::downmix_matrix`
+  mixed_channels: Option<u16>,
+  /// Length of the frame at the start of `bytes` that hasn't been filled into
+  /// the AAC decoder yet
+  unfilled_frame_len: usize,
+  /// Whether the ADTS header at the start of `bytes` has been checked
+  adts_synced: bool,
+  /// AudioSpecificConfig of each sample description entry of the MP4 track
+  audio_specific_configs: Vec<Vec<u8>>,
+  gapless_info: Option<GaplessInfo>,
+  replay_gain: Option<ReplayGain>,
+  metadata: Option<Metadata>,
+  loudness_info: Option<LoudnessInfo>,
+  /// Factor applied to the output, from `DecoderBuilder::replay_gain`
+  replay_gain_factor: Option<f32>,
+  /// Gain applied as samples are returned
+  gain: GainHandle,
+  quantizer: gain::Quantizer,
+  fade_in: Option<Duration>,
+  fade_out: Option<Duration>,
+  /// Start of the range from `decode_range`, where the fade-in starts
+  range_start: Duration,
+  /// Duration of the stream. MP4 input has it from the start, and AAC input
+  /// is scanned for it when it's needed for the fade-out.
+  duration: Option<Duration>,
+  /// Sample description entry that the AAC decoder is configured for
+  sample_entry: Option<usize>,
+  /// Sampling frequency index of the current sample description entry
+  sample_freq_index: usize,
+  /// Duration of one AAC frame in the MP4 track's timescale. Samples that are
+  /// longer may contain multiple frames.
+  frame_duration: u64,
+  timescale: u32,
+  /// Presentation time of the last MP4 access unit filled into the AAC
+  /// decoder, in the track's timescale
+  filled_time: i64,
+  /// Presentation time of the next access unit in the current MP4 sample
+  next_time: i64,
+  /// Presentation timestamp of the current frame
+  frame_pts: Duration,
+  /// Time of the next frame of AAC input
+  clock: Clock,
+  /// Position of the AAC stream in the reader
+  stream_start: u64,
+  /// Position where decoding finishes, set by `decode_range` or
+  /// `DecoderBuilder::preview`
+  end: Option<Duration>,
   track_id: u32,
-  position: u32,
+  /// ID of the next MP4 sample to read, starting at 1
+  position: u64,
+  /// First sample ID and start time of each fragment of a fragmented MP4
+  /// track, from `fragment_starts`
+  fragment_starts: Vec<(u64, u64)>,
+  /// Start time and fragment of each subsegment in the sidx box of a
+  /// fragmented MP4 file, from `segment_starts`
+  segment_starts: Vec<(u64, usize)>,
+  /// Error from decoding ahead, returned by the next decode call
+  pending_error: Option<Error>,
+  subscribers: Vec<mpsc::Sender<Event>>,
+  /// Handle from `completion`
+  completion: Option<Completion>,
+  /// Channels and sample rate of the last decoded frame
+  frame_format: Option<(u16, u32)>,
+  bad_access_units: i64,
+  /// Flags of the frame in `current_pcm`
+  frame_flags: FrameFlags,
+  ended: bool,
+  /// Time spent in `decode_frame`, for `DecoderBuilder::file_time_budget`
+  /// and `decode_stats`
+  decode_time: Duration,
+  decoded_frames: u64,
+  /// Duration of the frames from `decode_frame`
+  decoded_audio: Duration,
+  /// When and at what timestamp pacing for `DecoderBuilder::realtime`
+  /// started
+  pace_start: Option<(Instant, Duration)>,
+  /// Options the decoder was created with
+  options: DecoderBuilder,
+  /// Data from `from_shared`, for `reopen`
+  shared: Option<Arc<[u8]>>,
   /// If there's an error while iterating over the Decoder, that error is added here
   pub iter_error: Option<Error>,
 }
 
+#[cfg(feature = "std")]
+impl Decoder<File> {
+  /// Builder for configuring a decoder, which can open any reader
+  pub fn builder() -> DecoderBuilder {
+    DecoderBuilder::new()
+  }
+  /// Open a file, detecting whether it's MPEG-4 or AAC from its contents
+  pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+    DecoderBuilder::new().open_path(path)
+  }
+}
+
+#[cfg(feature = "std")]
+impl Decoder<Cursor<Arc<[u8]>>> {
+  /// Create from shared data in memory, detecting whether it's MPEG-4 or AAC.
+  /// Use `reopen` to decode the same data again without copying it.
+  pub fn from_shared<B: Into<Arc<[u8]>>>(bytes: B) -> Result<Self, Error> {
+    DecoderBuilder::new().open_shared(bytes)
+  }
+  /// Create a new decoder for the same data, with the same options. The data
+  /// isn't copied, so this is cheap for playing a sound many times at once.
+  pub fn reopen(&self) -> Result<Self, Error> {
+    match &self.shared {
+      Some(bytes) => self.options.open_shared(bytes.clone()),
+      None => Err(Error::InvalidConfig(
+        "reopen needs a decoder created from shared data",
+      )),
+    }
+  }
+}
+
+#[cfg(feature = "mmap")]
+impl Decoder<Cursor<memmap2::Mmap>> {
+  /// Memory-map a file and decode from the mapping, detecting whether it's
+  /// MPEG-4 or AAC. Reads and seeks don't need syscalls, which makes seeking
+  /// in large files fast. The file must not be modified while it's mapped.
+  pub fn from_mmap<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+    DecoderBuilder::new().open_mmap(path)
+  }
+}
+
+#[cfg(feature = "std")]
+impl Decoder<Cursor<Vec<u8>>> {
+  /// Create from data in memory, detecting whether it's MPEG-4 or AAC from
+  /// its contents. A slice is copied.
+  pub fn from_bytes<B: Into<Vec<u8>>>(bytes: B) -> Result<Self, Error> {
+    DecoderBuilder::new().open_bytes(bytes)
+  }
+}
+
+#[cfg(feature = "std")]
 impl<R> Decoder<R>
 where
   R: Read + Seek,
 {
   /// Create from an aac buffer
   pub fn new_aac(reader: R) -> Self {
-    let aac_decoder = AacDecoder::new(Transport::Adts);
-    let aac_decoder = Decoder {
-      format: Format::Aac,
-      reader: Reader::AacReader(reader),
-      aac_decoder: aac_decoder,
-      bytes: Vec::new(),
+    Self::from_aac(reader, &DecoderBuilder::new())
+  }
+  /// Create from an mpeg buffer
+  pub fn new_mpeg4(reader: R, size: u64) -> Result<Self, Error> {
+    Self::from_mpeg4(reader, size, &DecoderBuilder::new())
+  }
+  /// Create using a MIME type or file extension hint, such as a
+  /// Content-Type header, to pick between MPEG-4 and AAC. Falls back to
+  /// detecting the format from the contents when the hint isn't recognized.
+  /// `size` is only used for MPEG-4.
+  pub fn new_with_mime(reader: R, size: u64, mime: &str) -> Result<Self, Error> {
+    DecoderBuilder::new().open_with_mime(reader, size, mime)
+  }
+  /// Create, detecting the format, and start at `position`, like resuming
+  /// an audiobook. MP4 input seeks using its sample table, so only a few
+  /// frames before the position are decoded. AAC input has no index, so its
+  /// frame headers are scanned up to the position. `size` is only used for
+  /// MPEG-4.
+  pub fn open_at(reader: R, size: u64, position: Duration) -> Result<Self, Error> {
+    DecoderBuilder::new().start_at(position).open(reader, size)
+  }
+  /// Create, detecting the format, for decoding only the first `duration`.
+  /// See `DecoderBuilder::preview`.
+  pub fn preview(reader: R, size: u64, duration: Duration) -> Result<Self, Error> {
+    DecoderBuilder::new().preview(duration).open(reader, size)
+  }
+  fn from_parts(
+    format: Format,
+    reader: Reader<R>,
+    track_id: u32,
+    options: &DecoderBuilder,
+  ) -> Self {
+    let output_channels = options.downmix_stereo.then_some(2);
+    // an error is returned by the first decode call
+    let (frame_decoder, pending_error) = match new_aac_decoder(format, options, output_channels) {
+      Ok(aac_decoder) => (FrameDecoder::Aac(aac_decoder), None),
+      Err(err) => {
+        let frame_decoder = match AacDecoder::new(transport(format)) {
+          Ok(aac_decoder) => FrameDecoder::Aac(aac_decoder),
+          Err(err) => FrameDecoder::unavailable(err),
+        };
+        (frame_decoder, Some(Error::TrackDecodingError(err)))
+      }
+    };
+    Decoder {
+      format,
+      reader,
+      frame_decoder,
+      bytes: Vec::with_capacity(options.read_chunk_size),
+      read_chunk_size: options.read_chunk_size,
       current_pcm_index: 0,
-      current_pcm: Vec::new(),
-      track_id: 0,
+      current_pcm: Vec::with_capacity(options.pcm_capacity),
+      pcm_capacity: options.pcm_capacity,
+      frames_per_chunk: options.frames_per_chunk,
+      fixed_channels: options.fixed_channels,
+      output_channels,
+      mixed_channels: None,
+      unfilled_frame_len: 0,
+      adts_synced: false,
+      audio_specific_configs: Vec::new(),
+      gapless_info: None,
+      replay_gain: None,
+      metadata: None,
+      loudness_info: None,
+      replay_gain_factor: None,
+      gain: GainHandle::default(),
+      quantizer: gain::Quantizer::new(options.soft_limiter, options.dither),
+      fade_in: options.fade_in,
+      fade_out: options.fade_out,
+      range_start: Duration::ZERO,
+      duration: None,
+      sample_entry: None,
+      sample_freq_index: 0,
+      frame_duration: 0,
+      timescale: 0,
+      filled_time: 0,
+      next_time: 0,
+      frame_pts: Duration::ZERO,
+      clock: Clock::default(),
+      stream_start: 0,
+      end: options.preview,
+      track_id,
       position: 1,
+      fragment_starts: Vec::new(),
+      segment_starts: Vec::new(),
+      pending_error,
+      subscribers: Vec::new(),
+      completion: None,
+      frame_format: None,
+      bad_access_units: 0,
+      frame_flags: FrameFlags::default(),
+      decode_time: Duration::ZERO,
+      decoded_frames: 0,
+      decoded_audio: Duration::ZERO,
+      pace_start: None,
+      ended: false,
+      options: options.clone(),
+      shared: None,
       iter_error: None,
-    };
-    return aac_decoder;
+    }
   }
-  /// Create from an mpeg buffer
-  pub fn new_mpeg4(reader: R, size: u64) -> Result<Self, Error> {
-    let aac_decoder = AacDecoder::new(Transport::Adts);
+  fn from_aac(reader: R, options: &DecoderBuilder) -> Self {
+    let reader = BufferedReader::with_capacity(options.read_buffer_size, reader);
+    let stream_start = reader.position;
+    let mut decoder = Self::from_parts(Format::Aac, Reader::AacReader(reader), 0, options);
+    decoder.stream_start = stream_start;
+    decoder.decode_ahead();
+    decoder
+  }
+  fn from_mpeg4(reader: R, size: u64, options: &DecoderBuilder) -> Result<Self, Error> {
+    let mut reader = BufferedReader::with_capacity(options.read_buffer_size, reader);
+    let moov = boxes::read_moov(&mut reader, size).map_err(Error::ReaderError)?;
+    // fragmented files can have a sidx box for seeking
+    let segment_index = match moov.as_deref().and_then(|moov| boxes::find(moov, b"mvex")) {
+      Some(_) => boxes::read_segment_index(&mut reader, size).map_err(Error::ReaderError)?,
+      None => None,
+    };
     let mp4 = mp4::Mp4Reader::read_header(reader, size).or(Err(Error::FileHeaderError))?;
-    let mut track_id: Option<u32> = None;
-    {
-      for track in mp4.tracks().values() {
-        let media_type = match track.media_type() {
-          Ok(media_type) => media_type,
-          Err(_) => continue,
-        };
-        match media_type {
-          mp4::MediaType::AAC => {
-            track_id = Some(track.track_id());
-            break;
-          }
-          _ => {}
-        }
+    // tracks in an alternate group are alternatives to each other, so the
+    // first enabled one is used rather than whichever the map yields first
+    let mut candidates: Vec<&mp4::Mp4Track> = mp4
+      .tracks()
+      .values()
+      .filter(|track| matches!(track.media_type(), Ok(mp4::MediaType::AAC)))
+      .filter(|track| cfg!(feature = "mp3") || codec::mp3_object_type(track).is_none())
+      .filter(|track| options.track.is_none_or(|id| id == track.track_id()))
+      .collect();
+    candidates.sort_by_key(|track| (track.trak.tkhd.flags & 1 == 0, track.track_id()));
+    let track = match candidates.first() {
+      Some(track) => *track,
+      None => {
+        return Err(track_not_found(
+          moov.as_deref(),
+          mp4.tracks(),
+          options.track,
+        ))
       }
+    };
+    validate::validate_track(track)?;
+    let track_id = track.track_id();
+    let mp3_object_type = codec::mp3_object_type(track);
+    // use the track's own AudioSpecificConfigs so explicit SBR/PS signaling
+    // isn't lost
+    let mut configs = match &moov {
+      Some(moov) => boxes::audio_specific_configs(moov, track_id),
+      None => Vec::new(),
+    };
+    if configs.is_empty() {
+      configs.push(None);
+    }
+    let fallback = || {
+      boxes::construct_audio_specific_config(
+        track.audio_profile().or(Err(Error::TrackReadingError))?,
+        track
+          .sample_freq_index()
+          .or(Err(Error::TrackReadingError))?,
+        track.channel_config().or(Err(Error::TrackReadingError))?,
+      )
+    };
+    // MP3 tracks don't have an AudioSpecificConfig
+    let audio_specific_configs = match mp3_object_type {
+      Some(_) => Vec::new(),
+      None => configs
+        .into_iter()
+        .map(|config| config.map_or_else(fallback, Ok))
+        .collect::<Result<_, _>>()?,
+    };
+    let fragment_starts = fragment_starts(track);
+    let segment_starts = segment_starts(track, segment_index);
+    let mut decoder = Self::from_parts(
+      Format::Mp4,
+      Reader::Mp4Reader(Box::new(mp4)),
+      track_id,
+      options,
+    );
+    decoder.audio_specific_configs = audio_specific_configs;
+    decoder.fragment_starts = fragment_starts;
+    decoder.segment_starts = segment_starts;
+    #[cfg(feature = "mp3")]
+    if let Some(object_type) = mp3_object_type {
+      let result = new_mp3_decoder(object_type, decoder.output_channels);
+      decoder.frame_decoder = FrameDecoder::Mp3(result.map_err(Error::TrackDecodingError)?);
+      // from the AAC decoder's options
+      decoder.pending_error = None;
+    }
+    if let (Some(moov), None) = (&moov, options.preview) {
+      decoder.gapless_info = GaplessInfo::from_moov(moov);
+      decoder.replay_gain = ReplayGain::from_moov(moov);
+      decoder.metadata = Metadata::from_moov(moov);
+      decoder.loudness_info = LoudnessInfo::from_moov(moov, track_id);
+    }
+    if let (Some(mode), Some(replay_gain)) = (options.replay_gain, decoder.replay_gain) {
+      decoder.replay_gain_factor = replay_gain.factor(mode);
+    }
+    decoder.configure_sample_entry()?;
+    if options.preview.is_none() {
+      decoder.duration = Some(decoder.stream_duration()?);
     }
-    match track_id {
-      Some(track_id) => {
-        return Ok(Decoder {
-          format: Format::Mp4,
-          reader: Reader::Mp4Reader(mp4),
-          aac_decoder: aac_decoder,
-          bytes: Vec::new(),
-          current_pcm_index: 0,
-          current_pcm: Vec::new(),
-          track_id: track_id,
-          position: 1,
-          iter_error: None,
-        });
+    decoder.decode_ahead();
+    Ok(decoder)
+  }
+  /// Configure the AAC decoder for the sample description entry of the next
+  /// MP4 sample, if it's different from the current one
+  fn configure_sample_entry(&mut self) -> Result<(), Error> {
+    let (entry, timescale) = match &self.reader {
+      Reader::Mp4Reader(mp4_reader) => {
+        let tracks = mp4_reader.tracks();
+        let track = tracks.get(&self.track_id).ok_or(Error::TrackNotFound)?;
+        let sample_id = self.mp4_sample_id().unwrap_or(u32::MAX);
+        (sample_entry(track, sample_id), track.timescale())
       }
-      None => {
-        return Err(Error::TrackNotFound);
+      Reader::AacReader(_) => return Ok(()),
+    };
+    if self.sample_entry == Some(entry) {
+      return Ok(());
+    }
+    if self.frame_decoder.mp3_object_type().is_some() {
+      // MP3 frames are found by the MP3 decoder, so samples aren't split
+      self.frame_duration = u64::MAX;
+      self.sample_entry = Some(entry);
+      self.timescale = timescale;
+      return Ok(());
+    }
+    let config = self.audio_specific_configs.get(entry);
+    let config = config.ok_or(Error::TrackReadingError)?;
+    let result = self.frame_decoder.config_raw(config);
+    result.map_err(Error::TrackDecodingError)?;
+    match boxes::config_sample_freq_index(config) {
+      Some(sample_freq_index) => {
+        self.sample_freq_index = adts::freq_index(sample_freq_index) as usize;
+        self.frame_duration = 1024 * timescale as u64 / sample_freq_index.freq() as u64;
       }
+      // explicit frequency, so frames can't be parsed
+      None => self.frame_duration = u64::MAX,
     }
+    self.sample_entry = Some(entry);
+    self.timescale = timescale;
+    Ok(())
   }
+  /// Subscribe to stream events, such as format changes and the end of the
+  /// stream
+  pub fn subscribe(&mut self) -> mpsc::Receiver<Event> {
+    let (sender, receiver) = mpsc::channel();
+    self.subscribers.push(sender);
+    receiver
+  }
+  /// Get a handle for learning when the decoder's iterator finishes and
+  /// whether it was because of an error, like once it's appended to a rodio
+  /// `Sink`. `LoopingDecoder` only finishes on errors.
+  pub fn completion(&mut self) -> Completion {
+    self
+      .completion
+      .get_or_insert_with(Completion::default)
+      .clone()
+  }
+  /// End iteration with an error, adding it to `iter_error`
+  pub(crate) fn fail(&mut self, err: Error) {
+    if let Some(completion) = &self.completion {
+      completion.complete(Completed::Error(err.to_string()));
+    }
+    self.iter_error = Some(err);
+  }
+  fn emit(&mut self, event: Event) {
+    // drop subscribers whose receiver is gone
+    self
+      .subscribers
+      .retain(|sender| sender.send(event.clone()).is_ok());
+  }
+  /// Number of samples left in the current frame. The channels and sample
+  /// rate can change after that many samples.
   pub fn current_frame_len(&self) -> Option<usize> {
-    let frame_size: usize = self.aac_decoder.decoded_frame_size();
-    Some(frame_size)
+    Some(
+      self
+        .current_pcm
+        .len()
+        .saturating_sub(self.current_pcm_index),
+    )
   }
+  /// Number of channels of the current frame
   pub fn channels(&self) -> u16 {
-    let num_channels: i32 = self.aac_decoder.stream_info().numChannels;
+    if let Some(channels) = self.mixed_channels {
+      return channels;
+    }
+    let num_channels: i32 = self.frame_decoder.stream_info().numChannels;
     num_channels as _
   }
+  /// Sample rate of the current frame
   pub fn sample_rate(&self) -> u32 {
-    let sample_rate: i32 = self.aac_decoder.stream_info().sampleRate;
+    let sample_rate: i32 = self.frame_decoder.stream_info().sampleRate;
     sample_rate as _
   }
+  /// Samples per channel in a frame, which is 1024 for AAC-LC and 2048 with
+  /// SBR
+  pub fn samples_per_frame(&self) -> usize {
+    let frame_size: i32 = self.frame_decoder.stream_info().frameSize;
+    frame_size.max(0) as usize
+  }
+  /// Duration of a frame at the current sample rate
+  pub fn frame_duration(&self) -> Duration {
+    ticks_to_duration(self.samples_per_frame() as u64, self.sample_rate())
+  }
+  /// Stream info of the current frame from fdk-aac, like the audio object
+  /// type, core sample rate and channel layout
+  pub fn stream_info(&self) -> StreamInfo {
+    StreamInfo::from_fdk(self.frame_decoder.stream_info())
+  }
+  /// How long fdk-aac delays the output, from `StreamInfo::output_delay`.
+  /// Frame timestamps don't account for it, so for A/V sync the audio of a
+  /// frame is at `pts - output_delay()` in the input. 0 for MP3.
+  pub fn output_delay(&self) -> Duration {
+    let delay = self.frame_decoder.stream_info().outputDelay;
+    ticks_to_duration(delay as u64, self.sample_rate())
+  }
+  /// MPEG-4 audio object type of the stream, counting SBR and PS as their own
+  /// types like in codec strings, so HE-AAC is 5 rather than 2
+  pub(crate) fn object_type(&self) -> u32 {
+    let stream_info = self.frame_decoder.stream_info();
+    let flags = stream_info.flags;
+    // implicit PS isn't flagged, but makes mono AAC stereo. Only accurate
+    // without a fixed output channel count.
+    let ps = stream_info.aacNumChannels == 1 && stream_info.numChannels == 2;
+    if flags & fdk_aac_sys::AC_PS_PRESENT != 0 || (flags & fdk_aac_sys::AC_SBR_PRESENT != 0 && ps) {
+      29
+    } else if flags & fdk_aac_sys::AC_SBR_PRESENT != 0 {
+      5
+    } else {
+      stream_info.aot.max(0) as u32
+    }
+  }
+  /// objectTypeIndication of the MP4 track when it has MP3 samples
+  pub(crate) fn mp3_object_type(&self) -> Option<u8> {
+    self.frame_decoder.mp3_object_type()
+  }
+  /// MP4 track ID of the track being decoded. None for AAC input.
+  pub fn track_id(&self) -> Option<u32> {
+    match self.format {
+      Format::Mp4 => Some(self.track_id),
+      Format::Aac => None,
+    }
+  }
+  /// Timescale of the MP4 track in units per second, for converting the
+  /// durations from `sample_durations`. None for AAC input.
+  pub fn timescale(&self) -> Option<u32> {
+    self.mp4_track().map(|track| track.timescale())
+  }
+  /// Duration of each MP4 sample in the track's timescale, from the
+  /// time-to-sample table. A sample is usually one AAC frame. None for AAC
+  /// input.
+  pub fn sample_durations(&self) -> Option<Vec<u32>> {
+    let track = self.mp4_track()?;
+    let sample_count = track.sample_count() as usize;
+    if !track.trafs.is_empty() {
+      // fragmented, from the trun, tfhd or trex durations
+      let durations = fragment_sample_durations(track).map(|duration| duration as u32);
+      return Some(durations.collect());
+    }
+    let mut durations = Vec::new();
+    for entry in &track.trak.mdia.minf.stbl.stts.entries {
+      let count = (entry.sample_count as usize).min(sample_count - durations.len());
+      durations.resize(durations.len() + count, entry.sample_delta);
+    }
+    Some(durations)
+  }
+  /// Sample sizes and chunk layout of the MP4 track, like for pre-sizing
+  /// buffers or diagnosing unusual files. None for AAC input.
+  pub fn sample_table_stats(&self) -> Option<SampleTableStats> {
+    self.mp4_track().map(SampleTableStats::from_track)
+  }
+  /// Encoder delay and padding from the MP4 file's iTunSMPB tag. None for
+  /// AAC input.
+  pub fn gapless_info(&self) -> Option<GaplessInfo> {
+    self.gapless_info
+  }
+  /// Set the linear gain applied as samples are returned, where 1.0 leaves
+  /// them unchanged. Use `gain_handle` to change it while playing.
+  pub fn set_gain(&mut self, gain: f32) {
+    self.gain.set_gain(gain);
+  }
+  /// Set the gain in dB. See `set_gain`.
+  pub fn set_gain_db(&mut self, db: f32) {
+    self.gain.set_gain_db(db);
+  }
+  /// Handle for changing the gain from another thread, such as after the
+  /// decoder is given to rodio
+  pub fn gain_handle(&self) -> GainHandle {
+    self.gain.clone()
+  }
+  /// Gain to apply to samples that are being returned, if any
+  fn output_gain(&self) -> Option<f32> {
+    Some(self.gain.gain()).filter(|&gain| gain != 1.0)
+  }
+  /// Tags of the MP4 file, like the title and track number. None for AAC
+  /// input and files without tags.
+  pub fn metadata(&self) -> Option<&Metadata> {
+    self.metadata.as_ref()
+  }
+  /// ReplayGain from the MP4 file's tags. None for AAC input.
+  pub fn replay_gain(&self) -> Option<ReplayGain> {
+    self.replay_gain
+  }
+  /// Loudness measured by the encoder, from the MP4 file's ludt box. Can be
+  /// used for normalization instead of analyzing the whole stream.
+  pub fn loudness_info(&self) -> Option<LoudnessInfo> {
+    self.loudness_info
+  }
+  /// Duration of the whole stream, from the MP4 sample durations or by
+  /// scanning the ADTS frame headers of AAC input. The reader is left where it
+  /// was.
+  pub(crate) fn stream_duration(&mut self) -> Result<Duration, Error> {
+    if self.is_live() {
+      return Err(Error::InvalidConfig("live streams have no duration"));
+    }
+    match &mut self.reader {
+      Reader::Mp4Reader(_) => {
+        let ticks = self.mp4_track().map_or(0, track_duration);
+        Ok(ticks_to_duration(ticks, self.timescale))
+      }
+      Reader::AacReader(aac_reader) => {
+        let position = aac_reader.stream_position().map_err(Error::ReaderError)?;
+        let duration = scan_adts_frames(aac_reader, self.stream_start, |_, _| true)?;
+        aac_reader
+          .seek(SeekFrom::Start(position))
+          .map_err(Error::ReaderError)?;
+        Ok(duration)
+      }
+    }
+  }
+  /// Skip leading and trailing silence at or below `threshold_db` dBFS using
+  /// `decode_range`, and return where the audio is. A stream that's all
+  /// silent finishes right away.
+  pub fn trim_silence(&mut self, threshold_db: f32) -> Result<Option<silence::Bounds>, Error> {
+    let bounds = silence::detect(self, threshold_db)?;
+    match bounds {
+      Some(bounds) => self.decode_range(bounds.start, bounds.end)?,
+      None => self.decode_range(Duration::ZERO, Duration::ZERO)?,
+    }
+    Ok(bounds)
+  }
+  /// Start and end of the audio between the encoder delay and padding, from
+  /// the gapless info
+  pub(crate) fn gapless_range(&self) -> Option<(Duration, Duration)> {
+    let info = self.gapless_info?;
+    let delay = info.encoder_delay as u64;
+    let start = ticks_to_duration(delay, self.sample_rate());
+    let end = ticks_to_duration(delay.saturating_add(info.samples), self.sample_rate());
+    Some((start, end))
+  }
+  /// Trim the encoder delay and padding using `decode_range`, so files can be
+  /// played back to back without gaps. Does nothing without gapless info.
+  pub fn trim_gapless(&mut self) -> Result<(), Error> {
+    match self.gapless_range() {
+      Some((start, end)) => self.decode_range(start, end),
+      None => Ok(()),
+    }
+  }
+  /// The parsed MP4 file, for reading boxes that aren't exposed otherwise.
+  /// None for AAC input.
+  pub fn mp4(&self) -> Option<&mp4::Mp4Reader<BufferedReader<R>>> {
+    match &self.reader {
+      Reader::Mp4Reader(mp4_reader) => Some(mp4_reader),
+      Reader::AacReader(_) => None,
+    }
+  }
+  /// The MP4 track being decoded. None for AAC input.
+  pub fn mp4_track(&self) -> Option<&mp4::Mp4Track> {
+    self.mp4()?.tracks().get(&self.track_id)
+  }
+  /// Number of samples left, across all channels, when the length of the
+  /// stream is known. That's the case for MP4 input, and AAC input once it's
+  /// been scanned for a fade-out. The end of the range from `decode_range` is
+  /// taken into account. Assumes the channel count doesn't change.
+  pub fn remaining_samples(&self) -> Option<u64> {
+    if self.ended {
+      return Some(0);
+    }
+    let duration = self.duration?;
+    let end = self.end.map_or(duration, |end| end.min(duration));
+    let sample_rate = self.sample_rate();
+    let channels = self.channels() as u64;
+    let samples = |duration| duration_to_ticks(duration, sample_rate).saturating_mul(channels);
+    let frame_start = samples(self.frame_pts).saturating_add(self.current_pcm_index as u64);
+    Some(samples(end).saturating_sub(frame_start))
+  }
+  /// Whether the input is a live stream, from `DecoderBuilder::live`. Live
+  /// streams have no duration and can't be seeked.
+  pub fn is_live(&self) -> bool {
+    self.options.live.is_some() && self.format == Format::Aac
+  }
+  /// Duration of the output, from the sum of the track's sample durations in
+  /// the stts box for MP4 input, so it's exact to the sample. The range from
+  /// `decode_range`, `trim_gapless` or `DecoderBuilder::trim_start` is taken
+  /// into account, but the encoder delay and padding are included otherwise,
+  /// like they are in the output. Falls back to the duration in the mvhd box
+  /// when the sample durations aren't read, like in preview mode. For AAC
+  /// input it's known once the stream has been scanned.
   pub fn total_duration(&self) -> Option<Duration> {
-    return None;
+    let duration = match self.duration {
+      Some(duration) => duration,
+      None => {
+        let mvhd = &self.mp4()?.moov.mvhd;
+        if mvhd.timescale == 0 || mvhd.duration == 0 {
+          return None;
+        }
+        ticks_to_duration(mvhd.duration, mvhd.timescale)
+      }
+    };
+    let end = self.end.map_or(duration, |end| end.min(duration));
+    Some(end.saturating_sub(self.range_start))
   }
-  /// Consume and return the next sample, or None when finished
-  pub fn decode_next_sample(&mut self) -> Result<Option<i16>, Error> {
-    if self.current_pcm_index == self.current_pcm.len() {
-      let mut pcm = vec![0; 8192];
-      let result = match self.aac_decoder.decode_frame(&mut pcm) {
-        Err(DecoderError::NOT_ENOUGH_BITS) | Err(DecoderError::TRANSPORT_SYNC_ERROR) => {
-          match &mut self.reader {
-            // mp4
-            Reader::Mp4Reader(mp4_reader) => {
-              let sample_result = mp4_reader.read_sample(self.track_id, self.position);
-              let sample_opt = sample_result.or(Err(Error::SamplesError))?;
-              let sample = match sample_opt {
-                Some(sample) => sample,
-                None => return Ok(None), // EOF
-              };
-              let tracks = mp4_reader.tracks();
-              let track = tracks.get(&self.track_id).ok_or(Error::TrackNotFound)?;
-              let object_type = track.audio_profile().or(Err(Error::TrackReadingError))?;
-              let sample_freq_index = track
-                .sample_freq_index()
-                .or(Err(Error::TrackReadingError))?;
-              let channel_config = track.channel_config().or(Err(Error::TrackReadingError))?;
-              let adts_header = adts::construct_adts_header(
-                object_type,
-                sample_freq_index,
-                channel_config,
-                &sample,
-              )?;
-              let adts_bytes = mp4::Bytes::copy_from_slice(&adts_header);
-              self.bytes = [adts_bytes, sample.bytes].concat();
-              self.position += 1;
+  /// Read the next frame of input and fill it into the AAC decoder. Returns
+  /// false when there's no more input.
+  fn fill_decoder(&mut self) -> Result<bool, Error> {
+    if self.unfilled_frame_len == 0 && self.bytes.is_empty() {
+      self.configure_sample_entry()?;
+    }
+    if self.unfilled_frame_len == 0 {
+      match &mut self.reader {
+        // mp4
+        Reader::Mp4Reader(mp4_reader) => {
+          // the rest of a sample that contains multiple frames
+          let mut multiple_frames = !self.bytes.is_empty();
+          if self.bytes.is_empty() {
+            let sample_id = match u32::try_from(self.position) {
+              Ok(sample_id) => sample_id,
+              Err(_) => return Ok(false), // past the last sample ID of an MP4 file
+            };
+            // check the size first, since the mp4 crate allocates the whole sample
+            let size = match mp4_reader.tracks().get(&self.track_id) {
+              Some(track) => sample_size(track, sample_id),
+              None => None,
+            };
+            match size {
+              Some(size) if size as usize > self.options.max_sample_size => {
+                if self.options.lenient {
+                  self.position += 1;
+                  return Ok(true); // skip the sample
+                }
+                return Err(Error::SampleTooLarge(size));
+              }
+              _ => {}
             }
-            // aac
-            Reader::AacReader(aac_reader) => {
-              let old_bytes_len = self.bytes.len();
-              let mut new_bytes = vec![0; 8192 - old_bytes_len];
-              let bytes_read = match aac_reader.read(&mut new_bytes) {
-                Ok(bytes_read) => bytes_read,
-                Err(err) => return Err(Error::ReaderError(err)),
-              };
-              if bytes_read == 0 {
-                return Ok(None); // EOF
+            let sample_result = mp4_reader.read_sample(self.track_id, sample_id);
+            let sample_opt = match sample_result {
+              Ok(sample_opt) => sample_opt,
+              Err(mp4::Error::IoError(err)) => return Err(Error::ReaderError(err)),
+              Err(_) if self.options.lenient => {
+                self.position += 1;
+                return Ok(true); // skip the sample
               }
-              // aac files already have adts headers
-              self.bytes.extend(new_bytes);
+              Err(_) => return Err(Error::SamplesError),
+            };
+            let sample = match sample_opt {
+              Some(sample) => sample,
+              None => return Ok(false), // EOF
+            };
+            let start_time = match mp4_reader.tracks().get(&self.track_id) {
+              Some(track) => sample_start_time(track, &self.fragment_starts, &sample, sample_id),
+              None => sample.start_time,
+            };
+            self.position += 1;
+            if sample.bytes.is_empty() {
+              return Ok(true); // nothing to fill, try the next sample
             }
+            multiple_frames = sample.duration as u64 > self.frame_duration;
+            let start_time = start_time.min(i64::MAX as u64) as i64;
+            self.next_time = start_time.saturating_add(sample.rendering_offset as i64);
+            // samples are raw access units
+            self.bytes.extend_from_slice(&sample.bytes);
+          }
+          self.filled_time = self.next_time;
+          let frame_duration = self.frame_duration.min(i64::MAX as u64) as i64;
+          self.next_time = self.next_time.saturating_add(frame_duration);
+          self.unfilled_frame_len = self.bytes.len();
+          if multiple_frames {
+            let frame_len = syntax::raw_data_block_length(&self.bytes, self.sample_freq_index);
+            if let Some(frame_len) = frame_len {
+              self.unfilled_frame_len = frame_len;
+            }
+          }
+        }
+        // aac files already have adts headers
+        Reader::AacReader(aac_reader) => {
+          let synced = &mut self.adts_synced;
+          let mut skipped_bytes = 0;
+          let bytes = &mut self.bytes;
+          let frame_length = next_adts_frame(
+            aac_reader,
+            bytes,
+            synced,
+            &mut skipped_bytes,
+            self.read_chunk_size,
+            self.options.live,
+          )?;
+          if skipped_bytes > 0 {
+            self.emit(Event::Resync { skipped_bytes });
+          }
+          match frame_length {
+            Some(frame_length) => self.unfilled_frame_len = frame_length,
+            None => return Ok(false), // EOF
           }
-          let bytes_filled = match self.aac_decoder.fill(&self.bytes) {
-            Ok(bytes_filled) => bytes_filled,
-            Err(err) => return Err(Error::TrackDecodingError(err)),
+        }
+      }
+    }
+    let frame = self.bytes.get(..self.unfilled_frame_len);
+    let frame = frame.ok_or(Error::SamplesError)?;
+    let bytes_filled = match self.frame_decoder.fill(frame) {
+      Ok(bytes_filled) => bytes_filled,
+      Err(err) => return Err(Error::TrackDecodingError(err)),
+    };
+    if bytes_filled == 0 {
+      return Ok(false); // the decoder won't take the remaining bytes
+    }
+    self.bytes.drain(..bytes_filled);
+    self.unfilled_frame_len = self.unfilled_frame_len.saturating_sub(bytes_filled);
+    Ok(true)
+  }
+  /// ID of the next MP4 sample as the mp4 crate takes it. None past the last
+  /// ID an MP4 file can have.
+  fn mp4_sample_id(&self) -> Option<u32> {
+    u32::try_from(self.position).ok()
+  }
+  /// Position in the reader of the input that hasn't been filled into the AAC
+  /// decoder
+  fn input_offset(&self) -> u64 {
+    match &self.reader {
+      Reader::Mp4Reader(mp4_reader) => {
+        let track = mp4_reader.tracks().get(&self.track_id);
+        let sample_id = self.mp4_sample_id();
+        let offset = track.and_then(|track| sample_offset(track, sample_id?));
+        offset.unwrap_or(0)
+      }
+      Reader::AacReader(aac_reader) => aac_reader.position.saturating_sub(self.bytes.len() as u64),
+    }
+  }
+  /// Return `Error::TimeBudgetExceeded` if the frame that started decoding at
+  /// `started` has gone over a time budget
+  fn check_time_budget(&self, started: Instant, position: Duration) -> Result<(), Error> {
+    let elapsed = started.elapsed();
+    let frame_over = match self.options.frame_time_budget {
+      Some(budget) => elapsed > budget,
+      None => false,
+    };
+    let file_over = match self.options.file_time_budget {
+      Some(budget) => self.decode_time.saturating_add(elapsed) > budget,
+      None => false,
+    };
+    if frame_over || file_over {
+      return Err(Error::TimeBudgetExceeded(position));
+    }
+    Ok(())
+  }
+  /// Decode the next frame into `current_pcm`. Returns false when finished.
+  fn decode_frame(&mut self) -> Result<bool, Error> {
+    let position = self.frame_pts + self.samples_duration(self.current_pcm.len());
+    let budgeted =
+      self.options.frame_time_budget.is_some() || self.options.file_time_budget.is_some();
+    let started = Instant::now();
+    let result = self.decode_frame_timed(position, budgeted.then_some(started));
+    self.decode_time = self.decode_time.saturating_add(started.elapsed());
+    if let Ok(true) = result {
+      self.decoded_frames += 1;
+      let duration = self.samples_duration(self.current_pcm.len());
+      self.decoded_audio = self.decoded_audio.saturating_add(duration);
+      if self.options.realtime {
+        self.pace();
+      }
+    }
+    result
+  }
+  /// Sleep until the frame's time since pacing started has passed
+  fn pace(&mut self) {
+    let (started, start_pts) = match self.pace_start {
+      Some(pace_start) => pace_start,
+      None => {
+        self.pace_start = Some((Instant::now(), self.frame_pts));
+        return;
+      }
+    };
+    let due = started + self.frame_pts.saturating_sub(start_pts);
+    let now = Instant::now();
+    if due > now {
+      thread::sleep(due - now);
+    }
+  }
+  fn decode_frame_timed(
+    &mut self,
+    position: Duration,
+    started: Option<Instant>,
+  ) -> Result<bool, Error> {
+    self.current_pcm.resize(self.pcm_capacity, 0);
+    let mut concealed = false;
+    loop {
+      if let Some(started) = started {
+        self.check_time_budget(started, position)?;
+      }
+      match self.frame_decoder.decode_frame(&mut self.current_pcm) {
+        Ok(()) => break,
+        Err(DecoderError::NOT_ENOUGH_BITS) | Err(DecoderError::TRANSPORT_SYNC_ERROR) => {
+          let filled = match self.fill_decoder() {
+            Err(Error::ReaderError(error)) => Err(Error::StreamReaderError {
+              error,
+              offset: self.input_offset(),
+              position,
+            }),
+            result => result,
           };
-          self.bytes = self.bytes[bytes_filled..].to_vec();
-          self.aac_decoder.decode_frame(&mut pcm)
+          if !filled? {
+            self.finish();
+            return Ok(false);
+          }
+        }
+        // fdk-aac output a concealed frame
+        Err(err) if self.options.lenient && fdk::is_decode_error(&err) => {
+          concealed = true;
+          break;
         }
-        val => val,
-      };
-      if let Err(err) = result {
-        return Err(Error::TrackDecodingError(err));
+        Err(err) => return Err(Error::TrackDecodingError(err)),
+      }
+    }
+    if let Some(started) = started {
+      self.check_time_budget(started, position)?;
+    }
+    let decoded_frame_size = self.frame_decoder.decoded_frame_size();
+    self.current_pcm.truncate(decoded_frame_size);
+    self.current_pcm_index = 0;
+    self.mixed_channels = None;
+    if self.fixed_channels && self.output_channels.is_none() {
+      let channels = self.channels();
+      let result = self.frame_decoder.set_output_channels(channels);
+      result.map_err(Error::TrackDecodingError)?;
+      self.output_channels = Some(channels);
+    }
+    if let Some(matrix) = &self.options.downmix_matrix {
+      if matrix.inputs() == self.channels() {
+        matrix.apply(&mut self.current_pcm, &mut self.quantizer);
+        self.mixed_channels = Some(matrix.outputs());
       }
-      let decoded_frame_size = self.aac_decoder.decoded_frame_size();
-      if decoded_frame_size < pcm.len() {
-        let _ = pcm.split_off(decoded_frame_size);
+    }
+    let format = (self.channels(), self.sample_rate());
+    if let Some((channels, sample_rate)) = self.frame_format {
+      if format.1 != sample_rate {
+        self.emit(Event::SampleRateChanged(format.1));
       }
-      self.current_pcm = pcm;
-      self.current_pcm_index = 0;
+      if format.0 != channels {
+        self.emit(Event::ChannelsChanged(format.0));
+      }
+    }
+    self.frame_format = Some(format);
+    let bad_access_units = self.frame_decoder.stream_info().numBadAccessUnits;
+    if bad_access_units > self.bad_access_units {
+      self.emit(Event::Concealment);
+      concealed = true;
     }
-    let value = self.current_pcm[self.current_pcm_index];
+    self.bad_access_units = bad_access_units;
+    let object_type = self.object_type();
+    self.frame_flags = FrameFlags {
+      concealed,
+      sbr: matches!(object_type, 5 | 29),
+      ps: object_type == 29,
+    };
+    self.frame_pts = match self.format {
+      Format::Mp4 => ticks_to_duration(self.filled_time.max(0) as u64, self.timescale),
+      Format::Aac => {
+        let pts = self.clock.now();
+        let samples = self.current_pcm.len() / self.channels().max(1) as usize;
+        self.clock.advance(samples as u64, self.sample_rate());
+        pts
+      }
+    };
+    if let Some(end) = self.end {
+      if self.frame_pts >= end {
+        self.finish();
+        return Ok(false);
+      }
+      let samples = duration_to_ticks(end - self.frame_pts, self.sample_rate());
+      let len = (samples as usize).saturating_mul(self.channels() as usize);
+      self.current_pcm.truncate(len);
+    }
+    if let Some(factor) = self.replay_gain_factor {
+      gain::apply_gain(&mut self.current_pcm, factor, &mut self.quantizer);
+    }
+    if self.fade_in.is_some() || self.fade_out.is_some() {
+      self.apply_fades()?;
+    }
+    Ok(true)
+  }
+  /// Apply linear fades to the current frame, with the fade-in at the start of
+  /// the stream or range, and the fade-out before its end
+  fn apply_fades(&mut self) -> Result<(), Error> {
+    let sample_rate = self.sample_rate();
+    let channels = self.channels().max(1) as usize;
+    let frame_start = duration_to_ticks(self.frame_pts, sample_rate);
+    let frame_end = frame_start + (self.current_pcm.len() / channels) as u64;
+    let fade_in = self.fade_in.map(|fade_in| {
+      let start = duration_to_ticks(self.range_start, sample_rate);
+      (start, duration_to_ticks(fade_in, sample_rate).max(1))
+    });
+    let fade_in = fade_in.filter(|(start, len)| frame_start < start + len);
+    let mut fade_out = None;
+    // live streams only fade out at the end of a range
+    let fade_out_len = self
+      .fade_out
+      .filter(|_| self.end.is_some() || !self.is_live());
+    if let Some(len) = fade_out_len {
+      if self.end.is_none() && self.duration.is_none() {
+        self.duration = Some(self.stream_duration()?);
+      }
+      let end = self.end.or(self.duration).unwrap_or_default();
+      let end = duration_to_ticks(end, sample_rate);
+      let len = duration_to_ticks(len, sample_rate).max(1);
+      if frame_end > end.saturating_sub(len) {
+        fade_out = Some((end, len));
+      }
+    }
+    if fade_in.is_none() && fade_out.is_none() {
+      return Ok(());
+    }
+    for (i, samples) in self.current_pcm.chunks_exact_mut(channels).enumerate() {
+      let time = frame_start + i as u64;
+      let mut factor = 1.0;
+      if let Some((start, len)) = fade_in {
+        factor *= (time.saturating_sub(start) as f32 / len as f32).min(1.0);
+      }
+      if let Some((end, len)) = fade_out {
+        factor *= (end.saturating_sub(time) as f32 / len as f32).min(1.0);
+      }
+      for sample in samples {
+        *sample = self.quantizer.quantize(*sample as f32 * factor);
+      }
+    }
+    Ok(())
+  }
+  /// Clear the PCM buffer at the end of the stream
+  fn finish(&mut self) {
+    self.current_pcm.clear();
+    self.current_pcm_index = 0;
+    if !self.ended {
+      self.ended = true;
+      self.emit(Event::EndOfStream);
+    }
+  }
+  /// Seek to a position with sample accuracy. A few frames before the
+  /// position are decoded and discarded, since AAC frames overlap. AAC input
+  /// has no index, so its frame headers are scanned from the start of the
+  /// stream. A position past the end finishes the stream. Streams that use
+  /// noise substitution differ slightly from a full decode, since the noise
+  /// isn't repeatable.
+  pub fn seek(&mut self, position: Duration) -> Result<(), Error> {
+    if self.is_live() {
+      return Err(Error::InvalidConfig("live streams can't be seeked"));
+    }
+    self.pending_error = None;
+    self.ended = false;
+    self.bytes.clear();
+    self.unfilled_frame_len = 0;
+    let from_start = match &mut self.reader {
+      Reader::Mp4Reader(mp4_reader) => {
+        let tracks = mp4_reader.tracks();
+        let track = tracks.get(&self.track_id).ok_or(Error::TrackNotFound)?;
+        let ticks = duration_to_ticks(position, track.timescale());
+        let sample_id = sample_at(track, &self.fragment_starts, &self.segment_starts, ticks);
+        self.position = sample_id.saturating_sub(PREROLL_FRAMES as u64).max(1);
+        self.position == 1
+      }
+      Reader::AacReader(aac_reader) => {
+        let (offset, time) = find_adts_frame(aac_reader, self.stream_start, position)?;
+        aac_reader
+          .seek(SeekFrom::Start(offset))
+          .map_err(Error::ReaderError)?;
+        self.adts_synced = false;
+        self.clock = Clock::new(time);
+        offset == self.stream_start
+      }
+    };
+    // the first frames are decoded from a clean state, like the first time
+    if from_start {
+      self.reset_frame_decoder()?;
+    } else {
+      let result = self.frame_decoder.clear_buffer();
+      result.map_err(Error::TrackDecodingError)?;
+    }
+    // decode up to the frame that contains the position
+    loop {
+      // pacing restarts at the frame with the position, without sleeping
+      // for the frames before it
+      self.pace_start = None;
+      if !self.decode_frame()? {
+        return Ok(());
+      }
+      let frame_end = self.frame_pts + self.samples_duration(self.current_pcm.len());
+      if frame_end > position {
+        let skip = duration_to_ticks(position.saturating_sub(self.frame_pts), self.sample_rate());
+        let skip = (skip as usize).saturating_mul(self.channels() as usize);
+        self.current_pcm_index = skip.min(self.current_pcm.len());
+        return Ok(());
+      }
+    }
+  }
+  /// Replace the frame decoder with a new one, since clearing the AAC
+  /// decoder's buffer keeps the overlap from the last frame
+  fn reset_frame_decoder(&mut self) -> Result<(), Error> {
+    let result = match &self.frame_decoder {
+      FrameDecoder::Aac(_) | FrameDecoder::Unavailable(..) => {
+        new_aac_decoder(self.format, &self.options, self.output_channels).map(FrameDecoder::Aac)
+      }
+      #[cfg(feature = "mp3")]
+      FrameDecoder::Mp3(decoder) => {
+        new_mp3_decoder(decoder.object_type(), self.output_channels).map(FrameDecoder::Mp3)
+      }
+    };
+    self.frame_decoder = result.map_err(Error::TrackDecodingError)?;
+    self.sample_entry = None;
+    Ok(())
+  }
+  /// Decode only from `start` to `end`, with sample accuracy. Seeks to
+  /// `start`, and finishes the stream at `end`. Fades apply to the range.
+  pub fn decode_range(&mut self, start: Duration, end: Duration) -> Result<(), Error> {
+    self.end = Some(end);
+    self.range_start = start;
+    self.seek(start)
+  }
+  /// Range of bytes in the input that's needed to decode from `start` to
+  /// `end`, including the frames `seek` decodes before `start`. For MP4 input
+  /// it only covers the samples, so the moov box is needed too, and it can
+  /// include data from other tracks that's interleaved with them. AAC input
+  /// is scanned for frame headers, and the reader is left where it was.
+  pub fn byte_range(&mut self, start: Duration, end: Duration) -> Result<Range<u64>, Error> {
+    if self.is_live() {
+      return Err(Error::InvalidConfig("live streams have no byte range"));
+    }
+    match &mut self.reader {
+      Reader::Mp4Reader(mp4_reader) => {
+        let tracks = mp4_reader.tracks();
+        let track = tracks.get(&self.track_id).ok_or(Error::TrackNotFound)?;
+        let sample_count = track.sample_count().max(1) as u64;
+        let (fragment_starts, segment_starts) = (&self.fragment_starts, &self.segment_starts);
+        let sample_at = |time| {
+          let ticks = duration_to_ticks(time, track.timescale());
+          sample_at(track, fragment_starts, segment_starts, ticks)
+        };
+        let first = sample_at(start);
+        let first = first
+          .saturating_sub(PREROLL_FRAMES as u64)
+          .clamp(1, sample_count);
+        let last = sample_at(end);
+        let last = last.clamp(first, sample_count);
+        // within the sample count, so they fit in u32
+        let (first, last) = (first as u32, last as u32);
+        let sample_range = |sample_id| {
+          let offset = sample_offset(track, sample_id)?;
+          Some(offset..offset + sample_size(track, sample_id)? as u64)
+        };
+        let first = sample_range(first).ok_or(Error::SamplesError)?;
+        let last = sample_range(last).ok_or(Error::SamplesError)?;
+        Ok(first.start.min(last.start)..first.end.max(last.end))
+      }
+      Reader::AacReader(aac_reader) => {
+        let position = aac_reader.stream_position().map_err(Error::ReaderError)?;
+        let mut frames = VecDeque::with_capacity(PREROLL_FRAMES + 1);
+        let mut end_offset = None;
+        scan_adts_frames(aac_reader, self.stream_start, |offset, time| {
+          if time >= end && time > start {
+            end_offset = Some(offset);
+            return false;
+          }
+          if time <= start {
+            if frames.len() == PREROLL_FRAMES + 1 {
+              frames.pop_front();
+            }
+            frames.push_back(offset);
+          }
+          true
+        })?;
+        let end_offset = match end_offset {
+          Some(offset) => offset,
+          None => aac_reader
+            .seek(SeekFrom::End(0))
+            .map_err(Error::ReaderError)?,
+        };
+        aac_reader
+          .seek(SeekFrom::Start(position))
+          .map_err(Error::ReaderError)?;
+        let start_offset = frames.front().copied().unwrap_or(self.stream_start);
+        Ok(start_offset..end_offset.max(start_offset))
+      }
+    }
+  }
+  /// Decode exactly `len` samples per channel, starting at sample
+  /// `start_sample` per channel, and return them interleaved. Seeks with
+  /// `seek`, so decoding continues after the window. Fewer samples are
+  /// returned when the stream ends first. Assumes the channel count doesn't
+  /// change within the window.
+  pub fn extract(&mut self, start_sample: u64, len: usize) -> Result<Vec<i16>, Error> {
+    self.seek(ticks_to_duration(start_sample, self.sample_rate()))?;
+    // a window past the end returns fewer samples anyway
+    let len = len.saturating_mul(self.channels() as usize);
+    let remaining = self.remaining_samples().map_or(0, |remaining| {
+      usize::try_from(remaining).unwrap_or(usize::MAX)
+    });
+    let mut samples = Vec::with_capacity(len.min(remaining));
+    while samples.len() < len {
+      match self.decode_next_sample()? {
+        Some(sample) => samples.push(sample),
+        None => break,
+      }
+    }
+    Ok(samples)
+  }
+  /// Decode the next frame if the current one is used up, so that `channels`
+  /// and `sample_rate` describe the next sample even when they change
+  /// mid-stream. An error is kept until the next decode call.
+  fn decode_ahead(&mut self) {
+    if self.current_pcm_index < self.current_pcm.len() || self.pending_error.is_some() {
+      return;
+    }
+    if let Err(err) = self.decode_frame() {
+      self.pending_error = Some(err);
+    }
+  }
+  /// Duration of a number of interleaved samples of the current frame
+  fn samples_duration(&self, samples: usize) -> Duration {
+    let channels = self.channels().max(1) as u64;
+    ticks_to_duration(samples as u64 / channels, self.sample_rate())
+  }
+  /// Consume and return the rest of the current frame, decoding the next
+  /// frame first if it's used up. Returns None when finished.
+  pub fn next_frame(&mut self) -> Result<Option<Frame<'_>>, Error> {
+    if let Some(err) = self.pending_error.take() {
+      return Err(err);
+    }
+    while self.current_pcm_index == self.current_pcm.len() {
+      if !self.decode_frame()? {
+        return Ok(None);
+      }
+    }
+    let start = self.current_pcm_index;
+    self.current_pcm_index = self.current_pcm.len();
+    if let Some(gain) = self.output_gain() {
+      gain::apply_gain(&mut self.current_pcm[start..], gain, &mut self.quantizer);
+    }
+    Ok(Some(Frame {
+      pts: self.frame_pts + self.samples_duration(start),
+      channels: self.channels(),
+      sample_rate: self.sample_rate(),
+      samples: &self.current_pcm[start..],
+      flags: self.frame_flags,
+    }))
+  }
+  /// Consume and return the next sample, or None when finished
+  pub fn decode_next_sample(&mut self) -> Result<Option<i16>, Error> {
+    if let Some(err) = self.pending_error.take() {
+      return Err(err);
+    }
+    while self.current_pcm_index == self.current_pcm.len() {
+      if !self.decode_frame()? {
+        return Ok(None);
+      }
+    }
+    let mut value = [self.current_pcm[self.current_pcm_index]];
     self.current_pcm_index += 1;
-    return Ok(Some(value));
+    if let Some(gain) = self.output_gain() {
+      gain::apply_gain(&mut value, gain, &mut self.quantizer);
+    }
+    self.decode_ahead();
+    Ok(Some(value[0]))
+  }
+  /// Decode the next chunk of frames and append the samples to `buf`. Returns
+  /// the number of samples appended, which is 0 when finished. The number of
+  /// frames per chunk is set with `DecoderBuilder::frames_per_chunk`. A chunk
+  /// ends early when the channels or sample rate change, and the frame after
+  /// a chunk is decoded ahead, so the format from `channels` and
+  /// `sample_rate` before the call applies to the whole chunk.
+  pub fn next_chunk(&mut self, buf: &mut Vec<i16>) -> Result<usize, Error> {
+    if let Some(err) = self.pending_error.take() {
+      return Err(err);
+    }
+    let start_len = buf.len();
+    let mut format = (self.channels(), self.sample_rate());
+    let mut frames = 0;
+    // the frame decoded ahead, or samples left over from sample-by-sample
+    // decoding
+    if self.current_pcm_index < self.current_pcm.len() {
+      buf.extend_from_slice(&self.current_pcm[self.current_pcm_index..]);
+      self.current_pcm_index = self.current_pcm.len();
+      frames += 1;
+    }
+    while frames < self.frames_per_chunk {
+      if !self.decode_frame()? {
+        break;
+      }
+      let frame_format = (self.channels(), self.sample_rate());
+      if frame_format != format {
+        if buf.len() > start_len {
+          break; // keep the frame for the next chunk
+        }
+        // nothing was decoded ahead, like after `next_frame`
+        format = frame_format;
+      }
+      buf.extend_from_slice(&self.current_pcm);
+      self.current_pcm_index = self.current_pcm.len();
+      frames += 1;
+    }
+    self.decode_ahead();
+    if let Some(gain) = self.output_gain() {
+      gain::apply_gain(&mut buf[start_len..], gain, &mut self.quantizer);
+    }
+    Ok(buf.len() - start_len)
+  }
+}
+
+/// Transport of the AAC decoder for an input format
+#[cfg(feature = "std")]
+fn transport(format: Format) -> Transport {
+  match format {
+    Format::Mp4 => Transport::Raw,
+    Format::Aac => Transport::Adts,
+  }
+}
+
+/// Create an AAC decoder with the output options
+#[cfg(feature = "std")]
+fn new_aac_decoder(
+  format: Format,
+  options: &DecoderBuilder,
+  output_channels: Option<u16>,
+) -> Result<AacDecoder, DecoderError> {
+  let mut aac_decoder = AacDecoder::new(transport(format))?;
+  // AAC_PCM_OUTPUT_CHANNEL_MAPPING defaults to WAV order, but it's always set
+  // so the output doesn't depend on the default
+  aac_decoder.set_channel_order(options.channel_order)?;
+  match output_channels {
+    Some(channels) => aac_decoder.set_output_channels(channels)?,
+    // fdk-aac downmixes 7.1 to 5.1 by default
+    None => aac_decoder.set_max_output_channels(MAX_CHANNELS)?,
   }
+  if let Some(drc) = &options.drc {
+    aac_decoder.set_drc(drc)?;
+  }
+  Ok(aac_decoder)
 }
 
+/// Create an MP3 decoder with the output options
+#[cfg(feature = "mp3")]
+fn new_mp3_decoder(
+  object_type: u8,
+  output_channels: Option<u16>,
+) -> Result<Box<mp3::Mp3Decoder>, DecoderError> {
+  let mut mp3_decoder = mp3::Mp3Decoder::new(object_type)?;
+  if let Some(channels) = output_channels {
+    mp3_decoder.set_output_channels(channels)?;
+  }
+  Ok(Box::new(mp3_decoder))
+}
+
+/// ID of the MP4 sample that contains a time in the track's timescale
+#[cfg(feature = "std")]
+fn sample_at(
+  track: &mp4::Mp4Track,
+  fragment_starts: &[(u64, u64)],
+  segment_starts: &[(u64, usize)],
+  ticks: u64,
+) -> u64 {
+  if !track.trafs.is_empty() {
+    // fragmented. With a sidx box, the search starts at the subsegment with
+    // the time instead of the first fragment.
+    let segment = segment_starts.partition_point(|&(start, _)| start <= ticks);
+    let (mut time, first_fragment) = match segment.checked_sub(1) {
+      Some(segment) => segment_starts[segment],
+      None => (0, 0),
+    };
+    let first_sample = fragment_starts
+      .get(first_fragment)
+      .map(|&(sample_id, _)| sample_id);
+    let mut sample_id = first_sample.unwrap_or(1);
+    for fragment in first_fragment..track.trafs.len() {
+      for duration in traf_sample_durations(track, fragment) {
+        time = time.saturating_add(duration);
+        if ticks < time {
+          return sample_id;
+        }
+        sample_id += 1;
+      }
+    }
+    return sample_id;
+  }
+  let mut sample_id: u64 = 1;
+  let mut time: u64 = 0;
+  for entry in &track.trak.mdia.minf.stbl.stts.entries {
+    let entry_duration = entry.sample_count as u64 * entry.sample_delta as u64;
+    if ticks < time.saturating_add(entry_duration) {
+      return sample_id + (ticks - time) / entry.sample_delta as u64;
+    }
+    time = time.saturating_add(entry_duration);
+    sample_id += entry.sample_count as u64;
+  }
+  sample_id
+}
+
+/// Duration of an MP4 track in its timescale, from the sample durations.
+/// Summed as u64, since long files with large timescales don't fit in u32.
+#[cfg(feature = "std")]
+fn track_duration(track: &mp4::Mp4Track) -> u64 {
+  let sample_count = track.sample_count() as u64;
+  if !track.trafs.is_empty() {
+    return fragment_sample_durations(track).fold(0, u64::saturating_add);
+  }
+  let mut samples: u64 = 0;
+  let mut ticks: u64 = 0;
+  for entry in &track.trak.mdia.minf.stbl.stts.entries {
+    let count = (entry.sample_count as u64).min(sample_count - samples);
+    samples += count;
+    ticks = ticks.saturating_add(count * entry.sample_delta as u64);
+  }
+  ticks
+}
+
+/// Duration of each sample of a fragment of an MP4 track, from the trun
+/// sample durations or else the tfhd or trex default duration
+#[cfg(feature = "std")]
+fn traf_sample_durations(track: &mp4::Mp4Track, traf: usize) -> impl Iterator<Item = u64> + '_ {
+  let traf = &track.trafs[traf];
+  let default = traf.tfhd.default_sample_duration;
+  let default = default.unwrap_or(track.default_sample_duration) as u64;
+  let (count, durations) = match &traf.trun {
+    Some(trun) => (trun.sample_count as usize, &trun.sample_durations[..]),
+    None => (0, &[][..]),
+  };
+  (0..count).map(move |i| {
+    durations
+      .get(i)
+      .map_or(default, |&duration| duration as u64)
+  })
+}
+
+#[cfg(feature = "std")]
+fn fragment_sample_durations(track: &mp4::Mp4Track) -> impl Iterator<Item = u64> + '_ {
+  (0..track.trafs.len()).flat_map(move |traf| traf_sample_durations(track, traf))
+}
+
+/// Start time in the track's timescale and fragment of each subsegment of a
+/// sidx box. Empty without one, or if the subsegments don't each start at a
+/// fragment of the track.
+#[cfg(feature = "std")]
+fn segment_starts(track: &mp4::Mp4Track, index: Option<boxes::SegmentIndex>) -> Vec<(u64, usize)> {
+  let index = match index {
+    Some(index) if index.reference_id == track.track_id() && index.timescale != 0 => index,
+    _ => return Vec::new(),
+  };
+  // the trafs of the track are in the order of the moof boxes
+  if index.moofs.len() != track.trafs.len() {
+    return Vec::new();
+  }
+  let mut starts = Vec::with_capacity(index.subsegments.len());
+  for &(time, offset) in &index.subsegments {
+    let fragment = match index.moofs.binary_search(&offset) {
+      Ok(fragment) => fragment,
+      Err(_) => return Vec::new(),
+    };
+    let time = time as u128 * track.timescale() as u128 / index.timescale as u128;
+    starts.push((u64::try_from(time).unwrap_or(u64::MAX), fragment));
+  }
+  starts
+}
+
+/// First sample ID and start time of each fragment of a fragmented MP4 track
+#[cfg(feature = "std")]
+fn fragment_starts(track: &mp4::Mp4Track) -> Vec<(u64, u64)> {
+  let mut sample_id: u64 = 1;
+  let mut time: u64 = 0;
+  let mut starts = Vec::with_capacity(track.trafs.len());
+  for traf in 0..track.trafs.len() {
+    starts.push((sample_id, time));
+    for duration in traf_sample_durations(track, traf) {
+      sample_id += 1;
+      time = time.saturating_add(duration);
+    }
+  }
+  starts
+}
+
+/// Start time of an MP4 sample in the track's timescale. For fragmented
+/// tracks, the mp4 crate uses the default duration for every sample and
+/// multiplies it as u32, so the time is found from `fragment_starts`.
+#[cfg(feature = "std")]
+fn sample_start_time(
+  track: &mp4::Mp4Track,
+  fragment_starts: &[(u64, u64)],
+  sample: &mp4::Mp4Sample,
+  sample_id: u32,
+) -> u64 {
+  let sample_id = sample_id as u64;
+  let fragment = fragment_starts.partition_point(|&(first, _)| first <= sample_id);
+  let (traf, (first, start)) = match fragment.checked_sub(1) {
+    Some(traf) if traf < track.trafs.len() => (traf, fragment_starts[traf]),
+    _ => return sample.start_time,
+  };
+  let durations = traf_sample_durations(track, traf).take((sample_id - first) as usize);
+  durations.fold(start, u64::saturating_add)
+}
+
+/// Scan ADTS frame headers from the start of the stream, calling `frame` with
+/// the offset and time of each frame until it returns false. Returns the time
+/// where the scan stopped.
+#[cfg(feature = "std")]
+fn scan_adts_frames<R, F>(
+  reader: &mut R,
+  stream_start: u64,
+  mut frame: F,
+) -> Result<Duration, Error>
+where
+  R: Read + Seek,
+  F: FnMut(u64, Duration) -> bool,
+{
+  reader
+    .seek(SeekFrom::Start(stream_start))
+    .map_err(Error::ReaderError)?;
+  let mut bytes = Vec::new();
+  let mut synced = false;
+  let mut offset = stream_start;
+  let mut clock = Clock::default();
+  loop {
+    let mut skipped_bytes = 0;
+    let frame_length = next_adts_frame(
+      reader,
+      &mut bytes,
+      &mut synced,
+      &mut skipped_bytes,
+      8192,
+      None,
+    )?;
+    offset += skipped_bytes as u64;
+    let frame_length = match frame_length {
+      Some(frame_length) => frame_length,
+      None => return Ok(clock.now()),
+    };
+    if !frame(offset, clock.now()) {
+      return Ok(clock.now());
+    }
+    let header = adts::parse_header(&bytes)?;
+    let samples = 1024 * header.raw_data_blocks as u64;
+    clock.advance(samples, header.sample_freq_index.freq());
+    bytes.drain(..frame_length);
+    offset += frame_length as u64;
+  }
+}
+
+/// Find where to start decoding ADTS input for a position, including preroll
+/// frames. Returns the offset and time of that frame.
+#[cfg(feature = "std")]
+fn find_adts_frame<R: Read + Seek>(
+  reader: &mut R,
+  stream_start: u64,
+  position: Duration,
+) -> Result<(u64, Duration), Error> {
+  // the frame that contains the position, and the preroll frames before it
+  let mut frames = VecDeque::with_capacity(PREROLL_FRAMES + 1);
+  scan_adts_frames(reader, stream_start, |offset, time| {
+    if time > position {
+      return false;
+    }
+    if frames.len() == PREROLL_FRAMES + 1 {
+      frames.pop_front();
+    }
+    frames.push_back((offset, time));
+    true
+  })?;
+  let frame = frames.front().copied();
+  Ok(frame.unwrap_or((stream_start, Duration::ZERO)))
+}
+
+/// Error for MPEG-4 input without a track to decode. If there's an audio
+/// track in another codec, it's `UnsupportedCodec` so the codec can be shown
+/// to users.
+#[cfg(feature = "std")]
+fn track_not_found(
+  moov: Option<&[u8]>,
+  tracks: &HashMap<u32, mp4::Mp4Track>,
+  track: Option<u32>,
+) -> Error {
+  let audio_track = boxes::boxes(moov.unwrap_or_default()).find_map(|(box_type, trak)| {
+    let track_id = boxes::track_id(trak)?;
+    if &box_type != b"trak" || track.is_some_and(|id| id != track_id) {
+      return None;
+    }
+    Some((track_id, boxes::audio_sample_entry_type(trak)?))
+  });
+  let (track_id, entry_type) = match audio_track {
+    Some(audio_track) => audio_track,
+    None => return Error::TrackNotFound,
+  };
+  let mp3 = tracks.get(&track_id).and_then(codec::mp3_object_type);
+  match (&entry_type, mp3) {
+    (b"mp4a", Some(object_type)) => Error::UnsupportedCodec(format!("mp4a.{:02X}", object_type)),
+    (b"mp4a", None) => Error::TrackNotFound,
+    _ => Error::UnsupportedCodec(String::from_utf8_lossy(&entry_type).into_owned()),
+  }
+}
+
+/// Index of the sample description entry of an MP4 sample, from the
+/// sample-to-chunk table
+#[cfg(feature = "std")]
+fn sample_entry(track: &mp4::Mp4Track, sample_id: u32) -> usize {
+  let entries = &track.trak.mdia.minf.stbl.stsc.entries;
+  let index = entries.partition_point(|entry| entry.first_sample <= sample_id);
+  match index.checked_sub(1) {
+    Some(index) => entries[index].sample_description_index.saturating_sub(1) as usize,
+    None => 0,
+  }
+}
+
+/// Position of an MP4 sample in the file, from the sample-to-chunk, chunk
+/// offset and sample size tables
+#[cfg(feature = "std")]
+fn sample_offset(track: &mp4::Mp4Track, sample_id: u32) -> Option<u64> {
+  let stbl = &track.trak.mdia.minf.stbl;
+  let entries = &stbl.stsc.entries;
+  let index = entries.partition_point(|entry| entry.first_sample <= sample_id);
+  let entry = entries.get(index.checked_sub(1)?)?;
+  let samples_per_chunk = entry.samples_per_chunk.max(1);
+  let chunk = (sample_id - entry.first_sample) / samples_per_chunk;
+  let first_sample = entry.first_sample + chunk * samples_per_chunk;
+  let chunk_index = entry.first_chunk.checked_add(chunk)?.checked_sub(1)? as usize;
+  let chunk_offset = match (&stbl.stco, &stbl.co64) {
+    (Some(stco), _) => *stco.entries.get(chunk_index)? as u64,
+    (None, Some(co64)) => *co64.entries.get(chunk_index)?,
+    (None, None) => return None,
+  };
+  let preceding = (sample_id - first_sample) as usize;
+  let sizes_start = first_sample.checked_sub(1)? as usize;
+  let preceding_size = match stbl.stsz.sample_size {
+    0 => stbl
+      .stsz
+      .sample_sizes
+      .get(sizes_start..sizes_start + preceding)?
+      .iter()
+      .map(|&size| size as u64)
+      .sum(),
+    size => size as u64 * preceding as u64,
+  };
+  Some(chunk_offset + preceding_size)
+}
+
+/// Size of an MP4 sample in bytes, from the sample size table or the track
+/// fragments. None if the sample doesn't exist.
+#[cfg(feature = "std")]
+fn sample_size(track: &mp4::Mp4Track, sample_id: u32) -> Option<u32> {
+  if track.trafs.is_empty() {
+    let stsz = &track.trak.mdia.minf.stbl.stsz;
+    return match stsz.sample_size {
+      0 => stsz
+        .sample_sizes
+        .get(sample_id.checked_sub(1)? as usize)
+        .copied(),
+      size => Some(size),
+    };
+  }
+  let mut index = sample_id.checked_sub(1)?;
+  for trun in track.trafs.iter().filter_map(|traf| traf.trun.as_ref()) {
+    if index < trun.sample_count {
+      return trun.sample_sizes.get(index as usize).copied();
+    }
+    index -= trun.sample_count;
+  }
+  None
+}
+
+/// Find the next ADTS frame, reading more input into `bytes` as needed.
+/// Anything that isn't a valid ADTS header is skipped and counted in
+/// `skipped_bytes`. Unless `synced` is set, meaning the header was already
+/// checked, a frame also has to be followed by a valid header so that sync
+/// words in garbage data aren't mistaken for frames. Returns the frame
+/// length, or None at EOF.
+#[cfg(feature = "std")]
+fn next_adts_frame<R: Read>(
+  reader: &mut R,
+  bytes: &mut Vec<u8>,
+  synced: &mut bool,
+  skipped_bytes: &mut usize,
+  read_chunk_size: usize,
+  live: Option<live::Live>,
+) -> Result<Option<usize>, Error> {
+  let mut eof = false;
+  loop {
+    // whether what's at the start of `bytes` can be a frame once there's more
+    // input
+    let incomplete = match adts::parse_header(bytes) {
+      Ok(header) => {
+        let frame_length = header.frame_length;
+        match bytes.get(frame_length..) {
+          Some(next) if next.len() >= adts::ADTS_HEADER_LENGTH || eof => {
+            let next_valid = adts::parse_header(next).is_ok();
+            if next_valid || next.is_empty() || *synced {
+              *synced = next_valid;
+              return Ok(Some(frame_length));
+            }
+            false
+          }
+          _ => true,
+        }
+      }
+      Err(_) => bytes.len() < adts::ADTS_HEADER_LENGTH,
+    };
+    if !incomplete {
+      // skip to the next sync word
+      let next_sync = bytes[1..]
+        .windows(2)
+        .position(|pair| pair[0] == 0xFF && pair[1] & 0xF6 == 0xF0);
+      let skip = next_sync.map_or(bytes.len() - 1, |position| position + 1);
+      bytes.drain(..skip);
+      *skipped_bytes += skip;
+      *synced = false;
+    } else if eof {
+      return Ok(None); // drop an incomplete frame at the end
+    } else {
+      let old_len = bytes.len();
+      bytes.resize(old_len + read_chunk_size, 0);
+      let bytes_read = live::read(reader, &mut bytes[old_len..], live)?;
+      bytes.truncate(old_len + bytes_read);
+      eof = bytes_read == 0;
+    }
+  }
+}
+
+#[cfg(feature = "std")]
 impl<R> Iterator for Decoder<R>
 where
   R: Read + Seek,
@@ -233,30 +2093,88 @@ where
   /// to the iter_error error.
   fn next(&mut self) -> Option<i16> {
     match self.decode_next_sample() {
-      Ok(sample) => sample,
+      Ok(Some(sample)) => Some(sample),
+      Ok(None) => {
+        if let Some(completion) = &self.completion {
+          completion.complete(Completed::EndOfStream);
+        }
+        None
+      }
       Err(err) => {
-        self.iter_error = Some(err);
-        return None;
+        self.fail(err);
+        None
       }
     }
   }
+  /// Exact when `remaining_samples` is known, so `collect` can allocate
+  /// once. AAC input usually isn't, since that needs a scan of the file.
+  fn size_hint(&self) -> (usize, Option<usize>) {
+    match self.remaining_samples().map(usize::try_from) {
+      Some(Ok(remaining)) => (remaining, Some(remaining)),
+      Some(Err(_)) => (usize::MAX, None),
+      None => (
+        self
+          .current_pcm
+          .len()
+          .saturating_sub(self.current_pcm_index),
+        None,
+      ),
+    }
+  }
 }
 
-#[cfg(feature = "rodio")]
-impl<R> rodio::Source for Decoder<R>
-where
-  R: Read + Seek,
-{
-  fn current_frame_len(&self) -> Option<usize> {
-    return self.current_frame_len();
-  }
-  fn channels(&self) -> u16 {
-    return self.channels();
-  }
-  fn sample_rate(&self) -> u32 {
-    return self.sample_rate();
-  }
-  fn total_duration(&self) -> Option<Duration> {
-    return self.total_duration();
+#[cfg(all(test, feature = "std"))]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn fragment_sample_times() {
+    // a fragment with per-sample trun durations, one with a tfhd default
+    // duration and one that falls back to the trex default duration
+    let mut track = mp4::Mp4Track {
+      trak: Default::default(),
+      trafs: vec![Default::default(); 3],
+      default_sample_duration: 1024,
+    };
+    for (traf, sample_count) in track.trafs.iter_mut().zip([3, 2, 2]) {
+      let trun = traf.trun.get_or_insert_with(Default::default);
+      trun.sample_count = sample_count;
+    }
+    let trun = track.trafs[0].trun.as_mut().unwrap();
+    trun.sample_durations = vec![2048, 1024, 512];
+    track.trafs[1].tfhd.default_sample_duration = Some(4096);
+    track.trak.mdia.mdhd.timescale = 1000;
+
+    assert_eq!(track_duration(&track), 3584 + 8192 + 2048);
+    let starts = fragment_starts(&track);
+    assert_eq!(starts, [(1, 0), (4, 3584), (6, 11776)]);
+    // with and without a sidx box, which has subsegments of one and two
+    // fragments
+    let index = boxes::SegmentIndex {
+      reference_id: 0,
+      timescale: 1000,
+      subsegments: vec![(0, 100), (3584, 200)],
+      moofs: vec![100, 200, 300],
+    };
+    let segments = segment_starts(&track, Some(index));
+    assert_eq!(segments, [(0, 0), (3584, 1)]);
+    for segments in [&[][..], &segments] {
+      let sample_ids: Vec<u64> = [0, 2047, 2048, 3072, 3583, 3584, 11776, 13823, 13824]
+        .iter()
+        .map(|&ticks| sample_at(&track, &starts, segments, ticks))
+        .collect();
+      assert_eq!(sample_ids, [1, 1, 2, 3, 3, 4, 6, 7, 8]);
+    }
+    let sample = mp4::Mp4Sample {
+      start_time: 0,
+      duration: 1024,
+      rendering_offset: 0,
+      is_sync: true,
+      bytes: Default::default(),
+    };
+    let times: Vec<u64> = (1..=7)
+      .map(|sample_id| sample_start_time(&track, &starts, &sample, sample_id))
+      .collect();
+    assert_eq!(times, [0, 2048, 3072, 3584, 7680, 11776, 12800]);
   }
 }
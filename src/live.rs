@@ -0,0 +1,107 @@
+//! Reading from live streams, where running out of input usually means more
+//! hasn't arrived yet
+use crate::Error;
+use std::io::{ErrorKind, Read};
+use std::thread;
+use std::time::Duration;
+
+/// Default time to wait between reads when a live stream has no input
+pub const DEFAULT_LIVE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// Default time without input before a live stream is considered ended
+pub const DEFAULT_LIVE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Retry settings for live input, from `DecoderBuilder::live`
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Live {
+  pub poll_interval: Duration,
+  pub timeout: Duration,
+}
+
+/// Errors that can go away by reading again, like a connection hiccup
+fn is_transient(kind: ErrorKind) -> bool {
+  matches!(
+    kind,
+    ErrorKind::WouldBlock
+      | ErrorKind::TimedOut
+      | ErrorKind::UnexpectedEof
+      | ErrorKind::ConnectionReset
+      | ErrorKind::ConnectionAborted
+  )
+}
+
+/// Read into `buf`. For live input, reads that return nothing or fail with a
+/// transient error are retried until the timeout. Returns 0 at the end of the
+/// input, which for live input is when the timeout passes and a read returned
+/// nothing.
+pub(crate) fn read<R: Read>(
+  reader: &mut R,
+  buf: &mut [u8],
+  live: Option<Live>,
+) -> Result<usize, Error> {
+  let mut waited = Duration::ZERO;
+  let mut ended = false;
+  loop {
+    let error = match reader.read(buf) {
+      Ok(0) => None,
+      Ok(bytes_read) => return Ok(bytes_read),
+      Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+      Err(err) => Some(err),
+    };
+    ended |= error.is_none();
+    let live = match live {
+      Some(live) if waited < live.timeout => live,
+      Some(_) if ended => return Ok(0),
+      _ => return error.map_or(Ok(0), |err| Err(Error::ReaderError(err))),
+    };
+    if let Some(err) = error.filter(|err| !is_transient(err.kind())) {
+      return Err(Error::ReaderError(err));
+    }
+    thread::sleep(live.poll_interval);
+    waited += live.poll_interval;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Reader without more input, which fails with a transient error and, if
+  /// `returns_nothing`, returns nothing every other read
+  struct Ended {
+    returns_nothing: bool,
+    reads: usize,
+  }
+
+  impl Read for Ended {
+    fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+      self.reads += 1;
+      match self.returns_nothing && self.reads & 1 == 0 {
+        true => Ok(0),
+        false => Err(ErrorKind::TimedOut.into()),
+      }
+    }
+  }
+
+  #[test]
+  fn live_end() {
+    let live = Some(Live {
+      poll_interval: Duration::from_millis(1),
+      timeout: Duration::from_millis(10),
+    });
+    let mut buf = [0; 16];
+    // an error on the last retry doesn't hide that a read returned nothing
+    for reads in [0, 1] {
+      let mut reader = Ended {
+        returns_nothing: true,
+        reads,
+      };
+      assert!(matches!(read(&mut reader, &mut buf, live), Ok(0)));
+    }
+    let mut reader = Ended {
+      returns_nothing: false,
+      reads: 0,
+    };
+    let result = read(&mut reader, &mut buf, live);
+    assert!(matches!(result, Err(Error::ReaderError(_))));
+  }
+}
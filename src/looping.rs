@@ -0,0 +1,65 @@
+//! Looping playback, such as for game music and ambience
+use crate::{Decoder, Error};
+use std::io::{Read, Seek};
+use std::time::Duration;
+
+/// Decoder that plays its input, then loops from a loop start forever. When
+/// the track has gapless info, the encoder delay and padding are trimmed so
+/// there's no gap at the wrap-around.
+pub struct LoopingDecoder<R>
+where
+  R: Read + Seek,
+{
+  decoder: Decoder<R>,
+  /// Loop start, relative to the start of the decoder's output
+  loop_start: Duration,
+}
+
+impl<R> LoopingDecoder<R>
+where
+  R: Read + Seek,
+{
+  /// Loop `decoder` from `loop_start`, which is relative to the start of the
+  /// audio after the encoder delay
+  pub fn new(mut decoder: Decoder<R>, loop_start: Duration) -> Result<Self, Error> {
+    let start = decoder
+      .gapless_range()
+      .map_or(Duration::ZERO, |(start, _)| start);
+    decoder.trim_gapless()?;
+    Ok(LoopingDecoder {
+      decoder,
+      loop_start: start + loop_start,
+    })
+  }
+  pub fn decoder(&self) -> &Decoder<R> {
+    &self.decoder
+  }
+  pub fn into_decoder(self) -> Decoder<R> {
+    self.decoder
+  }
+}
+
+impl<R> Iterator for LoopingDecoder<R>
+where
+  R: Read + Seek,
+{
+  type Item = i16;
+  /// Returns the next sample, seeking to the loop start at the end of the
+  /// stream. Errors are added to the decoder's `iter_error` and end the
+  /// iterator, as does a loop without any samples.
+  fn next(&mut self) -> Option<i16> {
+    match self.decoder.decode_next_sample() {
+      Ok(None) if self.decoder.iter_error.is_none() => {}
+      Ok(sample) => return sample,
+      Err(err) => {
+        self.decoder.fail(err);
+        return None;
+      }
+    }
+    if let Err(err) = self.decoder.seek(self.loop_start) {
+      self.decoder.fail(err);
+      return None;
+    }
+    self.decoder.next()
+  }
+}
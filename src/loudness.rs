@@ -0,0 +1,332 @@
+//! EBU R128 loudness analysis, following ITU-R BS.1770-4 and EBU Tech 3342.
+//! Enabled with the `loudness` feature.
+use crate::{ChannelType, Decoder, Error, Frame};
+use std::f64::consts::PI;
+use std::io::{Read, Seek};
+
+/// Blocks are built from 100ms parts, so they can overlap
+const PARTS_PER_SECOND: u32 = 10;
+/// Momentary blocks used for the integrated loudness, in parts
+const MOMENTARY_PARTS: usize = 4;
+/// Short-term blocks used for the loudness range, in parts
+const SHORT_TERM_PARTS: usize = 30;
+const ABSOLUTE_GATE: f64 = -70.0;
+const INTEGRATED_RELATIVE_GATE: f64 = -10.0;
+const RANGE_RELATIVE_GATE: f64 = -20.0;
+/// True peak oversampling factor
+const OVERSAMPLING: usize = 4;
+/// Taps of the oversampling filter per phase
+const TAPS_PER_PHASE: usize = 12;
+
+/// Loudness of a stream
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Loudness {
+  /// Integrated loudness in LUFS. Negative infinity for silence.
+  pub integrated: f64,
+  /// Loudness range in LU
+  pub range: f64,
+  /// True peak in dBTP
+  pub true_peak: f64,
+}
+
+/// Decode the rest of a stream and measure its loudness
+pub fn measure<R: Read + Seek>(decoder: &mut Decoder<R>) -> Result<Loudness, Error> {
+  let mut meter = Meter::default();
+  while meter.push_next_frame(decoder)?.is_some() {}
+  Ok(meter.finish())
+}
+
+/// Biquad filter in direct form II transposed
+#[derive(Clone, Copy, Debug, Default)]
+struct Biquad {
+  b: [f64; 3],
+  a: [f64; 3],
+  z: [f64; 2],
+}
+
+impl Biquad {
+  fn process(&mut self, x: f64) -> f64 {
+    let y = self.b[0] * x + self.z[0];
+    self.z[0] = self.b[1] * x - self.a[1] * y + self.z[1];
+    self.z[1] = self.b[2] * x - self.a[2] * y;
+    y
+  }
+}
+
+/// K-weighting filter, which is a high shelf followed by a high pass
+fn k_weighting(sample_rate: u32) -> [Biquad; 2] {
+  let fs = sample_rate as f64;
+
+  let (f0, gain, q) = (1681.974450955533, 3.999843853973347, 0.7071752369554196);
+  let k = (PI * f0 / fs).tan();
+  let vh = 10f64.powf(gain / 20.0);
+  let vb = vh.powf(0.4996667741545416);
+  let a0 = 1.0 + k / q + k * k;
+  let shelf = Biquad {
+    b: [
+      (vh + vb * k / q + k * k) / a0,
+      2.0 * (k * k - vh) / a0,
+      (vh - vb * k / q + k * k) / a0,
+    ],
+    a: [1.0, 2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    z: [0.0; 2],
+  };
+
+  let (f0, q) = (38.13547087602444, 0.5003270373238773);
+  let k = (PI * f0 / fs).tan();
+  let a0 = 1.0 + k / q + k * k;
+  let high_pass = Biquad {
+    b: [1.0, -2.0, 1.0],
+    a: [1.0, 2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    z: [0.0; 2],
+  };
+  [shelf, high_pass]
+}
+
+/// Weight of a channel, where the LFE channel is ignored and surround
+/// channels are boosted. Channels of an unknown type count like front
+/// channels.
+fn channel_weight(channel_type: Option<ChannelType>) -> f64 {
+  match channel_type {
+    Some(ChannelType::Lfe) => 0.0,
+    Some(ChannelType::Side) | Some(ChannelType::Back) => 1.41,
+    _ => 1.0,
+  }
+}
+
+/// Coefficients of the oversampling filter, a windowed sinc with its cutoff at
+/// the original Nyquist frequency
+fn oversampling_filter() -> Vec<f64> {
+  let len = OVERSAMPLING * TAPS_PER_PHASE + 1;
+  let center = (len / 2) as f64;
+  (0..len)
+    .map(|i| {
+      let x = (i as f64 - center) / OVERSAMPLING as f64;
+      let sinc = match x == 0.0 {
+        true => 1.0,
+        false => (PI * x).sin() / (PI * x),
+      };
+      let window = 0.5 - 0.5 * (2.0 * PI * i as f64 / (len - 1) as f64).cos();
+      sinc * window
+    })
+    .collect()
+}
+
+/// Filter state of one channel
+struct Channel {
+  filters: [Biquad; 2],
+  weight: f64,
+  /// Recent input samples for oversampling, newest first
+  history: Vec<f64>,
+}
+
+/// Loudness meter that PCM is pushed to
+#[derive(Default)]
+pub(crate) struct Meter {
+  format: Option<(u16, u32)>,
+  channels: Vec<Channel>,
+  oversampling_filter: Vec<f64>,
+  /// Weighted energy and sample count of the current part
+  part_energy: f64,
+  part_samples: u32,
+  part_len: u32,
+  /// Mean weighted energy of each finished part
+  parts: Vec<f64>,
+  peak: f64,
+}
+
+impl Meter {
+  /// Decode the next frame and push it, weighting its channels by their
+  /// types from `Decoder::stream_info`, which follow the decoder's channel
+  /// order. Returns the frame, or None when finished.
+  pub fn push_next_frame<'a, R: Read + Seek>(
+    &mut self,
+    decoder: &'a mut Decoder<R>,
+  ) -> Result<Option<Frame<'a>>, Error> {
+    // decode the frame first, so the stream info describes it
+    decoder.decode_ahead();
+    let channel_types = decoder.stream_info().channel_types;
+    let frame = decoder.next_frame()?;
+    if let Some(frame) = &frame {
+      self.push(
+        frame.samples,
+        frame.channels,
+        frame.sample_rate,
+        &channel_types,
+      );
+    }
+    Ok(frame)
+  }
+  /// Push interleaved samples. `channel_types` are ignored unless there's one
+  /// for each channel, like when a downmix matrix changed the channels. A
+  /// format change resets the filters.
+  pub fn push(
+    &mut self,
+    samples: &[i16],
+    channels: u16,
+    sample_rate: u32,
+    channel_types: &[ChannelType],
+  ) {
+    if channels == 0 || sample_rate == 0 {
+      return;
+    }
+    let known_types = channel_types.len() == channels as usize;
+    let weights = (0..channels as usize)
+      .map(|channel| channel_weight(channel_types.get(channel).copied().filter(|_| known_types)));
+    let same_weights = weights
+      .clone()
+      .eq(self.channels.iter().map(|channel| channel.weight));
+    if self.format != Some((channels, sample_rate)) || !same_weights {
+      self.format = Some((channels, sample_rate));
+      self.channels = weights
+        .map(|weight| Channel {
+          filters: k_weighting(sample_rate),
+          weight,
+          history: vec![0.0; TAPS_PER_PHASE + 1],
+        })
+        .collect();
+      self.part_len = (sample_rate / PARTS_PER_SECOND).max(1);
+    }
+    if self.oversampling_filter.is_empty() {
+      self.oversampling_filter = oversampling_filter();
+    }
+    for frame in samples.chunks_exact(channels as usize) {
+      for (channel, &sample) in self.channels.iter_mut().zip(frame) {
+        let x = sample as f64 / 32768.0;
+        let mut y = x;
+        for filter in &mut channel.filters {
+          y = filter.process(y);
+        }
+        self.part_energy += channel.weight * y * y;
+
+        channel.history.rotate_right(1);
+        channel.history[0] = x;
+        for phase in 0..OVERSAMPLING {
+          let taps = self.oversampling_filter[phase..]
+            .iter()
+            .step_by(OVERSAMPLING);
+          let value: f64 = taps.zip(&channel.history).map(|(h, x)| h * x).sum();
+          self.peak = self.peak.max(value.abs());
+        }
+        self.peak = self.peak.max(x.abs());
+      }
+      self.part_samples += 1;
+      if self.part_samples == self.part_len {
+        self.parts.push(self.part_energy / self.part_samples as f64);
+        self.part_energy = 0.0;
+        self.part_samples = 0;
+      }
+    }
+  }
+  pub fn finish(&self) -> Loudness {
+    let momentary = block_energies(&self.parts, MOMENTARY_PARTS);
+    let integrated = match gate(&momentary, INTEGRATED_RELATIVE_GATE) {
+      gated if gated.is_empty() => f64::NEG_INFINITY,
+      gated => loudness(mean(&gated)),
+    };
+    let short_term = block_energies(&self.parts, SHORT_TERM_PARTS);
+    let mut gated: Vec<f64> = gate(&short_term, RANGE_RELATIVE_GATE)
+      .into_iter()
+      .map(loudness)
+      .collect();
+    gated.sort_by(|a, b| a.total_cmp(b));
+    let percentile = |p: f64| gated[((gated.len() - 1) as f64 * p).round() as usize];
+    let range = match gated.is_empty() {
+      true => 0.0,
+      false => percentile(0.95) - percentile(0.10),
+    };
+    Loudness {
+      integrated,
+      range,
+      true_peak: 20.0 * self.peak.log10(),
+    }
+  }
+}
+
+fn loudness(energy: f64) -> f64 {
+  -0.691 + 10.0 * energy.log10()
+}
+
+fn mean(values: &[f64]) -> f64 {
+  values.iter().sum::<f64>() / values.len() as f64
+}
+
+/// Energy of every block of `len` parts, with a hop of one part
+fn block_energies(parts: &[f64], len: usize) -> Vec<f64> {
+  parts.windows(len).map(mean).collect()
+}
+
+/// Blocks above the absolute gate, and above the relative gate below their
+/// mean loudness
+fn gate(blocks: &[f64], relative_gate: f64) -> Vec<f64> {
+  let blocks: Vec<f64> = blocks
+    .iter()
+    .copied()
+    .filter(|&energy| loudness(energy) > ABSOLUTE_GATE)
+    .collect();
+  if blocks.is_empty() {
+    return blocks;
+  }
+  let threshold = loudness(mean(&blocks)) + relative_gate;
+  blocks
+    .into_iter()
+    .filter(|&energy| loudness(energy) > threshold)
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Stereo sine wave at a level in dBFS
+  fn sine(level: f64, frequency: f64, seconds: f64, sample_rate: u32) -> Vec<i16> {
+    let amplitude = 32768.0 * 10f64.powf(level / 20.0);
+    let len = (seconds * sample_rate as f64) as usize;
+    (0..len)
+      .flat_map(|i| {
+        let t = i as f64 / sample_rate as f64;
+        let sample = (amplitude * (2.0 * PI * frequency * t).sin()).round() as i16;
+        [sample, sample]
+      })
+      .collect()
+  }
+
+  #[test]
+  fn sine_loudness() {
+    // EBU Tech 3341 test 1: a stereo 1 kHz sine at -23 dBFS is -23 LUFS
+    let mut meter = Meter::default();
+    meter.push(&sine(-23.0, 1000.0, 20.0, 48000), 2, 48000, &[]);
+    let loudness = meter.finish();
+    assert!((loudness.integrated + 23.0).abs() < 0.1, "{:?}", loudness);
+    assert!(loudness.range < 0.1);
+    assert!((loudness.true_peak + 23.0).abs() < 0.1);
+
+    // EBU Tech 3342 test 1: 20 seconds each at -20 and -30 LUFS is 10 LU
+    let mut meter = Meter::default();
+    meter.push(&sine(-20.0, 1000.0, 20.0, 44100), 2, 44100, &[]);
+    meter.push(&sine(-30.0, 1000.0, 20.0, 44100), 2, 44100, &[]);
+    let loudness = meter.finish();
+    assert!((loudness.range - 10.0).abs() < 0.1, "{:?}", loudness);
+
+    let mut meter = Meter::default();
+    meter.push(&[0; 48000], 1, 48000, &[]);
+    assert_eq!(meter.finish().integrated, f64::NEG_INFINITY);
+  }
+
+  #[test]
+  fn true_peak() {
+    // a sine at a quarter of the sample rate, sampled between its peaks
+    let amplitude = 16384.0;
+    let samples: Vec<i16> = (0..48000)
+      .map(|i| {
+        let phase = PI / 2.0 * i as f64 + PI / 4.0;
+        (amplitude * phase.sin()).round() as i16
+      })
+      .collect();
+    let mut meter = Meter::default();
+    meter.push(&samples, 1, 48000, &[]);
+    let true_peak = meter.finish().true_peak;
+    let expected = 20.0 * (amplitude / 32768.0).log10();
+    assert!((true_peak - expected).abs() < 0.2, "{}", true_peak);
+  }
+}
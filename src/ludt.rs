@@ -0,0 +1,91 @@
+//! Loudness boxes (ludt) of ISO/IEC 14496-12, which hold the track and album
+//! loudness measured by the encoder
+use crate::boxes::{self, find};
+use crate::syntax::BitReader;
+
+/// Method definition of program loudness, from ISO/IEC 23003-4
+const PROGRAM_LOUDNESS: u8 = 1;
+/// Method definition of anchor (dialog) loudness
+const ANCHOR_LOUDNESS: u8 = 2;
+
+/// Loudness measurements from a tlou or alou box
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct StoredLoudness {
+  /// Integrated loudness of the whole program in LUFS
+  pub program_loudness: Option<f32>,
+  /// Loudness of the dialog in LUFS
+  pub anchor_loudness: Option<f32>,
+  /// Sample peak in dBFS
+  pub sample_peak: Option<f32>,
+  /// True peak in dBTP
+  pub true_peak: Option<f32>,
+}
+
+/// Track and album loudness from the ludt box of an MP4 file
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LoudnessInfo {
+  /// From the tlou box
+  pub track: Option<StoredLoudness>,
+  /// From the alou box
+  pub album: Option<StoredLoudness>,
+}
+
+impl LoudnessInfo {
+  /// Read the ludt box of a track, falling back to the one of the movie. None
+  /// if there's neither.
+  pub(crate) fn from_moov(moov: &[u8], track_id: u32) -> Option<Self> {
+    let track_ludt = boxes::trak(moov, track_id)
+      .and_then(|trak| find(trak, b"udta"))
+      .and_then(|udta| find(udta, b"ludt"));
+    let ludt = track_ludt.or_else(|| find(find(moov, b"udta")?, b"ludt"))?;
+    Some(LoudnessInfo {
+      track: loudness(ludt, b"tlou"),
+      album: loudness(ludt, b"alou"),
+    })
+  }
+}
+
+/// Loudness from the boxes of a type. A ludt box can have one for each
+/// downmix and DRC set, so the one without either is preferred.
+fn loudness(ludt: &[u8], box_type: &[u8; 4]) -> Option<StoredLoudness> {
+  let parsed: Vec<_> = boxes::boxes(ludt)
+    .filter(|(t, _)| t == box_type)
+    .filter_map(|(_, data)| parse(data))
+    .collect();
+  let default = parsed.iter().find(|(default, _)| *default);
+  default.or(parsed.first()).map(|(_, loudness)| *loudness)
+}
+
+/// Parse a LoudnessBaseBox. Also returns whether it's for the default downmix
+/// and DRC set.
+fn parse(data: &[u8]) -> Option<(bool, StoredLoudness)> {
+  let version = *data.first()?;
+  // version and flags, then the EQ set ID in version 1
+  let start = if version >= 1 { 5 } else { 4 };
+  let mut reader = BitReader::new(data.get(start..)?);
+  reader.skip(3)?;
+  let downmix_id = reader.read(7)?;
+  let drc_set_id = reader.read(6)?;
+  let peak = |value: u32| (value != 0).then(|| 20.0 - value as f32 / 32.0);
+  let sample_peak = peak(reader.read(12)?);
+  let true_peak = peak(reader.read(12)?);
+  // measurement system and reliability of the true peak
+  reader.skip(8)?;
+  let mut loudness = StoredLoudness {
+    sample_peak,
+    true_peak,
+    ..StoredLoudness::default()
+  };
+  for _ in 0..reader.read(8)? {
+    let method = reader.read(8)? as u8;
+    let value = -57.75 + reader.read(8)? as f32 / 4.0;
+    // measurement system and reliability
+    reader.skip(8)?;
+    match method {
+      PROGRAM_LOUDNESS => loudness.program_loudness = Some(value),
+      ANCHOR_LOUDNESS => loudness.anchor_loudness = Some(value),
+      _ => {}
+    }
+  }
+  Some((downmix_id == 0 && drc_set_id == 0, loudness))
+}
@@ -0,0 +1,273 @@
+//! Tags from the iTunes metadata atoms of MP4 files
+use crate::boxes::{self, find, item_data};
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+
+/// Genres of ID3v1, which the gnre atom refers to by number
+const ID3V1_GENRES: [&str; 80] = [
+  "Blues",
+  "Classic Rock",
+  "Country",
+  "Dance",
+  "Disco",
+  "Funk",
+  "Grunge",
+  "Hip-Hop",
+  "Jazz",
+  "Metal",
+  "New Age",
+  "Oldies",
+  "Other",
+  "Pop",
+  "R&B",
+  "Rap",
+  "Reggae",
+  "Rock",
+  "Techno",
+  "Industrial",
+  "Alternative",
+  "Ska",
+  "Death Metal",
+  "Pranks",
+  "Soundtrack",
+  "Euro-Techno",
+  "Ambient",
+  "Trip-Hop",
+  "Vocal",
+  "Jazz+Funk",
+  "Fusion",
+  "Trance",
+  "Classical",
+  "Instrumental",
+  "Acid",
+  "House",
+  "Game",
+  "Sound Clip",
+  "Gospel",
+  "Noise",
+  "AlternRock",
+  "Bass",
+  "Soul",
+  "Punk",
+  "Space",
+  "Meditative",
+  "Instrumental Pop",
+  "Instrumental Rock",
+  "Ethnic",
+  "Gothic",
+  "Darkwave",
+  "Techno-Industrial",
+  "Electronic",
+  "Pop-Folk",
+  "Eurodance",
+  "Dream",
+  "Southern Rock",
+  "Comedy",
+  "Cult",
+  "Gangsta",
+  "Top 40",
+  "Christian Rap",
+  "Pop/Funk",
+  "Jungle",
+  "Native American",
+  "Cabaret",
+  "New Wave",
+  "Psychadelic",
+  "Rave",
+  "Showtunes",
+  "Trailer",
+  "Lo-Fi",
+  "Tribal",
+  "Acid Punk",
+  "Acid Jazz",
+  "Polka",
+  "Retro",
+  "Musical",
+  "Rock & Roll",
+  "Hard Rock",
+];
+
+/// Content rating of the rtng atom
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rating {
+  Clean,
+  Explicit,
+}
+
+/// Kind of media of the stik atom
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MediaKind {
+  Music,
+  Audiobook,
+  MusicVideo,
+  Movie,
+  TvShow,
+  Booklet,
+  Ringtone,
+  Podcast,
+  ITunesU,
+  /// Any other stik value
+  Other(u8),
+}
+
+impl MediaKind {
+  fn from_stik(stik: u8) -> Self {
+    match stik {
+      1 => MediaKind::Music,
+      2 => MediaKind::Audiobook,
+      6 => MediaKind::MusicVideo,
+      9 => MediaKind::Movie,
+      10 => MediaKind::TvShow,
+      11 => MediaKind::Booklet,
+      14 => MediaKind::Ringtone,
+      21 => MediaKind::Podcast,
+      23 => MediaKind::ITunesU,
+      stik => MediaKind::Other(stik),
+    }
+  }
+}
+
+/// Tags of an MP4 file
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Metadata {
+  pub title: Option<String>,
+  pub artist: Option<String>,
+  pub album: Option<String>,
+  pub album_artist: Option<String>,
+  pub composer: Option<String>,
+  pub comment: Option<String>,
+  /// Sort names, like "Beatles" for "The Beatles", to sort by instead of
+  /// the names above
+  pub title_sort: Option<String>,
+  pub artist_sort: Option<String>,
+  pub album_sort: Option<String>,
+  pub album_artist_sort: Option<String>,
+  pub composer_sort: Option<String>,
+  /// Embedded lyrics. iTunes separates lines with "\r".
+  pub lyrics: Option<String>,
+  /// From the ©gen atom, or the ID3v1 genre number of the gnre atom
+  pub genre: Option<String>,
+  /// Release date as it's written, usually a year or an ISO 8601 date
+  pub date: Option<String>,
+  pub track_number: Option<u16>,
+  pub track_total: Option<u16>,
+  pub disc_number: Option<u16>,
+  pub disc_total: Option<u16>,
+  /// Beats per minute
+  pub bpm: Option<u16>,
+  /// Whether the track is part of a compilation
+  pub compilation: bool,
+  /// None if the track isn't rated
+  pub rating: Option<Rating>,
+  pub media_kind: Option<MediaKind>,
+  /// Freeform "----" tags with text values by name, like iTunSMPB, iTunNORM
+  /// and tags from rippers. Their mean, usually "com.apple.iTunes", isn't
+  /// part of the key.
+  pub freeform: BTreeMap<String, String>,
+}
+
+impl Metadata {
+  /// Read the tags from the contents of the moov box. None if it has no
+  /// tags.
+  pub(crate) fn from_moov(moov: &[u8]) -> Option<Self> {
+    let mut metadata = Metadata::default();
+    for (box_type, item) in boxes::boxes(boxes::ilst(moov)?) {
+      let text = || text(item);
+      match &box_type {
+        b"\xa9nam" => metadata.title = text(),
+        b"\xa9ART" => metadata.artist = text(),
+        b"\xa9alb" => metadata.album = text(),
+        b"aART" => metadata.album_artist = text(),
+        b"\xa9wrt" => metadata.composer = text(),
+        b"\xa9cmt" => metadata.comment = text(),
+        b"sonm" => metadata.title_sort = text(),
+        b"soar" => metadata.artist_sort = text(),
+        b"soal" => metadata.album_sort = text(),
+        b"soaa" => metadata.album_artist_sort = text(),
+        b"soco" => metadata.composer_sort = text(),
+        b"\xa9lyr" => metadata.lyrics = text(),
+        b"\xa9gen" => metadata.genre = text(),
+        b"gnre" if metadata.genre.is_none() => metadata.genre = id3v1_genre(item),
+        b"\xa9day" => metadata.date = text(),
+        b"trkn" => (metadata.track_number, metadata.track_total) = number_pair(item),
+        b"disk" => (metadata.disc_number, metadata.disc_total) = number_pair(item),
+        b"cpil" => metadata.compilation = integer(item).is_some_and(|cpil| cpil != 0),
+        b"rtng" => metadata.rating = integer(item).and_then(rating),
+        b"stik" => metadata.media_kind = integer(item).map(|stik| MediaKind::from_stik(stik as u8)),
+        b"tmpo" => metadata.bpm = integer(item).and_then(|bpm| bpm.try_into().ok()),
+        b"----" => {
+          if let Some((name, value)) = freeform(item) {
+            metadata.freeform.entry(name).or_insert(value);
+          }
+        }
+        _ => {}
+      }
+    }
+    Some(metadata)
+  }
+  /// Get a freeform tag, comparing names case-insensitively
+  pub fn freeform_tag(&self, name: &str) -> Option<&str> {
+    let mut tags = self.freeform.iter();
+    let tag = tags.find(|(tag_name, _)| tag_name.eq_ignore_ascii_case(name));
+    tag.map(|(_, value)| value.as_str())
+  }
+  /// Year from the start of the date
+  pub fn year(&self) -> Option<i32> {
+    let date = self.date.as_deref()?.trim();
+    let end = date
+      .find(|c: char| !c.is_ascii_digit())
+      .unwrap_or(date.len());
+    date[..end].parse().ok()
+  }
+}
+
+/// Value of an item with UTF-8 text
+fn text(item: &[u8]) -> Option<String> {
+  let (_, value) = item_data(item)?;
+  String::from_utf8(value.to_vec()).ok()
+}
+
+/// Name and text value of a freeform item
+fn freeform(item: &[u8]) -> Option<(String, String)> {
+  let name = find(item, b"name")?.get(4..)?;
+  let name = String::from_utf8(name.to_vec()).ok()?;
+  Some((name, text(item)?))
+}
+
+/// Genre of a gnre item, which is the ID3v1 genre number plus one
+fn id3v1_genre(item: &[u8]) -> Option<String> {
+  let (_, value) = item_data(item)?;
+  let number = u16::from_be_bytes(value.get(0..2)?.try_into().ok()?);
+  let genre = ID3V1_GENRES.get((number as usize).checked_sub(1)?)?;
+  Some(genre.to_string())
+}
+
+/// Value of an item with a big-endian integer, which can be 1, 2, 4 or 8
+/// bytes long
+fn integer(item: &[u8]) -> Option<u64> {
+  let (_, value) = item_data(item)?;
+  match value.len() {
+    1 | 2 | 4 | 8 => Some(value.iter().fold(0, |n, &byte| n << 8 | byte as u64)),
+    _ => None,
+  }
+}
+
+/// Rating of an rtng value. 1 and 4 both mean explicit.
+fn rating(rtng: u64) -> Option<Rating> {
+  match rtng {
+    1 | 4 => Some(Rating::Explicit),
+    2 => Some(Rating::Clean),
+    _ => None,
+  }
+}
+
+/// Number and total of a trkn or disk item, which are 16-bit integers after 2
+/// bytes of padding. 0 means there isn't one.
+fn number_pair(item: &[u8]) -> (Option<u16>, Option<u16>) {
+  let value = item_data(item).map_or(&[][..], |(_, value)| value);
+  let number = |offset: usize| {
+    let bytes = value.get(offset..offset + 2)?;
+    Some(u16::from_be_bytes([bytes[0], bytes[1]])).filter(|&number| number != 0)
+  };
+  (number(2), number(4))
+}
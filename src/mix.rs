@@ -0,0 +1,59 @@
+//! Custom channel mixing
+use crate::gain::Quantizer;
+use crate::{Error, MAX_CHANNELS};
+
+/// Coefficients for mixing frames with `inputs` channels to `outputs`
+/// channels, used instead of fdk-aac's downmix. Set it with
+/// `DecoderBuilder::downmix_matrix`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DownmixMatrix {
+  inputs: u16,
+  outputs: u16,
+  coefficients: Vec<f32>,
+}
+
+impl DownmixMatrix {
+  /// Create from a row of `inputs` coefficients for each output channel, so
+  /// `coefficients[output * inputs + input]` is how much of the input
+  /// channel goes into the output channel. Channels are in the decoder's
+  /// channel order.
+  pub fn new(inputs: u16, outputs: u16, coefficients: Vec<f32>) -> Result<Self, Error> {
+    if inputs == 0 || inputs > MAX_CHANNELS || outputs == 0 || outputs > MAX_CHANNELS {
+      return Err(Error::InvalidConfig(
+        "downmix matrix channels must be from 1 to MAX_CHANNELS",
+      ));
+    }
+    if coefficients.len() != inputs as usize * outputs as usize {
+      return Err(Error::InvalidConfig(
+        "downmix matrix needs inputs * outputs coefficients",
+      ));
+    }
+    Ok(DownmixMatrix {
+      inputs,
+      outputs,
+      coefficients,
+    })
+  }
+  pub fn inputs(&self) -> u16 {
+    self.inputs
+  }
+  pub fn outputs(&self) -> u16 {
+    self.outputs
+  }
+  /// Mix interleaved samples with `inputs` channels in place
+  pub(crate) fn apply(&self, samples: &mut Vec<i16>, quantizer: &mut Quantizer) {
+    let (inputs, outputs) = (self.inputs as usize, self.outputs as usize);
+    let mut mixed = Vec::with_capacity(samples.len() / inputs * outputs);
+    for frame in samples.chunks_exact(inputs) {
+      for row in self.coefficients.chunks_exact(inputs) {
+        let sample: f32 = frame
+          .iter()
+          .zip(row)
+          .map(|(&sample, &coefficient)| sample as f32 * coefficient)
+          .sum();
+        mixed.push(quantizer.quantize(sample));
+      }
+    }
+    *samples = mixed;
+  }
+}
@@ -0,0 +1,130 @@
+//! MP3 decoder for MP4 tracks with MP3 samples, with the same interface as
+//! the AAC decoder
+use fdk_aac::dec::{DecoderError, StreamInfo};
+use fdk_aac_sys as sys;
+use std::mem;
+use symphonia_bundle_mp3::MpaDecoder;
+use symphonia_core::audio::{AudioBufferRef, Signal};
+use symphonia_core::codecs::{CodecParameters, Decoder, DecoderOptions, CODEC_TYPE_MP3};
+use symphonia_core::formats::Packet;
+
+pub(crate) struct Mp3Decoder {
+  decoder: MpaDecoder,
+  /// objectTypeIndication of the track
+  object_type: u8,
+  /// MP3 frame that has been filled but not decoded
+  frame: Vec<u8>,
+  output_channels: Option<u16>,
+  /// Stream info of the last decoded frame, in fdk-aac's format
+  stream_info: StreamInfo,
+}
+
+// The stream info's channel pointers are always null
+unsafe impl Send for Mp3Decoder {}
+unsafe impl Sync for Mp3Decoder {}
+
+impl Mp3Decoder {
+  pub fn new(object_type: u8) -> Result<Self, DecoderError> {
+    let mut params = CodecParameters::new();
+    params.for_codec(CODEC_TYPE_MP3);
+    let decoder = MpaDecoder::try_new(&params, &DecoderOptions::default());
+    Ok(Mp3Decoder {
+      decoder: decoder.or(Err(DecoderError::UNSUPPORTED_FORMAT))?,
+      object_type,
+      frame: Vec::new(),
+      output_channels: None,
+      // all fields are numbers or pointers, so zeroes are valid
+      stream_info: unsafe { mem::zeroed() },
+    })
+  }
+  pub fn object_type(&self) -> u8 {
+    self.object_type
+  }
+  /// Mix the output to mono or stereo
+  pub fn set_output_channels(&mut self, channels: u16) -> Result<(), DecoderError> {
+    if !(1..=2).contains(&channels) {
+      return Err(DecoderError::SET_PARAM_FAIL);
+    }
+    self.output_channels = Some(channels);
+    Ok(())
+  }
+  /// Discard buffered input, such as before seeking. The bit reservoir is
+  /// kept, like the AAC decoder's overlap.
+  pub fn clear_buffer(&mut self) -> Result<(), DecoderError> {
+    self.frame.clear();
+    Ok(())
+  }
+  /// Take an MP3 frame to decode. Returns the number of bytes taken, which is
+  /// 0 until the previous frame is decoded.
+  pub fn fill(&mut self, data: &[u8]) -> Result<usize, DecoderError> {
+    if !self.frame.is_empty() {
+      return Ok(0);
+    }
+    self.frame.extend_from_slice(data);
+    Ok(data.len())
+  }
+  pub fn decode_frame(&mut self, pcm: &mut [i16]) -> Result<(), DecoderError> {
+    if self.frame.is_empty() {
+      return Err(DecoderError::NOT_ENOUGH_BITS);
+    }
+    let frame_len = self.frame.len();
+    let packet = Packet::new_from_slice(0, 0, 0, &self.frame);
+    self.frame.clear();
+    let buffer = match self.decoder.decode(&packet) {
+      Ok(AudioBufferRef::F32(buffer)) => buffer,
+      // output silence in place of the frame, like fdk-aac's concealment
+      _ => {
+        let len = self.decoded_frame_size().min(pcm.len());
+        pcm[..len].fill(0);
+        return Err(DecoderError::PARSE_ERROR);
+      }
+    };
+    let frame_channels = buffer.spec().channels.count();
+    if frame_channels == 0 {
+      return Err(DecoderError::PARSE_ERROR);
+    }
+    let channels = self.output_channels.map_or(frame_channels, |c| c as usize);
+    let frames = buffer.frames();
+    let pcm = pcm
+      .get_mut(..frames * channels)
+      .ok_or(DecoderError::OUTPUT_BUFFER_TOO_SMALL)?;
+    let left = buffer.chan(0);
+    let right = buffer.chan(frame_channels.min(2) - 1);
+    for (i, samples) in pcm.chunks_exact_mut(channels).enumerate() {
+      match samples {
+        [sample] => *sample = quantize((left[i] + right[i]) / 2.0),
+        [l, r] => {
+          *l = quantize(left[i]);
+          *r = quantize(right[i]);
+        }
+        _ => {}
+      }
+    }
+    let sample_rate = buffer.spec().rate as i32;
+    let info = &mut self.stream_info;
+    info.sampleRate = sample_rate;
+    info.frameSize = frames as i32;
+    info.numChannels = channels as i32;
+    info.aacSampleRate = sample_rate;
+    info.aacSamplesPerFrame = frames as i32;
+    info.aacNumChannels = frame_channels as i32;
+    info.aot = sys::AUDIO_OBJECT_TYPE_AOT_MP3ONMP4_L3;
+    info.profile = -1;
+    info.bitRate = (frame_len * 8 * sample_rate as usize / frames.max(1)) as i32;
+    Ok(())
+  }
+  /// Number of samples in the last decoded frame, across all channels
+  pub fn decoded_frame_size(&self) -> usize {
+    let stream_info = self.stream_info();
+    stream_info.numChannels as usize * stream_info.frameSize as usize
+  }
+  pub fn stream_info(&self) -> &StreamInfo {
+    &self.stream_info
+  }
+}
+
+fn quantize(sample: f32) -> i16 {
+  (sample * 32768.0)
+    .round()
+    .clamp(i16::MIN as f32, i16::MAX as f32) as i16
+}
@@ -0,0 +1,87 @@
+//! Two-pass normalization, which measures a stream and then decodes it with a
+//! gain that brings it to a target level. Enabled with the `loudness`
+//! feature.
+use crate::loudness::Meter;
+use crate::{Decoder, DecoderBuilder, Error};
+use std::io::{Read, Seek};
+
+/// Level to normalize to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Target {
+  /// Integrated loudness in LUFS, like -14.0 for streaming services. The gain
+  /// isn't limited by the peak, so the output can clip unless the decoder
+  /// uses `DecoderBuilder::soft_limiter`.
+  Loudness(f64),
+  /// Sample peak in dBFS, like 0.0
+  Peak(f64),
+  /// True peak in dBTP, like -1.0
+  TruePeak(f64),
+}
+
+/// Levels measured in the first pass, and the gain that was applied
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Analysis {
+  /// Integrated loudness in LUFS. Negative infinity for silence.
+  pub integrated: f64,
+  /// Sample peak in dBFS
+  pub peak: f64,
+  /// True peak in dBTP
+  pub true_peak: f64,
+  /// Gain in dB that was added. 0 for silence, which can't be normalized.
+  pub gain_db: f64,
+}
+
+impl Target {
+  fn gain_db(&self, integrated: f64, peak: f64, true_peak: f64) -> f64 {
+    let gain = match *self {
+      Target::Loudness(target) => target - integrated,
+      Target::Peak(target) => target - peak,
+      Target::TruePeak(target) => target - true_peak,
+    };
+    match gain.is_finite() {
+      true => gain,
+      false => 0.0,
+    }
+  }
+}
+
+/// Decode the whole stream to measure it, then seek back to the start and
+/// return the decoder with the gain that normalizes it to `target`. Detects
+/// whether the input is MPEG-4 or AAC like `DecoderBuilder::open`.
+pub fn analyze_then_decode<R: Read + Seek>(
+  reader: R,
+  size: u64,
+  target: Target,
+) -> Result<(Decoder<R>, Analysis), Error> {
+  let mut decoder = DecoderBuilder::new().open(reader, size)?;
+  let analysis = analyze(&mut decoder, target)?;
+  Ok((decoder, analysis))
+}
+
+/// Measure the rest of the stream, then seek back to the start of the stream
+/// or range from `Decoder::decode_range`, and adjust the decoder's gain to
+/// normalize it to `target`. The gain is on top of any gain that was already
+/// set, since that's included in the measurement.
+pub fn analyze<R: Read + Seek>(
+  decoder: &mut Decoder<R>,
+  target: Target,
+) -> Result<Analysis, Error> {
+  let mut meter = Meter::default();
+  let mut peak: u16 = 0;
+  while let Some(frame) = meter.push_next_frame(decoder)? {
+    let frame_peak = frame.samples.iter().map(|sample| sample.unsigned_abs());
+    peak = peak.max(frame_peak.max().unwrap_or(0));
+  }
+  let loudness = meter.finish();
+  let peak = 20.0 * (peak as f64 / 32768.0).log10();
+  let gain_db = target.gain_db(loudness.integrated, peak, loudness.true_peak);
+  decoder.seek(decoder.range_start)?;
+  let gain = decoder.gain_handle().gain();
+  decoder.set_gain(gain * 10f32.powf(gain_db as f32 / 20.0));
+  Ok(Analysis {
+    integrated: loudness.integrated,
+    peak,
+    true_peak: loudness.true_peak,
+    gain_db,
+  })
+}
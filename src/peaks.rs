@@ -0,0 +1,60 @@
+//! Waveform peaks for drawing waveforms
+use crate::time::duration_to_ticks;
+use crate::{Decoder, Error};
+use std::io::{Read, Seek};
+
+/// Levels of a part of the waveform, across all channels
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Bucket {
+  pub min: i16,
+  pub max: i16,
+  /// RMS, where 1.0 is full scale
+  pub rms: f32,
+}
+
+/// Decode the rest of a stream and split it into `buckets` equally long
+/// parts, returning the levels of each. Decodes in one pass without keeping
+/// the PCM, but AAC input is scanned for frame headers first to find its
+/// duration.
+pub fn generate<R: Read + Seek>(
+  decoder: &mut Decoder<R>,
+  buckets: usize,
+) -> Result<Vec<Bucket>, Error> {
+  let end = match decoder.end {
+    Some(end) => end,
+    None => decoder.stream_duration()?,
+  };
+  let mut sums = vec![(0.0, 0u64); buckets];
+  let mut output = vec![Bucket::default(); buckets];
+  if buckets == 0 {
+    return Ok(output);
+  }
+  let mut start = None;
+  while let Some(frame) = decoder.next_frame()? {
+    let start = *start.get_or_insert(frame.pts);
+    // count in samples of the frame's sample rate, so rounding doesn't move
+    // samples between buckets
+    let span = duration_to_ticks(end.saturating_sub(start), frame.sample_rate).max(1);
+    let frame_offset = duration_to_ticks(frame.pts.saturating_sub(start), frame.sample_rate);
+    let channels = frame.channels.max(1) as usize;
+    for (i, samples) in frame.samples.chunks_exact(channels).enumerate() {
+      let offset = (frame_offset + i as u64) as u128;
+      // samples past the end go in the last bucket
+      let index = ((offset * buckets as u128 / span as u128) as usize).min(buckets - 1);
+      let bucket = &mut output[index];
+      let (sum_squares, count) = &mut sums[index];
+      for &sample in samples {
+        bucket.min = bucket.min.min(sample);
+        bucket.max = bucket.max.max(sample);
+        *sum_squares += sample as f64 * sample as f64;
+        *count += 1;
+      }
+    }
+  }
+  for (bucket, (sum_squares, count)) in output.iter_mut().zip(sums) {
+    if count > 0 {
+      bucket.rms = ((sum_squares / count as f64).sqrt() / 32768.0) as f32;
+    }
+  }
+  Ok(output)
+}
@@ -0,0 +1,88 @@
+//! Reader fed with chunks of bytes from another thread, for input that's
+//! pushed rather than read, like WebSocket messages
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::sync::mpsc;
+
+/// Create a pipe that holds up to `capacity` chunks. Writing blocks while it's
+/// full. The reader reaches the end once every writer is dropped.
+pub fn pipe(capacity: usize) -> (PipeWriter, PipeReader) {
+  let (sender, receiver) = mpsc::sync_channel(capacity);
+  let reader = PipeReader {
+    receiver,
+    chunk: Vec::new(),
+    chunk_index: 0,
+    position: 0,
+  };
+  (PipeWriter { sender }, reader)
+}
+
+/// Sending end of a `pipe`. Clone it to push from multiple places.
+#[derive(Clone, Debug)]
+pub struct PipeWriter {
+  sender: mpsc::SyncSender<Vec<u8>>,
+}
+
+impl PipeWriter {
+  /// Push a chunk without copying it. Fails if the reader is gone.
+  pub fn send(&self, chunk: Vec<u8>) -> io::Result<()> {
+    let result = self.sender.send(chunk);
+    result.map_err(|_| io::Error::new(ErrorKind::BrokenPipe, "PipeReader was dropped"))
+  }
+}
+
+impl Write for PipeWriter {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    if !buf.is_empty() {
+      self.send(buf.to_vec())?;
+    }
+    Ok(buf.len())
+  }
+  fn flush(&mut self) -> io::Result<()> {
+    Ok(())
+  }
+}
+
+/// Receiving end of a `pipe`, for decoding with `Decoder::new_aac`. Reads
+/// block until there's data. It can't seek, so the format can't be detected
+/// and MPEG-4 input isn't supported.
+#[derive(Debug)]
+pub struct PipeReader {
+  receiver: mpsc::Receiver<Vec<u8>>,
+  chunk: Vec<u8>,
+  /// Position in `chunk` of the next byte to read
+  chunk_index: usize,
+  position: u64,
+}
+
+impl Read for PipeReader {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    while self.chunk_index == self.chunk.len() {
+      match self.receiver.recv() {
+        Ok(chunk) => {
+          self.chunk = chunk;
+          self.chunk_index = 0;
+        }
+        Err(_) => return Ok(0), // every writer is gone
+      }
+    }
+    let mut rest = &self.chunk[self.chunk_index..];
+    let bytes_read = rest.read(buf)?;
+    self.chunk_index += bytes_read;
+    self.position += bytes_read as u64;
+    Ok(bytes_read)
+  }
+}
+
+impl Seek for PipeReader {
+  /// Only supports getting the current position
+  fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+    match pos {
+      SeekFrom::Current(0) => Ok(self.position),
+      SeekFrom::Start(position) if position == self.position => Ok(self.position),
+      _ => Err(io::Error::new(
+        ErrorKind::Unsupported,
+        "PipeReader can't seek",
+      )),
+    }
+  }
+}
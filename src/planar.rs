@@ -0,0 +1,75 @@
+//! Planar output, with the samples of each channel in a separate slice
+use crate::{Decoder, Error, Frame, FrameFlags};
+use std::io::{Read, Seek};
+use std::slice;
+use std::time::Duration;
+
+/// Decoded frame with each channel's samples in a separate slice, from
+/// `Decoder::next_frame_planar`
+pub struct PlanarFrame<'a> {
+  /// Presentation timestamp of the first sample
+  pub pts: Duration,
+  pub channels: u16,
+  pub sample_rate: u32,
+  /// Samples of the first channel, then the second, and so on
+  pub samples: &'a [i16],
+  pub flags: FrameFlags,
+}
+
+impl<'a> PlanarFrame<'a> {
+  /// Number of samples in each channel
+  pub fn len(&self) -> usize {
+    self.samples.len() / self.channels.max(1) as usize
+  }
+  pub fn is_empty(&self) -> bool {
+    self.samples.is_empty()
+  }
+  /// Samples of a channel. Panics if `index` isn't less than `channels`.
+  pub fn channel(&self, index: usize) -> &'a [i16] {
+    let len = self.len();
+    &self.samples[index * len..(index + 1) * len]
+  }
+  /// Slices of every channel, in order
+  pub fn planes(&self) -> slice::Chunks<'a, i16> {
+    self.samples.chunks(self.len().max(1))
+  }
+}
+
+impl<'a> Frame<'a> {
+  /// Write the samples to `buf` with the samples of each channel after each
+  /// other, instead of interleaved. `buf` is cleared first.
+  pub fn deinterleave_into(&self, buf: &mut Vec<i16>) {
+    let channels = self.channels.max(1) as usize;
+    buf.clear();
+    for channel in 0..channels {
+      let samples = self.samples.iter().skip(channel).step_by(channels);
+      buf.extend(samples);
+    }
+  }
+}
+
+impl<R> Decoder<R>
+where
+  R: Read + Seek,
+{
+  /// Like `next_frame`, with the samples of each channel in a separate slice,
+  /// like for DSP libraries that take planar buffers. The samples are written
+  /// to `buf`, which can be reused between frames.
+  pub fn next_frame_planar<'a>(
+    &mut self,
+    buf: &'a mut Vec<i16>,
+  ) -> Result<Option<PlanarFrame<'a>>, Error> {
+    let frame = match self.next_frame()? {
+      Some(frame) => frame,
+      None => return Ok(None),
+    };
+    frame.deinterleave_into(buf);
+    Ok(Some(PlanarFrame {
+      pts: frame.pts,
+      channels: frame.channels,
+      sample_rate: frame.sample_rate,
+      samples: buf,
+      flags: frame.flags,
+    }))
+  }
+}
@@ -0,0 +1,123 @@
+//! Playback on the default output device with cpal, for when rodio isn't
+//! needed
+use crate::{Decoder, DecoderBuilder, Error};
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use std::io::{Read, Seek};
+use std::path::Path;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+
+/// State shared with the audio callback
+#[derive(Default)]
+struct Shared {
+  finished: Mutex<bool>,
+  finished_changed: Condvar,
+  /// Error from decoding or from the output stream
+  error: Mutex<Option<Error>>,
+}
+
+/// Lock a mutex, ignoring poisoning since the state stays valid
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+  mutex.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+impl Shared {
+  fn finish(&self, error: Option<Error>) {
+    if let Some(error) = error {
+      lock(&self.error).get_or_insert(error);
+    }
+    *lock(&self.finished) = true;
+    self.finished_changed.notify_all();
+  }
+}
+
+/// Decoder playing on an output stream. The stream has the channels and
+/// sample rate of the first frame, so use `DecoderBuilder::fixed_channels`
+/// for input where they can change. Dropping the player stops playback.
+pub struct Player {
+  stream: cpal::Stream,
+  shared: Arc<Shared>,
+}
+
+impl Player {
+  /// Open an output stream on the default output device and start playing
+  /// `decoder` on it
+  pub fn new<R>(mut decoder: Decoder<R>) -> Result<Self, Error>
+  where
+    R: Read + Seek + Send + 'static,
+  {
+    let host = cpal::default_host();
+    let device = host
+      .default_output_device()
+      .ok_or_else(|| Error::PlaybackError("no output device".to_string()))?;
+    let config = cpal::StreamConfig {
+      channels: decoder.channels(),
+      sample_rate: cpal::SampleRate(decoder.sample_rate()),
+      buffer_size: cpal::BufferSize::Default,
+    };
+    let shared = Arc::new(Shared::default());
+    let callback_shared = shared.clone();
+    let error_shared = shared.clone();
+    let mut ended = false;
+    let stream = device
+      .build_output_stream(
+        &config,
+        move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
+          if ended {
+            // the last samples were handed to the device in the previous
+            // callback
+            callback_shared.finish(None);
+          }
+          for sample in data {
+            *sample = match decoder.decode_next_sample() {
+              Ok(Some(sample)) => sample as f32 / 32768.0,
+              Ok(None) => {
+                ended = true;
+                0.0
+              }
+              Err(err) => {
+                callback_shared.finish(Some(err));
+                0.0
+              }
+            };
+          }
+        },
+        move |err| error_shared.finish(Some(Error::PlaybackError(err.to_string()))),
+        None,
+      )
+      .map_err(|err| Error::PlaybackError(err.to_string()))?;
+    let player = Player { stream, shared };
+    player.start()?;
+    Ok(player)
+  }
+  /// Start or resume playback
+  pub fn start(&self) -> Result<(), Error> {
+    let result = self.stream.play();
+    result.map_err(|err| Error::PlaybackError(err.to_string()))
+  }
+  /// Pause playback. It continues from the same position with `start`.
+  pub fn stop(&self) -> Result<(), Error> {
+    let result = self.stream.pause();
+    result.map_err(|err| Error::PlaybackError(err.to_string()))
+  }
+  /// Whether the decoder has finished or failed
+  pub fn is_finished(&self) -> bool {
+    *lock(&self.shared.finished)
+  }
+  /// Block until the decoder has finished, then return the error that
+  /// stopped it, if any
+  pub fn wait(&self) -> Result<(), Error> {
+    let finished = lock(&self.shared.finished);
+    let finished_changed = &self.shared.finished_changed;
+    let result = finished_changed.wait_while(finished, |finished| !*finished);
+    drop(result.unwrap_or_else(|err| err.into_inner()));
+    lock(&self.shared.error).take().map_or(Ok(()), Err)
+  }
+}
+
+/// Play a file on the default output device and block until it ends. The
+/// format is detected from the contents. Returns the error if decoding or the
+/// output fails.
+pub fn play_file<P: AsRef<Path>>(path: P) -> Result<(), Error> {
+  let decoder = DecoderBuilder::new().fixed_channels().open_path(path)?;
+  Player::new(decoder)?.wait()
+}
@@ -0,0 +1,118 @@
+//! Gapless playback of multiple files through one decoder
+use crate::{Decoder, DecoderBuilder, Error};
+use std::collections::VecDeque;
+use std::io::{Read, Seek};
+
+/// Input file of a playlist
+pub enum Input<R> {
+  /// MPEG-4 input with its size in bytes
+  Mpeg4(R, u64),
+  /// AAC input in ADTS format
+  Aac(R),
+}
+
+/// Plays inputs back to back. The next input is opened when the current one
+/// starts, and the encoder delay and padding of each input are trimmed, so
+/// there are no gaps between them. Inputs that fail to open or decode are
+/// skipped, and their errors are added to `errors`.
+pub struct Playlist<R>
+where
+  R: Read + Seek,
+{
+  builder: DecoderBuilder,
+  inputs: VecDeque<Input<R>>,
+  /// Index of the next input to open
+  next_index: usize,
+  current: Option<(usize, Decoder<R>)>,
+  next: Option<(usize, Decoder<R>)>,
+  /// Errors with the index of the input they came from
+  pub errors: Vec<(usize, Error)>,
+}
+
+impl<R> Playlist<R>
+where
+  R: Read + Seek,
+{
+  pub fn new(inputs: Vec<Input<R>>) -> Self {
+    Self::with_builder(inputs, DecoderBuilder::new())
+  }
+  /// Create a playlist that opens its inputs with `builder`
+  pub fn with_builder(inputs: Vec<Input<R>>, builder: DecoderBuilder) -> Self {
+    let mut playlist = Playlist {
+      builder,
+      inputs: inputs.into(),
+      next_index: 0,
+      current: None,
+      next: None,
+      errors: Vec::new(),
+    };
+    playlist.next = playlist.open_next();
+    playlist.advance();
+    playlist
+  }
+  /// Index of the input that's playing, or None when finished
+  pub fn current_index(&self) -> Option<usize> {
+    self.current.as_ref().map(|(index, _)| *index)
+  }
+  /// Decoder of the input that's playing, or None when finished
+  pub fn current(&self) -> Option<&Decoder<R>> {
+    self.current.as_ref().map(|(_, decoder)| decoder)
+  }
+  fn open(&self, input: Input<R>) -> Result<Decoder<R>, Error> {
+    let mut decoder = match input {
+      Input::Mpeg4(reader, size) => self.builder.open_mpeg4(reader, size)?,
+      Input::Aac(reader) => self.builder.open_aac(reader)?,
+    };
+    decoder.trim_gapless()?;
+    Ok(decoder)
+  }
+  /// Open the next input that doesn't fail
+  fn open_next(&mut self) -> Option<(usize, Decoder<R>)> {
+    while let Some(input) = self.inputs.pop_front() {
+      let index = self.next_index;
+      self.next_index += 1;
+      match self.open(input) {
+        Ok(decoder) => return Some((index, decoder)),
+        Err(err) => self.errors.push((index, err)),
+      }
+    }
+    None
+  }
+  /// Switch to the next decoder, and open the one after it
+  fn advance(&mut self) {
+    if let Some((index, mut decoder)) = self.current.take() {
+      if let Some(err) = decoder.pending_error.take() {
+        self.errors.push((index, err));
+      }
+    }
+    self.current = self.next.take();
+    self.next = self.open_next();
+  }
+}
+
+impl<R> Iterator for Playlist<R>
+where
+  R: Read + Seek,
+{
+  type Item = i16;
+  fn next(&mut self) -> Option<i16> {
+    loop {
+      let (index, decoder) = self.current.as_mut()?;
+      match decoder.decode_next_sample() {
+        Ok(Some(sample)) => {
+          // switch as soon as the current input is used up, so the format
+          // reported to rodio is the next input's
+          if decoder.current_frame_len() == Some(0) {
+            self.advance();
+          }
+          return Some(sample);
+        }
+        Ok(None) => self.advance(),
+        Err(err) => {
+          self.errors.push((*index, err));
+          self.advance();
+        }
+      }
+    }
+  }
+}
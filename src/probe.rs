@@ -0,0 +1,278 @@
+//! Information about the audio tracks of a file without decoding it, such as
+//! for a track picker
+use crate::boxes::{self, find};
+use crate::{Decoder, DecoderBuilder, Error, Format};
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::time::Duration;
+
+/// Information about an audio track
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AudioInfo {
+  /// MP4 track ID, for `DecoderBuilder::track`. 0 for AAC input.
+  pub track_id: u32,
+  /// Codec string like "mp4a.40.2" for AAC-LC and "mp4a.40.5" for HE-AAC.
+  /// Other codecs have their sample entry type, like "ac-3".
+  pub codec: String,
+  /// ISO 639-2/T language code, like "eng". "und" if it's not specified.
+  pub language: String,
+  /// BCP 47 language tag like "en-US", from the extended language box
+  pub extended_language: Option<String>,
+  /// Whether the track is enabled in its track header. Players usually skip
+  /// disabled tracks.
+  pub enabled: bool,
+  /// Whether the track is the one to play by default, which is the first
+  /// enabled track of its alternate group
+  pub default: bool,
+  /// Tracks with the same alternate group are alternatives to each other,
+  /// like the same audio in different languages. 0 means it's not in a group.
+  pub alternate_group: u16,
+  pub channels: u16,
+  /// Output sample rate, which for HE-AAC is twice the core sample rate
+  pub sample_rate: u32,
+  /// Average bitrate in bits per second
+  pub bitrate: Option<u32>,
+  pub duration: Option<Duration>,
+  /// Whether the track can be decoded
+  pub decodable: bool,
+}
+
+/// Get info about every audio track of MPEG-4 input, or about the stream of
+/// AAC input. The format is detected from the contents. AAC tracks and AAC
+/// input have their first frame decoded to find the output format, and AAC
+/// input is scanned for its duration.
+pub fn probe<R: Read + Seek>(mut reader: R, size: u64) -> Result<Vec<AudioInfo>, Error> {
+  match Format::detect(&mut reader).map_err(Error::ReaderError)? {
+    Format::Mp4 => probe_mpeg4(reader, size),
+    Format::Aac => probe_aac(reader, size).map(|info| vec![info]),
+  }
+}
+
+/// Get info about the audio tracks of a file. See `probe`.
+pub fn probe_path<P: AsRef<Path>>(path: P) -> Result<Vec<AudioInfo>, Error> {
+  let file = File::open(path).map_err(Error::ReaderError)?;
+  let size = file.metadata().map_err(Error::ReaderError)?.len();
+  probe(BufReader::new(file), size)
+}
+
+/// Track IDs of each alternate group, for tracks that are in one. Only one
+/// track of a group should be played.
+pub fn alternate_groups(tracks: &[AudioInfo]) -> BTreeMap<u16, Vec<u32>> {
+  let mut groups: BTreeMap<u16, Vec<u32>> = BTreeMap::new();
+  for track in tracks.iter().filter(|track| track.alternate_group != 0) {
+    groups
+      .entry(track.alternate_group)
+      .or_default()
+      .push(track.track_id);
+  }
+  groups
+}
+
+/// The track a decoder uses when no track is chosen: the first enabled track
+/// that can be decoded, or the first one that can be decoded if none are
+/// enabled
+pub fn default_track(tracks: &[AudioInfo]) -> Option<&AudioInfo> {
+  let decodable = tracks.iter().filter(|track| track.decodable);
+  decodable.min_by_key(|track| (!track.enabled, track.track_id))
+}
+
+/// Whether MPEG-4 input has an audio track that's protected with DRM, like
+/// FairPlay from the iTunes Store, which can't be decoded. Only the moov box
+/// is read. AAC input is never protected.
+pub fn is_drm_protected<R: Read + Seek>(mut reader: R, size: u64) -> Result<bool, Error> {
+  if Format::detect(&mut reader).map_err(Error::ReaderError)? == Format::Aac {
+    return Ok(false);
+  }
+  let moov = boxes::read_moov(&mut reader, size).map_err(Error::ReaderError)?;
+  let moov = moov.ok_or(Error::FileHeaderError)?;
+  let mut entries = boxes::boxes(&moov)
+    .filter(|(box_type, _)| box_type == b"trak")
+    .filter_map(|(_, trak)| boxes::stsd(trak))
+    .flat_map(boxes::boxes);
+  // drms is FairPlay, and enca is any encrypted audio
+  Ok(entries.any(|(entry_type, _)| &entry_type == b"drms" || &entry_type == b"enca"))
+}
+
+fn probe_aac<R: Read + Seek>(mut reader: R, size: u64) -> Result<AudioInfo, Error> {
+  let stream_start = reader.stream_position().map_err(Error::ReaderError)?;
+  let mut decoder = DecoderBuilder::new().open_aac(reader)?;
+  let duration = decoder.stream_duration()?;
+  let bits = (size.saturating_sub(stream_start) * 8) as f64;
+  let bitrate = Some(bits / duration.as_secs_f64()).filter(|bitrate| bitrate.is_finite());
+  Ok(AudioInfo {
+    track_id: 0,
+    bitrate: bitrate.map(|bitrate| bitrate.round() as u32),
+    duration: Some(duration),
+    ..decoded_info(&decoder)
+  })
+}
+
+/// Info from a decoder that has decoded its first frame
+fn decoded_info<R: Read + Seek>(decoder: &Decoder<R>) -> AudioInfo {
+  AudioInfo {
+    track_id: decoder.track_id().unwrap_or(0),
+    codec: match decoder.mp3_object_type() {
+      Some(object_type) => format!("mp4a.{:02X}", object_type),
+      None => format!("mp4a.40.{}", decoder.object_type()),
+    },
+    language: "und".to_string(),
+    extended_language: None,
+    enabled: true,
+    default: true,
+    alternate_group: 0,
+    channels: decoder.channels(),
+    sample_rate: decoder.sample_rate(),
+    bitrate: None,
+    duration: None,
+    decodable: true,
+  }
+}
+
+fn probe_mpeg4<R: Read + Seek>(mut reader: R, size: u64) -> Result<Vec<AudioInfo>, Error> {
+  let start = reader.stream_position().map_err(Error::ReaderError)?;
+  let moov = boxes::read_moov(&mut reader, size).map_err(Error::ReaderError)?;
+  let moov = moov.ok_or(Error::FileHeaderError)?;
+  let mut tracks = Vec::new();
+  for (box_type, trak) in boxes::boxes(&moov) {
+    if &box_type != b"trak" {
+      continue;
+    }
+    let mut info = match track_info(trak) {
+      Some(info) => info,
+      None => continue, // not audio
+    };
+    if info.codec.starts_with("mp4a") {
+      reader
+        .seek(SeekFrom::Start(start))
+        .map_err(Error::ReaderError)?;
+      let builder = DecoderBuilder::new().track(info.track_id);
+      if let Ok(decoder) = builder.open_mpeg4(&mut reader, size) {
+        info = AudioInfo {
+          language: info.language,
+          extended_language: info.extended_language,
+          enabled: info.enabled,
+          alternate_group: info.alternate_group,
+          bitrate: info.bitrate,
+          duration: info.duration,
+          ..decoded_info(&decoder)
+        };
+      }
+    }
+    let group = info.alternate_group;
+    let earlier = |track: &AudioInfo| track.enabled && group != 0 && track.alternate_group == group;
+    info.default = info.enabled && !tracks.iter().any(earlier);
+    tracks.push(info);
+  }
+  reader
+    .seek(SeekFrom::Start(start))
+    .map_err(Error::ReaderError)?;
+  Ok(tracks)
+}
+
+/// Info about a track from the contents of its trak box. None if it's not an
+/// audio track.
+fn track_info(trak: &[u8]) -> Option<AudioInfo> {
+  let mdia = find(trak, b"mdia")?;
+  if find(mdia, b"hdlr")?.get(8..12)? != b"soun" {
+    return None;
+  }
+  let (box_type, entry) = boxes::boxes(boxes::stsd(trak)?).next()?;
+  let mut codec = String::from_utf8_lossy(&box_type).into_owned();
+  if &box_type == b"mp4a" {
+    let config = boxes::sample_entry_audio_specific_config(entry);
+    if let Some(object_type) = config.and_then(|config| config_object_type(&config)) {
+      codec = format!("mp4a.40.{}", object_type);
+    }
+  }
+  let mdhd = find(mdia, b"mdhd")?;
+  let (timescale, duration, language) = match mdhd.first()? {
+    1 => (
+      u32::from_be_bytes(mdhd.get(20..24)?.try_into().ok()?),
+      u64::from_be_bytes(mdhd.get(24..32)?.try_into().ok()?),
+      mdhd.get(32..34)?,
+    ),
+    _ => (
+      u32::from_be_bytes(mdhd.get(12..16)?.try_into().ok()?),
+      u32::from_be_bytes(mdhd.get(16..20)?.try_into().ok()?) as u64,
+      mdhd.get(20..22)?,
+    ),
+  };
+  // all 1s means unknown
+  let known = timescale > 0 && duration > 0 && duration != u32::MAX as u64 && duration != u64::MAX;
+  let duration = match known {
+    true => Some(crate::time::ticks_to_duration(duration, timescale)),
+    false => None,
+  };
+  let tkhd = find(trak, b"tkhd")?;
+  let flags = u32::from_be_bytes(tkhd.get(0..4)?.try_into().ok()?) & 0xff_ffff;
+  let group_offset = if *tkhd.first()? == 1 { 46 } else { 34 };
+  let alternate_group =
+    u16::from_be_bytes(tkhd.get(group_offset..group_offset + 2)?.try_into().ok()?);
+  // a null-terminated string after the full box header
+  let extended_language = find(mdia, b"elng").and_then(|elng| {
+    let tag = elng.get(4..)?.split(|&byte| byte == 0).next()?;
+    String::from_utf8(tag.to_vec())
+      .ok()
+      .filter(|tag| !tag.is_empty())
+  });
+  let bitrate = duration.and_then(|duration| {
+    let bits = total_sample_size(trak)? as f64 * 8.0;
+    Some((bits / duration.as_secs_f64()).round() as u32)
+  });
+  Some(AudioInfo {
+    track_id: boxes::track_id(trak)?,
+    codec,
+    language: language_code(u16::from_be_bytes(language.try_into().ok()?)),
+    extended_language,
+    enabled: flags & 1 != 0,
+    default: false,
+    alternate_group,
+    channels: u16::from_be_bytes(entry.get(16..18)?.try_into().ok()?),
+    sample_rate: u16::from_be_bytes(entry.get(24..26)?.try_into().ok()?) as u32,
+    bitrate,
+    duration,
+    decodable: false,
+  })
+}
+
+/// Audio object type from the start of an AudioSpecificConfig
+fn config_object_type(config: &[u8]) -> Option<u32> {
+  let mut reader = crate::syntax::BitReader::new(config);
+  match reader.read(5)? {
+    31 => Some(32 + reader.read(6)?),
+    object_type => Some(object_type),
+  }
+}
+
+/// Total size of the samples of a track, from its sample size table. None for
+/// fragmented files, where the samples are described elsewhere.
+fn total_sample_size(trak: &[u8]) -> Option<u64> {
+  let stbl = find(find(find(trak, b"mdia")?, b"minf")?, b"stbl")?;
+  let stsz = find(stbl, b"stsz")?;
+  let sample_size = u32::from_be_bytes(stsz.get(4..8)?.try_into().ok()?) as u64;
+  let sample_count = u32::from_be_bytes(stsz.get(8..12)?.try_into().ok()?) as u64;
+  if sample_count == 0 {
+    return None;
+  }
+  if sample_size != 0 {
+    return Some(sample_size * sample_count);
+  }
+  let sizes = stsz.get(12..)?.chunks_exact(4);
+  Some(
+    sizes
+      .map(|size| u32::from_be_bytes([size[0], size[1], size[2], size[3]]) as u64)
+      .sum(),
+  )
+}
+
+/// Decode a packed ISO 639-2/T language code from an mdhd box
+fn language_code(packed: u16) -> String {
+  let chars = [(packed >> 10) & 0x1f, (packed >> 5) & 0x1f, packed & 0x1f];
+  match chars.iter().all(|&c| (1..=26).contains(&c)) {
+    true => chars.iter().map(|&c| (c as u8 + 0x60) as char).collect(),
+    false => "und".to_string(),
+  }
+}
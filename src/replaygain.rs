@@ -0,0 +1,109 @@
+//! ReplayGain loudness normalization
+use crate::boxes;
+
+/// ReplayGain reference loudness, which is 5 dB above the EBU R128 reference
+/// of -23 LUFS
+const R128_OFFSET_DB: f32 = 5.0;
+
+/// Which gain to apply
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GainMode {
+  /// Normalize each track on its own
+  Track,
+  /// Keep the loudness differences between tracks of an album. Falls back to
+  /// the track gain when there's no album gain.
+  Album,
+}
+
+/// ReplayGain values from the tags of a file. Gains are in dB, and peaks are
+/// linear where 1.0 is full scale.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ReplayGain {
+  pub track_gain: Option<f32>,
+  pub track_peak: Option<f32>,
+  pub album_gain: Option<f32>,
+  pub album_peak: Option<f32>,
+}
+
+impl ReplayGain {
+  /// Read the REPLAYGAIN_* tags from the contents of the moov box, falling
+  /// back to the R128_* tags for gains. None if there are no tags.
+  pub(crate) fn from_moov(moov: &[u8]) -> Option<Self> {
+    let tag = |name| boxes::freeform_tag(moov, name);
+    let gain = |name, r128_name| {
+      tag(name)
+        .and_then(|value| parse_gain(&value))
+        .or_else(|| tag(r128_name).and_then(|value| parse_r128_gain(&value)))
+    };
+    let peak = |name| tag(name).and_then(|value| value.trim().parse().ok());
+    let replay_gain = ReplayGain {
+      track_gain: gain("replaygain_track_gain", "R128_TRACK_GAIN"),
+      track_peak: peak("replaygain_track_peak"),
+      album_gain: gain("replaygain_album_gain", "R128_ALBUM_GAIN"),
+      album_peak: peak("replaygain_album_peak"),
+    };
+    match replay_gain == ReplayGain::default() {
+      true => None,
+      false => Some(replay_gain),
+    }
+  }
+  /// Linear factor to scale samples by, lowered if needed so the peak doesn't
+  /// clip. None if there's no gain for the mode.
+  pub fn factor(&self, mode: GainMode) -> Option<f32> {
+    let (gain, peak) = match (mode, self.album_gain) {
+      (GainMode::Album, Some(album_gain)) => (album_gain, self.album_peak),
+      _ => (self.track_gain?, self.track_peak),
+    };
+    let factor = 10f32.powf(gain / 20.0);
+    match peak {
+      Some(peak) if peak > 0.0 => Some(factor.min(1.0 / peak)),
+      _ => Some(factor),
+    }
+  }
+}
+
+/// Parse a gain like "-6.48 dB"
+fn parse_gain(value: &str) -> Option<f32> {
+  let value = value.trim();
+  let number = match value.len().checked_sub(2) {
+    Some(end)
+      if value
+        .get(end..)
+        .is_some_and(|unit| unit.eq_ignore_ascii_case("db")) =>
+    {
+      &value[..end]
+    }
+    _ => value,
+  };
+  number.trim().parse().ok()
+}
+
+/// Parse an R128 gain, which is a Q7.8 number in dB relative to -23 LUFS
+fn parse_r128_gain(value: &str) -> Option<f32> {
+  let gain: i16 = value.trim().parse().ok()?;
+  Some(gain as f32 / 256.0 + R128_OFFSET_DB)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn gain_values() {
+    assert_eq!(parse_gain("-6.48 dB"), Some(-6.48));
+    assert_eq!(parse_gain(" +2.5dB"), Some(2.5));
+    assert_eq!(parse_gain("dB"), None);
+    assert_eq!(parse_gain("1€"), None);
+    assert_eq!(parse_r128_gain("-1280"), Some(0.0));
+
+    let replay_gain = ReplayGain {
+      track_gain: Some(-20.0),
+      track_peak: Some(0.5),
+      album_gain: Some(20.0),
+      album_peak: Some(0.5),
+    };
+    assert_eq!(replay_gain.factor(GainMode::Track), Some(0.1));
+    // limited by the peak
+    assert_eq!(replay_gain.factor(GainMode::Album), Some(2.0));
+  }
+}
@@ -0,0 +1,109 @@
+//! Reader that reconnects when reading fails, for input like HTTP streams
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom};
+use std::thread;
+use std::time::Duration;
+
+/// Default number of times to reconnect before returning the error
+pub const DEFAULT_MAX_RETRIES: u32 = 5;
+/// Default time to wait before the first reconnect. It doubles for every
+/// failed attempt.
+pub const DEFAULT_BACKOFF: Duration = Duration::from_millis(100);
+/// Default longest time to wait between reconnects
+pub const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+/// Reader adapter that re-establishes the reader when it fails. On an io
+/// error, `reconnect` is called with the current offset and should return a
+/// reader positioned there, such as a new HTTP request with a Range header.
+/// Decoding then resumes transparently.
+pub struct RetryReader<R, F> {
+  reader: R,
+  reconnect: F,
+  position: u64,
+  max_retries: u32,
+  backoff: Duration,
+  max_backoff: Duration,
+}
+
+impl<R, F> RetryReader<R, F>
+where
+  F: FnMut(u64) -> io::Result<R>,
+{
+  /// Wrap `reader`, which should be at offset 0
+  pub fn new(reader: R, reconnect: F) -> Self {
+    RetryReader {
+      reader,
+      reconnect,
+      position: 0,
+      max_retries: DEFAULT_MAX_RETRIES,
+      backoff: DEFAULT_BACKOFF,
+      max_backoff: DEFAULT_MAX_BACKOFF,
+    }
+  }
+  /// Number of times to reconnect in a row before returning the error
+  pub fn max_retries(mut self, retries: u32) -> Self {
+    self.max_retries = retries;
+    self
+  }
+  /// Time to wait before the first reconnect, and the most to wait between
+  /// later ones as the wait doubles
+  pub fn backoff(mut self, initial: Duration, max: Duration) -> Self {
+    self.backoff = initial;
+    self.max_backoff = max;
+    self
+  }
+  /// Offset of the next byte to read
+  pub fn position(&self) -> u64 {
+    self.position
+  }
+  pub fn get_ref(&self) -> &R {
+    &self.reader
+  }
+  pub fn into_inner(self) -> R {
+    self.reader
+  }
+  /// Run `op`, reconnecting and trying again when it fails
+  fn retry<T>(&mut self, mut op: impl FnMut(&mut R) -> io::Result<T>) -> io::Result<T> {
+    let mut retries = 0;
+    let mut backoff = self.backoff;
+    loop {
+      let err = match op(&mut self.reader) {
+        Ok(value) => return Ok(value),
+        Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+        Err(err) => err,
+      };
+      if retries >= self.max_retries {
+        return Err(err);
+      }
+      retries += 1;
+      thread::sleep(backoff);
+      backoff = backoff.saturating_mul(2).min(self.max_backoff);
+      // a failed reconnect counts as a retry
+      if let Ok(reader) = (self.reconnect)(self.position) {
+        self.reader = reader;
+      }
+    }
+  }
+}
+
+impl<R, F> Read for RetryReader<R, F>
+where
+  R: Read,
+  F: FnMut(u64) -> io::Result<R>,
+{
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    let bytes_read = self.retry(|reader| reader.read(buf))?;
+    self.position += bytes_read as u64;
+    Ok(bytes_read)
+  }
+}
+
+impl<R, F> Seek for RetryReader<R, F>
+where
+  R: Seek,
+  F: FnMut(u64) -> io::Result<R>,
+{
+  fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+    self.position = self.retry(|reader| reader.seek(pos))?;
+    Ok(self.position)
+  }
+}
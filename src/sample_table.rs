@@ -0,0 +1,105 @@
+//! Statistics about the sample table of an MP4 track
+use mp4::Mp4Track;
+
+/// Sizes and chunk layout of an MP4 track's samples, from
+/// `Decoder::sample_table_stats`. For fragmented files, the chunks are the
+/// track runs in the moof boxes.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SampleTableStats {
+  pub sample_count: u32,
+  /// Total size of the samples in bytes
+  pub total_size: u64,
+  /// Smallest sample in bytes
+  pub min_sample_size: u32,
+  /// Largest sample in bytes, which is how big a buffer for one sample needs
+  /// to be
+  pub max_sample_size: u32,
+  /// Average sample size in bytes
+  pub average_sample_size: f64,
+  /// Whether every sample has the same size, stored once in the stsz box
+  pub constant_sample_size: bool,
+  pub chunk_count: u32,
+  pub min_samples_per_chunk: u32,
+  pub max_samples_per_chunk: u32,
+  /// Whether chunk offsets are 64-bit, from a co64 box instead of stco
+  pub large_offsets: bool,
+  /// Whether the samples are described by moof boxes
+  pub fragmented: bool,
+}
+
+impl SampleTableStats {
+  pub(crate) fn from_track(track: &Mp4Track) -> Self {
+    let mut stats = SampleTableStats {
+      min_sample_size: u32::MAX,
+      min_samples_per_chunk: u32::MAX,
+      fragmented: !track.trafs.is_empty(),
+      ..Default::default()
+    };
+    if stats.fragmented {
+      for trun in track.trafs.iter().filter_map(|traf| traf.trun.as_ref()) {
+        stats.add_chunk(trun.sample_count);
+        trun
+          .sample_sizes
+          .iter()
+          .for_each(|&size| stats.add_sample(size));
+      }
+    } else {
+      let stbl = &track.trak.mdia.minf.stbl;
+      let stsz = &stbl.stsz;
+      stats.constant_sample_size = stsz.sample_size > 0;
+      match stsz.sample_size {
+        0 => stsz
+          .sample_sizes
+          .iter()
+          .for_each(|&size| stats.add_sample(size)),
+        size => {
+          stats.sample_count = stsz.sample_count;
+          stats.total_size = size as u64 * stsz.sample_count as u64;
+          stats.min_sample_size = size;
+          stats.max_sample_size = size;
+        }
+      }
+      stats.large_offsets = stbl.stco.is_none() && stbl.co64.is_some();
+      let chunk_count = match (&stbl.stco, &stbl.co64) {
+        (Some(stco), _) => stco.entries.len(),
+        (None, Some(co64)) => co64.entries.len(),
+        (None, None) => 0,
+      };
+      stats.chunk_count = chunk_count.min(u32::MAX as usize) as u32;
+      // each entry applies up to the first chunk of the next one
+      let entries = &stbl.stsc.entries;
+      for (i, entry) in entries.iter().enumerate() {
+        let last_chunk = match entries.get(i + 1) {
+          Some(next) => next.first_chunk.saturating_sub(1),
+          None => stats.chunk_count,
+        };
+        if entry.first_chunk <= last_chunk {
+          stats.add_chunk_size(entry.samples_per_chunk);
+        }
+      }
+    }
+    if stats.sample_count > 0 {
+      stats.average_sample_size = stats.total_size as f64 / stats.sample_count as f64;
+    } else {
+      stats.min_sample_size = 0;
+    }
+    if stats.chunk_count == 0 {
+      stats.min_samples_per_chunk = 0;
+    }
+    stats
+  }
+  fn add_sample(&mut self, size: u32) {
+    self.sample_count = self.sample_count.saturating_add(1);
+    self.total_size = self.total_size.saturating_add(size as u64);
+    self.min_sample_size = self.min_sample_size.min(size);
+    self.max_sample_size = self.max_sample_size.max(size);
+  }
+  fn add_chunk(&mut self, samples: u32) {
+    self.chunk_count = self.chunk_count.saturating_add(1);
+    self.add_chunk_size(samples);
+  }
+  fn add_chunk_size(&mut self, samples: u32) {
+    self.min_samples_per_chunk = self.min_samples_per_chunk.min(samples);
+    self.max_samples_per_chunk = self.max_samples_per_chunk.max(samples);
+  }
+}
@@ -0,0 +1,91 @@
+//! Detection of leading and trailing silence
+use crate::time::{duration_to_ticks, ticks_to_duration};
+use crate::{Decoder, Error};
+use std::io::{Read, Seek};
+use std::time::Duration;
+
+/// Length of the parts decoded while searching backwards for trailing silence
+const SCAN_WINDOW: Duration = Duration::from_secs(5);
+
+/// Where the audio starts and ends, excluding silence
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Bounds {
+  /// Time of the first sample above the threshold
+  pub start: Duration,
+  /// Time right after the last sample above the threshold
+  pub end: Duration,
+}
+
+/// Time of the first sample above `threshold` in the rest of the stream, and
+/// of the sample after the last one
+fn find_loud<R: Read + Seek>(
+  decoder: &mut Decoder<R>,
+  threshold: i32,
+  stop_at_first: bool,
+) -> Result<Option<(Duration, Duration)>, Error> {
+  let mut bounds = None;
+  while let Some(frame) = decoder.next_frame()? {
+    let channels = frame.channels.max(1) as usize;
+    let frame_start = duration_to_ticks(frame.pts, frame.sample_rate);
+    let sample_time = |i: usize| ticks_to_duration(frame_start + i as u64, frame.sample_rate);
+    let is_loud = |samples: &[i16]| samples.iter().any(|&s| (s as i32).abs() > threshold);
+    let first = match frame.samples.chunks_exact(channels).position(is_loud) {
+      Some(first) => first,
+      None => continue,
+    };
+    let last = frame
+      .samples
+      .chunks_exact(channels)
+      .rposition(is_loud)
+      .unwrap_or(first);
+    let start = bounds.map_or(sample_time(first), |(start, _)| start);
+    bounds = Some((start, sample_time(last + 1)));
+    if stop_at_first {
+      break;
+    }
+  }
+  Ok(bounds)
+}
+
+/// Find the leading and trailing silence of the rest of a stream, where
+/// silence is every sample of every channel being at most `threshold_db`
+/// dBFS. Returns None if it's all silent. Trailing silence is found by seeking
+/// backwards from the end, so most of the stream isn't decoded. The decoder is
+/// left at an unspecified position, so seek it before decoding further.
+pub fn detect<R: Read + Seek>(
+  decoder: &mut Decoder<R>,
+  threshold_db: f32,
+) -> Result<Option<Bounds>, Error> {
+  let threshold = (32768.0 * 10f64.powf(threshold_db as f64 / 20.0)) as i32;
+  // detect_bounds uses decode_range
+  let (original_start, original_end) = (decoder.range_start, decoder.end);
+  let result = detect_bounds(decoder, threshold);
+  decoder.range_start = original_start;
+  decoder.end = original_end;
+  result
+}
+
+fn detect_bounds<R: Read + Seek>(
+  decoder: &mut Decoder<R>,
+  threshold: i32,
+) -> Result<Option<Bounds>, Error> {
+  let start = match find_loud(decoder, threshold, true)? {
+    Some((start, _)) => start,
+    None => return Ok(None),
+  };
+  let mut window_end = match decoder.end {
+    Some(end) => end,
+    None => decoder.stream_duration()?,
+  };
+  while window_end > start {
+    let window_start = window_end.saturating_sub(SCAN_WINDOW).max(start);
+    decoder.decode_range(window_start, window_end)?;
+    if let Some((_, end)) = find_loud(decoder, threshold, false)? {
+      return Ok(Some(Bounds { start, end }));
+    }
+    window_end = window_start;
+  }
+  // the first loud sample is the only one
+  let end = start + ticks_to_duration(1, decoder.sample_rate());
+  Ok(Some(Bounds { start, end }))
+}
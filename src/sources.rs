@@ -0,0 +1,150 @@
+//! rodio `Source` impls for each supported rodio version. rodio 0.17 and
+//! 0.19 have the same trait with i16 samples, so the impls are shared. rodio
+//! 0.21 takes f32 samples, which `F32Samples` is for.
+use crate::{playlist::Playlist, ChannelSource, ControlledDecoder, Decoder, LoopingDecoder};
+use std::io::{Read, Seek};
+use std::time::Duration;
+
+macro_rules! impl_source {
+  ($rodio:ident) => {
+    impl<R> $rodio::Source for Decoder<R>
+    where
+      R: Read + Seek,
+    {
+      fn current_frame_len(&self) -> Option<usize> {
+        self.current_frame_len()
+      }
+      fn channels(&self) -> u16 {
+        self.channels()
+      }
+      fn sample_rate(&self) -> u32 {
+        self.sample_rate()
+      }
+      fn total_duration(&self) -> Option<Duration> {
+        self.total_duration()
+      }
+    }
+
+    impl<R> $rodio::Source for Box<Decoder<R>>
+    where
+      R: Read + Seek,
+    {
+      fn current_frame_len(&self) -> Option<usize> {
+        (**self).current_frame_len()
+      }
+      fn channels(&self) -> u16 {
+        (**self).channels()
+      }
+      fn sample_rate(&self) -> u32 {
+        (**self).sample_rate()
+      }
+      fn total_duration(&self) -> Option<Duration> {
+        (**self).total_duration()
+      }
+    }
+
+    impl<R> $rodio::Source for LoopingDecoder<R>
+    where
+      R: Read + Seek,
+    {
+      fn current_frame_len(&self) -> Option<usize> {
+        self.decoder().current_frame_len()
+      }
+      fn channels(&self) -> u16 {
+        self.decoder().channels()
+      }
+      fn sample_rate(&self) -> u32 {
+        self.decoder().sample_rate()
+      }
+      fn total_duration(&self) -> Option<Duration> {
+        None
+      }
+    }
+
+    impl<R> $rodio::Source for ControlledDecoder<R>
+    where
+      R: Read + Seek,
+    {
+      fn current_frame_len(&self) -> Option<usize> {
+        self.current_frame_len()
+      }
+      fn channels(&self) -> u16 {
+        self.decoder().channels()
+      }
+      fn sample_rate(&self) -> u32 {
+        self.decoder().sample_rate()
+      }
+      fn total_duration(&self) -> Option<Duration> {
+        None
+      }
+    }
+
+    impl<R> $rodio::Source for ChannelSource<R>
+    where
+      R: Read + Seek,
+    {
+      fn current_frame_len(&self) -> Option<usize> {
+        self.current_frame_len()
+      }
+      fn channels(&self) -> u16 {
+        1
+      }
+      fn sample_rate(&self) -> u32 {
+        self.sample_rate()
+      }
+      fn total_duration(&self) -> Option<Duration> {
+        None
+      }
+    }
+
+    impl<R> $rodio::Source for Playlist<R>
+    where
+      R: Read + Seek,
+    {
+      fn current_frame_len(&self) -> Option<usize> {
+        match self.current() {
+          Some(decoder) => decoder.current_frame_len(),
+          None => Some(0),
+        }
+      }
+      fn channels(&self) -> u16 {
+        self.current().map_or(2, |decoder| decoder.channels())
+      }
+      fn sample_rate(&self) -> u32 {
+        self
+          .current()
+          .map_or(44100, |decoder| decoder.sample_rate())
+      }
+      fn total_duration(&self) -> Option<Duration> {
+        None
+      }
+    }
+  };
+}
+
+#[cfg(feature = "rodio")]
+impl_source!(rodio);
+#[cfg(feature = "rodio-0_19")]
+impl_source!(rodio_0_19);
+
+/// Lets a decoder be used as a source without giving it away, so it can keep
+/// being controlled. `Iterator` is implemented for it by the standard library,
+/// and rodio 0.19 implements `Source` for `&mut` of any source.
+#[cfg(feature = "rodio")]
+impl<R> rodio::Source for &mut Decoder<R>
+where
+  R: Read + Seek,
+{
+  fn current_frame_len(&self) -> Option<usize> {
+    (**self).current_frame_len()
+  }
+  fn channels(&self) -> u16 {
+    (**self).channels()
+  }
+  fn sample_rate(&self) -> u32 {
+    (**self).sample_rate()
+  }
+  fn total_duration(&self) -> Option<Duration> {
+    (**self).total_duration()
+  }
+}
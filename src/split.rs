@@ -0,0 +1,126 @@
+//! Splitting a decoder into a mono source per channel
+use crate::{Decoder, Error};
+use std::collections::VecDeque;
+use std::io::{Read, Seek};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+struct Shared<R>
+where
+  R: Read + Seek,
+{
+  decoder: Decoder<R>,
+  /// Samples that have been decoded but not returned yet, per channel
+  queues: Vec<VecDeque<i16>>,
+  finished: bool,
+}
+
+impl<R> Shared<R>
+where
+  R: Read + Seek,
+{
+  /// Decode frames until `channel` has a sample or the stream ends
+  fn fill(&mut self, channel: usize) {
+    while self.queues[channel].is_empty() && !self.finished {
+      match self.decoder.next_frame() {
+        Ok(Some(frame)) => {
+          let channels = frame.channels.max(1) as usize;
+          for samples in frame.samples.chunks_exact(channels) {
+            for (i, queue) in self.queues.iter_mut().enumerate() {
+              queue.push_back(samples.get(i).copied().unwrap_or(0));
+            }
+          }
+        }
+        Ok(None) => self.finished = true,
+        Err(err) => {
+          self.decoder.fail(err);
+          self.finished = true;
+        }
+      }
+    }
+  }
+}
+
+/// One channel of a decoder, from `Decoder::split_channels`. The channels
+/// share one decoder, so each frame is only decoded once. Samples are kept
+/// until every channel has returned them, so channels that are consumed at
+/// different rates use memory for the difference.
+pub struct ChannelSource<R>
+where
+  R: Read + Seek,
+{
+  shared: Arc<Mutex<Shared<R>>>,
+  channel: usize,
+}
+
+impl<R> ChannelSource<R>
+where
+  R: Read + Seek,
+{
+  fn lock(&self) -> MutexGuard<'_, Shared<R>> {
+    // a panic while decoding doesn't leave the queues inconsistent
+    self.shared.lock().unwrap_or_else(|err| err.into_inner())
+  }
+  /// Index of the channel, in the decoder's channel order
+  pub fn channel(&self) -> usize {
+    self.channel
+  }
+  pub fn sample_rate(&self) -> u32 {
+    self.lock().decoder.sample_rate()
+  }
+  /// Number of samples until the end of the decoded frame, decoding the next
+  /// frame first if needed. 0 at the end of the stream.
+  pub fn current_frame_len(&self) -> Option<usize> {
+    let mut shared = self.lock();
+    shared.fill(self.channel);
+    Some(shared.queues[self.channel].len())
+  }
+  /// Seek every channel to `position`, discarding samples that haven't been
+  /// returned
+  pub fn seek(&self, position: Duration) -> Result<(), Error> {
+    let mut shared = self.lock();
+    shared.queues.iter_mut().for_each(VecDeque::clear);
+    shared.finished = false;
+    shared.decoder.seek(position)
+  }
+  /// Take the error that ended the stream, if any
+  pub fn take_error(&self) -> Option<Error> {
+    self.lock().decoder.iter_error.take()
+  }
+}
+
+impl<R> Iterator for ChannelSource<R>
+where
+  R: Read + Seek,
+{
+  type Item = i16;
+  fn next(&mut self) -> Option<i16> {
+    let mut shared = self.lock();
+    shared.fill(self.channel);
+    shared.queues[self.channel].pop_front()
+  }
+}
+
+impl<R> Decoder<R>
+where
+  R: Read + Seek,
+{
+  /// Split the decoder into a mono source for each of its channels, like for
+  /// processing channels independently. The channel count is the one when
+  /// it's split, so use `DecoderBuilder::fixed_channels` for streams where it
+  /// can change. Missing channels are silent.
+  pub fn split_channels(self) -> Vec<ChannelSource<R>> {
+    let channels = self.channels().max(1) as usize;
+    let shared = Arc::new(Mutex::new(Shared {
+      decoder: self,
+      queues: vec![VecDeque::new(); channels],
+      finished: false,
+    }));
+    (0..channels)
+      .map(|channel| ChannelSource {
+        shared: shared.clone(),
+        channel,
+      })
+      .collect()
+  }
+}
@@ -0,0 +1,142 @@
+//! Stream info reported by fdk-aac for the last decoded frame
+use fdk_aac_sys as sys;
+use std::convert::TryFrom;
+use std::slice;
+
+/// Order of interleaved channels in the output
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ChannelOrder {
+  /// Order used by WAV files and most audio APIs, with the front channels
+  /// first, then LFE, then surrounds. 5.1 is L, R, C, LFE, Ls, Rs.
+  #[default]
+  Wav,
+  /// Order of the channel elements in the AAC stream, with LFE last. 5.1 is
+  /// C, L, R, Ls, Rs, LFE.
+  Mpeg,
+}
+
+/// Position of an output channel
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChannelType {
+  Front,
+  Side,
+  Back,
+  Lfe,
+  FrontTop,
+  SideTop,
+  BackTop,
+  /// Any other fdk-aac channel type, like bottom channels
+  Other(u32),
+}
+
+impl ChannelType {
+  fn from_fdk(channel_type: sys::AUDIO_CHANNEL_TYPE) -> Self {
+    match channel_type {
+      sys::AUDIO_CHANNEL_TYPE_ACT_FRONT => ChannelType::Front,
+      sys::AUDIO_CHANNEL_TYPE_ACT_SIDE => ChannelType::Side,
+      sys::AUDIO_CHANNEL_TYPE_ACT_BACK => ChannelType::Back,
+      sys::AUDIO_CHANNEL_TYPE_ACT_LFE => ChannelType::Lfe,
+      sys::AUDIO_CHANNEL_TYPE_ACT_FRONT_TOP => ChannelType::FrontTop,
+      sys::AUDIO_CHANNEL_TYPE_ACT_SIDE_TOP => ChannelType::SideTop,
+      sys::AUDIO_CHANNEL_TYPE_ACT_BACK_TOP => ChannelType::BackTop,
+      channel_type => ChannelType::Other(channel_type),
+    }
+  }
+}
+
+/// Stream info of the last decoded frame, mirroring fdk-aac's CStreamInfo.
+/// Everything is 0 or empty before the first frame is decoded.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct StreamInfo {
+  /// Output sample rate, including SBR
+  pub sample_rate: u32,
+  /// Output samples per channel in a frame, like 1024 for AAC-LC and 2048
+  /// for HE-AAC
+  pub frame_size: usize,
+  /// Output channel count
+  pub channels: u16,
+  /// Position of each output channel
+  pub channel_types: Vec<ChannelType>,
+  /// Index of each output channel among the channels of the same type, from
+  /// front to back and left to right
+  pub channel_indices: Vec<u8>,
+  /// Sample rate of the AAC core, without SBR
+  pub aac_sample_rate: u32,
+  /// MPEG-2 profile, like 1 for AAC-LC. None when it doesn't apply.
+  pub profile: Option<u8>,
+  /// Audio object type, like 2 for AAC-LC
+  pub aot: u32,
+  /// Channel configuration, where 0 means it's defined by a program config
+  /// element
+  pub channel_config: u8,
+  /// Instantaneous bitrate in bits per second
+  pub bitrate: u32,
+  /// Samples per channel in a frame of the AAC core
+  pub aac_samples_per_frame: usize,
+  /// Channels of the AAC core, before parametric stereo
+  pub aac_channels: u16,
+  /// Extension audio object type signaled in the AudioSpecificConfig, like 5
+  /// for explicit SBR. 0 otherwise.
+  pub ext_aot: u32,
+  /// Sample rate of the extension, like the SBR output rate
+  pub ext_sample_rate: u32,
+  /// Samples the output is delayed by in the decoder, including the SBR
+  /// delay. It's at the output sample rate.
+  pub output_delay: u32,
+  /// Program reference level from the DRC metadata in dBFS, which is the
+  /// average loudness in LKFS. None when the stream doesn't have it.
+  pub drc_reference_level: Option<f32>,
+  /// DRC presentation mode from ETSI TS 101 154, where 0 means it isn't
+  /// indicated. None when the stream doesn't have it.
+  pub drc_presentation_mode: Option<u8>,
+}
+
+impl StreamInfo {
+  /// Part of `output_delay` that's from SBR, which is 962 samples at the
+  /// output sample rate, or 481 when SBR is downsampled to the core sample
+  /// rate. 0 for streams without SBR.
+  pub fn sbr_delay(&self) -> u32 {
+    let sbr = matches!(self.ext_aot, 5 | 29) || self.sample_rate > self.aac_sample_rate;
+    match (sbr, self.sample_rate > self.aac_sample_rate) {
+      (false, _) => 0,
+      (true, true) => 962,
+      (true, false) => 481,
+    }
+  }
+  pub(crate) fn from_fdk(info: &fdk_aac::dec::StreamInfo) -> Self {
+    let channels = info.numChannels.max(0) as usize;
+    // the channel arrays aren't allocated until the first frame is decoded
+    let channel_types = match info.pChannelType.is_null() {
+      true => Vec::new(),
+      false => unsafe { slice::from_raw_parts(info.pChannelType, channels) }.to_vec(),
+    };
+    let channel_indices = match info.pChannelIndices.is_null() {
+      true => Vec::new(),
+      false => unsafe { slice::from_raw_parts(info.pChannelIndices, channels) }.to_vec(),
+    };
+    StreamInfo {
+      sample_rate: info.sampleRate.max(0) as u32,
+      frame_size: info.frameSize.max(0) as usize,
+      channels: channels as u16,
+      channel_types: channel_types
+        .into_iter()
+        .map(ChannelType::from_fdk)
+        .collect(),
+      channel_indices,
+      aac_sample_rate: info.aacSampleRate.max(0) as u32,
+      profile: u8::try_from(info.profile).ok(),
+      aot: info.aot.max(0) as u32,
+      channel_config: info.channelConfig.max(0) as u8,
+      bitrate: info.bitRate.max(0) as u32,
+      aac_samples_per_frame: info.aacSamplesPerFrame.max(0) as usize,
+      aac_channels: info.aacNumChannels.max(0) as u16,
+      ext_aot: info.extAot.max(0) as u32,
+      ext_sample_rate: info.extSamplingRate.max(0) as u32,
+      output_delay: info.outputDelay,
+      drc_reference_level: u8::try_from(info.drcProgRefLev)
+        .ok()
+        .map(|level| level as f32 / -4.0),
+      drc_presentation_mode: u8::try_from(info.drcPresMode).ok(),
+    }
+  }
+}
@@ -0,0 +1,440 @@
+//! Minimal parser for AAC raw data blocks. It doesn't decode anything, it only
+//! walks the bitstream to find where each syntactic element starts and ends.
+//! Only the tools allowed in AAC-LC are supported.
+
+use alloc::{vec, vec::Vec};
+#[cfg(feature = "std")]
+use std::sync::OnceLock;
+
+mod tables;
+
+pub(crate) const ID_SCE: u8 = 0;
+pub(crate) const ID_CPE: u8 = 1;
+pub(crate) const ID_LFE: u8 = 3;
+pub(crate) const ID_DSE: u8 = 4;
+pub(crate) const ID_PCE: u8 = 5;
+pub(crate) const ID_FIL: u8 = 6;
+pub(crate) const ID_END: u8 = 7;
+
+const EIGHT_SHORT_SEQUENCE: u32 = 2;
+const ZERO_HCB: u8 = 0;
+const ESC_HCB: u8 = 11;
+const NOISE_HCB: u8 = 13;
+
+/// Bit positions of a syntactic element in a raw data block
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Element {
+  pub id: u8,
+  /// First bit after the element ID
+  pub start: usize,
+  /// First bit after the element
+  pub end: usize,
+  /// First bit of the second channel stream of a channel pair element
+  pub second_channel: Option<usize>,
+}
+
+/// Reads values bit by bit, most significant bit first
+pub(crate) struct BitReader<'a> {
+  bytes: &'a [u8],
+  position: usize,
+}
+
+impl<'a> BitReader<'a> {
+  pub fn new(bytes: &'a [u8]) -> Self {
+    BitReader { bytes, position: 0 }
+  }
+  pub fn position(&self) -> usize {
+    self.position
+  }
+  pub fn read(&mut self, bits: usize) -> Option<u32> {
+    let mut value = 0;
+    for _ in 0..bits {
+      let byte = self.bytes.get(self.position / 8)?;
+      let bit = (byte >> (7 - self.position % 8)) & 1;
+      value = (value << 1) | bit as u32;
+      self.position += 1;
+    }
+    Some(value)
+  }
+  pub fn skip(&mut self, bits: usize) -> Option<()> {
+    let position = self.position.checked_add(bits)?;
+    if position > self.bytes.len() * 8 {
+      return None;
+    }
+    self.position = position;
+    Some(())
+  }
+  fn byte_align(&mut self) -> Option<()> {
+    self.skip((8 - self.position % 8) % 8)
+  }
+}
+
+/// Binary tree for decoding a Huffman codebook. Each node holds its two
+/// children, where a negative value is a leaf holding `-(index + 1)` and 0 is
+/// an unused code.
+struct HuffmanTree(Vec<[i32; 2]>);
+
+impl HuffmanTree {
+  fn new(codebook: tables::Codebook) -> Self {
+    let mut nodes = vec![[0, 0]];
+    for (index, &(code, length)) in codebook.iter().enumerate() {
+      let mut node = 0;
+      for i in (0..length).rev() {
+        let bit = ((code >> i) & 1) as usize;
+        if i == 0 {
+          nodes[node][bit] = -(index as i32 + 1);
+        } else {
+          if nodes[node][bit] == 0 {
+            nodes.push([0, 0]);
+            nodes[node][bit] = nodes.len() as i32 - 1;
+          }
+          node = nodes[node][bit] as usize;
+        }
+      }
+    }
+    HuffmanTree(nodes)
+  }
+  fn decode(&self, reader: &mut BitReader) -> Option<usize> {
+    let mut node = 0;
+    loop {
+      let next = self.0[node][reader.read(1)? as usize];
+      match next {
+        0 => return None,
+        n if n < 0 => return Some((-n - 1) as usize),
+        n => node = n as usize,
+      }
+    }
+  }
+}
+
+/// Huffman trees for the scalefactor codebook followed by spectrum codebooks
+/// 1 to 11
+fn build_huffman_trees() -> Vec<HuffmanTree> {
+  let mut trees = vec![HuffmanTree::new(tables::SCALEFACTOR)];
+  trees.extend(
+    tables::SPECTRUM
+      .iter()
+      .map(|codebook| HuffmanTree::new(codebook)),
+  );
+  trees
+}
+
+#[cfg(feature = "std")]
+fn huffman_trees() -> &'static [HuffmanTree] {
+  static TREES: OnceLock<Vec<HuffmanTree>> = OnceLock::new();
+  TREES.get_or_init(build_huffman_trees)
+}
+
+/// Without std there's nothing to cache the trees in, so they're built for
+/// each channel stream
+#[cfg(not(feature = "std"))]
+fn huffman_trees() -> Vec<HuffmanTree> {
+  build_huffman_trees()
+}
+
+/// Window and band layout of an individual channel stream
+struct IcsInfo {
+  short: bool,
+  max_sfb: usize,
+  /// Number of windows in each window group
+  group_lengths: Vec<usize>,
+}
+
+impl IcsInfo {
+  fn parse(reader: &mut BitReader, swb_offsets: (&[u16], &[u16])) -> Option<Self> {
+    reader.read(1)?; // ics_reserved_bit
+    let window_sequence = reader.read(2)?;
+    reader.read(1)?; // window_shape
+    let info = if window_sequence == EIGHT_SHORT_SEQUENCE {
+      let max_sfb = reader.read(4)? as usize;
+      let grouping = reader.read(7)?;
+      let mut group_lengths = vec![1];
+      for i in (0..7).rev() {
+        match (grouping >> i) & 1 {
+          1 => *group_lengths.last_mut()? += 1,
+          _ => group_lengths.push(1),
+        }
+      }
+      IcsInfo {
+        short: true,
+        max_sfb,
+        group_lengths,
+      }
+    } else {
+      let max_sfb = reader.read(6)? as usize;
+      let predictor_data_present = reader.read(1)?;
+      if predictor_data_present == 1 {
+        return None;
+      }
+      IcsInfo {
+        short: false,
+        max_sfb,
+        group_lengths: vec![1],
+      }
+    };
+    if info.max_sfb >= info.swb_offsets(swb_offsets).len() {
+      return None;
+    }
+    Some(info)
+  }
+  fn swb_offsets<'a>(&self, swb_offsets: (&'a [u16], &'a [u16])) -> &'a [u16] {
+    match self.short {
+      true => swb_offsets.1,
+      false => swb_offsets.0,
+    }
+  }
+}
+
+/// Walk an individual channel stream. `common_info` is the shared ics_info of
+/// a channel pair element with a common window.
+fn individual_channel_stream(
+  reader: &mut BitReader,
+  swb_offsets: (&[u16], &[u16]),
+  common_info: Option<&IcsInfo>,
+) -> Option<()> {
+  reader.read(8)?; // global_gain
+  let parsed_info;
+  let info = match common_info {
+    Some(info) => info,
+    None => {
+      parsed_info = IcsInfo::parse(reader, swb_offsets)?;
+      &parsed_info
+    }
+  };
+
+  // section_data
+  let section_bits = if info.short { 3 } else { 5 };
+  let escape = (1 << section_bits) - 1;
+  let mut codebooks = Vec::with_capacity(info.group_lengths.len());
+  for _ in &info.group_lengths {
+    let mut group = Vec::with_capacity(info.max_sfb);
+    while group.len() < info.max_sfb {
+      let codebook = reader.read(4)? as u8;
+      if codebook == 12 {
+        return None;
+      }
+      let mut length = 0;
+      loop {
+        let increment = reader.read(section_bits)?;
+        length += increment as usize;
+        if increment != escape {
+          break;
+        }
+      }
+      if group.len() + length > info.max_sfb {
+        return None;
+      }
+      group.extend(core::iter::repeat_n(codebook, length));
+    }
+    codebooks.push(group);
+  }
+
+  // scale_factor_data
+  let trees = huffman_trees();
+  let mut noise_pcm = true;
+  for &codebook in codebooks.iter().flatten() {
+    match codebook {
+      ZERO_HCB => {}
+      NOISE_HCB if noise_pcm => {
+        noise_pcm = false;
+        reader.read(9)?;
+      }
+      _ => {
+        trees[0].decode(reader)?;
+      }
+    }
+  }
+
+  // pulse_data
+  if reader.read(1)? == 1 {
+    if info.short {
+      return None;
+    }
+    let number_pulse = reader.read(2)? as usize;
+    reader.skip(6 + (number_pulse + 1) * (5 + 4))?;
+  }
+
+  // tns_data
+  if reader.read(1)? == 1 {
+    let (windows, n_filt_bits, length_bits, order_bits) = match info.short {
+      true => (8, 1, 4, 3),
+      false => (1, 2, 6, 5),
+    };
+    for _ in 0..windows {
+      let n_filt = reader.read(n_filt_bits)?;
+      if n_filt == 0 {
+        continue;
+      }
+      let coef_res = reader.read(1)? as usize;
+      for _ in 0..n_filt {
+        reader.skip(length_bits)?;
+        let order = reader.read(order_bits)? as usize;
+        if order > 0 {
+          reader.read(1)?; // direction
+          let coef_compress = reader.read(1)? as usize;
+          reader.skip(order * (3 + coef_res - coef_compress))?;
+        }
+      }
+    }
+  }
+
+  // gain_control_data is only used by AAC-SSR
+  if reader.read(1)? == 1 {
+    return None;
+  }
+
+  // spectral_data
+  let offsets = info.swb_offsets(swb_offsets);
+  for (group, &group_length) in codebooks.iter().zip(&info.group_lengths) {
+    for (band, &codebook) in group.iter().enumerate() {
+      if codebook == ZERO_HCB || codebook >= NOISE_HCB {
+        continue;
+      }
+      let width = (offsets[band + 1] - offsets[band]) as usize * group_length;
+      let dimension = if codebook < 5 { 4 } else { 2 };
+      let (unsigned, modulo) = match codebook {
+        1 | 2 => (false, 3),
+        3 | 4 => (true, 3),
+        5 | 6 => (false, 9),
+        7 | 8 => (true, 8),
+        9 | 10 => (true, 13),
+        _ => (true, 17),
+      };
+      for _ in 0..width / dimension {
+        let mut index = trees[codebook as usize].decode(reader)?;
+        let mut values = [0; 4];
+        for value in &mut values[..dimension] {
+          *value = index % modulo;
+          index /= modulo;
+        }
+        if unsigned {
+          let signs = values.iter().filter(|&&value| value != 0).count();
+          reader.skip(signs)?;
+        }
+        for &value in &values[..dimension] {
+          if codebook == ESC_HCB && value == 16 {
+            let mut prefix = 0;
+            while reader.read(1)? == 1 {
+              prefix += 1;
+              if prefix > 8 {
+                return None;
+              }
+            }
+            reader.skip(prefix + 4)?;
+          }
+        }
+      }
+    }
+  }
+  Some(())
+}
+
+fn channel_pair_element(reader: &mut BitReader, swb_offsets: (&[u16], &[u16])) -> Option<usize> {
+  reader.read(4)?; // element_instance_tag
+  let common_window = reader.read(1)? == 1;
+  let common_info = match common_window {
+    true => {
+      let info = IcsInfo::parse(reader, swb_offsets)?;
+      match reader.read(2)? {
+        1 => reader.skip(info.group_lengths.len() * info.max_sfb)?,
+        3 => return None,
+        _ => {}
+      }
+      Some(info)
+    }
+    false => None,
+  };
+  individual_channel_stream(reader, swb_offsets, common_info.as_ref())?;
+  let second_channel = reader.position();
+  individual_channel_stream(reader, swb_offsets, common_info.as_ref())?;
+  Some(second_channel)
+}
+
+fn data_stream_element(reader: &mut BitReader) -> Option<()> {
+  reader.read(4)?; // element_instance_tag
+  let byte_align = reader.read(1)? == 1;
+  let mut count = reader.read(8)? as usize;
+  if count == 255 {
+    count += reader.read(8)? as usize;
+  }
+  if byte_align {
+    reader.byte_align()?;
+  }
+  reader.skip(count * 8)
+}
+
+fn program_config_element(reader: &mut BitReader) -> Option<()> {
+  reader.skip(4 + 2 + 4)?; // element_instance_tag, object_type, sampling_frequency_index
+  let front = reader.read(4)? as usize;
+  let side = reader.read(4)? as usize;
+  let back = reader.read(4)? as usize;
+  let lfe = reader.read(2)? as usize;
+  let assoc_data = reader.read(3)? as usize;
+  let valid_cc = reader.read(4)? as usize;
+  for mixdown_bits in [4, 4, 3] {
+    if reader.read(1)? == 1 {
+      reader.skip(mixdown_bits)?;
+    }
+  }
+  reader.skip((front + side + back) * 5 + lfe * 4 + assoc_data * 4 + valid_cc * 5)?;
+  reader.byte_align()?;
+  let comment_bytes = reader.read(8)? as usize;
+  reader.skip(comment_bytes * 8)
+}
+
+fn fill_element(reader: &mut BitReader) -> Option<()> {
+  let mut count = reader.read(4)? as usize;
+  if count == 15 {
+    count += reader.read(8)? as usize;
+    count -= 1;
+  }
+  reader.skip(count * 8)
+}
+
+/// Find the elements of a raw data block. Returns `None` if the data is
+/// malformed or uses tools that aren't supported, such as coupling channels.
+pub(crate) fn parse_raw_data_block(
+  reader: &mut BitReader,
+  sample_freq_index: usize,
+) -> Option<Vec<Element>> {
+  let swb_offsets = (
+    *tables::SWB_OFFSET_LONG.get(sample_freq_index)?,
+    *tables::SWB_OFFSET_SHORT.get(sample_freq_index)?,
+  );
+  let mut elements = Vec::new();
+  loop {
+    let id = reader.read(3)? as u8;
+    if id == ID_END {
+      return Some(elements);
+    }
+    let start = reader.position();
+    let mut second_channel = None;
+    match id {
+      ID_SCE | ID_LFE => {
+        reader.read(4)?; // element_instance_tag
+        individual_channel_stream(reader, swb_offsets, None)?;
+      }
+      ID_CPE => second_channel = Some(channel_pair_element(reader, swb_offsets)?),
+      ID_DSE => data_stream_element(reader)?,
+      ID_PCE => program_config_element(reader)?,
+      ID_FIL => fill_element(reader)?,
+      _ => return None,
+    }
+    elements.push(Element {
+      id,
+      start,
+      end: reader.position(),
+      second_channel,
+    });
+  }
+}
+
+/// Length in bytes of the raw data block at the start of `bytes`, which may be
+/// followed by more raw data blocks
+#[cfg(feature = "std")]
+pub(crate) fn raw_data_block_length(bytes: &[u8], sample_freq_index: usize) -> Option<usize> {
+  let mut reader = BitReader::new(bytes);
+  parse_raw_data_block(&mut reader, sample_freq_index)?;
+  reader.byte_align()?;
+  Some(reader.position() / 8)
+}
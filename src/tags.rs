@@ -0,0 +1,510 @@
+//! Writing iTunes tags to MP4 files. Enabled with the `tags` feature.
+//!
+//! The tags are updated in place when the new moov box fits where the old one
+//! and any free boxes after it were, or when it's the last box in the file.
+//! Otherwise the file is rewritten, and if that moves the samples, the chunk
+//! offsets are updated to match.
+use crate::boxes::{boxes, find};
+use crate::{Error, GaplessInfo};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Data type of a data box with UTF-8 text
+const TYPE_UTF8: u32 = 1;
+/// Data type of a data box with binary data, like trkn and disk
+const TYPE_IMPLICIT: u32 = 0;
+
+/// Image format of cover artwork
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArtworkFormat {
+  Jpeg,
+  /// The mp4 crate fails to read files with PNG artwork, so `Decoder` can't
+  /// open them
+  Png,
+}
+
+/// Cover artwork, stored in the covr atom
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Artwork {
+  pub format: ArtworkFormat,
+  /// The encoded image
+  pub data: Vec<u8>,
+}
+
+#[derive(Clone, Debug)]
+enum Change {
+  /// Replace or remove the items of a type
+  Item([u8; 4], Option<Vec<u8>>),
+  /// Replace or remove a freeform item by name
+  Freeform(String, Option<String>),
+}
+
+/// Changes to make to the tags of an MP4 file. Setting a tag to None removes
+/// it, and tags that aren't set are left as they are.
+///
+/// ```no_run
+/// use redlux::tags::TagWriter;
+///
+/// TagWriter::new()
+///   .title(Some("Energy"))
+///   .artist(Some("Simbai & Elke Bay"))
+///   .write_path("song.m4a")
+///   .unwrap();
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct TagWriter {
+  changes: Vec<Change>,
+}
+
+impl TagWriter {
+  pub fn new() -> Self {
+    Self::default()
+  }
+  fn text(mut self, item_type: &[u8; 4], value: Option<&str>) -> Self {
+    let data = value.map(|value| data_box(TYPE_UTF8, value.as_bytes()));
+    self.changes.push(Change::Item(*item_type, data));
+    self
+  }
+  pub fn title(self, title: Option<&str>) -> Self {
+    self.text(b"\xa9nam", title)
+  }
+  pub fn artist(self, artist: Option<&str>) -> Self {
+    self.text(b"\xa9ART", artist)
+  }
+  pub fn album(self, album: Option<&str>) -> Self {
+    self.text(b"\xa9alb", album)
+  }
+  pub fn album_artist(self, album_artist: Option<&str>) -> Self {
+    self.text(b"aART", album_artist)
+  }
+  pub fn composer(self, composer: Option<&str>) -> Self {
+    self.text(b"\xa9wrt", composer)
+  }
+  pub fn comment(self, comment: Option<&str>) -> Self {
+    self.text(b"\xa9cmt", comment)
+  }
+  /// Genre as text, in the ©gen atom. Removes any gnre atom, since readers
+  /// may prefer it.
+  pub fn genre(mut self, genre: Option<&str>) -> Self {
+    self.changes.push(Change::Item(*b"gnre", None));
+    self.text(b"\xa9gen", genre)
+  }
+  /// Release date, usually a year or an ISO 8601 date
+  pub fn date(self, date: Option<&str>) -> Self {
+    self.text(b"\xa9day", date)
+  }
+  /// Track number and total. A total of 0 means there isn't one.
+  pub fn track_number(mut self, track: Option<(u16, u16)>) -> Self {
+    let data = track.map(|(number, total)| {
+      let mut value = vec![0, 0];
+      value.extend_from_slice(&number.to_be_bytes());
+      value.extend_from_slice(&total.to_be_bytes());
+      value.extend_from_slice(&[0, 0]);
+      data_box(TYPE_IMPLICIT, &value)
+    });
+    self.changes.push(Change::Item(*b"trkn", data));
+    self
+  }
+  /// Disc number and total. A total of 0 means there isn't one.
+  pub fn disc_number(mut self, disc: Option<(u16, u16)>) -> Self {
+    let data = disc.map(|(number, total)| {
+      let mut value = vec![0, 0];
+      value.extend_from_slice(&number.to_be_bytes());
+      value.extend_from_slice(&total.to_be_bytes());
+      data_box(TYPE_IMPLICIT, &value)
+    });
+    self.changes.push(Change::Item(*b"disk", data));
+    self
+  }
+  /// Cover artwork. Replaces all existing artwork.
+  pub fn artwork(mut self, artwork: Option<&Artwork>) -> Self {
+    let data = artwork.map(|artwork| {
+      let data_type = match artwork.format {
+        ArtworkFormat::Jpeg => 13,
+        ArtworkFormat::Png => 14,
+      };
+      data_box(data_type, &artwork.data)
+    });
+    self.changes.push(Change::Item(*b"covr", data));
+    self
+  }
+  /// Freeform "----" tag with a text value, with the "com.apple.iTunes" mean.
+  /// Existing tags are matched by name case-insensitively.
+  pub fn freeform(mut self, name: &str, value: Option<&str>) -> Self {
+    let value = value.map(str::to_string);
+    self.changes.push(Change::Freeform(name.to_string(), value));
+    self
+  }
+  /// Encoder delay and padding, in the iTunSMPB tag
+  pub fn gapless_info(self, gapless_info: Option<GaplessInfo>) -> Self {
+    let value = gapless_info.map(|info| {
+      format!(
+        " 00000000 {:08X} {:08X} {:016X} 00000000 00000000 00000000 00000000 00000000 00000000 00000000 00000000",
+        info.encoder_delay, info.padding, info.samples
+      )
+    });
+    self.freeform("iTunSMPB", value.as_deref())
+  }
+
+  /// Update the tags of a file, in place if possible and by rewriting it to
+  /// a temporary file next to it otherwise
+  pub fn write_path<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+    let path = path.as_ref();
+    let mut file = OpenOptions::new()
+      .read(true)
+      .write(true)
+      .open(path)
+      .map_err(Error::ReaderError)?;
+    let len = file.metadata().map_err(Error::ReaderError)?.len();
+    let layout = Layout::read(&mut file, len)?;
+    let moov = self.new_moov(&mut file, &layout)?;
+    if let Some(end) = layout.in_place(&moov) {
+      layout.write_in_place(&mut file, &moov)?;
+      if end < len {
+        file.set_len(end).map_err(Error::WriterError)?;
+      }
+      return Ok(());
+    }
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    let result = File::create(&temp_path)
+      .map_err(Error::WriterError)
+      .and_then(|temp| layout.rewrite(&mut file, temp, moov))
+      .and_then(|_| fs::rename(&temp_path, path).map_err(Error::WriterError));
+    if result.is_err() {
+      let _ = fs::remove_file(&temp_path);
+    }
+    result
+  }
+  /// Update the tags in place, without moving the samples. Returns false
+  /// without writing anything if the tags don't fit.
+  pub fn write_in_place<F: Read + Write + Seek>(&self, mut file: F) -> Result<bool, Error> {
+    let len = file.seek(SeekFrom::End(0)).map_err(Error::ReaderError)?;
+    let layout = Layout::read(&mut file, len)?;
+    let moov = self.new_moov(&mut file, &layout)?;
+    match layout.in_place(&moov) {
+      // a file can't be truncated through Write, so only fill to the end
+      Some(end) if end >= len || layout.moov_end() < len => {
+        layout.write_in_place(&mut file, &moov)?;
+        Ok(true)
+      }
+      _ => Ok(false),
+    }
+  }
+  /// Copy `input` to `output` with the tags updated
+  pub fn rewrite<R: Read + Seek, W: Write>(&self, mut input: R, output: W) -> Result<(), Error> {
+    let len = input.seek(SeekFrom::End(0)).map_err(Error::ReaderError)?;
+    let layout = Layout::read(&mut input, len)?;
+    let moov = self.new_moov(&mut input, &layout)?;
+    layout.rewrite(&mut input, output, moov)
+  }
+
+  /// Contents of the moov box with the changes made
+  fn new_moov<R: Read + Seek>(&self, reader: &mut R, layout: &Layout) -> Result<Vec<u8>, Error> {
+    let moov = layout.read_moov(reader)?;
+    let udta = find(&moov, b"udta").unwrap_or_default();
+    let meta = find(udta, b"meta");
+    // meta is a full box in MP4, but not in QuickTime
+    let (meta_header, meta_children) = match meta {
+      Some(meta) if meta.get(0..4) == Some(&[0; 4]) => meta.split_at(4),
+      Some(meta) => (&[][..], meta),
+      None => (&[0; 4][..], &[][..]),
+    };
+    let ilst = find(meta_children, b"ilst").unwrap_or_default();
+    let ilst = self.apply(ilst);
+    let mut meta = meta_header.to_vec();
+    if find(meta_children, b"hdlr").is_none() {
+      // handler type mdir, with the reserved field set like iTunes does
+      let hdlr = [&[0; 8][..], b"mdirappl", &[0; 10]].concat();
+      write_box(&mut meta, b"hdlr", &hdlr);
+    }
+    meta.extend(replace_child(meta_children, b"ilst", &ilst));
+    let udta = replace_child(udta, b"meta", &meta);
+    Ok(replace_child(&moov, b"udta", &udta))
+  }
+  /// Contents of the ilst box with the changes made
+  fn apply(&self, ilst: &[u8]) -> Vec<u8> {
+    let mut items: Vec<([u8; 4], Vec<u8>)> = boxes(ilst)
+      .map(|(item_type, item)| (item_type, item.to_vec()))
+      .collect();
+    for change in &self.changes {
+      let (item_type, item) = match change {
+        Change::Item(item_type, data) => (*item_type, data.clone()),
+        Change::Freeform(name, value) => {
+          let item = value.as_ref().map(|value| freeform_item(name, value));
+          (*b"----", item)
+        }
+      };
+      let matches = |t: &[u8; 4], content: &[u8]| {
+        *t == item_type
+          && match change {
+            Change::Freeform(name, _) => find(content, b"name")
+              .and_then(|item_name| item_name.get(4..))
+              .is_some_and(|item_name| item_name.eq_ignore_ascii_case(name.as_bytes())),
+            Change::Item(..) => true,
+          }
+      };
+      let position = items.iter().position(|(t, content)| matches(t, content));
+      items.retain(|(t, content)| !matches(t, content));
+      if let Some(item) = item {
+        items.insert(position.unwrap_or(items.len()), (item_type, item));
+      }
+    }
+    let mut ilst = Vec::new();
+    for (item_type, item) in items {
+      write_box(&mut ilst, &item_type, &item);
+    }
+    ilst
+  }
+}
+
+/// Write a box with a 32-bit size
+fn write_box(output: &mut Vec<u8>, box_type: &[u8; 4], content: &[u8]) {
+  output.extend_from_slice(&(content.len() as u32 + 8).to_be_bytes());
+  output.extend_from_slice(box_type);
+  output.extend_from_slice(content);
+}
+
+/// A data box, with its type indicator and an empty locale
+fn data_box(data_type: u32, value: &[u8]) -> Vec<u8> {
+  let mut content = data_type.to_be_bytes().to_vec();
+  content.extend_from_slice(&[0; 4]);
+  content.extend_from_slice(value);
+  let mut data = Vec::new();
+  write_box(&mut data, b"data", &content);
+  data
+}
+
+/// Contents of a freeform item
+fn freeform_item(name: &str, value: &str) -> Vec<u8> {
+  let mut item = Vec::new();
+  write_box(
+    &mut item,
+    b"mean",
+    &[&[0; 4], &b"com.apple.iTunes"[..]].concat(),
+  );
+  write_box(&mut item, b"name", &[&[0; 4], name.as_bytes()].concat());
+  item.extend(data_box(TYPE_UTF8, value.as_bytes()));
+  item
+}
+
+/// Contents of a container with the first child of a type replaced, or
+/// added at the end if there isn't one
+fn replace_child(container: &[u8], box_type: &[u8; 4], content: &[u8]) -> Vec<u8> {
+  let mut output = Vec::with_capacity(container.len() + content.len());
+  let mut replaced = false;
+  for (child_type, child) in boxes(container) {
+    match &child_type == box_type && !replaced {
+      true => {
+        write_box(&mut output, box_type, content);
+        replaced = true;
+      }
+      false => write_box(&mut output, &child_type, child),
+    }
+  }
+  if !replaced {
+    write_box(&mut output, box_type, content);
+  }
+  output
+}
+
+/// Add `delta` to the chunk offsets in a moov box that are at or after
+/// `from`, for when the data there moves
+fn shift_chunk_offsets(moov: &mut [u8], from: u64, delta: i64) -> Result<(), Error> {
+  const PATH: [&[u8; 4]; 4] = [b"trak", b"mdia", b"minf", b"stbl"];
+  let mut containers = vec![(0, moov.len(), 0)];
+  while let Some((start, end, depth)) = containers.pop() {
+    for (box_type, range) in child_ranges(&moov[start..end]) {
+      let (child_start, child_end) = (start + range.0, start + range.1);
+      match (depth, &box_type) {
+        (depth, box_type) if depth < PATH.len() && box_type == PATH[depth] => {
+          containers.push((child_start, child_end, depth + 1));
+        }
+        (4, b"stco") | (4, b"co64") => {
+          let entry_size = if &box_type == b"stco" { 4 } else { 8 };
+          let entries = moov
+            .get_mut(child_start + 8..child_end)
+            .ok_or(Error::FileHeaderError)?;
+          for entry in entries.chunks_exact_mut(entry_size) {
+            let mut bytes = [0; 8];
+            bytes[8 - entry_size..].copy_from_slice(entry);
+            let offset = u64::from_be_bytes(bytes);
+            if offset < from {
+              continue;
+            }
+            let shifted = (offset as i64 + delta) as u64;
+            if entry_size == 4 && shifted > u32::MAX as u64 {
+              return Err(Error::TagWriteError(
+                "chunk offsets too large for an stco box",
+              ));
+            }
+            entry.copy_from_slice(&shifted.to_be_bytes()[8 - entry_size..]);
+          }
+        }
+        _ => {}
+      }
+    }
+  }
+  Ok(())
+}
+
+/// Types and content ranges of the boxes in a slice
+fn child_ranges(data: &[u8]) -> Vec<([u8; 4], (usize, usize))> {
+  boxes(data)
+    .map(|(box_type, content)| {
+      let start = content.as_ptr() as usize - data.as_ptr() as usize;
+      (box_type, (start, start + content.len()))
+    })
+    .collect()
+}
+
+/// A top-level box
+#[derive(Clone, Copy, Debug)]
+struct TopBox {
+  box_type: [u8; 4],
+  offset: u64,
+  size: u64,
+  header_len: u64,
+}
+
+/// The top-level boxes of a file
+struct Layout {
+  boxes: Vec<TopBox>,
+  moov: usize,
+  len: u64,
+}
+
+impl Layout {
+  fn read<R: Read + Seek>(reader: &mut R, len: u64) -> Result<Self, Error> {
+    let mut top_boxes = Vec::new();
+    let mut offset = 0;
+    while offset + 8 <= len {
+      reader
+        .seek(SeekFrom::Start(offset))
+        .map_err(Error::ReaderError)?;
+      let mut header = [0; 16];
+      reader
+        .read_exact(&mut header[..8])
+        .map_err(Error::ReaderError)?;
+      let mut size = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as u64;
+      let mut header_len = 8;
+      if size == 1 {
+        reader
+          .read_exact(&mut header[8..])
+          .map_err(Error::ReaderError)?;
+        let mut largesize = [0; 8];
+        largesize.copy_from_slice(&header[8..]);
+        size = u64::from_be_bytes(largesize);
+        header_len = 16;
+      } else if size == 0 {
+        size = len - offset;
+      }
+      if size < header_len || size > len - offset {
+        return Err(Error::FileHeaderError);
+      }
+      let mut box_type = [0; 4];
+      box_type.copy_from_slice(&header[4..8]);
+      top_boxes.push(TopBox {
+        box_type,
+        offset,
+        size,
+        header_len,
+      });
+      offset += size;
+    }
+    let moov = top_boxes.iter().position(|top| &top.box_type == b"moov");
+    Ok(Layout {
+      boxes: top_boxes,
+      moov: moov.ok_or(Error::FileHeaderError)?,
+      len,
+    })
+  }
+  fn moov_box(&self) -> TopBox {
+    self.boxes[self.moov]
+  }
+  fn moov_end(&self) -> u64 {
+    let moov = self.moov_box();
+    moov.offset + moov.size
+  }
+  fn read_moov<R: Read + Seek>(&self, reader: &mut R) -> Result<Vec<u8>, Error> {
+    let moov = self.moov_box();
+    reader
+      .seek(SeekFrom::Start(moov.offset + moov.header_len))
+      .map_err(Error::ReaderError)?;
+    let mut content = Vec::new();
+    reader
+      .take(moov.size - moov.header_len)
+      .read_to_end(&mut content)
+      .map_err(Error::ReaderError)?;
+    Ok(content)
+  }
+  /// Where a new moov box with `content` would end if it's written in place,
+  /// filling the space of the old one and any free boxes after it. None if
+  /// it doesn't fit.
+  fn in_place(&self, content: &[u8]) -> Option<u64> {
+    let moov = self.moov_box();
+    let free = self.boxes[self.moov + 1..]
+      .iter()
+      .take_while(|top| &top.box_type == b"free" || &top.box_type == b"skip");
+    let space_end = free
+      .last()
+      .map_or(moov.offset + moov.size, |top| top.offset + top.size);
+    let new_end = moov.offset + 8 + content.len() as u64;
+    let space = space_end.checked_sub(new_end);
+    match space {
+      // the rest is filled with a free box
+      Some(space) if space == 0 || space >= 8 => Some(space_end),
+      // the file ends after the moov box, so it can grow or shrink
+      _ if space_end == self.len => Some(new_end),
+      _ => None,
+    }
+  }
+  fn write_in_place<W: Write + Seek>(&self, writer: &mut W, content: &[u8]) -> Result<(), Error> {
+    let space_end = self.in_place(content).ok_or(Error::FileHeaderError)?;
+    let mut output = Vec::with_capacity(content.len() + 16);
+    write_box(&mut output, b"moov", content);
+    let new_end = self.moov_box().offset + output.len() as u64;
+    if space_end > new_end {
+      let padding = (space_end - new_end) as usize;
+      write_box(&mut output, b"free", &vec![0; padding - 8]);
+    }
+    writer
+      .seek(SeekFrom::Start(self.moov_box().offset))
+      .map_err(Error::WriterError)?;
+    writer.write_all(&output).map_err(Error::WriterError)
+  }
+  fn rewrite<R: Read + Seek, W: Write>(
+    &self,
+    reader: &mut R,
+    mut writer: W,
+    mut content: Vec<u8>,
+  ) -> Result<(), Error> {
+    let moov = self.moov_box();
+    let delta = 8 + content.len() as i64 - moov.size as i64;
+    let after = &self.boxes[self.moov + 1..];
+    if delta != 0 && after.iter().any(|top| &top.box_type == b"moof") {
+      // track fragments have absolute offsets
+      return Err(Error::TagWriteError(
+        "can't move the fragments of a fragmented file",
+      ));
+    }
+    shift_chunk_offsets(&mut content, moov.offset + moov.size, delta)?;
+    let mut copy = |start: u64, end: u64, writer: &mut W| {
+      reader
+        .seek(SeekFrom::Start(start))
+        .map_err(Error::ReaderError)?;
+      let copied = io::copy(&mut reader.take(end - start), writer);
+      match copied.map_err(Error::WriterError)? == end - start {
+        true => Ok(()),
+        false => Err(Error::FileHeaderError),
+      }
+    };
+    copy(0, moov.offset, &mut writer)?;
+    let mut output = Vec::with_capacity(content.len() + 8);
+    write_box(&mut output, b"moov", &content);
+    writer.write_all(&output).map_err(Error::WriterError)?;
+    copy(moov.offset + moov.size, self.len, &mut writer)?;
+    writer.flush().map_err(Error::WriterError)
+  }
+}
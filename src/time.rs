@@ -0,0 +1,51 @@
+//! Exact timekeeping in samples and timescale units
+use std::time::Duration;
+
+/// Convert a time in a timescale to a Duration. Whole seconds are split off
+/// first, so 64-bit tick counts don't overflow with any timescale.
+pub(crate) fn ticks_to_duration(ticks: u64, timescale: u32) -> Duration {
+  match timescale as u64 {
+    0 => Duration::ZERO,
+    timescale => {
+      let nanos = ticks % timescale * 1_000_000_000 / timescale;
+      Duration::new(ticks / timescale, nanos as u32)
+    }
+  }
+}
+
+/// Convert a Duration to a time in a timescale, rounded to the nearest tick so
+/// it round-trips with `ticks_to_duration`. Saturates at `u64::MAX`.
+pub(crate) fn duration_to_ticks(duration: Duration, timescale: u32) -> u64 {
+  let ticks = (duration.as_nanos() * timescale as u128 + 500_000_000) / 1_000_000_000;
+  ticks.min(u64::MAX as u128) as u64
+}
+
+/// Running time of a stream whose sample rate can change. Samples are counted
+/// per sample rate so rounding errors don't add up.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Clock {
+  start: Duration,
+  samples: u64,
+  sample_rate: u32,
+}
+
+impl Clock {
+  pub fn new(start: Duration) -> Self {
+    Clock {
+      start,
+      samples: 0,
+      sample_rate: 0,
+    }
+  }
+  pub fn now(&self) -> Duration {
+    self.start + ticks_to_duration(self.samples, self.sample_rate)
+  }
+  /// Advance by a number of samples per channel
+  pub fn advance(&mut self, samples: u64, sample_rate: u32) {
+    if sample_rate != self.sample_rate {
+      *self = Clock::new(self.now());
+      self.sample_rate = sample_rate;
+    }
+    self.samples = self.samples.saturating_add(samples);
+  }
+}
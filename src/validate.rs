@@ -0,0 +1,61 @@
+//! Sanity checks for MP4 sample tables, so that malformed files result in an
+//! error instead of a panic inside the mp4 crate
+use crate::Error;
+use mp4::Mp4Track;
+
+fn check(condition: bool) -> Result<(), Error> {
+  match condition {
+    true => Ok(()),
+    false => Err(Error::TrackReadingError),
+  }
+}
+
+/// Validate that the sample tables of a track can be read without panicking
+pub fn validate_track(track: &Mp4Track) -> Result<(), Error> {
+  let stbl = &track.trak.mdia.minf.stbl;
+  if !track.trafs.is_empty() {
+    // fragmented
+    let mut sample_count: u32 = 0;
+    for traf in &track.trafs {
+      if let Some(trun) = &traf.trun {
+        sample_count = sample_count
+          .checked_add(trun.sample_count)
+          .ok_or(Error::TrackReadingError)?;
+      }
+    }
+    // used as a divisor when checking for sync samples
+    check(sample_count as usize >= track.trafs.len())?;
+    // used to calculate sample times
+    check(
+      sample_count
+        .checked_mul(track.default_sample_duration)
+        .is_some(),
+    )?;
+    return Ok(());
+  }
+
+  check(!stbl.stsc.entries.is_empty())?;
+  for entry in &stbl.stsc.entries {
+    check(entry.first_chunk >= 1 && entry.first_sample >= 1 && entry.samples_per_chunk >= 1)?;
+  }
+  check(stbl.stco.is_some() || stbl.co64.is_some())?;
+
+  // sample offsets within a chunk are summed as u32
+  let max_sample_size = match stbl.stsz.sample_size {
+    0 => stbl.stsz.sample_sizes.iter().copied().max().unwrap_or(0),
+    sample_size => sample_size,
+  };
+  let max_samples_per_chunk = stbl.stsc.entries.iter().map(|e| e.samples_per_chunk).max();
+  let max_samples_per_chunk = max_samples_per_chunk.unwrap_or(0);
+  check(max_sample_size.checked_mul(max_samples_per_chunk).is_some())?;
+
+  // every sample needs a time
+  let mut stts_sample_count: u32 = 0;
+  for entry in &stbl.stts.entries {
+    stts_sample_count = stts_sample_count
+      .checked_add(entry.sample_count)
+      .ok_or(Error::TrackReadingError)?;
+  }
+  check(stts_sample_count >= stbl.stsz.sample_count)?;
+  Ok(())
+}
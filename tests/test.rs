@@ -1,7 +1,14 @@
-use redlux::Decoder;
+use redlux::playlist::{Input, Playlist};
+use redlux::{
+  adts, batch, levels, peaks, silence, ChannelOrder, ChannelType, Completed, Completion,
+  DecodeStats, Decoder, DecoderBuilder, DownmixMatrix, Drc, DrcEffect, Error, Event, Format,
+  FrameFlags, GainMode, LoopingDecoder, MediaKind, MetadataProfile, Rating, RetryReader,
+  SampleTableStats, MAX_FRAME_SIZE,
+};
 use rodio::{OutputStream, Sink};
+use std::convert::TryInto;
 use std::fs::File;
-use std::io::BufReader;
+use std::io::{BufReader, Cursor};
 use std::thread;
 use std::time::Duration;
 
@@ -44,3 +51,2892 @@ fn play_aac() {
   sink.set_volume(0.0);
   thread::sleep(Duration::from_millis(200));
 }
+
+#[cfg(feature = "play")]
+#[test]
+fn play_file_missing() {
+  let result = redlux::play_file("tests/samples/missing.m4a");
+  assert!(matches!(result, Err(Error::ReaderError(_))));
+}
+
+#[cfg(feature = "play")]
+#[test]
+fn play_cpal() {
+  let path = "tests/samples/Simbai & Elke Bay - Energy.m4a";
+  let mut decoder = Decoder::from_path(path).expect("Error creating decoder");
+  // play audio for 200ms at 0.0 volume
+  decoder.set_gain(0.0);
+  let player = redlux::play::Player::new(decoder).expect("Error creating player");
+  thread::sleep(Duration::from_millis(200));
+  player.stop().expect("Error stopping player");
+  assert!(!player.is_finished());
+}
+
+#[test]
+fn custom_buffer_sizes() {
+  let path = "tests/samples/Simbai & Elke Bay - Energy.aac";
+  let open = || BufReader::new(File::open(path).expect("Error opening file"));
+
+  let default_len = Decoder::new_aac(open()).count();
+  let mut decoder = DecoderBuilder::new()
+    .read_chunk_size(100)
+    .pcm_capacity(2048)
+    .open_aac(open())
+    .expect("Error creating decoder");
+  assert_eq!((&mut decoder).count(), default_len);
+  assert!(decoder.iter_error.is_none());
+
+  let result = DecoderBuilder::new()
+    .pcm_capacity(MAX_FRAME_SIZE + 1)
+    .open_aac(open());
+  assert!(matches!(result, Err(Error::InvalidConfig(_))));
+}
+
+#[test]
+fn batch_mode_chunks() {
+  let path = "tests/samples/Simbai & Elke Bay - Energy.m4a";
+  let file = File::open(path).expect("Error opening file");
+  let size = file.metadata().expect("Error getting file metadata").len();
+  let mut decoder = DecoderBuilder::new()
+    .batch_mode()
+    .open_mpeg4(BufReader::new(file), size)
+    .expect("Error creating decoder");
+
+  let mut pcm = Vec::new();
+  while decoder.next_chunk(&mut pcm).expect("Error decoding chunk") > 0 {}
+
+  let file = File::open(path).expect("Error opening file");
+  let samples: Vec<i16> = Decoder::new_mpeg4(BufReader::new(file), size)
+    .expect("Error creating decoder")
+    .collect();
+  assert_eq!(pcm, samples);
+}
+
+#[test]
+fn batch_decode_files() {
+  let paths = [
+    "tests/samples/Simbai & Elke Bay - Energy.m4a",
+    "tests/samples/Simbai & Elke Bay - Energy.aac",
+    "tests/samples/missing.m4a",
+  ];
+  let results = batch::decode_files(&paths, 2);
+  assert_eq!(results.len(), 3);
+  let m4a = results[0].as_ref().expect("Error decoding m4a");
+  assert_eq!((m4a.channels, m4a.sample_rate), (2, 44100));
+  assert_eq!(m4a.samples.len(), 638976);
+  let aac = results[1].as_ref().expect("Error decoding aac");
+  assert_eq!(aac.samples.len(), 636928);
+  assert!(matches!(results[2], Err(Error::ReaderError(_))));
+
+  let out_dir = std::env::temp_dir().join("redlux-batch-test");
+  std::fs::create_dir_all(&out_dir).expect("Error creating output dir");
+  let wav_paths = batch::decode_files_to_wav(&paths[..1], &out_dir, 1);
+  let wav_path = wav_paths[0].as_ref().expect("Error writing wav");
+  let wav_len = std::fs::metadata(wav_path)
+    .expect("Error reading wav")
+    .len();
+  assert_eq!(wav_len, 44 + 638976 * 2);
+}
+
+#[cfg(feature = "flac")]
+#[test]
+fn flac_export() {
+  let path = "tests/samples/Simbai & Elke Bay - Energy.m4a";
+  let mut decoder = Decoder::from_path(path).expect("Error opening file");
+  let mut flac = Cursor::new(Vec::new());
+  redlux::export::to_flac(&mut decoder, &mut flac).expect("Error exporting FLAC");
+  let flac = flac.into_inner();
+  assert_eq!(&flac[..4], b"fLaC");
+  let info = u64::from_be_bytes(flac[18..26].try_into().unwrap());
+  assert_eq!(info >> 44, 44100);
+  assert_eq!((info >> 41 & 0b111) + 1, 2);
+  assert_eq!(info & 0xF_FFFF_FFFF, 638976 / 2);
+  assert!(flac.len() < 638976 * 2);
+  assert_eq!(&flac[42..44], &[0xFF, 0xF8]);
+}
+
+#[test]
+fn raw_pcm_export() {
+  use redlux::export::{to_raw_pcm, to_raw_pcm_channels, PcmFormat};
+
+  let path = "tests/samples/Simbai & Elke Bay - Energy.m4a";
+  let open = || Decoder::from_path(path).expect("Error opening file");
+  let samples: Vec<i16> = open().collect();
+  let s16 = |bytes: &[u8]| -> Vec<i16> {
+    let chunks = bytes.chunks_exact(2);
+    chunks.map(|b| i16::from_le_bytes([b[0], b[1]])).collect()
+  };
+
+  let mut output = Vec::new();
+  to_raw_pcm(&mut open(), &mut output, PcmFormat::S16Le, None).unwrap();
+  assert_eq!(s16(&output), samples);
+
+  let mut output = Vec::new();
+  to_raw_pcm(&mut open(), &mut output, PcmFormat::S16Le, Some(&[1, 0, 5])).unwrap();
+  let swapped: Vec<i16> = samples.chunks(2).flat_map(|s| [s[1], s[0], 0]).collect();
+  assert_eq!(s16(&output), swapped);
+
+  let mut output = Vec::new();
+  to_raw_pcm(&mut open(), &mut output, PcmFormat::F32Le, None).unwrap();
+  let floats: Vec<f32> = output
+    .chunks_exact(4)
+    .map(|b| f32::from_le_bytes(b.try_into().unwrap()))
+    .collect();
+  let expected: Vec<f32> = samples.iter().map(|&s| s as f32 / 32768.0).collect();
+  assert_eq!(floats, expected);
+
+  let mut outputs = vec![Vec::new(); 3];
+  to_raw_pcm_channels(&mut open(), &mut outputs, PcmFormat::S16Le).unwrap();
+  let left: Vec<i16> = samples.iter().step_by(2).copied().collect();
+  let right: Vec<i16> = samples.iter().skip(1).step_by(2).copied().collect();
+  assert_eq!(s16(&outputs[0]), left);
+  assert_eq!(s16(&outputs[1]), right);
+  assert_eq!(s16(&outputs[2]), vec![0; left.len()]);
+}
+
+#[test]
+fn scan_files() {
+  let paths = [
+    "tests/samples/Simbai & Elke Bay - Energy.m4a",
+    "tests/samples/Simbai & Elke Bay - Energy.aac",
+    "tests/samples/missing.m4a",
+  ];
+  let results = redlux::scan(&paths, 2);
+  assert_eq!(results.len(), 3);
+  let m4a = results[0].as_ref().expect("Error scanning m4a");
+  assert_eq!(m4a.tracks.len(), 1);
+  assert_eq!(m4a.tracks[0].codec, "mp4a.40.2");
+  assert_eq!(m4a.duration, m4a.tracks[0].duration);
+  let metadata = m4a.metadata.as_ref().expect("Missing metadata");
+  assert!(metadata.freeform_tag("iTunSMPB").is_some());
+  let aac = results[1].as_ref().expect("Error scanning aac");
+  assert_eq!(aac.tracks.len(), 1);
+  assert_eq!(aac.metadata, None);
+  assert_eq!(aac.duration, Some(Duration::from_nanos(7_221_405_895)));
+  assert!(matches!(results[2], Err(Error::ReaderError(_))));
+}
+
+#[test]
+fn remux_with_crc() {
+  let path = "tests/samples/Simbai & Elke Bay - Energy.m4a";
+  let file = File::open(path).expect("Error opening file");
+  let size = file.metadata().expect("Error getting file metadata").len();
+  let mut mp4 = mp4::Mp4Reader::read_header(BufReader::new(file), size).expect("Error reading mp4");
+  let track = mp4.tracks().get(&1).expect("Error getting track");
+  let object_type = track.audio_profile().expect("Error getting profile");
+  let freq_index = track.sample_freq_index().expect("Error getting frequency");
+  let channel_config = track.channel_config().expect("Error getting channels");
+
+  let mut stream = Vec::new();
+  for sample_id in 1..=track.sample_count() {
+    let sample = mp4.read_sample(1, sample_id).expect("Error reading sample");
+    let sample = sample.expect("Missing sample");
+    let header =
+      adts::construct_adts_header_with_crc(object_type, freq_index, channel_config, &sample)
+        .expect("Error constructing header");
+    assert_eq!(header.len(), 9);
+    stream.extend_from_slice(&header);
+    stream.extend_from_slice(&sample.bytes);
+  }
+
+  let file = File::open(path).expect("Error opening file");
+  let samples: Vec<i16> = Decoder::new_mpeg4(BufReader::new(file), size)
+    .expect("Error creating decoder")
+    .collect();
+  let mut decoder = Decoder::new_aac(Cursor::new(stream.clone()));
+  assert_eq!((&mut decoder).collect::<Vec<_>>(), samples);
+  assert!(decoder.iter_error.is_none());
+
+  // flip a bit of the first CRC
+  stream[8] ^= 1;
+  let mut decoder = Decoder::new_aac(Cursor::new(stream));
+  assert_eq!((&mut decoder).count(), 0);
+  assert!(matches!(
+    decoder.iter_error,
+    Some(Error::TrackDecodingError(_))
+  ));
+}
+
+#[test]
+fn aac_resync() {
+  let path = "tests/samples/Simbai & Elke Bay - Energy.aac";
+  let data = std::fs::read(path).expect("Error reading file");
+  let samples: Vec<i16> = Decoder::new_aac(Cursor::new(data.clone())).collect();
+
+  // garbage with sync words before the first frame and between frames
+  let garbage = [0x00, 0xFF, 0xF1, 0x50, 0x80, 0x02, 0x1F, 0xFC, 0xFF, 0xFF];
+  let mut stream = garbage.to_vec();
+  let mut position = 0;
+  for frame in 0.. {
+    if position == data.len() {
+      break;
+    }
+    let header = adts::parse_header(&data[position..]).expect("Error parsing header");
+    stream.extend_from_slice(&data[position..position + header.frame_length]);
+    if frame % 10 == 5 {
+      stream.extend_from_slice(&garbage);
+    }
+    position += header.frame_length;
+  }
+  // trailing ID3v1 tag
+  stream.extend_from_slice(b"TAG");
+  stream.resize(stream.len() + 125, 0);
+  let mut decoder = Decoder::new_aac(Cursor::new(stream));
+  assert_eq!((&mut decoder).collect::<Vec<_>>(), samples);
+  assert!(decoder.iter_error.is_none());
+}
+
+#[test]
+fn mp4_multiple_frames_per_sample() {
+  let path = "tests/samples/Simbai & Elke Bay - Energy.m4a";
+  let file = File::open(path).expect("Error opening file");
+  let size = file.metadata().expect("Error getting file metadata").len();
+  let mut mp4 = mp4::Mp4Reader::read_header(BufReader::new(file), size).expect("Error reading mp4");
+  let track = mp4.tracks().get(&1).expect("Error getting track");
+  let track_config = mp4::TrackConfig {
+    track_type: mp4::TrackType::Audio,
+    timescale: track.timescale(),
+    language: "und".to_string(),
+    media_conf: mp4::MediaConfig::AacConfig(mp4::AacConfig {
+      bitrate: track.bitrate(),
+      profile: track.audio_profile().expect("Error getting profile"),
+      freq_index: track.sample_freq_index().expect("Error getting frequency"),
+      chan_conf: track.channel_config().expect("Error getting channels"),
+    }),
+  };
+  let config = mp4::Mp4Config {
+    major_brand: *mp4.major_brand(),
+    minor_version: mp4.minor_version(),
+    compatible_brands: mp4.compatible_brands().to_vec(),
+    timescale: mp4.timescale(),
+  };
+  let sample_count = track.sample_count();
+  let mut writer =
+    mp4::Mp4Writer::write_start(Cursor::new(Vec::new()), &config).expect("Error writing mp4");
+  writer.add_track(&track_config).expect("Error adding track");
+
+  // pack 3 frames into each sample
+  let mut packed: Option<mp4::Mp4Sample> = None;
+  for sample_id in 1..=sample_count {
+    let sample = mp4.read_sample(1, sample_id).expect("Error reading sample");
+    let sample = sample.expect("Missing sample");
+    packed = match packed {
+      Some(mut packed) => {
+        let mut bytes = packed.bytes.to_vec();
+        bytes.extend_from_slice(&sample.bytes);
+        packed.bytes = bytes.into();
+        packed.duration += sample.duration;
+        Some(packed)
+      }
+      None => Some(sample),
+    };
+    if sample_id % 3 == 0 || sample_id == sample_count {
+      let sample = packed.take().expect("Missing sample");
+      writer
+        .write_sample(1, &sample)
+        .expect("Error writing sample");
+    }
+  }
+  writer.write_end().expect("Error writing mp4");
+  let data = writer.into_writer().into_inner();
+
+  let file = File::open(path).expect("Error opening file");
+  let samples: Vec<i16> = Decoder::new_mpeg4(BufReader::new(file), size)
+    .expect("Error creating decoder")
+    .collect();
+  let data_len = data.len() as u64;
+  let mut decoder =
+    Decoder::new_mpeg4(Cursor::new(data), data_len).expect("Error creating decoder");
+  assert_eq!((&mut decoder).collect::<Vec<_>>(), samples);
+  assert!(decoder.iter_error.is_none());
+}
+
+/// Replace the content of the box at `path`, updating the sizes of its parents
+fn replace_box(data: &[u8], path: &[&[u8; 4]], content: &[u8]) -> Vec<u8> {
+  let mut output = Vec::new();
+  let mut position = 0;
+  while position < data.len() {
+    let size = u32::from_be_bytes([
+      data[position],
+      data[position + 1],
+      data[position + 2],
+      data[position + 3],
+    ]) as usize;
+    let box_type = &data[position + 4..position + 8];
+    let end = position + size;
+    if box_type == path[0] {
+      let new_content = match path.len() {
+        1 => content.to_vec(),
+        _ => replace_box(&data[position + 8..end], &path[1..], content),
+      };
+      output.extend_from_slice(&(new_content.len() as u32 + 8).to_be_bytes());
+      output.extend_from_slice(box_type);
+      output.extend_from_slice(&new_content);
+    } else {
+      output.extend_from_slice(&data[position..end]);
+    }
+    position = end;
+  }
+  output
+}
+
+/// Content of the first box of a type, found by searching for its type
+fn find_box<'a>(data: &'a [u8], box_type: &[u8; 4]) -> &'a [u8] {
+  let start = data
+    .windows(4)
+    .position(|w| w == box_type)
+    .expect("Missing box");
+  let size = u32::from_be_bytes(data[start - 4..start].try_into().unwrap()) as usize;
+  &data[start + 4..start - 4 + size]
+}
+
+#[test]
+fn mp4_multiple_sample_entries() {
+  use mp4::WriteBox;
+
+  let path = "tests/samples/Simbai & Elke Bay - Energy.m4a";
+  let file = File::open(path).expect("Error opening file");
+  let size = file.metadata().expect("Error getting file metadata").len();
+  let mut mp4 = mp4::Mp4Reader::read_header(BufReader::new(file), size).expect("Error reading mp4");
+  let track = mp4.tracks().get(&1).expect("Error getting track");
+  let stereo = mp4::AacConfig {
+    bitrate: track.bitrate(),
+    profile: track.audio_profile().expect("Error getting profile"),
+    freq_index: track.sample_freq_index().expect("Error getting frequency"),
+    chan_conf: track.channel_config().expect("Error getting channels"),
+  };
+  let stereo_entry = track.trak.mdia.minf.stbl.stsd.mp4a.clone();
+  let stereo_entry = stereo_entry.expect("Missing sample entry");
+  let mut mono_entry = stereo_entry.clone();
+  mono_entry.channelcount = 1;
+  mono_entry.samplerate = mp4::FixedPointU16::new(22050);
+  let esds = mono_entry.esds.as_mut().expect("Missing esds");
+  let dec_specific = &mut esds.es_desc.dec_config.dec_specific;
+  dec_specific.freq_index = mp4::SampleFreqIndex::Freq22050 as u8;
+  dec_specific.chan_conf = mp4::ChannelConfig::Mono as u8;
+  let config = mp4::Mp4Config {
+    major_brand: *mp4.major_brand(),
+    minor_version: mp4.minor_version(),
+    compatible_brands: mp4.compatible_brands().to_vec(),
+    timescale: mp4.timescale(),
+  };
+  let track_config = mp4::TrackConfig {
+    track_type: mp4::TrackType::Audio,
+    timescale: 44100,
+    language: "und".to_string(),
+    media_conf: mp4::MediaConfig::AacConfig(stereo),
+  };
+  let mut writer =
+    mp4::Mp4Writer::write_start(Cursor::new(Vec::new()), &config).expect("Error writing mp4");
+  writer.add_track(&track_config).expect("Error adding track");
+
+  // 2 chunks of 44 stereo samples, followed by mono samples
+  for sample_id in 1..=88 {
+    let sample = mp4.read_sample(1, sample_id).expect("Error reading sample");
+    let sample = sample.expect("Missing sample");
+    writer
+      .write_sample(1, &sample)
+      .expect("Error writing sample");
+  }
+  let mono_data = std::fs::read("tests/samples/sine-mono-22050.aac").expect("Error reading file");
+  let mut position = 0;
+  while position < mono_data.len() {
+    let header = adts::parse_header(&mono_data[position..]).expect("Error parsing header");
+    let frame = &mono_data[position + header.header_length()..position + header.frame_length];
+    let sample = mp4::Mp4Sample {
+      start_time: 0,
+      duration: 1024,
+      rendering_offset: 0,
+      is_sync: true,
+      bytes: mp4::Bytes::copy_from_slice(frame),
+    };
+    writer
+      .write_sample(1, &sample)
+      .expect("Error writing sample");
+    position += header.frame_length;
+  }
+  writer.write_end().expect("Error writing mp4");
+  let data = writer.into_writer().into_inner();
+
+  // add a mono sample entry, used from chunk 3
+  let mut stsd = vec![0, 0, 0, 0, 0, 0, 0, 2];
+  stereo_entry
+    .write_box(&mut stsd)
+    .expect("Error writing box");
+  mono_entry.write_box(&mut stsd).expect("Error writing box");
+  let data = replace_box(
+    &data,
+    &[b"moov", b"trak", b"mdia", b"minf", b"stbl", b"stsd"],
+    &stsd,
+  );
+  let data_len = data.len() as u64;
+  let mp4 =
+    mp4::Mp4Reader::read_header(Cursor::new(data.clone()), data_len).expect("Error reading mp4");
+  let mut stsc = mp4.tracks()[&1].trak.mdia.minf.stbl.stsc.clone();
+  let mut entry = stsc.entries[0].clone();
+  entry.first_chunk = 3;
+  entry.first_sample = 89;
+  stsc.entries.insert(1, entry);
+  for entry in &mut stsc.entries[1..] {
+    entry.sample_description_index = 2;
+  }
+  let mut stsc_box = Vec::new();
+  stsc.write_box(&mut stsc_box).expect("Error writing box");
+  let data = replace_box(
+    &data,
+    &[b"moov", b"trak", b"mdia", b"minf", b"stbl", b"stsc"],
+    &stsc_box[8..],
+  );
+
+  let file = File::open(path).expect("Error opening file");
+  let mut samples: Vec<i16> = Decoder::new_mpeg4(BufReader::new(file), size)
+    .expect("Error creating decoder")
+    .take(88 * 2048)
+    .collect();
+  samples.extend(Decoder::new_aac(Cursor::new(mono_data)));
+  let data_len = data.len() as u64;
+  let mut decoder =
+    Decoder::new_mpeg4(Cursor::new(data), data_len).expect("Error creating decoder");
+  let decoded: Vec<i16> = (&mut decoder).collect();
+  assert!(decoder.iter_error.is_none());
+  assert_eq!(decoded.len(), samples.len());
+  // the decoder crossfades the first frame after reconfiguring
+  let switch = 88 * 2048;
+  assert_eq!(decoded[..switch], samples[..switch]);
+  assert_eq!(decoded[switch + 1024..], samples[switch + 1024..]);
+  assert_eq!((decoder.channels(), decoder.sample_rate()), (1, 22050));
+}
+
+#[test]
+fn aac_sample_rate_change() {
+  let mut stream =
+    std::fs::read("tests/samples/Simbai & Elke Bay - Energy.aac").expect("Error reading file");
+  let mono_data = std::fs::read("tests/samples/sine-mono-22050.aac").expect("Error reading file");
+  stream.extend_from_slice(&mono_data);
+
+  // read frame by frame like rodio, checking the format before each frame
+  let mut decoder = Decoder::new_aac(Cursor::new(stream));
+  let mut sample_rates = Vec::new();
+  let mut sample_count = 0;
+  loop {
+    let frame_len = decoder.current_frame_len().expect("Missing frame length");
+    if frame_len == 0 {
+      break;
+    }
+    sample_rates.push(decoder.sample_rate());
+    sample_count += (&mut decoder).take(frame_len).count();
+  }
+  assert!(decoder.iter_error.is_none());
+  assert_eq!(sample_count, 636928 + 47104);
+  let mut expected = vec![44100; 636928 / 2048];
+  expected.resize(expected.len() + 47104 / 1024, 22050);
+  assert_eq!(sample_rates, expected);
+}
+
+#[test]
+fn aac_channel_change() {
+  let mut stream =
+    std::fs::read("tests/samples/Simbai & Elke Bay - Energy.aac").expect("Error reading file");
+  let mono_data = std::fs::read("tests/samples/sine-mono-22050.aac").expect("Error reading file");
+  stream.extend_from_slice(&mono_data);
+
+  // chunks end at the format change, including when it's at the end of a
+  // chunk
+  let builders = [
+    DecoderBuilder::new().batch_mode(),
+    DecoderBuilder::new().frames_per_chunk(1),
+  ];
+  for builder in builders {
+    let mut decoder = builder
+      .open_aac(Cursor::new(stream.clone()))
+      .expect("Error creating decoder");
+    let mut formats = Vec::new();
+    loop {
+      let format = (decoder.channels(), decoder.sample_rate());
+      let mut pcm = Vec::new();
+      let len = decoder.next_chunk(&mut pcm).expect("Error decoding chunk");
+      if len == 0 {
+        break;
+      }
+      match formats.last_mut() {
+        Some((last_format, last_len)) if *last_format == format => *last_len += len,
+        _ => formats.push((format, len)),
+      }
+    }
+    assert_eq!(formats, [((2, 44100), 636928), ((1, 22050), 47104)]);
+  }
+
+  // mono frames are mixed to stereo
+  let mut decoder = DecoderBuilder::new()
+    .fixed_channels()
+    .open_aac(Cursor::new(stream))
+    .expect("Error creating decoder");
+  let mut sample_count = 0;
+  while let Some(frame_len) = decoder.current_frame_len().filter(|&len| len > 0) {
+    assert_eq!(decoder.channels(), 2);
+    sample_count += (&mut decoder).take(frame_len).count();
+  }
+  assert!(decoder.iter_error.is_none());
+  assert_eq!(sample_count, 636928 + 47104 * 2);
+}
+
+#[test]
+fn downmix_matrix() {
+  let path = "tests/samples/sine-5.1.aac";
+  let full: Vec<i16> = Decoder::from_path(path).unwrap().collect();
+  // front left and right only, with the right channel at half volume
+  let mut coefficients = vec![0.0; 12];
+  coefficients[0] = 1.0;
+  coefficients[6 + 1] = 0.5;
+  let matrix = DownmixMatrix::new(6, 2, coefficients).expect("Invalid matrix");
+  let decoder = Decoder::builder()
+    .downmix_matrix(matrix)
+    .open_path(path)
+    .expect("Error creating decoder");
+  assert_eq!(decoder.channels(), 2);
+  let samples: Vec<i16> = decoder.collect();
+  assert_eq!(samples.len(), full.len() / 3);
+  for (mixed, frame) in samples.chunks(2).zip(full.chunks(6)) {
+    assert_eq!(mixed[0], frame[0]);
+    assert!((mixed[1] as f32 - frame[1] as f32 / 2.0).abs() <= 0.5);
+  }
+
+  // frames with other channel counts are left as is
+  let matrix = DownmixMatrix::new(1, 2, vec![1.0, 1.0]).expect("Invalid matrix");
+  let decoder = Decoder::builder().downmix_matrix(matrix).open_path(path);
+  assert_eq!(decoder.expect("Error creating decoder").channels(), 6);
+
+  assert!(DownmixMatrix::new(6, 2, vec![1.0; 6]).is_err());
+  assert!(DownmixMatrix::new(9, 2, vec![1.0; 18]).is_err());
+}
+
+#[test]
+fn drc() {
+  let drc = Drc {
+    target_level: Some(-24.0),
+    boost: 0.5,
+    heavy_compression: true,
+    effect: DrcEffect::LateNight,
+    metadata_profile: MetadataProfile::Dvb,
+    ..Drc::default()
+  };
+  let path = "tests/samples/Simbai & Elke Bay - Energy.aac";
+  let decoder = Decoder::builder().drc(drc).open_path(path);
+  let decoder = decoder.expect("Error creating decoder");
+  assert_eq!(decoder.stream_info().drc_reference_level, None);
+  assert_eq!(decoder.count(), 636928);
+
+  let decoder = Decoder::builder()
+    .drc(drc)
+    .downmix_stereo(true)
+    .open_path("tests/samples/sine-5.1.aac");
+  let mut decoder = decoder.expect("Error creating decoder");
+  assert_eq!(decoder.channels(), 2);
+  assert!(decoder.by_ref().count() > 0);
+  assert!(decoder.iter_error.is_none());
+
+  let invalid = [
+    Drc {
+      target_level: Some(-40.0),
+      ..Drc::default()
+    },
+    Drc {
+      cut: 1.5,
+      ..Drc::default()
+    },
+  ];
+  for drc in invalid {
+    let result = Decoder::builder().drc(drc).open_path(path);
+    assert!(matches!(result, Err(Error::InvalidConfig(_))));
+  }
+}
+
+#[test]
+fn surround_7_1() {
+  use rodio::Source;
+  let path = "tests/samples/sine-7.1.aac";
+  let mut decoder = Decoder::from_path(path).expect("Error creating decoder");
+  assert_eq!(Source::channels(&decoder), 8);
+  let info = decoder.stream_info();
+  assert_eq!(info.channel_config, 7);
+  assert_eq!(info.channel_types[3], ChannelType::Lfe);
+  let mut pcm = Vec::new();
+  while decoder.next_chunk(&mut pcm).expect("Error decoding chunk") > 0 {
+    assert_eq!(decoder.channels(), 8);
+  }
+  assert_eq!(pcm.len() % (8 * 1024), 0);
+  // every channel but LFE has audio
+  for channel in (0..8).filter(|&channel| channel != 3) {
+    let peak = pcm.iter().skip(channel).step_by(8).map(|s| s.abs()).max();
+    assert!(peak > Some(5000), "channel {} is silent", channel);
+  }
+
+  let decoder = Decoder::builder()
+    .downmix_stereo(true)
+    .open_path(path)
+    .expect("Error creating decoder");
+  assert_eq!(decoder.channels(), 2);
+  assert_eq!(decoder.count(), pcm.len() / 4);
+}
+
+#[cfg(feature = "rodio-0_19")]
+#[test]
+fn rodio_0_19_source() {
+  use rodio_0_19::Source;
+  let path = "tests/samples/Simbai & Elke Bay - Energy.m4a";
+  let mut decoder = Decoder::from_path(path).expect("Error creating decoder");
+  let source = &mut decoder;
+  assert_eq!(source.channels(), 2);
+  assert_eq!(source.sample_rate(), 44100);
+  let samples: Vec<i16> = source.take_duration(Duration::from_secs(1)).collect();
+  assert!(samples.len().abs_diff(44100 * 2) < 16);
+  let playlist = redlux::playlist::Playlist::<File>::new(Vec::new());
+  assert_eq!(Source::current_frame_len(&playlist), Some(0));
+}
+
+#[cfg(feature = "rodio-0_21")]
+#[test]
+fn rodio_0_21_source() {
+  use rodio_0_21::Source;
+  let path = "tests/samples/Simbai & Elke Bay - Energy.m4a";
+  let decoder = Decoder::from_path(path).expect("Error creating decoder");
+  let expected: Vec<f32> = decoder.take(4096).map(|s| s as f32 / 32768.0).collect();
+  let decoder = Decoder::from_path(path).expect("Error creating decoder");
+  let source = decoder.into_f32();
+  assert_eq!(source.channels(), 2);
+  assert_eq!(source.sample_rate(), 44100);
+  let samples: Vec<f32> = source.amplify(1.0).take(4096).collect();
+  assert_eq!(samples, expected);
+}
+
+#[cfg(feature = "kira")]
+#[test]
+fn kira_decoder() {
+  use kira::sound::streaming::Decoder as _;
+  let path = "tests/samples/Simbai & Elke Bay - Energy.m4a";
+  let samples: Vec<i16> = Decoder::from_path(path).unwrap().collect();
+  let decoder = Decoder::from_path(path).expect("Error creating decoder");
+  let mut kira_decoder = decoder.into_kira().expect("Error creating kira decoder");
+  assert_eq!(kira_decoder.sample_rate(), 44100);
+  assert_eq!(kira_decoder.num_frames(), 638976 / 2);
+  let mut frames = Vec::new();
+  while frames.len() < kira_decoder.num_frames() {
+    frames.extend(kira_decoder.decode().expect("Error decoding"));
+  }
+  assert_eq!(frames.len(), 638976 / 2);
+  assert_eq!(frames[1000].left, samples[2000] as f32 / 32768.0);
+  assert_eq!(frames[1000].right, samples[2001] as f32 / 32768.0);
+
+  assert_eq!(kira_decoder.seek(44100).expect("Error seeking"), 44100);
+  let frame = kira_decoder.decode().expect("Error decoding")[0];
+  assert_eq!(frame.left, samples[44100 * 2] as f32 / 32768.0);
+}
+
+#[test]
+fn controlled_decoder() {
+  use rodio::Source;
+  let path = "tests/samples/Simbai & Elke Bay - Energy.m4a";
+  let samples: Vec<i16> = Decoder::from_path(path).unwrap().collect();
+  let decoder = Decoder::from_path(path).expect("Error creating decoder");
+  let (mut controlled, controller) = decoder.into_controlled();
+  assert_eq!(controlled.channels(), 2);
+  assert_eq!(
+    controlled.by_ref().take(1001).collect::<Vec<_>>(),
+    samples[..1001]
+  );
+
+  // the seek waits for the end of the frame, mid-way through which rodio
+  // was told its length
+  controller.seek(Duration::from_secs(1));
+  let frame_left = controlled.current_frame_len().unwrap();
+  let frame: Vec<i16> = controlled.by_ref().take(frame_left).collect();
+  assert_eq!(frame, samples[1001..1001 + frame_left]);
+  let start = 44100 * 2;
+  let seeked: Vec<i16> = controlled.by_ref().take(1000).collect();
+  assert_eq!(seeked, samples[start..start + 1000]);
+
+  let from_thread = controller.clone();
+  thread::spawn(move || from_thread.set_gain(0.0))
+    .join()
+    .unwrap();
+  assert!(controlled.by_ref().take(1000).all(|sample| sample == 0));
+
+  controller.stop();
+  let frame_left = controlled.current_frame_len().unwrap();
+  assert_eq!(controlled.by_ref().count(), frame_left);
+  assert!(controlled.is_stopped());
+  assert_eq!(controlled.current_frame_len(), Some(0));
+  assert!(controlled.decoder().iter_error.is_none());
+}
+
+#[test]
+fn completion() {
+  let path = "tests/samples/Simbai & Elke Bay - Energy.m4a";
+  let mut decoder = Decoder::from_path(path).expect("Error creating decoder");
+  let completion = decoder.completion();
+  let waiter = completion.clone();
+  let waiting = thread::spawn(move || waiter.wait());
+  assert_eq!(completion.get(), None);
+  assert_eq!(decoder.by_ref().take(1000).count(), 1000);
+  assert!(!completion.is_finished());
+  assert_eq!(decoder.count(), 638976 - 1000);
+  assert_eq!(completion.get(), Some(Completed::EndOfStream));
+  assert_eq!(waiting.join().unwrap(), Completed::EndOfStream);
+
+  let data = std::fs::read(path).expect("Error reading file");
+  let reader = Connection {
+    data: Cursor::new(data.clone()),
+    bytes_left: 200000,
+  };
+  let mut decoder = Decoder::builder()
+    .open(reader, data.len() as u64)
+    .expect("Error creating decoder");
+  let completion = decoder.completion();
+  assert!(decoder.by_ref().count() < 638976);
+  let message = decoder.iter_error.take().unwrap().to_string();
+  assert_eq!(completion.get(), Some(Completed::Error(message)));
+  let timeout = Duration::from_millis(10);
+  assert!(completion.wait_timeout(timeout).is_some());
+  assert_eq!(Completion::default().wait_timeout(timeout), None);
+
+  // stopped through a controller, and dropped before the end
+  let mut decoder = Decoder::from_path(path).expect("Error creating decoder");
+  let completion = decoder.completion();
+  let (mut controlled, controller) = decoder.into_controlled();
+  assert_eq!(controlled.by_ref().take(1000).count(), 1000);
+  controller.stop();
+  // the rest of the frame is returned first
+  assert!(controlled.by_ref().count() < 2048);
+  assert_eq!(completion.wait(), Completed::Stopped);
+  let mut decoder = Decoder::from_path(path).expect("Error creating decoder");
+  let completion = decoder.completion();
+  assert_eq!(decoder.by_ref().take(1000).count(), 1000);
+  drop(decoder);
+  assert_eq!(completion.wait(), Completed::Stopped);
+}
+
+#[test]
+fn stream_events() {
+  let mut stream =
+    std::fs::read("tests/samples/Simbai & Elke Bay - Energy.aac").expect("Error reading file");
+  stream.extend_from_slice(&[0; 10]);
+  let mono_data = std::fs::read("tests/samples/sine-mono-22050.aac").expect("Error reading file");
+  stream.extend_from_slice(&mono_data);
+
+  let mut decoder = Decoder::new_aac(Cursor::new(stream));
+  let events = decoder.subscribe();
+  assert_eq!((&mut decoder).count(), 636928 + 47104);
+  let events: Vec<Event> = events.try_iter().collect();
+  assert_eq!(
+    events,
+    [
+      Event::Resync { skipped_bytes: 10 },
+      Event::SampleRateChanged(22050),
+      Event::ChannelsChanged(1),
+      Event::EndOfStream,
+    ]
+  );
+}
+
+#[test]
+fn frame_timestamps() {
+  let path = "tests/samples/Simbai & Elke Bay - Energy.m4a";
+  let file = File::open(path).expect("Error opening file");
+  let size = file.metadata().expect("Error getting file metadata").len();
+  let mut mp4 = Decoder::new_mpeg4(BufReader::new(file), size).expect("Error creating decoder");
+  let file =
+    File::open("tests/samples/Simbai & Elke Bay - Energy.aac").expect("Error opening file");
+  let mut aac = Decoder::new_aac(BufReader::new(file));
+
+  for decoder in [&mut mp4, &mut aac] {
+    // a partly consumed frame starts later
+    assert_eq!((&mut *decoder).take(1000).count(), 1000);
+    let frame = decoder.next_frame().expect("Error decoding frame");
+    let frame = frame.expect("Missing frame");
+    assert_eq!(frame.pts, Duration::from_nanos(500 * 1_000_000_000 / 44100));
+    assert_eq!(frame.samples.len(), 1048);
+
+    let mut frame_count = 1;
+    let mut samples = Vec::new();
+    while let Some(frame) = decoder.next_frame().expect("Error decoding frame") {
+      let pts = Duration::from_nanos(frame_count * 1024 * 1_000_000_000 / 44100);
+      assert_eq!(frame.pts, pts);
+      assert_eq!((frame.channels, frame.sample_rate), (2, 44100));
+      samples.extend_from_slice(frame.samples);
+      frame_count += 1;
+    }
+    assert_eq!(samples.len() % 2048, 0);
+  }
+}
+
+#[test]
+fn mp4_timing_tables() {
+  let path = "tests/samples/Simbai & Elke Bay - Energy.m4a";
+  let file = File::open(path).expect("Error opening file");
+  let size = file.metadata().expect("Error getting file metadata").len();
+  let decoder = Decoder::new_mpeg4(BufReader::new(file), size).expect("Error creating decoder");
+  assert_eq!(decoder.timescale(), Some(44100));
+  let durations = decoder.sample_durations().expect("Missing durations");
+  assert_eq!(durations, vec![1024; 312]);
+
+  let file =
+    File::open("tests/samples/Simbai & Elke Bay - Energy.aac").expect("Error opening file");
+  let decoder = Decoder::new_aac(BufReader::new(file));
+  assert_eq!(decoder.timescale(), None);
+  assert_eq!(decoder.sample_durations(), None);
+}
+
+#[test]
+fn seek() {
+  let path = "tests/samples/Simbai & Elke Bay - Energy.m4a";
+  let file = File::open(path).expect("Error opening file");
+  let size = file.metadata().expect("Error getting file metadata").len();
+  let open_mp4 = || {
+    let file = File::open(path).expect("Error opening file");
+    Decoder::new_mpeg4(BufReader::new(file), size).expect("Error creating decoder")
+  };
+  let path = "tests/samples/Simbai & Elke Bay - Energy.aac";
+  let open_aac = || {
+    Decoder::new_aac(BufReader::new(
+      File::open(path).expect("Error opening file"),
+    ))
+  };
+
+  let full: Vec<i16> = open_mp4().collect();
+  let mut decoder = open_mp4();
+  decoder.seek(Duration::from_secs(5)).expect("Error seeking");
+  let samples: Vec<i16> = decoder.by_ref().collect();
+  assert_eq!(samples, full[5 * 44100 * 2..]);
+  decoder
+    .seek(Duration::from_secs(60))
+    .expect("Error seeking");
+  assert_eq!(decoder.next(), None);
+
+  // the AAC file uses noise substitution, so only the length is exact
+  let full_len = open_aac().count();
+  let mut decoder = open_aac();
+  decoder.seek(Duration::from_secs(5)).expect("Error seeking");
+  let frame = decoder.next_frame().expect("Error decoding frame");
+  let pts = frame.expect("Missing frame").pts;
+  assert!(Duration::from_secs(5) - pts < Duration::from_micros(1));
+  decoder.seek(Duration::from_secs(5)).expect("Error seeking");
+  assert_eq!(decoder.count(), full_len - 5 * 44100 * 2);
+
+  let (start, end) = (Duration::from_secs(1), Duration::from_secs(2));
+  let mut decoder = open_mp4();
+  decoder.decode_range(start, end).expect("Error seeking");
+  let samples: Vec<i16> = decoder.collect();
+  assert_eq!(samples, full[44100 * 2..2 * 44100 * 2]);
+  let mut decoder = open_aac();
+  decoder.decode_range(start, end).expect("Error seeking");
+  assert_eq!(decoder.count(), 44100 * 2);
+
+  let path = "tests/samples/Simbai & Elke Bay - Energy.m4a";
+  let file = BufReader::new(File::open(path).expect("Error opening file"));
+  let decoder = Decoder::open_at(file, size, Duration::from_secs(5));
+  let samples: Vec<i16> = decoder.expect("Error creating decoder").collect();
+  assert_eq!(samples, full[5 * 44100 * 2..]);
+  let decoder = DecoderBuilder::new()
+    .start_at(Duration::from_secs(5))
+    .open_path("tests/samples/Simbai & Elke Bay - Energy.aac");
+  let decoder = decoder.expect("Error creating decoder");
+  assert_eq!(decoder.count(), full_len - 5 * 44100 * 2);
+
+  let mut decoder = open_mp4();
+  let start = 3 * 44100 + 17;
+  let samples = decoder.extract(start, 1000).expect("Error extracting");
+  assert_eq!(
+    samples,
+    full[start as usize * 2..(start as usize + 1000) * 2]
+  );
+  let samples = decoder.extract(0, 5).expect("Error extracting");
+  assert_eq!(samples, full[..10]);
+  let end = full.len() as u64 / 2;
+  let samples = decoder.extract(end - 10, 1000).expect("Error extracting");
+  assert_eq!(samples, full[full.len() - 20..]);
+  let samples = decoder
+    .extract(end - 10, usize::MAX)
+    .expect("Error extracting");
+  assert_eq!(samples, full[full.len() - 20..]);
+}
+
+#[test]
+fn trim_start_end() {
+  let path = "tests/samples/Simbai & Elke Bay - Energy.m4a";
+  let full: Vec<i16> = Decoder::from_path(path).unwrap().collect();
+  // tracks in a cue sheet, with the last one playing to the end
+  let cues = [
+    Duration::ZERO,
+    Duration::from_millis(1500),
+    Duration::from_secs(4),
+  ];
+  let mut joined = Vec::new();
+  for (i, &start) in cues.iter().enumerate() {
+    let mut builder = DecoderBuilder::new().trim_start(start);
+    if let Some(&end) = cues.get(i + 1) {
+      builder = builder.trim_end(end);
+    }
+    let decoder = builder.open_path(path).expect("Error creating decoder");
+    let remaining = decoder.remaining_samples();
+    let samples: Vec<i16> = decoder.collect();
+    assert_eq!(remaining, Some(samples.len() as u64));
+    joined.extend(samples);
+  }
+  assert_eq!(joined, full);
+
+  let decoder = DecoderBuilder::new()
+    .trim_end(Duration::from_secs(1))
+    .open_path("tests/samples/Simbai & Elke Bay - Energy.aac");
+  assert_eq!(decoder.expect("Error creating decoder").count(), 44100 * 2);
+
+  let builder = DecoderBuilder::new()
+    .trim_start(Duration::from_secs(2))
+    .trim_end(Duration::from_secs(1));
+  assert!(matches!(
+    builder.open_path(path),
+    Err(Error::InvalidConfig(_))
+  ));
+  let builder = DecoderBuilder::new()
+    .trim_silence(-60.0)
+    .trim_start(Duration::from_secs(1));
+  assert!(matches!(
+    builder.open_path(path),
+    Err(Error::InvalidConfig(_))
+  ));
+}
+
+#[test]
+fn preview() {
+  let path = "tests/samples/Simbai & Elke Bay - Energy.m4a";
+  let full: Vec<i16> = Decoder::from_path(path).unwrap().collect();
+  let data = std::fs::read(path).expect("Error reading file");
+  let size = data.len() as u64;
+  let decoder = Decoder::preview(Cursor::new(data), size, Duration::from_secs(2));
+  let decoder = decoder.expect("Error creating decoder");
+  assert_eq!(decoder.metadata(), None);
+  let samples: Vec<i16> = decoder.collect();
+  assert_eq!(samples, full[..2 * 44100 * 2]);
+
+  let decoder = DecoderBuilder::new()
+    .preview(Duration::from_secs(2))
+    .open_path("tests/samples/Simbai & Elke Bay - Energy.aac");
+  let decoder = decoder.expect("Error creating decoder");
+  assert_eq!(decoder.count(), 2 * 44100 * 2);
+
+  let decoder = DecoderBuilder::new()
+    .preview(Duration::from_secs(2))
+    .trim_start(Duration::from_secs(1))
+    .open_path(path);
+  let samples: Vec<i16> = decoder.expect("Error creating decoder").collect();
+  assert_eq!(samples, full[44100 * 2..2 * 44100 * 2]);
+}
+
+#[test]
+fn total_duration() {
+  let path = "tests/samples/Simbai & Elke Bay - Energy.m4a";
+  let mut decoder = Decoder::from_path(path).expect("Error creating decoder");
+  let duration = Duration::from_nanos(638976 / 2 * 1_000_000_000 / 44100);
+  assert_eq!(decoder.total_duration(), Some(duration));
+  let mvhd = decoder.mp4().unwrap().moov.mvhd.clone();
+  let mvhd_duration = Duration::from_secs_f64(mvhd.duration as f64 / mvhd.timescale as f64);
+  assert_ne!(decoder.total_duration(), Some(mvhd_duration));
+
+  decoder
+    .decode_range(Duration::from_secs(1), Duration::from_secs(3))
+    .unwrap();
+  assert_eq!(decoder.total_duration(), Some(Duration::from_secs(2)));
+  decoder
+    .decode_range(Duration::from_secs(5), Duration::from_secs(60))
+    .unwrap();
+  assert_eq!(
+    decoder.total_duration(),
+    Some(duration - Duration::from_secs(5))
+  );
+
+  let decoder = DecoderBuilder::new().preview(Duration::from_secs(2));
+  let decoder = decoder.open_path(path).expect("Error creating decoder");
+  assert_eq!(decoder.total_duration(), Some(Duration::from_secs(2)));
+}
+
+#[test]
+fn long_timescale() {
+  let path = "tests/samples/Simbai & Elke Bay - Energy.m4a";
+  let mut data = std::fs::read(path).expect("Error reading file");
+  let full: Vec<i16> = Decoder::from_path(path).unwrap().collect();
+  // 90000 ticks per sample, so the track's duration doesn't fit in u32
+  let scale: u32 = 90_000;
+  let mdhd = data.windows(4).position(|w| w == b"mdhd").unwrap();
+  data[mdhd + 16..mdhd + 20].copy_from_slice(&(44100 * scale).to_be_bytes());
+  data[mdhd + 20..mdhd + 24].copy_from_slice(&u32::MAX.to_be_bytes());
+  let stts = data.windows(4).position(|w| w == b"stts").unwrap();
+  data[stts + 16..stts + 20].copy_from_slice(&(1024 * scale).to_be_bytes());
+  let size = data.len() as u64;
+
+  let mut decoder = Decoder::new_mpeg4(Cursor::new(data), size).expect("Error creating decoder");
+  assert_eq!(decoder.timescale(), Some(44100 * scale));
+  let duration = Duration::from_nanos(638976 / 2 * 1_000_000_000 / 44100);
+  assert_eq!(decoder.total_duration(), Some(duration));
+  let samples: Vec<i16> = decoder.by_ref().collect();
+  assert_eq!(samples, full);
+
+  decoder.seek(Duration::from_secs(5)).unwrap();
+  let frame = decoder.next_frame().unwrap().unwrap();
+  let mut original = Decoder::from_path(path).unwrap();
+  original.seek(Duration::from_secs(5)).unwrap();
+  let original_frame = original.next_frame().unwrap().unwrap();
+  assert_eq!(frame.pts, original_frame.pts);
+  assert_eq!(frame.samples, original_frame.samples);
+}
+
+#[test]
+fn estimate_duration() {
+  let path = "tests/samples/Simbai & Elke Bay - Energy.aac";
+  let exact = Duration::from_nanos(636928 / 2 * 1_000_000_000 / 44100);
+  let mut decoder = Decoder::from_path(path).expect("Error creating decoder");
+  let estimate = decoder.estimate_duration().expect("Error estimating");
+  assert!(!estimate.exact);
+  assert!(estimate.min <= estimate.duration && estimate.duration <= estimate.max);
+  assert!(estimate.min <= exact && exact <= estimate.max);
+  let error = estimate.duration.as_secs_f64() / exact.as_secs_f64() - 1.0;
+  assert!(error.abs() < 0.05);
+  assert_eq!(decoder.total_duration(), None);
+  assert_eq!(decoder.count(), 636928);
+
+  // shorter than the sampled frames
+  let path = "tests/samples/sine-he-aac-v1.aac";
+  let mut decoder = Decoder::from_path(path).expect("Error creating decoder");
+  let estimate = decoder.estimate_duration().expect("Error estimating");
+  assert!(estimate.exact);
+  assert_eq!(decoder.total_duration(), Some(estimate.duration));
+
+  let path = "tests/samples/Simbai & Elke Bay - Energy.m4a";
+  let mut decoder = Decoder::from_path(path).expect("Error creating decoder");
+  let estimate = decoder.estimate_duration().expect("Error estimating");
+  assert!(estimate.exact);
+  assert_eq!(Some(estimate.duration), decoder.total_duration());
+}
+
+#[test]
+fn looping() {
+  let path = "tests/samples/Simbai & Elke Bay - Energy.m4a";
+  let file = File::open(path).expect("Error opening file");
+  let size = file.metadata().expect("Error getting file metadata").len();
+  let open = || {
+    let file = File::open(path).expect("Error opening file");
+    Decoder::new_mpeg4(BufReader::new(file), size).expect("Error creating decoder")
+  };
+  let full: Vec<i16> = open().collect();
+  let info = open().gapless_info().expect("Missing gapless info");
+  assert_eq!(info.encoder_delay, 2112);
+  assert_eq!(info.padding, 263);
+  assert_eq!(info.samples, 317113);
+
+  let decoder = LoopingDecoder::new(open(), Duration::from_secs(1));
+  let decoder = decoder.expect("Error creating looping decoder");
+  let audio = &full[2112 * 2..(2112 + 317113) * 2];
+  let looped = &audio[44100 * 2..];
+  let samples: Vec<i16> = decoder.take(audio.len() + looped.len() * 2).collect();
+  assert_eq!(samples, [audio, looped, looped].concat());
+}
+
+#[test]
+fn gapless_playlist() {
+  let m4a_path = "tests/samples/Simbai & Elke Bay - Energy.m4a";
+  let aac_path = "tests/samples/Simbai & Elke Bay - Energy.aac";
+  let open = |path| File::open(path).expect("Error opening file");
+  let size = open(m4a_path)
+    .metadata()
+    .expect("Error getting metadata")
+    .len();
+  let m4a: Vec<i16> = Decoder::new_mpeg4(open(m4a_path), size)
+    .expect("Error creating decoder")
+    .collect();
+  let aac: Vec<i16> = Decoder::new_aac(open(aac_path)).collect();
+  let m4a = &m4a[2112 * 2..(2112 + 317113) * 2];
+
+  let mut playlist = Playlist::new(vec![
+    Input::Mpeg4(open(m4a_path), size),
+    Input::Mpeg4(open(aac_path), 1000),
+    Input::Aac(open(aac_path)),
+    Input::Mpeg4(open(m4a_path), size),
+  ]);
+  let samples: Vec<i16> = playlist.by_ref().collect();
+  assert_eq!(samples, [m4a, &aac, m4a].concat());
+  assert_eq!(playlist.current_index(), None);
+  let errors: Vec<usize> = playlist.errors.iter().map(|(index, _)| *index).collect();
+  assert_eq!(errors, [1]);
+}
+
+/// Add a freeform iTunes tag to an MP4 file whose moov box is at the end
+fn add_itunes_tag(data: &[u8], name: &str, value: &str) -> Vec<u8> {
+  let item = [
+    mp4_box(b"mean", b"\0\0\0\0com.apple.iTunes"),
+    mp4_box(b"name", &[b"\0\0\0\0", name.as_bytes()].concat()),
+    mp4_box(b"data", &[b"\0\0\0\x01\0\0\0\0", value.as_bytes()].concat()),
+  ];
+  add_ilst_item(data, &mp4_box(b"----", &item.concat()))
+}
+
+/// Add a tag to an MP4 file with moov at the end, with a data box of the
+/// given type
+fn add_tag(data: &[u8], atom: &[u8; 4], data_type: u8, value: &[u8]) -> Vec<u8> {
+  let header = [0, 0, 0, data_type, 0, 0, 0, 0];
+  let item = mp4_box(b"data", &[&header[..], value].concat());
+  add_ilst_item(data, &mp4_box(atom, &item))
+}
+
+fn mp4_box(box_type: &[u8; 4], content: &[u8]) -> Vec<u8> {
+  let size = content.len() as u32 + 8;
+  [&size.to_be_bytes()[..], box_type, content].concat()
+}
+
+/// Add an item to the ilst box of an MP4 file with moov at the end
+fn add_ilst_item(data: &[u8], item: &[u8]) -> Vec<u8> {
+  let moov_start = data
+    .windows(4)
+    .rposition(|w| w == b"moov")
+    .expect("Missing moov")
+    - 4;
+  let moov = &data[moov_start..];
+  let meta = find_box(moov, b"meta");
+  let ilst = [find_box(meta, b"ilst"), item].concat();
+  let meta = [&meta[..4], &replace_box(&meta[4..], &[b"ilst"], &ilst)].concat();
+  let moov = replace_box(moov, &[b"moov", b"udta", b"meta"], &meta);
+  [&data[..moov_start], &moov].concat()
+}
+
+#[cfg(feature = "tags")]
+#[test]
+fn tag_writer() {
+  use redlux::tags::{Artwork, ArtworkFormat, TagWriter};
+  use redlux::GaplessInfo;
+
+  let path = "tests/samples/Simbai & Elke Bay - Energy.m4a";
+  let data = std::fs::read(path).expect("Error reading file");
+  let original: Vec<i16> = Decoder::from_bytes(&data[..]).unwrap().collect();
+  let gapless = GaplessInfo {
+    encoder_delay: 1024,
+    padding: 500,
+    samples: 317000,
+  };
+  let artwork = Artwork {
+    format: ArtworkFormat::Jpeg,
+    data: vec![0xFF; 200_000],
+  };
+  let writer = TagWriter::new()
+    .title(Some("New title"))
+    .artist(Some("New artist"))
+    .album(None)
+    .track_number(Some((3, 12)))
+    .artwork(Some(&artwork))
+    .freeform("Ripper", Some("redlux"))
+    .gapless_info(Some(gapless));
+  let check = |data: &[u8]| {
+    let decoder = Decoder::from_bytes(data).expect("Error creating decoder");
+    let metadata = decoder.metadata().expect("Missing metadata").clone();
+    assert_eq!(metadata.title.as_deref(), Some("New title"));
+    assert_eq!(metadata.artist.as_deref(), Some("New artist"));
+    assert_eq!(metadata.album, None);
+    assert_eq!(metadata.track_number, Some(3));
+    assert_eq!(metadata.track_total, Some(12));
+    assert_eq!(metadata.freeform["Ripper"], "redlux");
+    assert_eq!(decoder.gapless_info(), Some(gapless));
+    let covr = find_box(find_box(data, b"ilst"), b"covr");
+    assert_eq!(&find_box(covr, b"data")[..4], &[0, 0, 0, 13]);
+    assert_eq!(decoder.collect::<Vec<i16>>(), original);
+  };
+
+  // moov is the last box, so it can grow in place
+  let mut file = Cursor::new(data.clone());
+  assert!(writer
+    .write_in_place(&mut file)
+    .expect("Error writing tags"));
+  check(file.get_ref());
+  assert_eq!(&file.get_ref()[..115258], &data[..115258]);
+
+  // shrinking in place leaves a free box
+  let mut shrunk = Cursor::new(file.into_inner());
+  let remove = TagWriter::new().artwork(None);
+  assert!(remove
+    .write_in_place(&mut shrunk)
+    .expect("Error writing tags"));
+  let shrunk = shrunk.into_inner();
+  assert!(shrunk.windows(4).any(|w| w == b"free"));
+  let decoder = Decoder::from_bytes(&shrunk[..]).expect("Error creating decoder");
+  assert_eq!(
+    decoder.metadata().unwrap().title.as_deref(),
+    Some("New title")
+  );
+  assert!(!find_box(&shrunk, b"ilst").windows(4).any(|w| w == b"covr"));
+
+  // with moov before mdat, growing it moves the samples
+  let moov = &data[115258..];
+  let mut faststart = [&data[..28], moov, &data[28..115258]].concat();
+  let stco = 28 + moov.windows(4).position(|w| w == b"stco").unwrap() + 4;
+  let count = u32::from_be_bytes(faststart[stco + 4..stco + 8].try_into().unwrap()) as usize;
+  for i in 0..count {
+    let entry = stco + 8 + i * 4;
+    let offset = u32::from_be_bytes(faststart[entry..entry + 4].try_into().unwrap());
+    let shifted = offset + moov.len() as u32;
+    faststart[entry..entry + 4].copy_from_slice(&shifted.to_be_bytes());
+  }
+  let faststart_samples: Vec<i16> = Decoder::from_bytes(&faststart[..]).unwrap().collect();
+  assert_eq!(faststart_samples, original);
+  let unchanged = faststart.clone();
+  assert!(!writer.write_in_place(Cursor::new(&mut faststart)).unwrap());
+  assert_eq!(faststart, unchanged);
+  let mut output = Vec::new();
+  writer
+    .rewrite(Cursor::new(&faststart), &mut output)
+    .expect("Error rewriting file");
+  check(&output);
+}
+
+#[test]
+fn drm_protection() {
+  let path = "tests/samples/Simbai & Elke Bay - Energy.m4a";
+  let mut data = std::fs::read(path).expect("Error reading file");
+  let size = data.len() as u64;
+  let protected = redlux::is_drm_protected(Cursor::new(&data), size);
+  assert!(!protected.expect("Error checking protection"));
+  let aac = std::fs::read("tests/samples/Simbai & Elke Bay - Energy.aac").unwrap();
+  let protected = redlux::is_drm_protected(Cursor::new(&aac), aac.len() as u64);
+  assert!(!protected.expect("Error checking protection"));
+
+  let stsd = data.windows(4).rposition(|w| w == b"stsd").unwrap();
+  let entry = stsd + data[stsd..].windows(4).position(|w| w == b"mp4a").unwrap();
+  data[entry..entry + 4].copy_from_slice(b"drms");
+  let protected = redlux::is_drm_protected(Cursor::new(&data), size);
+  assert!(protected.expect("Error checking protection"));
+}
+
+#[cfg(feature = "mp3")]
+#[test]
+fn mp3_in_mp4() {
+  let path = "tests/samples/sine-mp3.m4a";
+  let mut decoder = Decoder::from_path(path).expect("Error creating decoder");
+  assert_eq!(decoder.channels(), 2);
+  assert_eq!(decoder.sample_rate(), 44100);
+  assert_eq!(decoder.samples_per_frame(), 1152);
+  let pcm: Vec<i16> = decoder.by_ref().collect();
+  assert_eq!(decoder.iter_error.take().map(|e| e.to_string()), None);
+  assert_eq!(pcm.len(), 38 * 1152 * 2);
+  let peak = pcm.iter().map(|s| s.abs()).max();
+  assert!(peak > Some(5000));
+
+  let file = File::open(path).expect("Error opening file");
+  let size = file.metadata().expect("Error getting file metadata").len();
+  let mut decoder =
+    Decoder::open_at(file, size, Duration::from_millis(500)).expect("Error creating decoder");
+  let skipped = 44100 / 2 * 2;
+  assert_eq!(decoder.by_ref().count(), pcm.len() - skipped);
+
+  let decoder = Decoder::builder()
+    .downmix_stereo(true)
+    .fixed_channels()
+    .open_path(path)
+    .expect("Error creating decoder");
+  assert_eq!(decoder.count(), pcm.len());
+
+  let tracks = redlux::probe::probe_path(path).unwrap();
+  assert_eq!(tracks[0].codec, "mp4a.6B");
+  assert!(tracks[0].decodable);
+}
+
+#[cfg(not(feature = "mp3"))]
+#[test]
+fn mp3_in_mp4_unsupported() {
+  let result = Decoder::from_path("tests/samples/sine-mp3.m4a");
+  assert!(matches!(result, Err(Error::UnsupportedCodec(codec)) if codec == "mp4a.6B"));
+}
+
+#[test]
+fn stored_loudness() {
+  let path = "tests/samples/Simbai & Elke Bay - Energy.m4a";
+  let data = std::fs::read(path).expect("Error reading file");
+  let open = |data: &Vec<u8>| Decoder::from_bytes(data.clone()).expect("Error creating decoder");
+  assert_eq!(open(&data).loudness_info(), None);
+
+  // -1 dBFS sample peak, -0.5 dBTP true peak, -14 LUFS program loudness and
+  // -20 LUFS anchor loudness
+  let tlou = [
+    0, 0, 0, 0, 0, 0, 0x2a, 0x02, 0x90, 0, 2, 1, 175, 0, 2, 151, 0,
+  ];
+  // an alternative for a downmix, then -12 LUFS album loudness
+  let alou = [0, 0, 0, 0, 0, 0x40, 0, 0, 0, 0, 1, 1, 100, 0];
+  let alou_default = [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 183, 0];
+  let ludt = [
+    mp4_box(b"tlou", &tlou),
+    mp4_box(b"alou", &alou),
+    mp4_box(b"alou", &alou_default),
+  ];
+  let moov_start = data.windows(4).rposition(|w| w == b"moov").unwrap() - 4;
+  let moov = &data[moov_start..];
+  let udta = [find_box(moov, b"udta"), &mp4_box(b"ludt", &ludt.concat())].concat();
+  let moov = replace_box(moov, &[b"moov", b"udta"], &udta);
+  let data = [&data[..moov_start], &moov].concat();
+
+  let info = open(&data).loudness_info().expect("Missing loudness info");
+  let track = info.track.expect("Missing track loudness");
+  assert_eq!(track.program_loudness, Some(-14.0));
+  assert_eq!(track.anchor_loudness, Some(-20.0));
+  assert_eq!(track.sample_peak, Some(-1.0));
+  assert_eq!(track.true_peak, Some(-0.5));
+  let album = info.album.expect("Missing album loudness");
+  assert_eq!(album.program_loudness, Some(-12.0));
+  assert_eq!(album.sample_peak, None);
+}
+
+#[test]
+fn replay_gain() {
+  let path = "tests/samples/Simbai & Elke Bay - Energy.m4a";
+  let data = std::fs::read(path).expect("Error reading file");
+  let open = |data: &Vec<u8>, builder: DecoderBuilder| {
+    let size = data.len() as u64;
+    let decoder = builder.open_mpeg4(Cursor::new(data.clone()), size);
+    decoder.expect("Error creating decoder")
+  };
+  let full: Vec<i16> = open(&data, DecoderBuilder::new()).collect();
+  assert_eq!(open(&data, DecoderBuilder::new()).replay_gain(), None);
+
+  let data = add_itunes_tag(&data, "replaygain_track_gain", "-6.02 dB");
+  let data = add_itunes_tag(&data, "REPLAYGAIN_TRACK_PEAK", "0.9");
+  let data = add_itunes_tag(&data, "R128_ALBUM_GAIN", "512");
+  let decoder = open(&data, DecoderBuilder::new());
+  let replay_gain = decoder.replay_gain().expect("Missing ReplayGain");
+  assert_eq!(replay_gain.track_gain, Some(-6.02));
+  assert_eq!(replay_gain.track_peak, Some(0.9));
+  assert_eq!(replay_gain.album_gain, Some(7.0));
+  assert_eq!(replay_gain.album_peak, None);
+  // tags don't change the output unless enabled
+  assert_eq!(decoder.collect::<Vec<_>>(), full);
+
+  let factor = replay_gain.factor(GainMode::Track).expect("Missing gain");
+  let decoder = open(&data, DecoderBuilder::new().replay_gain(GainMode::Track));
+  let samples: Vec<i16> = decoder.collect();
+  assert_eq!(samples.len(), full.len());
+  for (sample, full_sample) in samples.iter().zip(&full) {
+    assert_eq!(*sample, (*full_sample as f32 * factor).round() as i16);
+  }
+}
+
+#[cfg(feature = "loudness")]
+#[test]
+fn measure_loudness() {
+  let path = "tests/samples/Simbai & Elke Bay - Energy.m4a";
+  let file = File::open(path).expect("Error opening file");
+  let size = file.metadata().expect("Error getting file metadata").len();
+  let mut decoder = Decoder::new_mpeg4(BufReader::new(file), size).expect("Error creating decoder");
+  let loudness = redlux::loudness::measure(&mut decoder).expect("Error measuring loudness");
+  assert!(loudness.integrated > -30.0 && loudness.integrated < 0.0);
+  assert!(loudness.true_peak > -10.0 && loudness.true_peak < 3.0);
+  assert_eq!(decoder.next(), None);
+
+  // channels are weighted by their type, so the channel order doesn't matter
+  let path = "tests/samples/sine-5.1.aac";
+  let measure = |order| {
+    let mut decoder = Decoder::builder()
+      .channel_order(order)
+      .open_path(path)
+      .expect("Error creating decoder");
+    redlux::loudness::measure(&mut decoder).expect("Error measuring loudness")
+  };
+  let wav = measure(ChannelOrder::Wav);
+  let mpeg = measure(ChannelOrder::Mpeg);
+  assert!(
+    (wav.integrated - mpeg.integrated).abs() < 1e-9,
+    "{:?} {:?}",
+    wav,
+    mpeg
+  );
+}
+
+#[cfg(feature = "loudness")]
+#[test]
+fn normalize() {
+  use redlux::normalize::{analyze_then_decode, Target};
+  let path = "tests/samples/Simbai & Elke Bay - Energy.m4a";
+  let data = std::fs::read(path).expect("Error reading file");
+  let size = data.len() as u64;
+  let (decoder, analysis) =
+    analyze_then_decode(Cursor::new(data.clone()), size, Target::Peak(-6.0))
+      .expect("Error analyzing");
+  let peak = decoder.map(|s| s.unsigned_abs()).max().unwrap();
+  let peak_db = 20.0 * (peak as f64 / 32768.0).log10();
+  assert!((peak_db + 6.0).abs() < 0.01);
+  assert!((analysis.peak + analysis.gain_db + 6.0).abs() < 1e-9);
+
+  let target = Target::Loudness(-23.0);
+  let (mut decoder, analysis) =
+    analyze_then_decode(Cursor::new(data), size, target).expect("Error analyzing");
+  assert!(analysis.gain_db < 0.0);
+  let loudness = redlux::loudness::measure(&mut decoder).expect("Error measuring loudness");
+  assert!((loudness.integrated + 23.0).abs() < 0.1);
+}
+
+#[test]
+fn peak_and_rms_levels() {
+  let path = "tests/samples/Simbai & Elke Bay - Energy.m4a";
+  let file = File::open(path).expect("Error opening file");
+  let size = file.metadata().expect("Error getting file metadata").len();
+  let open = || {
+    let file = File::open(path).expect("Error opening file");
+    Decoder::new_mpeg4(BufReader::new(file), size).expect("Error creating decoder")
+  };
+  let samples: Vec<i16> = open().collect();
+  let mut decoder = open();
+  let levels = levels::scan(&mut decoder, Some(Duration::from_secs(1))).expect("Error scanning");
+  assert_eq!(levels.channels.len(), 2);
+  for (channel, channel_levels) in levels.channels.iter().enumerate() {
+    let channel_samples = samples.iter().skip(channel).step_by(2);
+    let peak = channel_samples
+      .clone()
+      .map(|s| s.unsigned_abs())
+      .max()
+      .unwrap();
+    assert_eq!(channel_levels.peak, peak as f64 / 32768.0);
+    let sum_squares: f64 = channel_samples.map(|&s| s as f64 * s as f64).sum();
+    let rms = (sum_squares / (samples.len() / 2) as f64).sqrt() / 32768.0;
+    assert!((channel_levels.rms - rms).abs() < 1e-9);
+    assert!(channel_levels.rms_db() < channel_levels.peak_db());
+  }
+  // 7.2 seconds
+  assert_eq!(levels.windows.len(), 8);
+  let window_peak = levels.windows.iter().map(|w| w[0].peak).fold(0.0, f64::max);
+  assert_eq!(window_peak, levels.channels[0].peak);
+
+  let levels = levels::scan(&mut open(), None).expect("Error scanning");
+  assert!(levels.windows.is_empty());
+}
+
+#[test]
+fn waveform_peaks() {
+  let path = "tests/samples/Simbai & Elke Bay - Energy.aac";
+  let open = || {
+    Decoder::new_aac(BufReader::new(
+      File::open(path).expect("Error opening file"),
+    ))
+  };
+  let samples: Vec<i16> = open().collect();
+  // 311 frames of 1024 samples
+  let buckets = peaks::generate(&mut open(), 311).expect("Error generating peaks");
+  assert_eq!(buckets.len(), 311);
+  for (bucket, frame) in buckets.iter().zip(samples.chunks(2048)) {
+    assert_eq!(bucket.min, *frame.iter().min().unwrap());
+    assert_eq!(bucket.max, *frame.iter().max().unwrap());
+    let sum_squares: f64 = frame.iter().map(|&s| s as f64 * s as f64).sum();
+    let rms = (sum_squares / frame.len() as f64).sqrt() / 32768.0;
+    assert!((bucket.rms as f64 - rms).abs() < 1e-6);
+  }
+
+  let path = "tests/samples/Simbai & Elke Bay - Energy.m4a";
+  let file = File::open(path).expect("Error opening file");
+  let size = file.metadata().expect("Error getting file metadata").len();
+  let mut decoder = Decoder::new_mpeg4(BufReader::new(file), size).expect("Error creating decoder");
+  let buckets = peaks::generate(&mut decoder, 100).expect("Error generating peaks");
+  assert_eq!(buckets.len(), 100);
+  // the track starts with silence
+  assert_eq!(buckets[0], Default::default());
+  assert!(buckets[1..]
+    .iter()
+    .all(|bucket| bucket.min < 0 && bucket.max > 0));
+}
+
+#[test]
+fn silence_trimming() {
+  let path = "tests/samples/Simbai & Elke Bay - Energy.m4a";
+  let data = std::fs::read(path).expect("Error reading file");
+  let size = data.len() as u64;
+  let open = |builder: DecoderBuilder| {
+    let decoder = builder.open_mpeg4(Cursor::new(data.clone()), size);
+    decoder.expect("Error creating decoder")
+  };
+  let full: Vec<i16> = open(DecoderBuilder::new()).collect();
+  let threshold = (32768.0 * 10f64.powf(-40.0 / 20.0)) as i32;
+  let is_loud = |s: &[i16]| s.iter().any(|&s| (s as i32).abs() > threshold);
+  let frames: Vec<&[i16]> = full.chunks(2).collect();
+  let start = frames.iter().position(|s| is_loud(s)).unwrap();
+  let end = frames.iter().rposition(|s| is_loud(s)).unwrap() + 1;
+  assert!(start > 0);
+
+  let mut decoder = open(DecoderBuilder::new());
+  let total_duration = decoder.total_duration();
+  let bounds = silence::detect(&mut decoder, -40.0).expect("Error detecting silence");
+  let bounds = bounds.expect("Missing bounds");
+  let to_time = |samples: usize| Duration::from_nanos(samples as u64 * 1_000_000_000 / 44100);
+  assert_eq!(bounds.start, to_time(start));
+  assert_eq!(bounds.end, to_time(end));
+  // the range that detect decodes isn't kept
+  assert_eq!(decoder.total_duration(), total_duration);
+
+  let decoder = open(DecoderBuilder::new().trim_silence(-40.0));
+  let samples: Vec<i16> = decoder.collect();
+  assert_eq!(samples, full[start * 2..end * 2]);
+
+  // louder than everything
+  let mut decoder = open(DecoderBuilder::new());
+  let bounds = silence::detect(&mut decoder, 6.0).expect("Error detecting silence");
+  assert_eq!(bounds, None);
+}
+
+#[test]
+fn fades() {
+  let path = "tests/samples/Simbai & Elke Bay - Energy.aac";
+  let open = |builder: DecoderBuilder| {
+    let file = File::open(path).expect("Error opening file");
+    builder
+      .open_aac(BufReader::new(file))
+      .expect("Error creating decoder")
+  };
+  let full: Vec<i16> = open(DecoderBuilder::new()).collect();
+  let builder = DecoderBuilder::new()
+    .fade_in(Duration::from_secs(1))
+    .fade_out(Duration::from_secs(2));
+  let samples: Vec<i16> = open(builder.clone()).collect();
+  assert_eq!(samples.len(), full.len());
+  let len = full.len() / 2;
+  let fade_out_start = len - 2 * 44100;
+  for i in 0..len {
+    let mut factor = (i as f32 / 44100.0).min(1.0);
+    factor *= ((len - i) as f32 / (2.0 * 44100.0)).min(1.0);
+    for channel in 0..2 {
+      let expected = (full[i * 2 + channel] as f32 * factor).round() as i16;
+      assert_eq!(samples[i * 2 + channel], expected, "sample {}", i);
+    }
+  }
+  assert_eq!(
+    samples[44100 * 2..fade_out_start * 2],
+    full[44100 * 2..fade_out_start * 2]
+  );
+
+  // fades apply to a range
+  let mut decoder = open(builder);
+  let (start, end) = (Duration::from_secs(3), Duration::from_secs(6));
+  decoder.decode_range(start, end).expect("Error seeking");
+  let samples: Vec<i16> = decoder.collect();
+  assert_eq!(samples.len(), 3 * 44100 * 2);
+  assert_eq!(samples[..2], [0, 0]);
+  assert!(samples[44100 * 2..].iter().any(|&s| s != 0));
+}
+
+#[test]
+fn output_gain() {
+  let path = "tests/samples/Simbai & Elke Bay - Energy.aac";
+  let open = || {
+    Decoder::new_aac(BufReader::new(
+      File::open(path).expect("Error opening file"),
+    ))
+  };
+  let full: Vec<i16> = open().collect();
+  let mut decoder = open();
+  let handle = decoder.gain_handle();
+  let first: Vec<i16> = decoder.by_ref().take(1000).collect();
+  assert_eq!(first, full[..1000]);
+  // takes effect on the next sample, even though the frame is decoded
+  handle.set_gain(0.5);
+  assert_eq!(handle.gain(), 0.5);
+  let rest: Vec<i16> = decoder.by_ref().take(1000).collect();
+  let expected: Vec<i16> = full[1000..2000]
+    .iter()
+    .map(|&s| (s as f32 * 0.5).round() as i16)
+    .collect();
+  assert_eq!(rest, expected);
+
+  decoder.set_gain_db(0.0);
+  let mut chunk = Vec::new();
+  decoder
+    .next_chunk(&mut chunk)
+    .expect("Error decoding chunk");
+  assert_eq!(chunk, full[2000..2000 + chunk.len()]);
+  decoder.set_gain(4.0);
+  let frame = decoder.next_frame().expect("Error decoding frame");
+  let frame = frame.expect("Missing frame");
+  let start = 2000 + chunk.len();
+  for (sample, full_sample) in frame.samples.iter().zip(&full[start..]) {
+    let expected = (*full_sample as f32 * 4.0).round();
+    assert_eq!(*sample, expected.clamp(-32768.0, 32767.0) as i16);
+  }
+}
+
+#[test]
+fn soft_limiter() {
+  let path = "tests/samples/Simbai & Elke Bay - Energy.aac";
+  let open = |builder: DecoderBuilder| {
+    let file = File::open(path).expect("Error opening file");
+    builder
+      .open_aac(BufReader::new(file))
+      .expect("Error creating decoder")
+  };
+  let mut clipped = open(DecoderBuilder::new());
+  clipped.set_gain(1.3);
+  let clipped: Vec<i16> = clipped.collect();
+  assert!(clipped.contains(&i16::MAX));
+
+  let mut limited = open(DecoderBuilder::new().soft_limiter());
+  limited.set_gain(1.3);
+  let limited: Vec<i16> = limited.collect();
+  assert_eq!(limited.len(), clipped.len());
+  assert!(!limited.contains(&i16::MAX) && !limited.contains(&i16::MIN));
+  // quiet samples are unchanged
+  for (limited, clipped) in limited.iter().zip(&clipped) {
+    if clipped.unsigned_abs() < 29000 {
+      assert_eq!(limited, clipped);
+    }
+  }
+}
+
+#[test]
+fn dither() {
+  let path = "tests/samples/Simbai & Elke Bay - Energy.aac";
+  let open = |builder: DecoderBuilder| {
+    let file = File::open(path).expect("Error opening file");
+    let mut decoder = builder
+      .open_aac(BufReader::new(file))
+      .expect("Error creating decoder");
+    decoder.set_gain_db(-30.0);
+    decoder.collect::<Vec<i16>>()
+  };
+  let plain = open(DecoderBuilder::new());
+  let dithered = open(DecoderBuilder::new().dither());
+  assert_eq!(plain.len(), dithered.len());
+  assert_ne!(plain, dithered);
+  let differences = plain.iter().zip(&dithered).map(|(a, b)| (a - b).abs());
+  assert!(differences.max() <= Some(1));
+}
+
+#[test]
+fn open_path() {
+  let path = "tests/samples/Simbai & Elke Bay - Energy.m4a";
+  let decoder = Decoder::from_path(path).expect("Error opening file");
+  assert_eq!(decoder.count(), 638976);
+  let path = "tests/samples/Simbai & Elke Bay - Energy.aac";
+  let decoder = Decoder::from_path(path).expect("Error opening file");
+  assert_eq!(decoder.count(), 636928);
+
+  let result = Decoder::from_path("tests/samples/missing.m4a");
+  assert!(matches!(result, Err(Error::ReaderError(_))));
+  let message = result.err().unwrap().to_string();
+  assert!(message.starts_with("Error reading file: "));
+  assert!(message.len() > "Error reading file: ".len());
+}
+
+#[test]
+fn open_bytes() {
+  let data =
+    std::fs::read("tests/samples/Simbai & Elke Bay - Energy.m4a").expect("Error reading file");
+  let decoder = Decoder::from_bytes(&data[..]).expect("Error creating decoder");
+  assert_eq!(decoder.count(), 638976);
+  let data =
+    std::fs::read("tests/samples/Simbai & Elke Bay - Energy.aac").expect("Error reading file");
+  let decoder = Decoder::from_bytes(data).expect("Error creating decoder");
+  assert_eq!(decoder.count(), 636928);
+}
+
+#[test]
+fn reopen_shared() {
+  let data =
+    std::fs::read("tests/samples/Simbai & Elke Bay - Energy.m4a").expect("Error reading file");
+  let data: std::sync::Arc<[u8]> = data.into();
+  let decoder = DecoderBuilder::new()
+    .fade_in(Duration::from_millis(10))
+    .open_shared(data.clone())
+    .expect("Error creating decoder");
+  let handles: Vec<_> = (0..3)
+    .map(|_| {
+      let decoder = decoder.reopen().expect("Error reopening decoder");
+      thread::spawn(move || decoder.collect::<Vec<i16>>())
+    })
+    .collect();
+  let samples: Vec<i16> = decoder.collect();
+  assert_eq!(samples.len(), 638976);
+  for handle in handles {
+    assert_eq!(handle.join().expect("Decoding thread panicked"), samples);
+  }
+
+  let decoder = Decoder::new_mpeg4(Cursor::new(data.clone()), data.len() as u64);
+  let result = decoder.expect("Error creating decoder").reopen();
+  assert!(matches!(result, Err(Error::InvalidConfig(_))));
+}
+
+#[test]
+fn builder_options() {
+  let m4a = "tests/samples/Simbai & Elke Bay - Energy.m4a";
+  let file = File::open(m4a).expect("Error opening file");
+  let size = file.metadata().expect("Error getting file metadata").len();
+  let decoder = Decoder::builder()
+    .track(1)
+    .downmix_stereo(true)
+    .lenient(true)
+    .open_mpeg4(BufReader::new(file), size)
+    .expect("Error creating decoder");
+  assert_eq!(decoder.count(), 638976);
+  let file = File::open(m4a).expect("Error opening file");
+  let result = Decoder::builder()
+    .track(2)
+    .open_mpeg4(BufReader::new(file), size);
+  assert!(matches!(result, Err(Error::TrackNotFound)));
+
+  // the same file with its sample entry renamed to E-AC-3
+  let mut data = std::fs::read(m4a).expect("Error reading file");
+  let entry = data
+    .windows(4)
+    .rposition(|window| window == b"mp4a")
+    .unwrap();
+  data[entry..entry + 4].copy_from_slice(b"ec-3");
+  let result = Decoder::new_mpeg4(Cursor::new(data), size);
+  assert!(matches!(result, Err(Error::UnsupportedCodec(codec)) if codec == "ec-3"));
+
+  let file = File::open("tests/samples/sine-5.1.aac").expect("Error opening file");
+  let mut decoder = Decoder::builder()
+    .downmix_stereo(true)
+    .open_aac(BufReader::new(file))
+    .expect("Error creating decoder");
+  assert_eq!(decoder.channels(), 2);
+  let frame = decoder.next_frame().expect("Error decoding").unwrap();
+  assert_eq!(frame.channels, 2);
+
+  use ChannelType::{Back, Front, Lfe};
+  let path = "tests/samples/sine-5.1.aac";
+  let decoder = Decoder::from_path(path).expect("Error creating decoder");
+  let info = decoder.stream_info();
+  assert_eq!(info.channel_types, [Front, Front, Front, Lfe, Back, Back]);
+  assert_eq!(info.channel_indices, [1, 2, 0, 0, 0, 1]);
+  let decoder = Decoder::builder()
+    .channel_order(ChannelOrder::Mpeg)
+    .open_path(path)
+    .expect("Error creating decoder");
+  let info = decoder.stream_info();
+  assert_eq!(info.channel_types, [Front, Front, Front, Back, Back, Lfe]);
+  assert_eq!(info.channel_indices, [0, 1, 2, 0, 1, 0]);
+
+  let mut data =
+    std::fs::read("tests/samples/Simbai & Elke Bay - Energy.aac").expect("Error reading file");
+  // corrupt the payload of a frame
+  for byte in &mut data[5000..5004] {
+    *byte ^= 0x5a;
+  }
+  let strict = Decoder::builder().open_bytes(data.clone()).unwrap();
+  let samples: Vec<i16> = strict.collect();
+  assert!(samples.len() < 636928);
+  let mut lenient = Decoder::builder().lenient(true).open_bytes(data).unwrap();
+  let events = lenient.subscribe();
+  assert_eq!(lenient.by_ref().count(), 636928);
+  assert!(lenient.iter_error.is_none());
+  assert!(events.try_iter().any(|event| event == Event::Concealment));
+}
+
+#[test]
+fn mime_hint() {
+  assert_eq!(Format::from_mime("audio/mp4"), Some(Format::Mp4));
+  assert_eq!(
+    Format::from_mime("Audio/AAC; codecs=mp4a.40.2"),
+    Some(Format::Aac)
+  );
+  assert_eq!(Format::from_mime("audio/mpeg"), None);
+  assert_eq!(Format::from_extension(".M4A"), Some(Format::Mp4));
+  assert_eq!(Format::from_extension("aac"), Some(Format::Aac));
+
+  let open = |path: &str, mime: &str| {
+    let data = std::fs::read(path).expect("Error reading file");
+    let size = data.len() as u64;
+    Decoder::new_with_mime(Cursor::new(data), size, mime).expect("Error creating decoder")
+  };
+  let m4a = "tests/samples/Simbai & Elke Bay - Energy.m4a";
+  let aac = "tests/samples/Simbai & Elke Bay - Energy.aac";
+  let decoder = open(m4a, "audio/mp4");
+  assert_eq!(decoder.format, Format::Mp4);
+  assert_eq!(decoder.count(), 638976);
+  let decoder = open(aac, "audio/aac");
+  assert_eq!(decoder.format, Format::Aac);
+  assert_eq!(decoder.count(), 636928);
+  // unknown hints fall back to detection
+  let decoder = open(m4a, "application/octet-stream");
+  assert_eq!(decoder.format, Format::Mp4);
+}
+
+/// Reader that returns nothing or a transient error on some reads, like a
+/// stream that's waiting for more data
+struct Hiccups {
+  data: Cursor<Vec<u8>>,
+  reads: usize,
+}
+
+impl std::io::Read for Hiccups {
+  fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+    self.reads += 1;
+    match self.reads % 5 {
+      2 => Ok(0),
+      4 => Err(std::io::ErrorKind::TimedOut.into()),
+      _ => self.data.read(buf),
+    }
+  }
+}
+
+impl std::io::Seek for Hiccups {
+  fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+    self.data.seek(pos)
+  }
+}
+
+#[test]
+fn live_stream() {
+  let data =
+    std::fs::read("tests/samples/Simbai & Elke Bay - Energy.aac").expect("Error reading file");
+  let open = |builder: DecoderBuilder| {
+    let reader = Hiccups {
+      data: Cursor::new(data.clone()),
+      reads: 0,
+    };
+    builder.open_aac(reader).expect("Error creating decoder")
+  };
+  let decoder = open(DecoderBuilder::new().read_chunk_size(1000));
+  assert!(!decoder.is_live());
+  assert!(decoder.count() < 636928);
+
+  let builder = DecoderBuilder::new()
+    .read_chunk_size(1000)
+    .live_retry(Duration::from_millis(1), Duration::from_millis(50));
+  let mut decoder = open(builder);
+  assert!(decoder.is_live());
+  assert_eq!(decoder.total_duration(), None);
+  assert!(matches!(
+    decoder.seek(Duration::from_secs(1)),
+    Err(Error::InvalidConfig(_))
+  ));
+  assert_eq!(decoder.by_ref().count(), 636928);
+  assert!(decoder.iter_error.is_none());
+}
+
+/// Connection that drops after reading a number of bytes
+struct Connection {
+  data: Cursor<Vec<u8>>,
+  bytes_left: usize,
+}
+
+impl std::io::Read for Connection {
+  fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+    if self.bytes_left == 0 {
+      return Err(std::io::ErrorKind::ConnectionReset.into());
+    }
+    let len = buf.len().min(self.bytes_left);
+    let bytes_read = self.data.read(&mut buf[..len])?;
+    self.bytes_left -= bytes_read;
+    Ok(bytes_read)
+  }
+}
+
+impl std::io::Seek for Connection {
+  fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+    self.data.seek(pos)
+  }
+}
+
+#[test]
+fn retry_reader() {
+  let data =
+    std::fs::read("tests/samples/Simbai & Elke Bay - Energy.aac").expect("Error reading file");
+  let connect = |offset: u64| {
+    let mut data = Cursor::new(data.clone());
+    data.set_position(offset);
+    Ok(Connection {
+      data,
+      bytes_left: 20000,
+    })
+  };
+  let mut connections = 0;
+  let reader = RetryReader::new(connect(0).unwrap(), |offset| {
+    connections += 1;
+    connect(offset)
+  })
+  .backoff(Duration::ZERO, Duration::ZERO);
+  let mut decoder = Decoder::new_aac(reader);
+  assert_eq!(decoder.by_ref().count(), 636928);
+  assert!(decoder.iter_error.is_none());
+  drop(decoder);
+  assert_eq!(connections, data.len() / 20000);
+
+  let reader = RetryReader::new(connect(0).unwrap(), |_| {
+    Err(std::io::ErrorKind::ConnectionRefused.into())
+  })
+  .max_retries(2)
+  .backoff(Duration::ZERO, Duration::ZERO);
+  let mut decoder = Decoder::new_aac(reader);
+  assert!(decoder.by_ref().count() < 636928);
+  assert!(matches!(
+    decoder.iter_error,
+    Some(Error::StreamReaderError { .. })
+  ));
+}
+
+#[test]
+fn stream_reader_error() {
+  let paths = [
+    (
+      "tests/samples/Simbai & Elke Bay - Energy.m4a",
+      638976,
+      200000,
+    ),
+    (
+      "tests/samples/Simbai & Elke Bay - Energy.aac",
+      636928,
+      60000,
+    ),
+  ];
+  for (path, len, bytes_left) in paths {
+    let data = std::fs::read(path).expect("Error reading file");
+    let size = data.len() as u64;
+    let reader = Connection {
+      data: Cursor::new(data.clone()),
+      bytes_left,
+    };
+    let mut decoder = Decoder::builder()
+      .open(reader, size)
+      .expect("Error creating decoder");
+    let decoded = decoder.by_ref().count();
+    assert!(decoded < len);
+    let (offset, position) = match decoder.iter_error.take() {
+      Some(Error::StreamReaderError {
+        offset, position, ..
+      }) => (offset, position),
+      err => panic!("Unexpected error {:?}", err),
+    };
+    assert!(offset > 0 && offset < size);
+    let resumed = Decoder::open_at(Cursor::new(data), size, position).expect("Error resuming");
+    assert_eq!(decoded + resumed.count(), len);
+  }
+}
+
+#[test]
+fn decode_stats() {
+  let path = "tests/samples/Simbai & Elke Bay - Energy.m4a";
+  let mut decoder = Decoder::from_path(path).expect("Error opening file");
+  assert_eq!(DecodeStats::default().realtime_factor(), None);
+  let samples = decoder.by_ref().count();
+  let stats = decoder.decode_stats();
+  assert_eq!(stats.frames, 312);
+  let audio_time = Duration::from_secs_f64(samples as f64 / 2.0 / 44100.0);
+  // each frame's duration is rounded down to a nanosecond
+  assert!(audio_time - stats.audio_time < Duration::from_micros(1));
+  assert!(stats.decode_time > Duration::ZERO);
+  let factor = stats.realtime_factor().expect("Missing realtime factor");
+  assert!(factor > 1.0, "{}", factor);
+}
+
+#[test]
+fn time_budget() {
+  let path = "tests/samples/Simbai & Elke Bay - Energy.m4a";
+  let budgets = [
+    Decoder::builder().frame_time_budget(Duration::from_nanos(1)),
+    Decoder::builder().file_time_budget(Duration::from_nanos(1)),
+  ];
+  for builder in budgets {
+    let mut decoder = builder.open_path(path).expect("Error creating decoder");
+    assert_eq!(decoder.by_ref().count(), 0);
+    match decoder.iter_error.take() {
+      Some(Error::TimeBudgetExceeded(position)) => assert_eq!(position, Duration::ZERO),
+      err => panic!("Unexpected error {:?}", err),
+    }
+  }
+  let decoder = Decoder::builder()
+    .frame_time_budget(Duration::from_secs(10))
+    .file_time_budget(Duration::from_secs(60))
+    .open_path(path)
+    .expect("Error creating decoder");
+  assert_eq!(decoder.count(), 638976);
+}
+
+#[test]
+fn realtime() {
+  use std::time::Instant;
+
+  let path = "tests/samples/Simbai & Elke Bay - Energy.m4a";
+  let mut decoder = Decoder::builder()
+    .realtime()
+    .file_time_budget(Duration::from_millis(500))
+    .open_path(path)
+    .expect("Error creating decoder");
+  let started = Instant::now();
+  decoder
+    .decode_range(Duration::from_secs(3), Duration::from_millis(3600))
+    .unwrap();
+  let samples = decoder.by_ref().count();
+  assert_eq!(samples, 44100 * 6 / 10 * 2);
+  // the first frame is returned right away, and the last at its timestamp.
+  // Pacing the frames before the position would take another 3 seconds.
+  let elapsed = started.elapsed();
+  assert!(elapsed > Duration::from_millis(550), "{:?}", elapsed);
+  assert!(elapsed < Duration::from_millis(3500), "{:?}", elapsed);
+  assert!(decoder.iter_error.is_none());
+}
+
+#[test]
+fn max_sample_size() {
+  let path = "tests/samples/Simbai & Elke Bay - Energy.m4a";
+  let mut data = std::fs::read(path).expect("Error reading file");
+  let stsz = data
+    .windows(4)
+    .position(|window| window == b"stsz")
+    .expect("No stsz box");
+  // the last entry of the sample size table
+  let sample_count = u32::from_be_bytes(data[stsz + 12..stsz + 16].try_into().unwrap());
+  let last = stsz + 12 + 4 * sample_count as usize;
+  data[last..last + 4].copy_from_slice(&0x40_0000u32.to_be_bytes());
+  let mut decoder = Decoder::builder()
+    .open_bytes(data.clone())
+    .expect("Error creating decoder");
+  let decoded = decoder.by_ref().count();
+  assert!(decoded > 0 && decoded < 638976);
+  match decoder.iter_error.take() {
+    Some(Error::SampleTooLarge(size)) => assert_eq!(size, 0x40_0000),
+    err => panic!("Unexpected error {:?}", err),
+  }
+  let mut decoder = Decoder::builder()
+    .lenient(true)
+    .open_bytes(data)
+    .expect("Error creating decoder");
+  assert_eq!(decoder.by_ref().count(), decoded);
+  assert!(decoder.iter_error.is_none());
+
+  let mut decoder = Decoder::builder()
+    .max_sample_size(1)
+    .open_path(path)
+    .expect("Error creating decoder");
+  assert_eq!(decoder.by_ref().count(), 0);
+  assert!(matches!(decoder.iter_error, Some(Error::SampleTooLarge(_))));
+  let result = Decoder::builder().max_sample_size(0).open_path(path);
+  assert!(matches!(result, Err(Error::InvalidConfig(_))));
+}
+
+#[test]
+fn byte_range() {
+  let path = "tests/samples/Simbai & Elke Bay - Energy.m4a";
+  let data = std::fs::read(path).expect("Error reading file");
+  let moov = data
+    .windows(4)
+    .position(|window| window == b"moov")
+    .expect("No moov box");
+  let (start, end) = (Duration::from_secs(3), Duration::from_secs(5));
+  let mut decoder = Decoder::builder()
+    .open_bytes(data.clone())
+    .expect("Error creating decoder");
+  let range = decoder.byte_range(start, end).expect("Error getting range");
+  assert!(range.start >= 44 && range.start < range.end && range.end < moov as u64);
+  let full = decoder
+    .byte_range(Duration::ZERO, Duration::from_secs(60))
+    .expect("Error getting range");
+  assert!(full.start <= range.start && full.end >= range.end);
+  decoder.decode_range(start, end).expect("Error seeking");
+  let expected: Vec<i16> = decoder.collect();
+
+  // keep only the ftyp box, the large mdat header, the range and the moov box
+  let mut partial = data.clone();
+  partial[44..range.start as usize].fill(0);
+  partial[range.end as usize..moov - 4].fill(0);
+  let mut decoder = Decoder::builder()
+    .open_bytes(partial)
+    .expect("Error creating decoder");
+  decoder.decode_range(start, end).expect("Error seeking");
+  assert_eq!(decoder.collect::<Vec<_>>(), expected);
+
+  let path = "tests/samples/Simbai & Elke Bay - Energy.aac";
+  let mut decoder = Decoder::builder()
+    .open_path(path)
+    .expect("Error creating decoder");
+  let range = decoder.byte_range(start, end).expect("Error getting range");
+  let full = decoder
+    .byte_range(Duration::ZERO, Duration::from_secs(60))
+    .expect("Error getting range");
+  assert_eq!(full.start, 0);
+  assert_eq!(full.end, std::fs::metadata(path).unwrap().len());
+  assert!(range.start > 0 && range.start < range.end && range.end < full.end);
+  // the reader is left where it was
+  assert_eq!(decoder.count(), 636928);
+}
+
+#[test]
+fn pipe_reader() {
+  let data =
+    std::fs::read("tests/samples/Simbai & Elke Bay - Energy.aac").expect("Error reading file");
+  let (mut writer, reader) = redlux::pipe(4);
+  let sender = thread::spawn(move || {
+    for chunk in data.chunks(4096) {
+      std::io::Write::write_all(&mut writer, chunk).expect("Error writing to pipe");
+    }
+  });
+  let mut decoder = Decoder::new_aac(reader);
+  assert_eq!(decoder.by_ref().count(), 636928);
+  assert!(decoder.iter_error.is_none());
+  sender.join().expect("Sending thread panicked");
+
+  let (writer, reader) = redlux::pipe(1);
+  drop(reader);
+  assert!(writer.send(vec![0]).is_err());
+}
+
+#[test]
+#[cfg(feature = "mmap")]
+fn open_mmap() {
+  let decoder = Decoder::from_mmap("tests/samples/Simbai & Elke Bay - Energy.m4a")
+    .expect("Error creating decoder");
+  assert_eq!(decoder.count(), 638976);
+  let mut decoder = Decoder::from_mmap("tests/samples/Simbai & Elke Bay - Energy.aac")
+    .expect("Error creating decoder");
+  decoder.seek(Duration::from_secs(5)).expect("Error seeking");
+  assert_eq!(decoder.count(), 636928 - 5 * 44100 * 2);
+}
+
+/// Reader that counts the calls to `read`
+struct CountingReader<R> {
+  inner: R,
+  reads: std::rc::Rc<std::cell::Cell<usize>>,
+}
+
+impl<R: std::io::Read> std::io::Read for CountingReader<R> {
+  fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+    self.reads.set(self.reads.get() + 1);
+    self.inner.read(buf)
+  }
+}
+
+impl<R: std::io::Seek> std::io::Seek for CountingReader<R> {
+  fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+    self.inner.seek(pos)
+  }
+}
+
+#[test]
+fn buffered_reading() {
+  let path = "tests/samples/Simbai & Elke Bay - Energy.m4a";
+  let decode = |builder: DecoderBuilder| {
+    let file = File::open(path).expect("Error opening file");
+    let size = file.metadata().expect("Error getting file metadata").len();
+    let reads = std::rc::Rc::new(std::cell::Cell::new(0));
+    let reader = CountingReader {
+      inner: file,
+      reads: reads.clone(),
+    };
+    let decoder = builder
+      .open_mpeg4(reader, size)
+      .expect("Error creating decoder");
+    assert_eq!(decoder.count(), 638976);
+    reads.get()
+  };
+  let buffered_reads = decode(DecoderBuilder::new());
+  let unbuffered_reads = decode(DecoderBuilder::new().read_buffer_size(0));
+  // the file is 117 KB
+  assert!(buffered_reads < 20, "{} reads", buffered_reads);
+  assert!(unbuffered_reads > 300, "{} reads", unbuffered_reads);
+}
+
+#[test]
+fn decoder_is_send() {
+  fn assert_send<T: Send>() {}
+  assert_send::<Decoder<BufReader<File>>>();
+  assert_send::<Decoder<Cursor<Vec<u8>>>>();
+  assert_send::<Decoder<redlux::PipeReader>>();
+  assert_send::<LoopingDecoder<BufReader<File>>>();
+  assert_send::<Playlist<BufReader<File>>>();
+}
+
+#[test]
+fn planar_frames() {
+  let path = "tests/samples/sine-5.1.aac";
+  let full: Vec<i16> = Decoder::from_path(path).unwrap().collect();
+  let mut decoder = Decoder::from_path(path).unwrap();
+  let mut buf = Vec::new();
+  let mut interleaved = Vec::new();
+  while let Some(frame) = decoder.next_frame_planar(&mut buf).unwrap() {
+    assert_eq!(frame.channels, 6);
+    assert_eq!(frame.planes().len(), 6);
+    assert_eq!(frame.channel(5), frame.planes().last().unwrap());
+    for i in 0..frame.len() {
+      interleaved.extend(frame.planes().map(|plane| plane[i]));
+    }
+  }
+  assert_eq!(interleaved, full);
+}
+
+#[test]
+fn frame_flags() {
+  let flags = |path: &str| {
+    let mut decoder = Decoder::from_path(path).expect("Error creating decoder");
+    decoder.next_frame().unwrap().unwrap().flags
+  };
+  let lc = flags("tests/samples/Simbai & Elke Bay - Energy.m4a");
+  assert_eq!(lc, FrameFlags::default());
+  let v1 = flags("tests/samples/sine-he-aac-v1.aac");
+  assert!(v1.sbr && !v1.ps);
+  let v2 = flags("tests/samples/sine-he-aac-v2.aac");
+  assert!(v2.sbr && v2.ps);
+
+  let mut data =
+    std::fs::read("tests/samples/Simbai & Elke Bay - Energy.aac").expect("Error reading file");
+  for byte in &mut data[5000..5004] {
+    *byte ^= 0x5a;
+  }
+  let mut decoder = Decoder::builder().lenient(true).open_bytes(data).unwrap();
+  let mut concealed = 0;
+  let mut buf = Vec::new();
+  while let Some(frame) = decoder.next_frame_planar(&mut buf).unwrap() {
+    if frame.flags.concealed {
+      concealed += 1;
+    }
+  }
+  assert!(concealed > 0 && concealed < 10);
+}
+
+#[test]
+fn split_channels() {
+  use rodio::Source;
+  let path = "tests/samples/Simbai & Elke Bay - Energy.m4a";
+  let full: Vec<i16> = Decoder::from_path(path).unwrap().collect();
+  let left: Vec<i16> = full.iter().copied().step_by(2).collect();
+  let right: Vec<i16> = full.iter().copied().skip(1).step_by(2).collect();
+
+  let mut sources = Decoder::from_path(path).unwrap().split_channels();
+  assert_eq!(sources.len(), 2);
+  assert_eq!(
+    (sources[0].channels(), sources[0].sample_rate()),
+    (1, 44100)
+  );
+  // one channel can get ahead of the other
+  let right_source = sources.pop().unwrap();
+  let left_source = sources.pop().unwrap();
+  assert_eq!(left_source.collect::<Vec<_>>(), left);
+  let handle = thread::spawn(move || right_source.collect::<Vec<_>>());
+  assert_eq!(handle.join().unwrap(), right);
+
+  let mut sources = Decoder::from_path(path).unwrap().split_channels();
+  let first: Vec<i16> = sources[1].by_ref().take(1000).collect();
+  assert_eq!(first, right[..1000]);
+  sources[0]
+    .seek(Duration::from_secs(5))
+    .expect("Error seeking");
+  assert!(sources[1].by_ref().eq(right[5 * 44100..].iter().copied()));
+  assert_eq!(sources[0].current_frame_len(), Some(left.len() - 5 * 44100));
+  assert!(sources[0].take_error().is_none());
+}
+
+#[test]
+fn borrowed_source() {
+  use rodio::Source;
+  fn first_second<S: Source<Item = i16>>(source: S) -> Vec<i16> {
+    source.take_duration(Duration::from_secs(1)).collect()
+  }
+  let path = "tests/samples/Simbai & Elke Bay - Energy.aac";
+  let open = || {
+    Decoder::new_aac(BufReader::new(
+      File::open(path).expect("Error opening file"),
+    ))
+  };
+  let full: Vec<i16> = open().collect();
+  let mut decoder = open();
+  let first = first_second(&mut decoder);
+  assert!(first.len() >= 44100 * 2);
+  assert!(first == full[..first.len()]);
+  // the decoder keeps going from where the borrow left off
+  let rest: Vec<i16> = decoder.collect();
+  assert!(rest == full[first.len()..]);
+
+  let boxed = Box::new(open());
+  assert_eq!(boxed.channels(), 2);
+  assert!(first_second(boxed) == first);
+}
+
+#[test]
+fn known_length() {
+  let path = "tests/samples/Simbai & Elke Bay - Energy.m4a";
+  let mut decoder = Decoder::from_path(path).expect("Error creating decoder");
+  assert_eq!(decoder.size_hint(), (638976, Some(638976)));
+  decoder.by_ref().take(1001).for_each(drop);
+  assert_eq!(decoder.remaining_samples(), Some(638976 - 1001));
+  assert_eq!(decoder.by_ref().count(), 638976 - 1001);
+  assert_eq!(decoder.size_hint(), (0, Some(0)));
+
+  let mut decoder = Decoder::from_path(path).expect("Error creating decoder");
+  let (start, end) = (Duration::from_millis(1500), Duration::from_secs(3));
+  decoder.decode_range(start, end).expect("Error seeking");
+  assert_eq!(decoder.remaining_samples(), Some(66150 * 2));
+  assert_eq!(decoder.count(), 66150 * 2);
+
+  let path = "tests/samples/Simbai & Elke Bay - Energy.aac";
+  let mut decoder = Decoder::from_path(path).expect("Error creating decoder");
+  assert_eq!(decoder.size_hint().1, None);
+  let samples: Vec<i16> = decoder.by_ref().collect();
+  assert_eq!(samples.len(), 636928);
+  let decoder = DecoderBuilder::new()
+    .fade_out(Duration::from_secs(1))
+    .open_path(path)
+    .expect("Error creating decoder");
+  assert_eq!(decoder.size_hint(), (636928, Some(636928)));
+}
+
+/// Add a copy of the first track of an MP4 file with moov at the end, with a
+/// different track ID and language
+fn add_track(data: &[u8], track_id: u32, language: &str, elng: Option<&str>) -> Vec<u8> {
+  let moov_start = data
+    .windows(4)
+    .rposition(|w| w == b"moov")
+    .expect("Missing moov")
+    - 4;
+  let moov = &data[moov_start + 8..];
+  let trak_start = moov
+    .windows(4)
+    .position(|w| w == b"trak")
+    .expect("Missing trak")
+    - 4;
+  let trak_size = u32::from_be_bytes(moov[trak_start..trak_start + 4].try_into().unwrap());
+  let trak = &moov[trak_start..trak_start + trak_size as usize];
+  let mut tkhd = find_box(trak, b"tkhd").to_vec();
+  tkhd[12..16].copy_from_slice(&track_id.to_be_bytes());
+  let mut mdhd = find_box(trak, b"mdhd").to_vec();
+  let packed = language
+    .bytes()
+    .fold(0u16, |packed, c| (packed << 5) | (c - 0x60) as u16);
+  mdhd[20..22].copy_from_slice(&packed.to_be_bytes());
+  let trak = replace_box(trak, &[b"trak", b"tkhd"], &tkhd);
+  let mut trak = replace_box(&trak, &[b"trak", b"mdia", b"mdhd"], &mdhd);
+  if let Some(elng) = elng {
+    let content = [&[0; 4][..], elng.as_bytes(), &[0]].concat();
+    let elng_box = [
+      &(content.len() as u32 + 8).to_be_bytes()[..],
+      b"elng",
+      &content,
+    ]
+    .concat();
+    let mdia = [find_box(&trak, b"mdia"), &elng_box].concat();
+    trak = replace_box(&trak, &[b"trak", b"mdia"], &mdia);
+  }
+  let moov = [moov, &trak].concat();
+  let moov = replace_box(&data[moov_start..], &[b"moov"], &moov);
+  [&data[..moov_start], &moov].concat()
+}
+
+#[test]
+fn probe_tracks() {
+  use redlux::probe::{self, AudioInfo};
+  let info = AudioInfo {
+    track_id: 1,
+    codec: "mp4a.40.2".to_string(),
+    language: "und".to_string(),
+    extended_language: None,
+    enabled: true,
+    default: true,
+    alternate_group: 0,
+    channels: 2,
+    sample_rate: 44100,
+    bitrate: Some(127227),
+    duration: Some(Duration::from_nanos(7_244_625_850)),
+    decodable: true,
+  };
+  let path = "tests/samples/Simbai & Elke Bay - Energy.m4a";
+  assert_eq!(probe::probe_path(path).unwrap(), vec![info.clone()]);
+
+  let data = std::fs::read(path).expect("Error reading file");
+  let data = add_track(&data, 2, "jpn", None);
+  let tracks = probe::probe(Cursor::new(&data), data.len() as u64).unwrap();
+  let japanese = AudioInfo {
+    track_id: 2,
+    language: "jpn".to_string(),
+    ..info.clone()
+  };
+  assert_eq!(tracks, [info, japanese]);
+  let decoder = DecoderBuilder::new()
+    .track(2)
+    .open_mpeg4(Cursor::new(&data), data.len() as u64)
+    .expect("Error creating decoder");
+  assert_eq!(decoder.track_id(), Some(2));
+  assert_eq!(decoder.count(), 638976);
+
+  let path = "tests/samples/sine-he-aac-v1.aac";
+  let tracks = probe::probe_path(path).unwrap();
+  assert_eq!(tracks.len(), 1);
+  assert_eq!(tracks[0].track_id, 0);
+  assert_eq!(tracks[0].codec, "mp4a.40.5");
+  assert!(tracks[0].decodable);
+  assert!(tracks[0].duration.is_some());
+  let tracks = probe::probe_path("tests/samples/sine-he-aac-v2.aac").unwrap();
+  assert_eq!(tracks[0].codec, "mp4a.40.29");
+}
+
+#[test]
+fn open_tracks() {
+  use rodio::Source;
+  let path = "tests/samples/Simbai & Elke Bay - Energy.m4a";
+  let data = std::fs::read(path).expect("Error reading file");
+  let full: Vec<i16> = Decoder::from_path(path).unwrap().collect();
+  let data = add_track(&data, 2, "jpn", None);
+  let decoders = DecoderBuilder::new()
+    .open_tracks(data, &[1, 2])
+    .expect("Error creating decoders");
+  assert_eq!(decoders.len(), 2);
+  assert_eq!(decoders[0].track_id(), Some(1));
+  assert_eq!(decoders[1].track_id(), Some(2));
+  let mut decoders = decoders.into_iter();
+  let (first, second) = (decoders.next().unwrap(), decoders.next().unwrap());
+  let mixed: Vec<i16> = first.mix(second).collect();
+  assert_eq!(mixed.len(), full.len());
+  let expected = full.iter().map(|&s| s.saturating_add(s));
+  assert!(mixed.iter().copied().eq(expected));
+
+  let result = DecoderBuilder::new().open_tracks(std::fs::read(path).unwrap(), &[1, 2]);
+  assert!(result.is_err());
+}
+
+#[test]
+fn track_flags() {
+  use redlux::probe;
+  let path = "tests/samples/Simbai & Elke Bay - Energy.m4a";
+  let data = std::fs::read(path).expect("Error reading file");
+  let data = add_track(&data, 2, "jpn", Some("ja-JP"));
+  let mut data = add_track(&data, 3, "eng", None);
+  // put the tracks in alternate group 1, and disable the first one
+  let tkhds: Vec<usize> = (0..data.len() - 4)
+    .filter(|&i| &data[i..i + 4] == b"tkhd")
+    .map(|i| i + 4)
+    .collect();
+  for &tkhd in &tkhds {
+    data[tkhd + 34..tkhd + 36].copy_from_slice(&1u16.to_be_bytes());
+  }
+  data[tkhds[0] + 3] &= !1;
+  let tracks = probe::probe(Cursor::new(&data), data.len() as u64).unwrap();
+  let flags: Vec<_> = tracks
+    .iter()
+    .map(|track| (track.track_id, track.enabled, track.default))
+    .collect();
+  assert_eq!(
+    flags,
+    [(1, false, false), (2, true, true), (3, true, false)]
+  );
+  assert_eq!(tracks[1].language, "jpn");
+  assert_eq!(tracks[1].extended_language.as_deref(), Some("ja-JP"));
+  assert_eq!(tracks[2].extended_language, None);
+  assert!(tracks.iter().all(|track| track.alternate_group == 1));
+}
+
+#[test]
+fn alternate_groups() {
+  use redlux::probe;
+  let path = "tests/samples/Simbai & Elke Bay - Energy.m4a";
+  let data = std::fs::read(path).expect("Error reading file");
+  let data = add_track(&data, 2, "jpn", None);
+  let mut data = add_track(&data, 3, "eng", None);
+  let tkhds: Vec<usize> = (0..data.len() - 4)
+    .filter(|&i| &data[i..i + 4] == b"tkhd")
+    .map(|i| i + 4)
+    .collect();
+  // tracks 2 and 3 are alternatives, and track 1 is disabled
+  for &tkhd in &tkhds[1..] {
+    data[tkhd + 34..tkhd + 36].copy_from_slice(&1u16.to_be_bytes());
+  }
+  data[tkhds[0] + 3] &= !1;
+  let tracks = probe::probe(Cursor::new(&data), data.len() as u64).unwrap();
+  let groups = probe::alternate_groups(&tracks);
+  assert_eq!(groups.into_iter().collect::<Vec<_>>(), [(1, vec![2, 3])]);
+  assert_eq!(probe::default_track(&tracks).map(|t| t.track_id), Some(2));
+  for _ in 0..5 {
+    let decoder = Decoder::new_mpeg4(Cursor::new(&data), data.len() as u64).unwrap();
+    assert_eq!(decoder.track_id(), Some(2));
+  }
+  let decoder = DecoderBuilder::new()
+    .track(1)
+    .open_mpeg4(Cursor::new(&data), data.len() as u64)
+    .unwrap();
+  assert_eq!(decoder.track_id(), Some(1));
+}
+
+#[test]
+fn metadata_tags() {
+  let path = "tests/samples/Simbai & Elke Bay - Energy.m4a";
+  let data = std::fs::read(path).expect("Error reading file");
+  let open = |data: &Vec<u8>| {
+    let decoder = Decoder::new_mpeg4(Cursor::new(data.clone()), data.len() as u64);
+    decoder.expect("Error creating decoder")
+  };
+  let decoder = open(&data);
+  let metadata = decoder.metadata().expect("Missing metadata");
+  assert_eq!(metadata.title, None);
+  let freeform: Vec<&String> = metadata.freeform.keys().collect();
+  assert_eq!(freeform, ["iTunSMPB"]);
+  let smpb = metadata.freeform_tag("itunsmpb").expect("Missing iTunSMPB");
+  assert!(smpb.starts_with(" 00000000 00000840 00000107"));
+
+  let data = add_tag(&data, b"\xa9nam", 1, "Energy".as_bytes());
+  let data = add_tag(&data, b"\xa9ART", 1, "Simbai & Elke Bay".as_bytes());
+  let data = add_tag(&data, b"soar", 1, "Simbai and Elke Bay".as_bytes());
+  let data = add_tag(&data, b"gnre", 0, &[0, 53]);
+  let data = add_tag(&data, b"\xa9lyr", 1, b"Feel the energy\r\nFeel it");
+  let data = add_tag(&data, b"\xa9day", 1, b"2019-06-14T07:00:00Z");
+  let data = add_tag(&data, b"trkn", 0, &[0, 0, 0, 3, 0, 12, 0, 0]);
+  let data = add_tag(&data, b"disk", 0, &[0, 0, 0, 1, 0, 0]);
+  let data = add_tag(&data, b"tmpo", 21, &[0, 128]);
+  let data = add_tag(&data, b"cpil", 21, &[1]);
+  let data = add_tag(&data, b"rtng", 21, &[4]);
+  let data = add_tag(&data, b"stik", 21, &[21]);
+  let data = add_itunes_tag(&data, "Encoded by", "Custom Ripper");
+  let decoder = open(&data);
+  let metadata = decoder.metadata().expect("Missing metadata");
+  assert_eq!(metadata.title.as_deref(), Some("Energy"));
+  assert_eq!(metadata.artist.as_deref(), Some("Simbai & Elke Bay"));
+  assert_eq!(metadata.album, None);
+  assert_eq!(metadata.artist_sort.as_deref(), Some("Simbai and Elke Bay"));
+  assert_eq!(metadata.title_sort, None);
+  assert_eq!(
+    metadata.lyrics.as_deref(),
+    Some("Feel the energy\r\nFeel it")
+  );
+  assert_eq!(metadata.genre.as_deref(), Some("Electronic"));
+  assert_eq!(metadata.year(), Some(2019));
+  assert_eq!(
+    (metadata.track_number, metadata.track_total),
+    (Some(3), Some(12))
+  );
+  assert_eq!((metadata.disc_number, metadata.disc_total), (Some(1), None));
+  assert_eq!(metadata.bpm, Some(128));
+  assert!(metadata.compilation);
+  assert_eq!(metadata.rating, Some(Rating::Explicit));
+  assert_eq!(metadata.media_kind, Some(MediaKind::Podcast));
+  assert_eq!(metadata.freeform.len(), 2);
+  assert_eq!(metadata.freeform_tag("Encoded by"), Some("Custom Ripper"));
+
+  // a text genre takes precedence
+  let data = add_tag(&data, b"\xa9gen", 1, "Electro House".as_bytes());
+  let decoder = open(&data);
+  let genre = decoder
+    .metadata()
+    .and_then(|metadata| metadata.genre.clone());
+  assert_eq!(genre.as_deref(), Some("Electro House"));
+
+  let path = "tests/samples/Simbai & Elke Bay - Energy.aac";
+  assert_eq!(Decoder::from_path(path).unwrap().metadata(), None);
+}
+
+#[test]
+fn stream_info() {
+  let path = "tests/samples/Simbai & Elke Bay - Energy.m4a";
+  let decoder = Decoder::from_path(path).expect("Error creating decoder");
+  let info = decoder.stream_info();
+  assert_eq!((info.sample_rate, info.aac_sample_rate), (44100, 44100));
+  assert_eq!((info.frame_size, info.aac_samples_per_frame), (1024, 1024));
+  assert_eq!((info.channels, info.aac_channels), (2, 2));
+  assert_eq!(info.channel_types, [ChannelType::Front, ChannelType::Front]);
+  assert_eq!(info.channel_indices, [0, 1]);
+  assert_eq!((info.aot, info.ext_aot), (2, 0));
+  assert_eq!(info.channel_config, 2);
+  assert_eq!(decoder.samples_per_frame(), 1024);
+  assert_eq!(decoder.frame_duration(), Duration::from_nanos(23_219_954));
+
+  assert!(info.output_delay > 0);
+  assert_eq!(info.sbr_delay(), 0);
+  let path = "tests/samples/sine-he-aac-v1.aac";
+  let decoder = Decoder::from_path(path).expect("Error creating decoder");
+  assert_eq!(decoder.samples_per_frame(), 2048);
+  let info = decoder.stream_info();
+  assert_eq!((info.output_delay, info.sbr_delay()), (3671, 962));
+  let delay = 3671 * 1_000_000_000 / info.sample_rate as u64;
+  assert_eq!(decoder.output_delay(), Duration::from_nanos(delay));
+
+  let path = "tests/samples/Simbai & Elke Bay - Energy.aac";
+  let decoder = Decoder::from_path(path).expect("Error creating decoder");
+  let info = decoder.stream_info();
+  assert_eq!(info.profile, Some(1));
+  assert_eq!(info.channels, 2);
+  assert!(info.bitrate > 0);
+}
+
+#[test]
+fn mp4_access() {
+  let path = "tests/samples/Simbai & Elke Bay - Energy.m4a";
+  let decoder = Decoder::from_path(path).expect("Error creating decoder");
+  let mp4 = decoder.mp4().expect("Missing MP4 reader");
+  assert_eq!(mp4.major_brand(), &redlux::mp4::FourCC::from(*b"M4A "));
+  let track = decoder.mp4_track().expect("Missing track");
+  assert_eq!(Some(track.track_id()), decoder.track_id());
+  assert_eq!(track.timescale(), 44100);
+
+  let path = "tests/samples/Simbai & Elke Bay - Energy.aac";
+  let decoder = Decoder::from_path(path).expect("Error creating decoder");
+  assert!(decoder.mp4().is_none());
+  assert!(decoder.mp4_track().is_none());
+}
+
+#[test]
+fn sample_table_stats() {
+  let path = "tests/samples/Simbai & Elke Bay - Energy.m4a";
+  let decoder = Decoder::from_path(path).expect("Error creating decoder");
+  let stats = decoder.sample_table_stats().expect("Missing stats");
+  assert_eq!(
+    stats,
+    SampleTableStats {
+      sample_count: 312,
+      total_size: 115214,
+      min_sample_size: 6,
+      max_sample_size: 586,
+      average_sample_size: 115214.0 / 312.0,
+      constant_sample_size: false,
+      chunk_count: 8,
+      min_samples_per_chunk: 11,
+      max_samples_per_chunk: 43,
+      large_offsets: false,
+      fragmented: false,
+    }
+  );
+
+  let path = "tests/samples/Simbai & Elke Bay - Energy.aac";
+  let decoder = Decoder::from_path(path).expect("Error creating decoder");
+  assert!(decoder.sample_table_stats().is_none());
+}
+
+/// File with a gap of zeros that aren't stored, for testing large files
+#[derive(Clone)]
+struct SparseFile {
+  /// Stored parts, as offsets and data
+  parts: Vec<(u64, Vec<u8>)>,
+  len: u64,
+  position: u64,
+}
+
+impl std::io::Read for SparseFile {
+  fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+    let len = (self.len.saturating_sub(self.position)).min(buf.len() as u64) as usize;
+    let buf = &mut buf[..len];
+    buf.fill(0);
+    for (offset, data) in &self.parts {
+      let end = offset + data.len() as u64;
+      if self.position < end && self.position + len as u64 > *offset {
+        let start = self.position.max(*offset);
+        let stop = end.min(self.position + len as u64);
+        let part = &data[(start - offset) as usize..(stop - offset) as usize];
+        buf[(start - self.position) as usize..(stop - self.position) as usize]
+          .copy_from_slice(part);
+      }
+    }
+    self.position += len as u64;
+    Ok(len)
+  }
+}
+
+impl std::io::Seek for SparseFile {
+  fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+    self.position = match pos {
+      std::io::SeekFrom::Start(position) => position,
+      std::io::SeekFrom::End(offset) => (self.len as i64 + offset) as u64,
+      std::io::SeekFrom::Current(offset) => (self.position as i64 + offset) as u64,
+    };
+    Ok(self.position)
+  }
+}
+
+#[test]
+fn fragmented_sidx() {
+  use mp4::WriteBox;
+
+  let path = "tests/samples/Simbai & Elke Bay - Energy.m4a";
+  let data = std::fs::read(path).expect("Error reading file");
+  let size = data.len() as u64;
+  let mut mp4 = mp4::Mp4Reader::read_header(Cursor::new(&data), size).expect("Error reading mp4");
+  let full: Vec<i16> = Decoder::from_path(path).unwrap().collect();
+
+  // the same samples in fragments of one sample each, since the mp4 crate
+  // reads every sample of a fragment from the start of its data
+  let mut moov = mp4.moov.clone();
+  let track_id = moov.traks[0].tkhd.track_id;
+  let timescale = moov.traks[0].mdia.mdhd.timescale;
+  let stbl = &mut moov.traks[0].mdia.minf.stbl;
+  stbl.stts.entries.clear();
+  stbl.stsc.entries.clear();
+  stbl.stsz.sample_count = 0;
+  stbl.stsz.sample_sizes.clear();
+  stbl.stco.as_mut().expect("Missing stco").entries.clear();
+  let ftyp_len = u32::from_be_bytes(data[..4].try_into().unwrap()) as usize;
+  let mut file = data[..ftyp_len].to_vec();
+  moov.write_box(&mut file).expect("Error writing moov");
+
+  // the mp4 crate doesn't write mvex and trun boxes, so they're written by
+  // hand
+  fn push_u32s(data: &mut Vec<u8>, values: &[u32]) {
+    for value in values {
+      data.extend_from_slice(&value.to_be_bytes());
+    }
+  }
+  push_u32s(&mut file, &[40, u32::from_be_bytes(*b"mvex")]);
+  push_u32s(&mut file, &[32, u32::from_be_bytes(*b"trex"), 0, track_id]);
+  push_u32s(&mut file, &[1, 1024, 0, 0]);
+  let moov_len = (file.len() - ftyp_len) as u32;
+  file[ftyp_len..ftyp_len + 4].copy_from_slice(&moov_len.to_be_bytes());
+
+  let fragment = |sample_id: u32, offset: u64, sample: &mp4::Mp4Sample| {
+    let mut fragment = Vec::new();
+    let mut push = |values: &[u32]| push_u32s(&mut fragment, values);
+    let moof_len = 80;
+    let base_data_offset = offset + moof_len + 8;
+    push(&[moof_len as u32, u32::from_be_bytes(*b"moof")]);
+    push(&[16, u32::from_be_bytes(*b"mfhd"), 0, sample_id]);
+    push(&[56, u32::from_be_bytes(*b"traf")]);
+    // with a base data offset
+    push(&[24, u32::from_be_bytes(*b"tfhd"), 0x01, track_id]);
+    push(&[(base_data_offset >> 32) as u32, base_data_offset as u32]);
+    // with sample durations and sizes
+    push(&[24, u32::from_be_bytes(*b"trun"), 0x300, 1]);
+    push(&[sample.duration, sample.bytes.len() as u32]);
+    push(&[8 + sample.bytes.len() as u32, u32::from_be_bytes(*b"mdat")]);
+    fragment.extend_from_slice(&sample.bytes);
+    fragment
+  };
+
+  // a sidx box with subsegments of 10 fragments
+  let sample_count = mp4.sample_count(track_id).expect("Error reading samples");
+  let samples: Vec<mp4::Mp4Sample> = (1..=sample_count)
+    .map(|sample_id| mp4.read_sample(track_id, sample_id).unwrap().unwrap())
+    .collect();
+  let sidx_len = 32 + 12 * samples.len().div_ceil(10);
+  let sidx_start = file.len();
+  let mut offset = (sidx_start + sidx_len) as u64;
+  let mut references = Vec::new();
+  let mut body = Vec::new();
+  for (i, subsegment) in samples.chunks(10).enumerate() {
+    let subsegment_start = body.len();
+    for (j, sample) in subsegment.iter().enumerate() {
+      let fragment = fragment((i * 10 + j + 1) as u32, offset, sample);
+      offset += fragment.len() as u64;
+      body.extend_from_slice(&fragment);
+    }
+    let size = (body.len() - subsegment_start) as u32;
+    let duration: u32 = subsegment.iter().map(|sample| sample.duration).sum();
+    references.extend_from_slice(&size.to_be_bytes());
+    references.extend_from_slice(&duration.to_be_bytes());
+    references.extend_from_slice(&0x9000_0000u32.to_be_bytes()); // starts with a SAP
+  }
+  file.extend_from_slice(&(sidx_len as u32).to_be_bytes());
+  file.extend_from_slice(b"sidx");
+  file.extend_from_slice(&[0; 4]); // version and flags
+  file.extend_from_slice(&track_id.to_be_bytes());
+  file.extend_from_slice(&timescale.to_be_bytes());
+  file.extend_from_slice(&[0; 10]); // earliest time, first offset and reserved
+  file.extend_from_slice(&(references.len() as u16 / 12).to_be_bytes());
+  file.extend_from_slice(&references);
+  assert_eq!(file.len(), sidx_start + sidx_len);
+  file.extend_from_slice(&body);
+
+  let mut decoder = Decoder::from_bytes(file).expect("Error creating decoder");
+  let stats = decoder.sample_table_stats().expect("Missing stats");
+  assert!(stats.fragmented);
+  assert_eq!(decoder.sample_durations(), Some(vec![1024; 312]));
+  let samples: Vec<i16> = decoder.by_ref().collect();
+  assert_eq!(samples, full);
+  for seconds in [5, 1, 6] {
+    decoder
+      .seek(Duration::from_secs(seconds))
+      .expect("Error seeking");
+    let skip = seconds as usize * 44100 * 2;
+    assert_eq!(
+      decoder.by_ref().take(1000).collect::<Vec<_>>(),
+      full[skip..skip + 1000]
+    );
+  }
+}
+
+#[test]
+fn large_file() {
+  use mp4::WriteBox;
+
+  let path = "tests/samples/Simbai & Elke Bay - Energy.m4a";
+  let data = std::fs::read(path).expect("Error reading file");
+  let size = data.len() as u64;
+  let mp4 = mp4::Mp4Reader::read_header(Cursor::new(&data), size).expect("Error reading mp4");
+  let full: Vec<i16> = Decoder::from_path(path).unwrap().collect();
+
+  // the same file with 4 GiB before the samples, so it needs a co64 box
+  let gap = 1 << 32;
+  let mut moov = mp4.moov.clone();
+  let stbl = &mut moov.traks[0].mdia.minf.stbl;
+  let stco = stbl.stco.take().expect("Missing stco");
+  let entries = stco.entries.iter().map(|&offset| offset as u64 + gap);
+  // the mp4 crate doesn't export the co64 box type
+  let co64 = stbl.co64.insert(Default::default());
+  co64.entries = entries.collect();
+  let mut moov_box = Vec::new();
+  moov.write_box(&mut moov_box).expect("Error writing moov");
+  let moov_start = data.windows(4).position(|w| w == b"moov").unwrap() - 4;
+  let payload = &data[44..moov_start];
+  let mut header = data[..44].to_vec();
+  header[36..].copy_from_slice(&(16 + gap + payload.len() as u64).to_be_bytes());
+  let payload_offset = 44 + gap;
+  let moov_offset = payload_offset + payload.len() as u64;
+  let len = moov_offset + moov_box.len() as u64;
+  let sparse = SparseFile {
+    parts: vec![
+      (0, header),
+      (payload_offset, payload.to_vec()),
+      (moov_offset, moov_box),
+    ],
+    len,
+    position: 0,
+  };
+
+  let tracks = redlux::probe::probe(sparse.clone(), len).expect("Error probing");
+  let original = redlux::probe::probe_path(path).expect("Error probing");
+  assert_eq!(tracks[0].duration, original[0].duration);
+  assert_eq!(tracks[0].bitrate, original[0].bitrate);
+  let decoder = DecoderBuilder::new().open(sparse.clone(), len);
+  assert_eq!(decoder.expect("Error creating decoder").format, Format::Mp4);
+
+  let mut decoder = Decoder::new_mpeg4(sparse, len).expect("Error creating decoder");
+  let stats = decoder.sample_table_stats().expect("Missing stats");
+  assert!(stats.large_offsets);
+  let range = decoder
+    .byte_range(Duration::from_secs(3), Duration::from_secs(5))
+    .expect("Error getting range");
+  assert!(range.start > gap && range.end < moov_offset);
+  let samples: Vec<i16> = decoder.by_ref().collect();
+  assert_eq!(samples, full);
+  decoder.seek(Duration::from_secs(5)).expect("Error seeking");
+  let skip = 5 * 44100 * 2;
+  assert_eq!(
+    decoder.take(1000).collect::<Vec<_>>(),
+    full[skip..skip + 1000]
+  );
+}
+
+#[test]
+fn malformed_input() {
+  let paths = [
+    "tests/samples/Simbai & Elke Bay - Energy.m4a",
+    "tests/samples/sine-he-aac-v1.aac",
+  ];
+  for path in paths {
+    let data = std::fs::read(path).expect("Error reading file");
+    for i in (0..data.len()).step_by(997) {
+      let mut corrupted = data.clone();
+      for byte in &mut corrupted[i..(i + 8).min(data.len())] {
+        *byte = !*byte;
+      }
+      for data in [&data[..i], &corrupted[..]] {
+        if let Ok(decoder) = Decoder::builder().open_bytes(data.to_vec()) {
+          decoder.take(100_000).for_each(drop);
+        }
+      }
+    }
+  }
+
+  // chunk offsets that don't fit in an i64
+  use mp4::WriteBox;
+  let data = std::fs::read(paths[0]).expect("Error reading file");
+  let size = data.len() as u64;
+  let mp4 = mp4::Mp4Reader::read_header(Cursor::new(&data), size).expect("Error reading mp4");
+  let mut moov = mp4.moov.clone();
+  let stbl = &mut moov.traks[0].mdia.minf.stbl;
+  let stco = stbl.stco.take().expect("Missing stco");
+  let co64 = stbl.co64.insert(Default::default());
+  co64.entries = stco
+    .entries
+    .iter()
+    .map(|&offset| offset as u64 | 1 << 63)
+    .collect();
+  let moov_start = data.windows(4).position(|w| w == b"moov").unwrap() - 4;
+  let mut data = data[..moov_start].to_vec();
+  moov.write_box(&mut data).expect("Error writing moov");
+  let mut decoder = Decoder::from_bytes(data).expect("Error creating decoder");
+  assert_eq!(decoder.by_ref().count(), 0);
+  assert!(decoder.iter_error.is_some());
+}